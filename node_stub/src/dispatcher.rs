@@ -6,6 +6,10 @@ use std::collections::HashMap;
 
 use std::ops::{Deref, DerefMut};
 
+use crate::framing::{self, Framing};
+
+// Handlers only signal that a message was bad, the details get logged.
+#[allow(clippy::result_unit_err)]
 pub trait Module {
     fn handle_incoming_msg(
         &mut self,
@@ -14,8 +18,15 @@ pub trait Module {
         node_id: u32,
         msg_str: &str,
     ) -> Result<(), ()>;
+
+    /// Returns a message the module wants to push to the server on its own,
+    /// if there is one.
+    fn poll_push_msg(&mut self) -> Option<String> {
+        None
+    }
 }
 
+#[allow(clippy::result_unit_err)]
 pub trait MsgSender {
     fn write_msg(
         &mut self,
@@ -23,14 +34,21 @@ pub trait MsgSender {
         module_name: &str,
         msg_str: &str,
     ) -> Result<(), ()>;
+
+    fn write_push_msg(&mut self, node_id: u32, module_name: &str, msg_str: &str) -> Result<(), ()>;
 }
 
+/// Transaction ID reserved for messages pushed by the node on its own.
+const PUSH_TRANSACTION_ID: u32 = 0;
+
 enum ParserState {
     WaitingForDollar,
     Receiving,
 }
 
-struct Comm<T: Write + Read>(T);
+/// Link to the server, its framing, and the node ID replies echo when on a
+/// half-duplex bus.
+struct Comm<T: Write + Read>(T, Framing, Option<u32>);
 
 impl<T> Deref for Comm<T>
 where
@@ -54,6 +72,10 @@ where
 
 pub struct Dispatcher<T: Write + Read> {
     comm: Comm<T>,
+    node_id: u32,
+    /// Whether other nodes share the link, so requests for them must be
+    /// ignored.
+    bus: bool,
     modules: HashMap<String, Box<dyn Module>>,
 }
 
@@ -61,9 +83,11 @@ impl<T> Dispatcher<T>
 where
     T: Write + Read,
 {
-    pub fn new(comm: T) -> Self {
+    pub fn new(comm: T, node_id: u32, framing: Framing, bus: bool) -> Self {
         Dispatcher {
-            comm: Comm(comm),
+            comm: Comm(comm, framing, if bus { Some(node_id) } else { None }),
+            node_id,
+            bus,
             modules: HashMap::new(),
         }
     }
@@ -72,38 +96,68 @@ where
         self.modules.insert(module_name.into(), module).is_some()
     }
 
+    /// Dispatches incoming messages until `done_flag` is set, or the other
+    /// side closes the connection.
     pub fn run_until(&mut self, done_flag: &Arc<AtomicBool>) {
-        let mut curr_msg = String::new();
+        let mut curr_frame = Vec::new();
         let mut parser_state = ParserState::WaitingForDollar;
 
         while !done_flag.load(Ordering::SeqCst) {
             let mut recv_buf = [0; 100];
 
             if let Ok(incoming_len) = self.comm.read(&mut recv_buf) {
-                for byte in recv_buf.iter().take(incoming_len) {
-                    let ch = *byte as char;
+                if incoming_len == 0 {
+                    trace!("End of stream.");
+                    return;
+                }
 
-                    match parser_state {
-                        ParserState::WaitingForDollar => {
-                            if ch == '$' {
+                for byte in recv_buf.iter().take(incoming_len) {
+                    match (self.comm.1, &parser_state) {
+                        (Framing::Ascii, ParserState::WaitingForDollar) => {
+                            if *byte == b'$' {
                                 parser_state = ParserState::Receiving;
                             }
                         }
-                        ParserState::Receiving => {
-                            curr_msg.push(ch);
-
-                            let len = curr_msg.len();
+                        (Framing::Ascii, ParserState::Receiving) => {
+                            curr_frame.push(*byte);
 
-                            if len >= 2 && &curr_msg[(len - 2)..] == "\r\n" {
-                                self.handle_incoming_msg(&curr_msg[..(len - 2)]);
+                            if curr_frame.ends_with(b"\r\n") {
+                                let len = curr_frame.len();
+                                let raw_msg =
+                                    String::from_utf8_lossy(&curr_frame[..(len - 2)]).into_owned();
+                                self.handle_incoming_msg(&raw_msg);
 
-                                curr_msg.clear();
+                                curr_frame.clear();
                                 parser_state = ParserState::WaitingForDollar;
                             }
                         }
+                        (Framing::Cobs, _) => {
+                            if *byte != 0 {
+                                curr_frame.push(*byte);
+                            } else if !curr_frame.is_empty() {
+                                self.handle_incoming_frame(&curr_frame);
+                                curr_frame.clear();
+                            }
+                        }
                     }
                 }
             }
+
+            self.push_pending_msgs();
+        }
+    }
+
+    fn push_pending_msgs(&mut self) {
+        for (module_name, module) in self.modules.iter_mut() {
+            while let Some(msg_str) = module.poll_push_msg() {
+                if self
+                    .comm
+                    .write_push_msg(self.node_id, module_name, &msg_str)
+                    .is_err()
+                {
+                    warn!("Error while pushing message: {}", msg_str);
+                }
+            }
         }
     }
 
@@ -124,7 +178,7 @@ where
         };
 
         let msg_str = &raw_msg[..(raw_msg.len() - 3)];
-        let expected_csum = calc_csum(msg_str);
+        let expected_csum = framing::calc_csum(msg_str);
 
         if msg_csum != expected_csum {
             warn!("Invalid checksum in message: {}", raw_msg);
@@ -135,7 +189,7 @@ where
             return;
         }
 
-        let mut msg_parts = msg_str.splitn(4, ',');
+        let mut msg_parts = msg_str.splitn(3, ',');
 
         let transaction_id = match msg_parts
             .next()
@@ -161,7 +215,7 @@ where
             }
         };
 
-        let module_name = match msg_parts.next() {
+        let module_msg_str = match msg_parts.next() {
             Some(val) => val,
             None => {
                 warn!("Could not parse module name from message: {}", msg_str);
@@ -169,10 +223,66 @@ where
             }
         };
 
+        self.dispatch_msg(transaction_id, node_id, module_msg_str);
+    }
+
+    fn handle_incoming_frame(&mut self, encoded_frame: &[u8]) {
+        trace!("Incoming raw frame: {:02X?}", encoded_frame);
+
+        let frame = match framing::cobs_decode(encoded_frame) {
+            Some(frame) if frame.len() >= 10 => frame,
+            _ => {
+                warn!("Invalid frame: {:02X?}", encoded_frame);
+                return;
+            }
+        };
+
+        let (content, crc_bytes) = frame.split_at(frame.len() - 2);
+        let frame_crc = u16::from_be_bytes([crc_bytes[0], crc_bytes[1]]);
+        let expected_crc = framing::crc16(content);
+
+        if frame_crc != expected_crc {
+            warn!("Invalid CRC in frame: {:02X?}", frame);
+            warn!(
+                "Expected '{:04X}', but received '{:04X}'.",
+                expected_crc, frame_crc
+            );
+            return;
+        }
+
+        let transaction_id = u32::from_le_bytes([content[0], content[1], content[2], content[3]]);
+        let node_id = u32::from_le_bytes([content[4], content[5], content[6], content[7]]);
+
+        match std::str::from_utf8(&content[8..]) {
+            Ok(module_msg_str) => self.dispatch_msg(transaction_id, node_id, module_msg_str),
+            Err(_) => warn!("Frame payload is not valid UTF-8: {:02X?}", frame),
+        }
+    }
+
+    /// Hands a `<module>,<payload>` message over to its module.
+    fn dispatch_msg(&mut self, transaction_id: u32, node_id: u32, module_msg_str: &str) {
+        if self.bus && node_id != self.node_id {
+            trace!("Ignoring message for node {}: {}", node_id, module_msg_str);
+            return;
+        }
+
+        let mut msg_parts = module_msg_str.splitn(2, ',');
+
+        let module_name = match msg_parts.next() {
+            Some(val) => val,
+            None => {
+                warn!(
+                    "Could not parse module name from message: {}",
+                    module_msg_str
+                );
+                return;
+            }
+        };
+
         let msg_payload_str = match msg_parts.next() {
             Some(val) => val,
             None => {
-                warn!("Message does not contain payload: {}", msg_str);
+                warn!("Message does not contain payload: {}", module_msg_str);
                 return;
             }
         };
@@ -182,10 +292,10 @@ where
                 .handle_incoming_msg(&mut self.comm, transaction_id, node_id, msg_payload_str)
                 .is_err()
             {
-                warn!("Error while handling message: {}", msg_str);
+                warn!("Error while handling message: {}", module_msg_str);
             }
         } else {
-            warn!("Unknown module name in message: {}", msg_str);
+            warn!("Unknown module name in message: {}", module_msg_str);
             warn!("Module name: {}", module_name);
         }
     }
@@ -201,22 +311,67 @@ where
         module_name: &str,
         msg_str: &str,
     ) -> Result<(), ()> {
-        let mut out_str = format!("${},{},{}", transaction_id, module_name, msg_str);
-
-        let csum = calc_csum(&out_str[1..]);
-
-        out_str.push_str(&format!("*{:02X}\r\n", csum));
+        let module_msg_str = format!("{},{}", module_name, msg_str);
 
         trace!(
-            "Responding with message: {}",
-            &out_str[..(out_str.len() - 2)]
+            "Responding with message: {} {}",
+            transaction_id,
+            module_msg_str
         );
 
+        self.write_frame(transaction_id, self.2, &module_msg_str)
+    }
+
+    fn write_push_msg(&mut self, node_id: u32, module_name: &str, msg_str: &str) -> Result<(), ()> {
+        let module_msg_str = format!("{},{}", module_name, msg_str);
+
+        trace!("Pushing message: {} {}", node_id, module_msg_str);
+
+        self.write_frame(PUSH_TRANSACTION_ID, Some(node_id), &module_msg_str)
+    }
+}
+
+impl<T> Comm<T>
+where
+    T: Write + Read,
+{
+    /// Writes a frame in the configured framing. Only pushes, and replies on a
+    /// bus, carry the node ID.
+    fn write_frame(
+        &mut self,
+        transaction_id: u32,
+        node_id: Option<u32>,
+        module_msg_str: &str,
+    ) -> Result<(), ()> {
+        let out = match self.1 {
+            Framing::Ascii => {
+                let msg_str = match node_id {
+                    Some(node_id) => format!("{},{},{}", transaction_id, node_id, module_msg_str),
+                    None => format!("{},{}", transaction_id, module_msg_str),
+                };
+
+                format!("${}*{:02X}\r\n", msg_str, framing::calc_csum(&msg_str)).into_bytes()
+            }
+            Framing::Cobs => {
+                let mut frame = transaction_id.to_le_bytes().to_vec();
+                if let Some(node_id) = node_id {
+                    frame.extend_from_slice(&node_id.to_le_bytes());
+                }
+                frame.extend_from_slice(module_msg_str.as_bytes());
+                let crc = framing::crc16(&frame);
+                frame.extend_from_slice(&crc.to_be_bytes());
+
+                let mut out = framing::cobs_encode(&frame);
+                out.push(0);
+                out
+            }
+        };
+
         self.0
-            .write(out_str.as_bytes())
+            .write(&out)
             .map_err(|_| ())
             .and_then(|bytes_written| {
-                if bytes_written == out_str.as_bytes().len() {
+                if bytes_written == out.len() {
                     Ok(())
                 } else {
                     Err(())
@@ -224,7 +379,3 @@ where
             })
     }
 }
-
-fn calc_csum(msg_str: &str) -> u8 {
-    msg_str.as_bytes().iter().fold(0, |csum, ch| csum ^ ch)
-}
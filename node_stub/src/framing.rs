@@ -0,0 +1,93 @@
+use std::str::FromStr;
+
+/// Wire encoding of frames, matching the server's `<prefix>_FRAMING` setting
+/// for the comm path the stub is on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Framing {
+    /// `$<trans_id>,<node_id>,<payload>*CS\r\n`, `CS` being the hex XOR of
+    /// the bytes between `$` and `*`.
+    Ascii,
+    /// Little endian `u32` transaction and node IDs, the payload text and a
+    /// big endian CRC-16/CCITT-FALSE, COBS encoded and zero terminated.
+    Cobs,
+}
+
+impl FromStr for Framing {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "ascii" => Ok(Framing::Ascii),
+            "cobs" => Ok(Framing::Cobs),
+            _ => Err(()),
+        }
+    }
+}
+
+pub fn calc_csum(msg_str: &str) -> u8 {
+    msg_str.as_bytes().iter().fold(0, |csum, ch| csum ^ ch)
+}
+
+/// CRC-16/CCITT-FALSE (polynomial 0x1021, initial value 0xFFFF).
+pub fn crc16(data: &[u8]) -> u16 {
+    data.iter().fold(0xFFFF, |crc, byte| {
+        (0..8).fold(crc ^ (u16::from(*byte) << 8), |crc, _| {
+            if crc & 0x8000 != 0 {
+                (crc << 1) ^ 0x1021
+            } else {
+                crc << 1
+            }
+        })
+    })
+}
+
+pub fn cobs_encode(data: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(data.len() + data.len() / 254 + 2);
+    let mut code_idx = 0;
+    let mut code = 1u8;
+    out.push(0);
+
+    for byte in data {
+        if *byte == 0 {
+            out[code_idx] = code;
+            code_idx = out.len();
+            out.push(0);
+            code = 1;
+        } else {
+            out.push(*byte);
+            code += 1;
+
+            if code == 0xFF {
+                out[code_idx] = code;
+                code_idx = out.len();
+                out.push(0);
+                code = 1;
+            }
+        }
+    }
+
+    out[code_idx] = code;
+    out
+}
+
+pub fn cobs_decode(data: &[u8]) -> Option<Vec<u8>> {
+    let mut out = Vec::with_capacity(data.len());
+    let mut idx = 0;
+
+    while idx < data.len() {
+        let code = data[idx] as usize;
+
+        if code == 0 || idx + code > data.len() {
+            return None;
+        }
+
+        out.extend_from_slice(&data[(idx + 1)..(idx + code)]);
+        idx += code;
+
+        if code < 0xFF && idx < data.len() {
+            out.push(0);
+        }
+    }
+
+    Some(out)
+}
@@ -0,0 +1,13 @@
+//! Simulated node and devices of the node stub, which the server's tests
+//! drive directly.
+
+#[macro_use]
+extern crate log;
+
+extern crate rand;
+
+pub mod dispatcher;
+pub mod framing;
+pub mod meteo;
+pub mod modbus;
+pub mod sys;
@@ -11,19 +11,109 @@ extern crate env_logger;
 
 extern crate rand;
 
-mod dispatcher;
-mod meteo;
+extern crate ratfist_node_stub;
 
+use ratfist_node_stub::{dispatcher, framing, meteo, modbus, sys};
 use serial::prelude::*;
 
+use std::io::{Read, Write};
+use std::net::TcpListener;
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
+use std::time::Duration;
+
+struct StubConfig {
+    node_id: u32,
+    push_interval: Option<Duration>,
+    framing: framing::Framing,
+    bus: bool,
+    /// Simulate Modbus RTU slaves at these addresses instead of a node.
+    modbus_addrs: Option<Vec<u8>>,
+}
+
+impl StubConfig {
+    /// Modbus RTU frames end with silence, which reads timing out detect.
+    fn read_timeout(&self) -> Duration {
+        match self.modbus_addrs {
+            Some(_) => modbus::FRAME_GAP,
+            None => Duration::from_millis(100),
+        }
+    }
+}
+
+fn run_dispatcher<T: Read + Write>(comm: T, config: &StubConfig, end_condition: &Arc<AtomicBool>) {
+    let mut disp = dispatcher::Dispatcher::new(comm, config.node_id, config.framing, config.bus);
+    disp.register_handler_module("SYS", Box::new(sys::SysModule));
+    disp.register_handler_module(
+        "METEO",
+        Box::new(meteo::MeteoModule::new(config.push_interval)),
+    );
+
+    trace!("Starting main loop.");
+
+    disp.run_until(end_condition);
+}
+
+fn run_link<T: Read + Write>(comm: T, config: &StubConfig, end_condition: &Arc<AtomicBool>) {
+    match &config.modbus_addrs {
+        Some(slave_addrs) => {
+            trace!("Starting Modbus slaves {:?}.", slave_addrs);
+
+            modbus::Slaves::new(comm, slave_addrs).run_until(end_condition);
+        }
+        None => run_dispatcher(comm, config, end_condition),
+    }
+}
+
+/// Serves one TCP connection at a time, the way a ser2net style bridge would.
+fn serve_tcp(listen_addr: &str, config: &StubConfig, end_condition: &Arc<AtomicBool>) {
+    let listener = TcpListener::bind(listen_addr).expect("could not bind TCP listener");
+    listener
+        .set_nonblocking(true)
+        .expect("could not make TCP listener non-blocking");
+
+    info!("Listening on {}.", listen_addr);
+
+    while !end_condition.load(Ordering::SeqCst) {
+        match listener.accept() {
+            Ok((stream, peer_addr)) => {
+                info!("Connection from {}.", peer_addr);
+
+                stream
+                    .set_nonblocking(false)
+                    .expect("could not make TCP stream blocking");
+                stream
+                    .set_read_timeout(Some(config.read_timeout()))
+                    .expect("could not set TCP stream read timeout");
+
+                run_link(stream, config, end_condition);
+
+                info!("Connection from {} closed.", peer_addr);
+            }
+            Err(_) => std::thread::sleep(Duration::from_millis(100)),
+        }
+    }
+}
 
 fn main() {
     // Setup program usage & get arguments
     let matches = clap::App::new("Ratfist MCU node stub")
         .version(crate_version!())
         .arg_from_usage("<serial port> 'device file of the serial port used for communication'")
+        .arg_from_usage(
+            "-l, --listen 'treat <serial port> as a TCP address to listen on, e.g. 127.0.0.1:5000'",
+        )
+        .arg_from_usage("-n, --node-id=[ID] 'node ID used in pushed messages (default 0)'")
+        .arg_from_usage(
+            "-p, --push-interval=[SECS] 'push a temperature reading this often, unasked'",
+        )
+        .arg_from_usage("-f, --framing=[FRAMING] 'ascii (default) or cobs, as set on the server'")
+        .arg_from_usage(
+            "-b, --bus 'share a half-duplex bus: answer only requests for this node ID, echoing it'",
+        )
+        .arg_from_usage(
+            "-m, --modbus=[ADDRS] 'act as Modbus RTU slaves at these comma separated addresses instead'",
+        )
         .get_matches();
 
     // Initialize logger
@@ -36,6 +126,40 @@ fn main() {
     ctrlc::set_handler(move || end_condition_handler.store(true, Ordering::SeqCst))
         .expect("error setting signal handler");
 
+    let config = StubConfig {
+        node_id: matches
+            .value_of("node-id")
+            .map(|val| val.parse().expect("invalid node ID"))
+            .unwrap_or(0),
+        push_interval: matches
+            .value_of("push-interval")
+            .map(|val| Duration::from_secs(val.parse().expect("invalid push interval"))),
+        framing: matches
+            .value_of("framing")
+            .map(|val| val.parse().expect("invalid framing"))
+            .unwrap_or(framing::Framing::Ascii),
+        bus: matches.is_present("bus"),
+        modbus_addrs: matches.value_of("modbus").map(|val| {
+            val.split(',')
+                .map(|addr| match addr.trim().parse() {
+                    Ok(addr) if (1..=247).contains(&addr) => addr,
+                    _ => panic!("invalid Modbus slave address: {}", addr),
+                })
+                .collect()
+        }),
+    };
+
+    if matches.is_present("listen") {
+        let listen_addr = matches
+            .value_of("serial port")
+            .expect("missing TCP listen address");
+
+        serve_tcp(listen_addr, &config, &end_condition);
+
+        trace!("Graceful end.");
+        return;
+    }
+
     // Init serial port
     let sp_path = matches
         .value_of("serial port")
@@ -53,14 +177,12 @@ fn main() {
     serial_port
         .configure(&settings)
         .expect("could not configure the serial port");
+    serial_port
+        .set_timeout(config.read_timeout())
+        .expect("could not set the serial port timeout");
 
     // Start dispatcher & loop until Ctrl-C
-    let mut disp = dispatcher::Dispatcher::new(serial_port);
-    disp.register_handler_module("METEO", Box::new(meteo::MeteoModule::new()));
-
-    trace!("Starting main loop.");
-
-    disp.run_until(&end_condition);
+    run_link(serial_port, &config, &end_condition);
 
     trace!("Graceful end.");
 }
@@ -5,6 +5,7 @@ use rand::distributions::Normal;
 use rand::prelude::*;
 
 use std::collections::HashMap;
+use std::time::{Duration, Instant};
 
 #[derive(PartialEq, Eq, Debug, Copy, Clone, Hash)]
 enum MeasurementType {
@@ -14,14 +15,41 @@ enum MeasurementType {
     LightLevel,
 }
 
+impl MeasurementType {
+    fn from_token(token: &str) -> Option<MeasurementType> {
+        match token {
+            "TEMPERATURE" => Some(MeasurementType::Temperature),
+            "HUMIDITY" => Some(MeasurementType::Humidity),
+            "PRESSURE" => Some(MeasurementType::Pressure),
+            "LIGHT_LEVEL" => Some(MeasurementType::LightLevel),
+            _ => None,
+        }
+    }
+}
+
+/// Return value reported for readings of sensor types the stub doesn't know.
+const RET_VAL_UNSUPPORTED_TYPE: i32 = -5;
+
+/// Sensor channels the stub reports when asked for its sensor list.
+const SENSOR_LIST: &[(&str, u32)] = &[
+    ("PRESSURE", 0),
+    ("TEMPERATURE", 0),
+    ("HUMIDITY", 0),
+    ("LIGHT_LEVEL", 0),
+];
+
 #[derive(Debug)]
 pub struct MeteoModule {
     last_values: HashMap<MeasurementType, HashMap<u32, f64>>,
     rng: SmallRng,
+    push_interval: Option<Duration>,
+    last_push: Instant,
 }
 
 impl MeteoModule {
-    pub fn new() -> MeteoModule {
+    /// With a `push_interval`, the module also pushes a temperature reading
+    /// of channel 0 that often, without being asked.
+    pub fn new(push_interval: Option<Duration>) -> MeteoModule {
         let mut last_values = HashMap::new();
         last_values.insert(MeasurementType::Temperature, HashMap::new());
         last_values.insert(MeasurementType::Humidity, HashMap::new());
@@ -30,7 +58,12 @@ impl MeteoModule {
 
         let rng = SmallRng::from_entropy();
 
-        MeteoModule { last_values, rng }
+        MeteoModule {
+            last_values,
+            rng,
+            push_interval,
+            last_push: Instant::now(),
+        }
     }
     fn generate_new_value(&mut self, meas_type: MeasurementType, ch_num: u32) -> f32 {
         let dist = {
@@ -70,6 +103,38 @@ impl Module for MeteoModule {
         let mut values = msg_str.split(',');
 
         let msg_type = values.next().ok_or(())?;
+
+        if msg_type == "LIST_SENSORS" {
+            let sensor_list_str = SENSOR_LIST
+                .iter()
+                .map(|(sensor_type, ch_num)| format!(",{}:{}", sensor_type, ch_num))
+                .collect::<String>();
+
+            return msg_writer.write_msg(
+                transaction_id,
+                "METEO",
+                &format!("SENSOR_LIST{}", sensor_list_str),
+            );
+        }
+
+        if msg_type == "GET_MULTI" {
+            let mut reply_str = "MULTI_REPLY".to_string();
+
+            for entry in values {
+                let (type_token, ch_str) = entry.split_once(':').ok_or(())?;
+                let ch_num = ch_str.parse::<u32>().map_err(|_| ())?;
+
+                let val_str = match MeasurementType::from_token(type_token) {
+                    Some(meas_type) => self.generate_new_value(meas_type, ch_num).to_string(),
+                    None => format!("ERR:{}", RET_VAL_UNSUPPORTED_TYPE),
+                };
+
+                reply_str.push_str(&format!(",{}:{}:{}", type_token, ch_num, val_str));
+            }
+
+            return msg_writer.write_msg(transaction_id, "METEO", &reply_str);
+        }
+
         let ch_num = values.next().ok_or(())?.parse::<u32>().map_err(|_| ())?;
 
         let response_payload_str = match msg_type {
@@ -101,4 +166,19 @@ impl Module for MeteoModule {
 
         msg_writer.write_msg(transaction_id, "METEO", &response_payload_str)
     }
+
+    fn poll_push_msg(&mut self) -> Option<String> {
+        let push_interval = self.push_interval?;
+
+        if self.last_push.elapsed() < push_interval {
+            return None;
+        }
+
+        self.last_push = Instant::now();
+
+        Some(format!(
+            "TEMPERATURE_REPLY,0,{}",
+            self.generate_new_value(MeasurementType::Temperature, 0)
+        ))
+    }
 }
@@ -0,0 +1,239 @@
+//! Simulated Modbus RTU slaves, standing in for the anemometer and rain gauge
+//! of a weather mast. Every slave answers reads of these registers:
+//!
+//! - input 0: wind speed in 0.1 m/s (u16)
+//! - input 1-2: wind speed in m/s (f32, high word first)
+//! - holding 0-1: rain total in 0.1 mm (u32, high word first)
+//! - holding 2: temperature in 0.1 °C (i16)
+
+use rand::distributions::Normal;
+use rand::prelude::*;
+
+use std::collections::HashMap;
+use std::io::{Read, Write};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Silence ending a frame. Reads time out after it, so the link must be set
+/// up with it as the read timeout.
+pub const FRAME_GAP: Duration = Duration::from_millis(5);
+
+const FUNC_READ_HOLDING: u8 = 0x03;
+const FUNC_READ_INPUT: u8 = 0x04;
+
+const EXC_ILLEGAL_FUNCTION: u8 = 0x01;
+const EXC_ILLEGAL_DATA_ADDRESS: u8 = 0x02;
+const EXC_ILLEGAL_DATA_VALUE: u8 = 0x03;
+
+const BROADCAST_ADDR: u8 = 0;
+const MAX_READ_COUNT: u16 = 125;
+
+/// CRC-16/MODBUS (polynomial 0xA001 reflected, initial value 0xFFFF), sent
+/// low byte first.
+pub fn crc16(data: &[u8]) -> u16 {
+    data.iter().fold(0xFFFF, |crc, byte| {
+        (0..8).fold(crc ^ u16::from(*byte), |crc, _| {
+            if crc & 0x0001 != 0 {
+                (crc >> 1) ^ 0xA001
+            } else {
+                crc >> 1
+            }
+        })
+    })
+}
+
+#[derive(Debug)]
+struct Station {
+    /// m/s
+    wind_speed: f64,
+    /// 0.1 mm
+    rain_total: u32,
+    /// °C
+    temperature: f64,
+}
+
+impl Station {
+    fn new() -> Station {
+        Station {
+            wind_speed: 4.0,
+            rain_total: 0,
+            temperature: 15.0,
+        }
+    }
+
+    fn update(&mut self, rng: &mut SmallRng) {
+        self.wind_speed = rng.sample(Normal::new(self.wind_speed, 0.5)).max(0.0);
+        self.temperature = rng.sample(Normal::new(self.temperature, 0.2));
+
+        if rng.gen_bool(0.2) {
+            self.rain_total += 1;
+        }
+    }
+
+    fn register(&self, function: u8, reg_addr: u16) -> Option<u16> {
+        let wind_speed_bits = (self.wind_speed as f32).to_bits();
+
+        match (function, reg_addr) {
+            (FUNC_READ_INPUT, 0) => Some((self.wind_speed * 10.0).round() as u16),
+            (FUNC_READ_INPUT, 1) => Some((wind_speed_bits >> 16) as u16),
+            (FUNC_READ_INPUT, 2) => Some(wind_speed_bits as u16),
+            (FUNC_READ_HOLDING, 0) => Some((self.rain_total >> 16) as u16),
+            (FUNC_READ_HOLDING, 1) => Some(self.rain_total as u16),
+            (FUNC_READ_HOLDING, 2) => Some((self.temperature * 10.0).round() as i16 as u16),
+            _ => None,
+        }
+    }
+}
+
+/// Stations at their slave addresses, answering request frames.
+pub struct Stations {
+    stations: HashMap<u8, Station>,
+    rng: SmallRng,
+}
+
+impl Stations {
+    pub fn new(slave_addrs: &[u8]) -> Stations {
+        Stations {
+            stations: slave_addrs
+                .iter()
+                .map(|&addr| (addr, Station::new()))
+                .collect(),
+            rng: SmallRng::from_entropy(),
+        }
+    }
+
+    /// Reply frame to the request `frame`, `None` if it's not for any of the
+    /// stations or not a valid frame at all.
+    pub fn answer(&mut self, frame: &[u8]) -> Option<Vec<u8>> {
+        debug!("Incoming frame: {:02X?}", frame);
+
+        if frame.len() < 4 {
+            warn!("Frame too short.");
+            return None;
+        }
+
+        let (body, crc_bytes) = frame.split_at(frame.len() - 2);
+
+        if crc16(body) != u16::from_le_bytes([crc_bytes[0], crc_bytes[1]]) {
+            warn!("CRC mismatch.");
+            return None;
+        }
+
+        let slave_addr = body[0];
+        let function = body[1];
+
+        if slave_addr == BROADCAST_ADDR {
+            trace!("Broadcast, not answering.");
+            return None;
+        }
+
+        let station = match self.stations.get_mut(&slave_addr) {
+            Some(station) => station,
+            None => {
+                trace!("Frame for slave {}, ignoring.", slave_addr);
+                return None;
+            }
+        };
+
+        let reply = match function {
+            FUNC_READ_HOLDING | FUNC_READ_INPUT if body.len() == 6 => {
+                let start = u16::from_be_bytes([body[2], body[3]]);
+                let count = u16::from_be_bytes([body[4], body[5]]);
+
+                if count == 0 || count > MAX_READ_COUNT {
+                    Err(EXC_ILLEGAL_DATA_VALUE)
+                } else {
+                    station.update(&mut self.rng);
+
+                    (0..count)
+                        .map(|offset| start.checked_add(offset))
+                        .map(|reg_addr| reg_addr.and_then(|a| station.register(function, a)))
+                        .collect::<Option<Vec<u16>>>()
+                        .ok_or(EXC_ILLEGAL_DATA_ADDRESS)
+                }
+            }
+            FUNC_READ_HOLDING | FUNC_READ_INPUT => Err(EXC_ILLEGAL_DATA_VALUE),
+            _ => Err(EXC_ILLEGAL_FUNCTION),
+        };
+
+        let mut out = vec![slave_addr];
+
+        match reply {
+            Ok(registers) => {
+                out.push(function);
+                out.push(registers.len() as u8 * 2);
+                out.extend(registers.iter().flat_map(|reg| reg.to_be_bytes()));
+            }
+            Err(exception) => {
+                warn!("Answering with exception 0x{:02X}.", exception);
+                out.push(function | 0x80);
+                out.push(exception);
+            }
+        }
+
+        out.extend_from_slice(&crc16(&out).to_le_bytes());
+
+        debug!("Outgoing frame: {:02X?}", out);
+
+        Some(out)
+    }
+}
+
+/// Answers requests for any of its slave addresses, ignoring the rest of the
+/// bus traffic.
+pub struct Slaves<T: Read + Write> {
+    comm: T,
+    stations: Stations,
+}
+
+impl<T> Slaves<T>
+where
+    T: Read + Write,
+{
+    pub fn new(comm: T, slave_addrs: &[u8]) -> Slaves<T> {
+        Slaves {
+            comm,
+            stations: Stations::new(slave_addrs),
+        }
+    }
+
+    pub fn run_until(&mut self, done_flag: &Arc<AtomicBool>) {
+        let mut curr_frame = Vec::new();
+
+        while !done_flag.load(Ordering::SeqCst) {
+            let mut recv_buf = [0; 256];
+
+            match self.comm.read(&mut recv_buf) {
+                Ok(0) => {
+                    trace!("End of stream.");
+                    return;
+                }
+                Ok(incoming_len) => curr_frame.extend_from_slice(&recv_buf[..incoming_len]),
+                // Timed out, the line has been silent for a frame gap
+                Err(_) => {
+                    if !curr_frame.is_empty() {
+                        self.handle_incoming_frame(&curr_frame);
+                        curr_frame.clear();
+                    }
+                }
+            }
+        }
+    }
+
+    fn handle_incoming_frame(&mut self, frame: &[u8]) {
+        let out = match self.stations.answer(frame) {
+            Some(out) => out,
+            None => return,
+        };
+
+        if self
+            .comm
+            .write_all(&out)
+            .and_then(|_| self.comm.flush())
+            .is_err()
+        {
+            warn!("Error while writing reply.");
+        }
+    }
+}
@@ -0,0 +1,35 @@
+use crate::dispatcher::Module;
+use crate::dispatcher::MsgSender;
+
+/// Version of the framed message protocol spoken by the stub.
+const PROTOCOL_VERSION: u32 = 1;
+
+/// Answers the messages every node firmware has to understand.
+#[derive(Debug)]
+pub struct SysModule;
+
+impl Module for SysModule {
+    fn handle_incoming_msg(
+        &mut self,
+        msg_writer: &mut dyn MsgSender,
+        transaction_id: u32,
+        _node_id: u32,
+        msg_str: &str,
+    ) -> Result<(), ()> {
+        debug!("Handling message: {} {}", transaction_id, msg_str);
+
+        let response_payload_str = match msg_str {
+            "HELLO" => format!(
+                "HELLO_REPLY,{},{}",
+                env!("CARGO_PKG_VERSION"),
+                PROTOCOL_VERSION
+            ),
+            unknown_msg_type_str => {
+                warn!("Unknown message type: {}", unknown_msg_type_str);
+                return Err(());
+            }
+        };
+
+        msg_writer.write_msg(transaction_id, "SYS", &response_payload_str)
+    }
+}
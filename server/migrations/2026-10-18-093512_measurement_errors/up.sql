@@ -0,0 +1,10 @@
+PRAGMA foreign_keys = ON;
+
+CREATE TABLE measurement_errors (
+	id INTEGER PRIMARY KEY NOT NULL,
+	sensor_id INTEGER NOT NULL,
+	kind TEXT NOT NULL,
+	message TEXT NOT NULL,
+	occurred_at INTEGER NOT NULL,
+	FOREIGN KEY (sensor_id) REFERENCES sensors(id) ON DELETE CASCADE
+);
@@ -3,14 +3,23 @@ use diesel::prelude::*;
 use diesel::result::Error as DieselError;
 use diesel::sqlite::SqliteConnection;
 
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::time::Duration;
+
 use clap::{arg_enum, crate_version, value_t_or_exit, App, AppSettings, Arg};
 
 use prettytable as pt;
 use pt::{cell, row};
 
 use ratfist_server::run_migrations;
+use ratfist_server::comm;
+use ratfist_server::comm::stats::{CommStats, LinkStats};
 use ratfist_server::db::models::Node;
 use ratfist_server::meteo::models::{Sensor, SensorTypeEnum};
+use ratfist_server::meteo::node::NodeCapabilities;
+
+use serde::de::DeserializeOwned;
 
 /// Prints the supplied data to STDOUT as a formatted ASCII table
 fn print_table(mut title_row: pt::Row, table_rows: Vec<pt::Row>) {
@@ -175,10 +184,8 @@ fn db_add_sensor(
     parent_node_id: i32,
     sensor_id: i32,
     sensor_name: &str,
-    sensor_type: SensorTypes,
+    sensor_type_enum: SensorTypeEnum,
 ) -> Result<(), DieselError> {
-    let sensor_type_enum: SensorTypeEnum = sensor_type.into();
-
     let nid = {
         use ratfist_server::db::schema::nodes::dsl::*;
 
@@ -213,7 +220,13 @@ fn add_sensor(
     sensor_name: &str,
     sensor_type: SensorTypes,
 ) {
-    match db_add_sensor(db_conn, parent_node_id, sensor_id, sensor_name, sensor_type) {
+    match db_add_sensor(
+        db_conn,
+        parent_node_id,
+        sensor_id,
+        sensor_name,
+        sensor_type.into(),
+    ) {
         Ok(_) => {
             println!(
                 "Succesfully created new sensor for node #{}: public_id {}, name '{}', type {}",
@@ -236,11 +249,274 @@ fn add_sensor(
     }
 }
 
+/// Asks a node for the sensors it has, through the capabilities endpoint of a
+/// running server, and adds the ones not yet in the database, named after
+/// their type and ID.
+fn discover_sensors(db_conn: &SqliteConnection, server_addr: &str, public_node_id: i32) {
+    let capabilities: NodeCapabilities = match fetch_json(
+        server_addr,
+        &format!("/meteo/{}/capabilities", public_node_id),
+    ) {
+        Ok(capabilities) => capabilities,
+        Err(e) => {
+            println!("Failed to query node {}: {}", public_node_id, e);
+            return;
+        }
+    };
+
+    println!(
+        "Node {}: firmware version {}, protocol version {}",
+        public_node_id,
+        capabilities
+            .firmware_version
+            .unwrap_or_else(|| "n/a".to_string()),
+        capabilities
+            .protocol_version
+            .map(|v| v.to_string())
+            .unwrap_or_else(|| "n/a".to_string())
+    );
+
+    let known_sensors = db_get_sensor_list(db_conn, public_node_id).expect("database access error");
+
+    let mut table_rows = Vec::new();
+
+    for sensor in capabilities.sensors {
+        let sensor_id = sensor.sensor_id as i32;
+        let sensor_name = format!("{}_{}", sensor.sensor_type.as_ref(), sensor_id);
+
+        let status = if known_sensors
+            .iter()
+            .any(|s| s.public_id == sensor_id && s.sensor_type == sensor.sensor_type)
+        {
+            "already present".to_string()
+        } else {
+            match db_add_sensor(
+                db_conn,
+                public_node_id,
+                sensor_id,
+                &sensor_name,
+                sensor.sensor_type,
+            ) {
+                Ok(_) => "added".to_string(),
+                Err(e) => format!("DB error: {:?}", e),
+            }
+        };
+
+        table_rows.push(row![
+            sensor_id,
+            sensor.sensor_type.as_ref(),
+            sensor_name,
+            status
+        ]);
+    }
+
+    print_table(row!["Public ID", "Type", "Name", "Status"], table_rows);
+}
+
+/// Fetches `path` from a running server and parses the JSON response. Comm
+/// paths only live in the server process, so anything going through them is
+/// asked of the server.
+fn fetch_json<T: DeserializeOwned>(server_addr: &str, path: &str) -> Result<T, String> {
+    let mut stream = TcpStream::connect(server_addr)
+        .map_err(|e| format!("could not connect to {}: {}", server_addr, e))?;
+
+    stream
+        .set_read_timeout(Some(Duration::from_secs(10)))
+        .map_err(|e| e.to_string())?;
+
+    write!(
+        stream,
+        "GET {} HTTP/1.0\r\nHost: {}\r\nAccept: application/json\r\n\r\n",
+        path, server_addr
+    )
+    .map_err(|e| format!("request failed: {}", e))?;
+
+    let mut response = String::new();
+    stream
+        .read_to_string(&mut response)
+        .map_err(|e| format!("reading the response failed: {}", e))?;
+
+    let (head, body) = response
+        .split_once("\r\n\r\n")
+        .ok_or_else(|| "malformed HTTP response".to_string())?;
+
+    let status_line = head.lines().next().unwrap_or("");
+    if status_line.split_whitespace().nth(1) != Some("200") {
+        return Err(format!("server responded with '{}'", status_line));
+    }
+
+    serde_json::from_str(body).map_err(|e| format!("invalid JSON response: {}", e))
+}
+
+/// Prints tables with the link quality counters and latency histograms of all
+/// comm paths the server has opened.
+fn print_comm_stats(server_addr: &str) {
+    let comm_stats: CommStats = match fetch_json(server_addr, "/diag/comm") {
+        Ok(comm_stats) => comm_stats,
+        Err(e) => {
+            println!("Failed to get comm stats from the server: {}", e);
+            return;
+        }
+    };
+
+    let paths: Vec<(String, LinkStats)> = comm_stats
+        .serial
+        .into_iter()
+        .map(|(id, stats)| (format!("serial {}", id), stats))
+        .chain(
+            comm_stats
+                .tcp
+                .into_iter()
+                .map(|(id, stats)| (format!("tcp {}", id), stats)),
+        )
+        .chain(
+            comm_stats
+                .i2c
+                .into_iter()
+                .map(|(id, stats)| (format!("i2c {}", id), stats)),
+        )
+        .chain(
+            comm_stats
+                .modbus
+                .into_iter()
+                .map(|(id, stats)| (format!("modbus {}", id), stats)),
+        )
+        .collect();
+
+    let format_ms = |ms: Option<f64>| ms.map(|ms| format!("{:.1}", ms)).unwrap_or_default();
+
+    print_table(
+        row![
+            "Path",
+            "Up",
+            "Sent",
+            "Replies",
+            "Pushes",
+            "Checksum Fails",
+            "Malformed",
+            "Unexpected IDs",
+            "Foreign",
+            "Retransmits",
+            "Timeouts",
+            "Transfer Errors",
+            "Reconnects",
+            "Mean ms",
+            "Max ms"
+        ],
+        paths
+            .iter()
+            .map(|(path, stats)| {
+                row![
+                    path,
+                    if stats.link_up { "yes" } else { "no" },
+                    stats.frames_sent,
+                    stats.replies_received,
+                    stats.pushes_received,
+                    stats.checksum_failures,
+                    stats.malformed_frames,
+                    stats.unexpected_trans_ids,
+                    stats.foreign_frames,
+                    stats.retransmits,
+                    stats.timeouts,
+                    stats.transfer_errors,
+                    stats.reconnects,
+                    format_ms(stats.latency.mean_ms()),
+                    format_ms(Some(stats.latency.max_ms).filter(|_| stats.latency.count > 0))
+                ]
+            })
+            .collect(),
+    );
+
+    let bucket_titles = match paths.first() {
+        Some((_, stats)) => stats
+            .latency
+            .buckets
+            .iter()
+            .map(|bucket| match bucket.le_ms {
+                Some(le_ms) => format!("<= {} ms", le_ms),
+                None => "slower".to_string(),
+            })
+            .collect(),
+        None => Vec::new(),
+    };
+
+    println!("Round-trip latency histogram:");
+    print_table(
+        pt::Row::new(
+            std::iter::once("Path".to_string())
+                .chain(bucket_titles)
+                .map(|title| pt::Cell::new(&title))
+                .collect(),
+        ),
+        paths
+            .iter()
+            .map(|(path, stats)| {
+                pt::Row::new(
+                    std::iter::once(path.clone())
+                        .chain(
+                            stats
+                                .latency
+                                .buckets
+                                .iter()
+                                .map(|bucket| bucket.count.to_string()),
+                        )
+                        .map(|val| pt::Cell::new(&val))
+                        .collect(),
+                )
+            })
+            .collect(),
+    );
+}
+
+/// Prints a table with the devices answering on an I2C bus, and the known
+/// chips among them.
+fn scan_i2c_bus(bus_id: u32) {
+    let comm_path = match comm::get_i2c_comm_path(bus_id) {
+        Ok(comm_path) => comm_path,
+        Err(e) => {
+            println!("Failed to open I2C bus {}: {:?}", bus_id, e);
+            return;
+        }
+    };
+
+    let devices = comm_path.lock().expect("mutex poisoned").scan();
+
+    println!("Devices on I2C bus {}:", bus_id);
+    print_table(
+        row!["Address", "Chip", "Identified By"],
+        devices
+            .into_iter()
+            .map(|device| {
+                row![
+                    format!("0x{:02x}", device.addr),
+                    device.chip.unwrap_or("unknown"),
+                    match (device.chip, device.chip_id_matched) {
+                        (Some(_), true) => "chip ID",
+                        (Some(_), false) => "address",
+                        (None, _) => "",
+                    }
+                ]
+            })
+            .collect(),
+    );
+}
+
 arg_enum! {
     #[derive(Debug, Clone, Copy)]
     enum RouteTypes {
         Serial,
-        EnviroPHat
+        Tcp,
+        EnviroPHat,
+        Bme280,
+        Sht3x,
+        Htu21d,
+        Bh1750,
+        Veml7700,
+        GenericI2c,
+        ModbusRtu,
+        OneWire,
+        Iio,
+        Hwmon
     }
 }
 
@@ -248,7 +524,18 @@ impl AsRef<str> for RouteTypes {
     fn as_ref(&self) -> &'static str {
         match &self {
             RouteTypes::Serial => "serial",
+            RouteTypes::Tcp => "tcp",
             RouteTypes::EnviroPHat => "envirophat",
+            RouteTypes::Bme280 => "bme280",
+            RouteTypes::Sht3x => "sht3x",
+            RouteTypes::Htu21d => "htu21d",
+            RouteTypes::Bh1750 => "bh1750",
+            RouteTypes::Veml7700 => "veml7700",
+            RouteTypes::GenericI2c => "generic_i2c",
+            RouteTypes::ModbusRtu => "modbus_rtu",
+            RouteTypes::OneWire => "onewire",
+            RouteTypes::Iio => "iio",
+            RouteTypes::Hwmon => "hwmon",
         }
     }
 }
@@ -259,7 +546,22 @@ arg_enum! {
         Pressure,
         Temperature,
         Humidity,
-        LightLevel
+        LightLevel,
+        ColorRed,
+        ColorGreen,
+        ColorBlue,
+        Illuminance,
+        ColorTemperature,
+        AccelerationX,
+        AccelerationY,
+        AccelerationZ,
+        MagneticFieldX,
+        MagneticFieldY,
+        MagneticFieldZ,
+        Heading,
+        Voltage,
+        WindSpeed,
+        Rainfall
     }
 }
 
@@ -270,6 +572,21 @@ impl From<SensorTypes> for SensorTypeEnum {
             SensorTypes::Temperature => SensorTypeEnum::Temperature,
             SensorTypes::Humidity => SensorTypeEnum::Humidity,
             SensorTypes::LightLevel => SensorTypeEnum::LightLevel,
+            SensorTypes::ColorRed => SensorTypeEnum::ColorRed,
+            SensorTypes::ColorGreen => SensorTypeEnum::ColorGreen,
+            SensorTypes::ColorBlue => SensorTypeEnum::ColorBlue,
+            SensorTypes::Illuminance => SensorTypeEnum::Illuminance,
+            SensorTypes::ColorTemperature => SensorTypeEnum::ColorTemperature,
+            SensorTypes::AccelerationX => SensorTypeEnum::AccelerationX,
+            SensorTypes::AccelerationY => SensorTypeEnum::AccelerationY,
+            SensorTypes::AccelerationZ => SensorTypeEnum::AccelerationZ,
+            SensorTypes::MagneticFieldX => SensorTypeEnum::MagneticFieldX,
+            SensorTypes::MagneticFieldY => SensorTypeEnum::MagneticFieldY,
+            SensorTypes::MagneticFieldZ => SensorTypeEnum::MagneticFieldZ,
+            SensorTypes::Heading => SensorTypeEnum::Heading,
+            SensorTypes::Voltage => SensorTypeEnum::Voltage,
+            SensorTypes::WindSpeed => SensorTypeEnum::WindSpeed,
+            SensorTypes::Rainfall => SensorTypeEnum::Rainfall,
         }
     }
 }
@@ -286,6 +603,74 @@ fn is_positive_integer_i32(arg: String) -> Result<(), String> {
     }
 }
 
+/// Checks a `<bus>[,<addr>]` route param of a node on an I2C bus.
+fn is_i2c_route_param(arg: String) -> Result<(), String> {
+    let mut parts = arg.splitn(2, ',').map(str::trim);
+
+    is_positive_integer_i32(parts.next().unwrap_or("").to_string())?;
+
+    if let Some(addr_str) = parts.next() {
+        match addr_str.strip_prefix("0x") {
+            Some(hex_str) => u16::from_str_radix(hex_str, 16),
+            None => addr_str.parse(),
+        }
+        .map_err(|_| "I2C address must be a number, e.g. 0x76".to_string())?;
+    }
+
+    Ok(())
+}
+
+fn is_modbus_route_param(arg: String) -> Result<(), String> {
+    let (port_str, slave_addr_str) = arg
+        .split_once(',')
+        .ok_or_else(|| "expected <serial_port>,<slave_addr>".to_string())?;
+
+    is_positive_integer_i32(port_str.trim().to_string())?;
+
+    match slave_addr_str.trim().parse::<u8>() {
+        Ok(slave_addr) if (1..=247).contains(&slave_addr) => Ok(()),
+        _ => Err("Modbus slave address must be within 1 to 247".to_string()),
+    }
+}
+
+fn is_onewire_route_param(arg: String) -> Result<(), String> {
+    for pair in arg.split(',').map(str::trim) {
+        let (sensor_id_str, serial) = pair
+            .split_once(':')
+            .ok_or_else(|| format!("expected <sensor_id>:<serial>, got '{}'", pair))?;
+
+        is_positive_integer_i32(sensor_id_str.trim().to_string())?;
+
+        if !serial.trim().starts_with("28-") {
+            return Err(format!("'{}' is not a DS18B20 serial number", serial));
+        }
+    }
+
+    Ok(())
+}
+
+fn is_sysfs_route_param(arg: String) -> Result<(), String> {
+    let mut parts = arg.split(',').map(str::trim);
+
+    if parts.next().unwrap_or("").is_empty() {
+        return Err("missing device name".to_string());
+    }
+
+    for pair in parts {
+        let (sensor_id_str, channel) = pair
+            .split_once(':')
+            .ok_or_else(|| format!("expected <sensor_id>:<channel>, got '{}'", pair))?;
+
+        is_positive_integer_i32(sensor_id_str.trim().to_string())?;
+
+        if channel.trim().is_empty() {
+            return Err(format!("missing channel name in '{}'", pair));
+        }
+    }
+
+    Ok(())
+}
+
 fn main() {
     let matches = App::new("meteo_cli")
         .version(crate_version!())
@@ -326,6 +711,38 @@ fn main() {
                     ]),
                 ])
                 .setting(AppSettings::SubcommandRequiredElseHelp),
+            App::new("discover")
+                .about("Queries a node for its sensors through a running server and adds the missing ones")
+                .arg(
+                    Arg::with_name("node_public_id")
+                        .required(true)
+                        .validator(is_positive_integer_i32),
+                )
+                .arg(
+                    Arg::with_name("server")
+                        .long("server")
+                        .takes_value(true)
+                        .default_value("127.0.0.1:8000")
+                        .help("Address of the server's HTTP interface"),
+                ),
+            App::new("diag")
+                .about("Shows the link stats of the comm paths of a running server")
+                .arg(
+                    Arg::with_name("server")
+                        .long("server")
+                        .takes_value(true)
+                        .default_value("127.0.0.1:8000")
+                        .help("Address of the server's HTTP interface"),
+                ),
+            App::new("i2c")
+                .subcommands(vec![App::new("scan")
+                    .about("Probes all addresses of an I2C bus and identifies known chips")
+                    .arg(
+                        Arg::with_name("bus")
+                            .required(true)
+                            .validator(is_positive_integer_i32),
+                    )])
+                .setting(AppSettings::SubcommandRequiredElseHelp),
         ])
         .setting(AppSettings::SubcommandRequiredElseHelp)
         .get_matches();
@@ -356,10 +773,13 @@ fn main() {
                 let route_type = value_t_or_exit!(node_matches, "route_type", RouteTypes);
 
                 let route_params = match route_type {
-                    RouteTypes::Serial | RouteTypes::EnviroPHat => {
+                    RouteTypes::Serial | RouteTypes::Tcp | RouteTypes::EnviroPHat => {
                         let param_str =
                             node_matches.value_of("route_params").unwrap_or_else(|| {
-                                panic!("route_params parameter is required with route_type Serial")
+                                panic!(
+                                    "route_params parameter is required with route_type {:?}",
+                                    route_type
+                                )
                             });
 
                         is_positive_integer_i32(param_str.to_string())
@@ -367,6 +787,84 @@ fn main() {
 
                         Some(param_str)
                     }
+                    RouteTypes::Bme280
+                    | RouteTypes::Sht3x
+                    | RouteTypes::Htu21d
+                    | RouteTypes::Bh1750
+                    | RouteTypes::Veml7700 => {
+                        let param_str =
+                            node_matches.value_of("route_params").unwrap_or_else(|| {
+                                panic!(
+                                    "route_params parameter (<bus>[,<addr>]) is required with route_type {:?}",
+                                    route_type
+                                )
+                            });
+
+                        is_i2c_route_param(param_str.to_string())
+                            .unwrap_or_else(|s| panic!("route_params validation error: {}", s));
+
+                        Some(param_str)
+                    }
+                    RouteTypes::GenericI2c => {
+                        let param_str =
+                            node_matches.value_of("route_params").unwrap_or_else(|| {
+                                panic!(
+                                    "route_params parameter (<bus>,<addr>) is required with route_type {:?}",
+                                    route_type
+                                )
+                            });
+
+                        if !param_str.contains(',') {
+                            panic!("route_params validation error: missing I2C address");
+                        }
+
+                        is_i2c_route_param(param_str.to_string())
+                            .unwrap_or_else(|s| panic!("route_params validation error: {}", s));
+
+                        Some(param_str)
+                    }
+                    RouteTypes::ModbusRtu => {
+                        let param_str =
+                            node_matches.value_of("route_params").unwrap_or_else(|| {
+                                panic!(
+                                    "route_params parameter (<serial_port>,<slave_addr>) is required with route_type {:?}",
+                                    route_type
+                                )
+                            });
+
+                        is_modbus_route_param(param_str.to_string())
+                            .unwrap_or_else(|s| panic!("route_params validation error: {}", s));
+
+                        Some(param_str)
+                    }
+                    RouteTypes::Iio | RouteTypes::Hwmon => {
+                        let param_str =
+                            node_matches.value_of("route_params").unwrap_or_else(|| {
+                                panic!(
+                                    "route_params parameter (<device>[,<sensor_id>:<channel>...]) is required with route_type {:?}",
+                                    route_type
+                                )
+                            });
+
+                        is_sysfs_route_param(param_str.to_string())
+                            .unwrap_or_else(|s| panic!("route_params validation error: {}", s));
+
+                        Some(param_str)
+                    }
+                    RouteTypes::OneWire => {
+                        let param_str =
+                            node_matches.value_of("route_params").unwrap_or_else(|| {
+                                panic!(
+                                    "route_params parameter (<sensor_id>:<serial>,...) is required with route_type {:?}",
+                                    route_type
+                                )
+                            });
+
+                        is_onewire_route_param(param_str.to_string())
+                            .unwrap_or_else(|s| panic!("route_params validation error: {}", s));
+
+                        Some(param_str)
+                    }
                 };
 
                 add_node(&db_conn, node_id, node_name, route_type, route_params);
@@ -383,6 +881,29 @@ fn main() {
             }
             _ => unreachable!(),
         },
+        ("discover", Some(discover_matches)) => {
+            let node_id = value_t_or_exit!(discover_matches, "node_public_id", i32);
+            let server_addr = discover_matches
+                .value_of("server")
+                .expect("missing server address");
+
+            discover_sensors(&db_conn, server_addr, node_id);
+        }
+        ("diag", Some(diag_matches)) => {
+            let server_addr = diag_matches
+                .value_of("server")
+                .expect("missing server address");
+
+            print_comm_stats(server_addr);
+        }
+        ("i2c", Some(i2c_matches)) => match i2c_matches.subcommand() {
+            ("scan", Some(scan_matches)) => {
+                let bus_id = value_t_or_exit!(scan_matches, "bus", u32);
+
+                scan_i2c_bus(bus_id);
+            }
+            _ => unreachable!(),
+        },
         _ => unreachable!(),
     }
 }
@@ -1,5 +1,5 @@
 
-use log::{debug, trace, warn};
+use log::{debug, trace};
 
 #[cfg(feature = "meteo")]
 use std::time::Duration;
@@ -7,7 +7,7 @@ use std::time::Duration;
 #[cfg(feature = "meteo")]
 use ratfist_server::meteo;
 
-use ratfist_server::{run_migrations, db};
+use ratfist_server::{run_migrations, db, diag};
 
 #[rocket::main]
 async fn main() {
@@ -28,11 +28,9 @@ async fn main() {
     let connection = db_pool.get().expect("Could not get DB connection.");
     run_migrations(&connection);
 
-    let rocket = rocket.manage(db_pool.clone());
-
-    #[cfg(feature = "meteo")]
-    let executor =
-        scheduled_executor::CoreExecutor::new().expect("Could not start periodic task executor");
+    let rocket = rocket
+        .manage(db_pool.clone())
+        .mount("/diag", diag::get_routes());
 
     #[cfg(feature = "meteo")]
     let rocket = {
@@ -46,16 +44,12 @@ async fn main() {
             .parse()
             .expect("METEO_FETCHER_TASK_RATE_SECS parsing error");
 
-        let node_registry_clone = node_registry.clone();
+        meteo::push::start_push_listener(db_pool.clone(), &node_registry);
 
-        executor.schedule_fixed_rate(
-            Duration::from_secs(fetcher_task_rate),
+        meteo::fetcher::start_fetcher(
+            db_pool,
+            node_registry.clone(),
             Duration::from_secs(fetcher_task_rate),
-            move |_remote| {
-                if let Err(err) = meteo::fetcher::fetcher_iteration(&db_pool, &node_registry_clone) {
-                    warn!("Fetcher task error.: {err}");
-                }
-            },
         );
 
         rocket
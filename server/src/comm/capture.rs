@@ -0,0 +1,404 @@
+//! Recording of the raw traffic on a comm path, and replaying it later.
+//!
+//! A capture file has one line per chunk of bytes written to or read from the
+//! link, as `<RFC 3339 timestamp> <tx|rx> <bytes>`, the bytes escaped like
+//! Rust byte string literals (`\r`, `\n`, `\xNN`, ...). Every time the link is
+//! (re)opened, a `# <timestamp> link '<name>' up, framing <framing>` line,
+//! followed by `, half-duplex` for buses, starts a new session.
+
+use std::collections::VecDeque;
+use std::fs::{File, OpenOptions};
+use std::io::{self, LineWriter, Read, Write};
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{ready, Context, Poll, Waker};
+
+use chrono::{SecondsFormat, Utc};
+
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::task::JoinHandle;
+
+use log::warn;
+
+use super::framing::Framing;
+use super::serial::{spawn_link_task, CommChannelTx, TransactionConfig};
+
+use crate::utils::Result;
+use anyhow::anyhow;
+
+fn timestamp() -> String {
+    Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true)
+}
+
+/// Starts a new session at the end of the capture file at `capture_path`.
+pub(super) fn open_capture(
+    capture_path: &str,
+    link_name: &str,
+    framing: Framing,
+    half_duplex: bool,
+) -> Result<LineWriter<File>> {
+    let file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(capture_path)
+        .map_err(|e| anyhow!("Could not open capture file '{capture_path}'. {e:?}"))?;
+
+    let mut capture = LineWriter::new(file);
+
+    writeln!(
+        capture,
+        "# {} link '{}' up, framing {}{}",
+        timestamp(),
+        link_name,
+        framing.as_ref(),
+        if half_duplex { ", half-duplex" } else { "" }
+    )
+    .map_err(|e| anyhow!("Could not write to capture file '{capture_path}'. {e:?}"))?;
+
+    Ok(capture)
+}
+
+/// Link passing everything through to `inner`, while logging it to a capture
+/// file opened by `open_capture()`.
+pub(super) struct CaptureLink<T> {
+    inner: T,
+    capture: LineWriter<File>,
+}
+
+impl<T> CaptureLink<T> {
+    pub(super) fn new(inner: T, capture: LineWriter<File>) -> CaptureLink<T> {
+        CaptureLink { inner, capture }
+    }
+
+    fn record(&mut self, direction: &str, bytes: &[u8]) {
+        if let Err(e) = writeln!(
+            self.capture,
+            "{} {} {}",
+            timestamp(),
+            direction,
+            bytes.escape_ascii()
+        ) {
+            warn!("Failed to write to capture file. {:?}", e);
+        }
+    }
+}
+
+impl<T: AsyncRead + Unpin> AsyncRead for CaptureLink<T> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        let filled_len = buf.filled().len();
+
+        ready!(Pin::new(&mut this.inner).poll_read(cx, buf))?;
+
+        let received = &buf.filled()[filled_len..];
+
+        if !received.is_empty() {
+            this.record("rx", received);
+        }
+
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<T: AsyncWrite + Unpin> AsyncWrite for CaptureLink<T> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        let len = ready!(Pin::new(&mut this.inner).poll_write(cx, buf))?;
+
+        this.record("tx", &buf[..len]);
+
+        Poll::Ready(Ok(len))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+    }
+}
+
+#[derive(Debug)]
+enum Record {
+    Tx(Vec<u8>),
+    Rx(Vec<u8>),
+}
+
+/// One link session of a capture file.
+#[derive(Debug)]
+struct Session {
+    framing: Framing,
+    half_duplex: bool,
+    records: VecDeque<Record>,
+}
+
+/// Reverses `escape_ascii()`.
+fn unescape(escaped: &str) -> Option<Vec<u8>> {
+    let mut bytes = Vec::with_capacity(escaped.len());
+    let mut chars = escaped.bytes();
+
+    while let Some(byte) = chars.next() {
+        if byte != b'\\' {
+            bytes.push(byte);
+            continue;
+        }
+
+        match chars.next()? {
+            b'n' => bytes.push(b'\n'),
+            b'r' => bytes.push(b'\r'),
+            b't' => bytes.push(b'\t'),
+            b'0' => bytes.push(0),
+            b'x' => {
+                let hex = [chars.next()?, chars.next()?];
+                bytes.push(u8::from_str_radix(std::str::from_utf8(&hex).ok()?, 16).ok()?);
+            }
+            other => bytes.push(other),
+        }
+    }
+
+    Some(bytes)
+}
+
+fn load_capture(capture_path: &str) -> Result<Vec<Session>> {
+    let mut contents = String::new();
+
+    File::open(capture_path)
+        .and_then(|mut file| file.read_to_string(&mut contents))
+        .map_err(|e| anyhow!("Could not read capture file '{capture_path}'. {e:?}"))?;
+
+    let mut sessions: Vec<Session> = Vec::new();
+
+    for (line_idx, line) in contents.lines().enumerate() {
+        let line_no = line_idx + 1;
+
+        if let Some(header) = line.strip_prefix('#') {
+            let mut link_settings = header
+                .rsplit_once("framing ")
+                .ok_or(anyhow!("Missing framing in capture line {line_no}."))?
+                .1
+                .split(',')
+                .map(str::trim);
+
+            let framing = link_settings
+                .next()
+                .unwrap_or("")
+                .parse()
+                .map_err(|e| anyhow!("Invalid framing in capture line {line_no}. {e}"))?;
+
+            let half_duplex = link_settings.any(|setting| setting == "half-duplex");
+
+            sessions.push(Session {
+                framing,
+                half_duplex,
+                records: VecDeque::new(),
+            });
+            continue;
+        }
+
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        let mut tokens = line.splitn(3, ' ');
+        let _timestamp = tokens.next();
+        let direction = tokens.next();
+        let bytes = tokens
+            .next()
+            .and_then(unescape)
+            .ok_or(anyhow!("Invalid capture line {line_no}."))?;
+
+        let record = match direction {
+            Some("tx") => Record::Tx(bytes),
+            Some("rx") => Record::Rx(bytes),
+            _ => return Err(anyhow!("Invalid direction in capture line {line_no}.").into()),
+        };
+
+        sessions
+            .last_mut()
+            .ok_or(anyhow!(
+                "Capture line {line_no} precedes the first session header."
+            ))?
+            .records
+            .push_back(record);
+    }
+
+    Ok(sessions)
+}
+
+/// Link playing the node side of a captured session. What was received after
+/// each write in the capture is only handed out once the matching write is
+/// replayed, so the order of requests and replies is the same as in the
+/// capture, however long it took back then. Writing anything else than what
+/// was captured fails, and so does every later session of the replay.
+pub struct ReplayLink {
+    records: VecDeque<Record>,
+    pending_rx: VecDeque<u8>,
+    /// Read waiting for the next write to release more received bytes.
+    read_waker: Option<Waker>,
+    diverged: Arc<AtomicBool>,
+}
+
+impl ReplayLink {
+    fn diverge(&self, msg: String) -> io::Error {
+        warn!("{}", msg);
+        self.diverged.store(true, Ordering::SeqCst);
+        io::Error::new(io::ErrorKind::InvalidData, msg)
+    }
+}
+
+impl AsyncRead for ReplayLink {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+
+        while let Some(Record::Rx(_)) = this.records.front() {
+            if let Some(Record::Rx(bytes)) = this.records.pop_front() {
+                this.pending_rx.extend(bytes);
+            }
+        }
+
+        if this.pending_rx.is_empty() {
+            if this.records.is_empty() {
+                // End of the session, the link went down here.
+                return Poll::Ready(Ok(()));
+            }
+
+            this.read_waker = Some(cx.waker().clone());
+            return Poll::Pending;
+        }
+
+        let len = buf.remaining().min(this.pending_rx.len());
+        let bytes: Vec<u8> = this.pending_rx.drain(..len).collect();
+        buf.put_slice(&bytes);
+
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl AsyncWrite for ReplayLink {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        _cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+
+        match this.records.iter().position(|r| matches!(r, Record::Tx(_))) {
+            Some(tx_idx) => {
+                // Anything the server didn't read before writing is still
+                // handed out, ahead of what followed the write.
+                for record in this.records.drain(..tx_idx) {
+                    if let Record::Rx(bytes) = record {
+                        this.pending_rx.extend(bytes);
+                    }
+                }
+
+                if let Some(Record::Tx(expected)) = this.records.pop_front() {
+                    if expected != buf {
+                        return Poll::Ready(Err(this.diverge(format!(
+                            "Replay diverged, wrote '{}' instead of '{}'.",
+                            buf.escape_ascii(),
+                            expected.escape_ascii()
+                        ))));
+                    }
+                }
+            }
+            _ => {
+                return Poll::Ready(Err(this.diverge(format!(
+                    "Replay diverged, unexpected write '{}'.",
+                    buf.escape_ascii()
+                ))))
+            }
+        }
+
+        if let Some(read_waker) = this.read_waker.take() {
+            read_waker.wake();
+        }
+
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+}
+
+/// Starts a comm task on the current Tokio runtime replaying the capture file
+/// at `capture_path`, one session per (re)opening of the link. Sending it the
+/// same requests as back then reproduces what the server saw, e.g. in a test
+/// driving a `SensorNodeRegistry` through `comm::mock::MockCommPaths`.
+pub fn create_replay_comm_task(capture_path: &str) -> Result<(CommChannelTx, JoinHandle<()>)> {
+    let sessions = load_capture(capture_path)?;
+
+    let (framing, half_duplex) = sessions
+        .first()
+        .map(|session| (session.framing, session.half_duplex))
+        .ok_or(anyhow!("No sessions in capture file '{capture_path}'."))?;
+
+    // Start counting so that the first request gets the same transaction ID
+    // as in the capture, and replies to it match.
+    let transaction_id_ctr = sessions
+        .iter()
+        .flat_map(|session| session.records.iter())
+        .find_map(|record| match record {
+            Record::Tx(bytes) => framing.request_trans_id(bytes),
+            Record::Rx(_) => None,
+        })
+        .unwrap_or(1)
+        .checked_sub(1)
+        .ok_or(anyhow!(
+            "Capture file '{capture_path}' has a request with transaction ID 0."
+        ))?;
+
+    let sessions = Mutex::new(VecDeque::from(sessions));
+    let diverged = Arc::new(AtomicBool::new(false));
+
+    Ok(spawn_link_task(
+        format!("replay://{}", capture_path),
+        move || {
+            if diverged.load(Ordering::SeqCst) {
+                let err = anyhow!("Replay diverged from the capture, not replaying further.");
+                return std::future::ready(Err(err.into()));
+            }
+
+            let link = sessions
+                .lock()
+                .expect("mutex poisoned")
+                .pop_front()
+                .ok_or_else(|| anyhow!("Capture fully replayed.").into())
+                .map(|session| {
+                    if session.framing != framing || session.half_duplex != half_duplex {
+                        warn!("Capture sessions use different link settings, replaying all with the first one's.");
+                    }
+
+                    ReplayLink {
+                        records: session.records,
+                        pending_rx: VecDeque::new(),
+                        read_waker: None,
+                        diverged: diverged.clone(),
+                    }
+                });
+
+            std::future::ready(link)
+        },
+        TransactionConfig::for_replay(framing, half_duplex),
+        transaction_id_ctr,
+    ))
+}
@@ -0,0 +1,471 @@
+//! Wire encodings of the frames exchanged with the nodes.
+//!
+//! Both carry the same fields, only encoded differently:
+//!
+//! * `ascii`: `$<trans_id>,<node_id>,<payload>*CS\r\n` requests, with `CS` the
+//!   hex XOR of the bytes between `$` and `*`. Replies leave out the node ID,
+//!   pushes (transaction ID 0) keep it.
+//! * `cobs`: the transaction ID and node ID as little endian `u32`s, followed
+//!   by the payload text and a big endian CRC-16/CCITT-FALSE of all of it,
+//!   COBS encoded and terminated by a zero byte. As with `ascii`, replies
+//!   leave out the node ID.
+//!
+//! On half-duplex buses shared by several nodes, replies keep the node ID too,
+//! so that replies from the wrong node can be told apart.
+
+use std::str::FromStr;
+
+use super::serial::{PushMessage, PUSH_TRANSACTION_ID};
+
+use crate::utils::Result;
+use anyhow::anyhow;
+
+/// Wire encoding used on a comm path, set by the `<prefix>_FRAMING` env
+/// variable. Nodes on the path must be set up for the same one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Framing {
+    Ascii,
+    Cobs,
+}
+
+impl FromStr for Framing {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "ascii" => Ok(Framing::Ascii),
+            "cobs" => Ok(Framing::Cobs),
+            _ => Err("expected one of ascii, cobs".to_string()),
+        }
+    }
+}
+
+impl AsRef<str> for Framing {
+    fn as_ref(&self) -> &'static str {
+        match self {
+            Framing::Ascii => "ascii",
+            Framing::Cobs => "cobs",
+        }
+    }
+}
+
+impl Framing {
+    /// Encodes a request for node `node_id`.
+    pub(super) fn encode(&self, trans_id: u64, node_id: u32, msg: &str) -> Vec<u8> {
+        match self {
+            Framing::Ascii => {
+                let msg_str = format!("{},{},{}", trans_id, node_id, msg);
+                format!("${}*{:02X}\r\n", msg_str, calc_checksum(msg_str.as_bytes())).into_bytes()
+            }
+            Framing::Cobs => {
+                let mut frame = Vec::with_capacity(msg.len() + 10);
+                frame.extend_from_slice(&(trans_id as u32).to_le_bytes());
+                frame.extend_from_slice(&node_id.to_le_bytes());
+                frame.extend_from_slice(msg.as_bytes());
+                frame.extend_from_slice(&crc16(&frame).to_be_bytes());
+
+                let mut out = cobs_encode(&frame);
+                out.push(0);
+                out
+            }
+        }
+    }
+
+    /// Extracts the transaction ID from an encoded request.
+    pub(super) fn request_trans_id(&self, encoded: &[u8]) -> Option<u64> {
+        match self {
+            Framing::Ascii => {
+                let msg = std::str::from_utf8(encoded).ok()?;
+                let (trans_id_str, _) = msg.strip_prefix('$')?.split_once(',')?;
+                trans_id_str.parse().ok()
+            }
+            Framing::Cobs => {
+                let frame = cobs_decode(encoded.strip_suffix(&[0])?)?;
+                let trans_id_bytes = frame.get(..4)?;
+                Some(u32::from_le_bytes([
+                    trans_id_bytes[0],
+                    trans_id_bytes[1],
+                    trans_id_bytes[2],
+                    trans_id_bytes[3],
+                ]) as u64)
+            }
+        }
+    }
+}
+
+/// Marks decoding errors caused by a frame's checksum or CRC not matching its
+/// contents, as opposed to frames that are malformed altogether.
+#[derive(Debug)]
+pub(super) struct ChecksumMismatch;
+
+impl std::fmt::Display for ChecksumMismatch {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(fmt, "Checksum mismatch.")
+    }
+}
+
+impl std::error::Error for ChecksumMismatch {}
+
+/// Frame received from a node.
+#[derive(Debug)]
+pub(super) enum IncomingFrame {
+    Reply {
+        trans_id: u64,
+        /// Only set on paths where replies echo the node ID.
+        node_id: Option<u32>,
+        payload: String,
+    },
+    Push(PushMessage),
+}
+
+enum ParserState {
+    WaitingForStart,
+    Receiving,
+}
+
+/// Splits the bytes received on a path into frames.
+pub(super) struct FrameDecoder {
+    framing: Framing,
+    replies_with_node_id: bool,
+    state: ParserState,
+    current_frame: Vec<u8>,
+}
+
+impl FrameDecoder {
+    pub(super) fn new(framing: Framing, replies_with_node_id: bool) -> FrameDecoder {
+        FrameDecoder {
+            framing,
+            replies_with_node_id,
+            state: ParserState::WaitingForStart,
+            current_frame: Vec::new(),
+        }
+    }
+
+    /// Feeds `bytes` to the decoder, returning the frames they completed.
+    pub(super) fn push_bytes(&mut self, bytes: &[u8]) -> Vec<Result<IncomingFrame>> {
+        let mut frames = Vec::new();
+
+        for byte in bytes {
+            match self.framing {
+                Framing::Ascii => match self.state {
+                    ParserState::WaitingForStart => {
+                        if *byte == b'$' {
+                            self.state = ParserState::Receiving;
+                        }
+                    }
+                    ParserState::Receiving => {
+                        self.current_frame.push(*byte);
+
+                        if self.current_frame.ends_with(b"\r\n") {
+                            let len = self.current_frame.len();
+                            frames.push(decode_ascii(
+                                &self.current_frame[..(len - 2)],
+                                self.replies_with_node_id,
+                            ));
+
+                            self.current_frame.clear();
+                            self.state = ParserState::WaitingForStart;
+                        }
+                    }
+                },
+                Framing::Cobs => {
+                    if *byte != 0 {
+                        self.current_frame.push(*byte);
+                    } else if !self.current_frame.is_empty() {
+                        frames.push(decode_cobs(&self.current_frame, self.replies_with_node_id));
+                        self.current_frame.clear();
+                    }
+                }
+            }
+        }
+
+        frames
+    }
+}
+
+fn calc_checksum(input: &[u8]) -> u8 {
+    input.iter().fold(0, |csum, ch| csum ^ ch)
+}
+
+/// Builds the frame for a message, `node_id` being set for all pushes.
+fn incoming_frame(trans_id: u64, node_id: Option<u32>, payload: &str) -> IncomingFrame {
+    match (trans_id, node_id) {
+        (PUSH_TRANSACTION_ID, Some(node_id)) => IncomingFrame::Push(PushMessage {
+            node_id,
+            payload: payload.to_string(),
+        }),
+        _ => IncomingFrame::Reply {
+            trans_id,
+            node_id,
+            payload: payload.to_string(),
+        },
+    }
+}
+
+fn decode_ascii(raw_msg: &[u8], replies_with_node_id: bool) -> Result<IncomingFrame> {
+    let raw_msg = std::str::from_utf8(raw_msg)
+        .map_err(|e| anyhow!("Incoming message not valid UTF-8. {e:?}"))?;
+
+    if raw_msg.len() < 4 {
+        return Err(anyhow!("Incoming message too short: '{raw_msg}'").into());
+    }
+
+    if raw_msg.as_bytes()[raw_msg.len() - 3] as char != '*' {
+        return Err(anyhow!("Incoming message invalid format: '{raw_msg}'").into());
+    }
+
+    let packet_csum = u8::from_str_radix(&raw_msg[(raw_msg.len() - 2)..], 16)
+        .map_err(|e| anyhow!("Incoming message invalid checksum format: '{raw_msg}'. {e:?}"))?;
+
+    let msg_str = &raw_msg[..(raw_msg.len() - 3)];
+    let calc_csum = calc_checksum(msg_str.as_bytes());
+
+    if packet_csum != calc_csum {
+        return Err(anyhow::Error::new(ChecksumMismatch)
+            .context(format!("Incoming message invalid checksum: '{raw_msg}'. Expecting 0x{calc_csum:2X}, got 0x{packet_csum:2X}."))
+            .into());
+    }
+
+    let (trans_id_str, rest) = msg_str
+        .split_once(',')
+        .ok_or(anyhow!("Incoming message invalid format: '{raw_msg}'"))?;
+
+    let trans_id = trans_id_str
+        .parse()
+        .map_err(|e| anyhow!("Incoming message parsing error: '{raw_msg}'. {e:?}"))?;
+
+    if trans_id != PUSH_TRANSACTION_ID && !replies_with_node_id {
+        return Ok(incoming_frame(trans_id, None, rest));
+    }
+
+    let (node_id_str, payload) = rest
+        .split_once(',')
+        .ok_or(anyhow!("Incoming message without node ID: '{raw_msg}'"))?;
+
+    let node_id = node_id_str
+        .parse()
+        .map_err(|e| anyhow!("Incoming message with invalid node ID: '{raw_msg}'. {e:?}"))?;
+
+    Ok(incoming_frame(trans_id, Some(node_id), payload))
+}
+
+fn decode_cobs(encoded: &[u8], replies_with_node_id: bool) -> Result<IncomingFrame> {
+    let frame = cobs_decode(encoded).ok_or(anyhow!(
+        "Incoming frame invalid COBS encoding: {encoded:02X?}"
+    ))?;
+
+    if frame.len() < 6 {
+        return Err(anyhow!("Incoming frame too short: {frame:02X?}").into());
+    }
+
+    let (content, crc_bytes) = frame.split_at(frame.len() - 2);
+    let packet_crc = u16::from_be_bytes([crc_bytes[0], crc_bytes[1]]);
+    let calc_crc = crc16(content);
+
+    if packet_crc != calc_crc {
+        return Err(anyhow::Error::new(ChecksumMismatch)
+            .context(format!("Incoming frame invalid CRC: {frame:02X?}. Expecting 0x{calc_crc:04X}, got 0x{packet_crc:04X}."))
+            .into());
+    }
+
+    let trans_id = u32::from_le_bytes([content[0], content[1], content[2], content[3]]) as u64;
+
+    let (node_id, payload) = if trans_id == PUSH_TRANSACTION_ID || replies_with_node_id {
+        if content.len() < 8 {
+            return Err(anyhow!("Incoming frame without node ID: {frame:02X?}").into());
+        }
+
+        let node_id = u32::from_le_bytes([content[4], content[5], content[6], content[7]]);
+        (Some(node_id), &content[8..])
+    } else {
+        (None, &content[4..])
+    };
+
+    let payload = std::str::from_utf8(payload)
+        .map_err(|e| anyhow!("Incoming frame payload not valid UTF-8. {e:?}"))?;
+
+    Ok(incoming_frame(trans_id, node_id, payload))
+}
+
+/// CRC-16/CCITT-FALSE (polynomial 0x1021, initial value 0xFFFF).
+fn crc16(data: &[u8]) -> u16 {
+    data.iter().fold(0xFFFF, |crc, byte| {
+        (0..8).fold(crc ^ (u16::from(*byte) << 8), |crc, _| {
+            if crc & 0x8000 != 0 {
+                (crc << 1) ^ 0x1021
+            } else {
+                crc << 1
+            }
+        })
+    })
+}
+
+/// Consistent Overhead Byte Stuffing, leaving no zero bytes in the output.
+fn cobs_encode(data: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(data.len() + data.len() / 254 + 2);
+    let mut code_idx = 0;
+    let mut code = 1u8;
+    out.push(0);
+
+    for byte in data {
+        if *byte == 0 {
+            out[code_idx] = code;
+            code_idx = out.len();
+            out.push(0);
+            code = 1;
+        } else {
+            out.push(*byte);
+            code += 1;
+
+            if code == 0xFF {
+                out[code_idx] = code;
+                code_idx = out.len();
+                out.push(0);
+                code = 1;
+            }
+        }
+    }
+
+    out[code_idx] = code;
+    out
+}
+
+fn cobs_decode(data: &[u8]) -> Option<Vec<u8>> {
+    let mut out = Vec::with_capacity(data.len());
+    let mut idx = 0;
+
+    while idx < data.len() {
+        let code = data[idx] as usize;
+
+        if code == 0 || idx + code > data.len() {
+            return None;
+        }
+
+        out.extend_from_slice(&data[(idx + 1)..(idx + code)]);
+        idx += code;
+
+        if code < 0xFF && idx < data.len() {
+            out.push(0);
+        }
+    }
+
+    Some(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use ratfist_node_stub::framing as stub;
+
+    /// Payloads hitting the COBS edge cases: nothing at all, zeros at the ends
+    /// and in between, and non-zero runs around the 254 byte block limit.
+    fn payloads() -> Vec<Vec<u8>> {
+        vec![
+            vec![],
+            vec![0],
+            vec![0, 0],
+            vec![0, 0x11, 0x22, 0],
+            vec![0x11, 0, 0x22, 0, 0, 0x33],
+            vec![0xAB; 253],
+            vec![0xAB; 254],
+            vec![0xAB; 255],
+            [vec![0xAB; 254], vec![0], vec![0xCD; 300]].concat(),
+        ]
+    }
+
+    /// COBS frame of a reply, the way the stub's dispatcher sends it.
+    fn stub_reply_frame(trans_id: u32, payload: &str) -> Vec<u8> {
+        let mut frame = trans_id.to_le_bytes().to_vec();
+        frame.extend_from_slice(payload.as_bytes());
+        frame.extend_from_slice(&stub::crc16(&frame).to_be_bytes());
+
+        let mut out = stub::cobs_encode(&frame);
+        out.push(0);
+        out
+    }
+
+    #[test]
+    fn cobs_round_trips_with_the_stub() {
+        for payload in payloads() {
+            let encoded = cobs_encode(&payload);
+
+            assert!(!encoded.contains(&0), "zero in {:02X?}", encoded);
+            assert_eq!(encoded, stub::cobs_encode(&payload));
+            assert_eq!(stub::cobs_decode(&encoded).as_ref(), Some(&payload));
+            assert_eq!(cobs_decode(&stub::cobs_encode(&payload)), Some(payload));
+        }
+    }
+
+    #[test]
+    fn cobs_encodes_254_byte_run_as_full_block() {
+        let encoded = cobs_encode(&[0xAB; 254]);
+
+        assert_eq!(encoded.len(), 256);
+        assert_eq!(encoded[0], 0xFF);
+        assert_eq!(encoded[255], 0x01);
+        assert_eq!(cobs_encode(&[]), vec![0x01]);
+    }
+
+    #[test]
+    fn crc_matches_the_stub() {
+        // Check value of CRC-16/CCITT-FALSE
+        assert_eq!(crc16(b"123456789"), 0x29B1);
+
+        for payload in payloads() {
+            assert_eq!(crc16(&payload), stub::crc16(&payload));
+        }
+    }
+
+    #[test]
+    fn stub_decodes_cobs_request() {
+        let encoded = Framing::Cobs.encode(1, 0x100, "METEO,GET_TEMPERATURE,0");
+
+        assert_eq!(encoded.last(), Some(&0));
+
+        let frame = stub::cobs_decode(&encoded[..(encoded.len() - 1)]).unwrap();
+        let (content, crc_bytes) = frame.split_at(frame.len() - 2);
+
+        assert_eq!(
+            u16::from_be_bytes([crc_bytes[0], crc_bytes[1]]),
+            stub::crc16(content)
+        );
+        assert_eq!(&content[..8], &[1, 0, 0, 0, 0, 1, 0, 0]);
+        assert_eq!(&content[8..], b"METEO,GET_TEMPERATURE,0");
+    }
+
+    #[test]
+    fn decodes_cobs_reply_of_the_stub() {
+        let mut decoder = FrameDecoder::new(Framing::Cobs, false);
+
+        let mut frames = decoder.push_bytes(&stub_reply_frame(1, "METEO,TEMPERATURE_REPLY,0,21.5"));
+
+        match frames.pop() {
+            Some(Ok(IncomingFrame::Reply {
+                trans_id: 1,
+                node_id: None,
+                payload,
+            })) => assert_eq!(payload, "METEO,TEMPERATURE_REPLY,0,21.5"),
+            frame => panic!("unexpected frame {:?}", frame),
+        }
+
+        assert!(frames.is_empty());
+    }
+
+    #[test]
+    fn rejects_cobs_frame_with_crc_mismatch() {
+        let mut frame = stub_reply_frame(1, "METEO,TEMPERATURE_REPLY,0,21.5");
+
+        // Low byte of the CRC, right before the terminating zero
+        let crc_idx = frame.len() - 2;
+        frame[crc_idx] ^= 0x01;
+
+        let mut decoder = FrameDecoder::new(Framing::Cobs, false);
+        let frames = decoder.push_bytes(&frame);
+
+        match frames.as_slice() {
+            [Err(e)] => assert!(e.downcast_ref::<ChecksumMismatch>().is_some()),
+            frames => panic!("unexpected frames {:?}", frames),
+        }
+    }
+}
@@ -1,8 +1,123 @@
 use i2cdev::core::I2CTransfer;
 use i2cdev::linux::{LinuxI2CBus, LinuxI2CMessage};
 
+use std::time::Instant;
+
+use super::stats::LinkStats;
+use super::I2cTransport;
+
 use anyhow::anyhow;
-pub struct CommChannel(LinuxI2CBus);
+
+/// Lowest and highest 7-bit addresses not reserved by the I2C spec.
+const SCAN_ADDR_FIRST: u16 = 0x08;
+const SCAN_ADDR_LAST: u16 = 0x77;
+
+/// Chip that can be recognized on a scanned bus, by the value of its chip ID
+/// register, or by address alone for chips without one.
+struct KnownChip {
+    name: &'static str,
+    addrs: &'static [u16],
+    /// Register address byte to write, and the ID read back from it.
+    chip_id: Option<(u8, u8)>,
+}
+
+const KNOWN_CHIPS: [KnownChip; 10] = [
+    KnownChip {
+        name: "BMP280",
+        addrs: &[0x76, 0x77],
+        chip_id: Some((0xd0, 0x58)),
+    },
+    KnownChip {
+        name: "BME280",
+        addrs: &[0x76, 0x77],
+        chip_id: Some((0xd0, 0x60)),
+    },
+    KnownChip {
+        name: "TCS34725",
+        addrs: &[0x29],
+        chip_id: Some((0x80 | 0x12, 0x44)),
+    },
+    KnownChip {
+        name: "TCS34727",
+        addrs: &[0x29],
+        chip_id: Some((0x80 | 0x12, 0x4d)),
+    },
+    KnownChip {
+        name: "LSM303D",
+        addrs: &[0x1d, 0x1e],
+        chip_id: Some((0x0f, 0x49)),
+    },
+    KnownChip {
+        name: "ADS1015",
+        addrs: &[0x48, 0x49, 0x4a, 0x4b],
+        chip_id: None,
+    },
+    KnownChip {
+        name: "SHT3x",
+        addrs: &[0x44, 0x45],
+        chip_id: None,
+    },
+    KnownChip {
+        name: "HTU21D/Si7021",
+        addrs: &[0x40],
+        chip_id: None,
+    },
+    KnownChip {
+        name: "BH1750",
+        addrs: &[0x23, 0x5c],
+        chip_id: None,
+    },
+    KnownChip {
+        name: "VEML7700",
+        addrs: &[0x10],
+        chip_id: Some((0x07, 0x81)),
+    },
+];
+
+/// CRC-8 with the 0x31 polynomial, protecting the readings of Sensirion and
+/// TE/Silicon Labs humidity sensors (which start from different `init`
+/// values).
+pub fn crc8(data: &[u8], init: u8) -> u8 {
+    data.iter().fold(init, |crc, byte| {
+        (0..8).fold(crc ^ byte, |crc, _| {
+            if crc & 0x80 != 0 {
+                (crc << 1) ^ 0x31
+            } else {
+                crc << 1
+            }
+        })
+    })
+}
+
+/// Device that answered during a bus scan.
+#[derive(Debug, Clone, Serialize)]
+pub struct ScannedDevice {
+    pub addr: u16,
+    /// Name of the chip, if it is one of the known ones.
+    pub chip: Option<&'static str>,
+    /// Whether `chip` was confirmed by its chip ID, rather than guessed from
+    /// the address.
+    pub chip_id_matched: bool,
+}
+
+/// A single message of a combined I2C transaction.
+#[derive(Debug)]
+pub enum Message<'a> {
+    Write { addr: u16, data: &'a [u8] },
+    Read { addr: u16, data: &'a mut [u8] },
+}
+
+impl<'a> Message<'a> {
+    pub fn write(addr: u16, data: &'a [u8]) -> Message<'a> {
+        Message::Write { addr, data }
+    }
+
+    pub fn read(addr: u16, data: &'a mut [u8]) -> Message<'a> {
+        Message::Read { addr, data }
+    }
+}
+
+pub struct CommChannel(LinuxI2CBus, LinkStats);
 
 impl CommChannel {
     pub fn new(i2c_comm_path_id: u32) -> crate::utils::Result<CommChannel> {
@@ -14,13 +129,103 @@ impl CommChannel {
         )
         .map_err(|e| anyhow!("Could not open I2C bus. {e:?}"))?;
 
-        Ok(CommChannel(bus))
+        let stats = LinkStats {
+            link_up: true,
+            ..LinkStats::default()
+        };
+
+        Ok(CommChannel(bus, stats))
     }
 
-    pub fn transfer(&mut self, msgs: &mut [LinuxI2CMessage]) -> crate::utils::Result<()> {
+    /// Probes all 7-bit addresses with a single byte read, and identifies the
+    /// known chips among the devices that acknowledge it. Probing doesn't
+    /// count towards the bus stats.
+    pub fn scan(&mut self) -> Vec<ScannedDevice> {
+        let mut devices = Vec::new();
+
+        for addr in SCAN_ADDR_FIRST..=SCAN_ADDR_LAST {
+            if self
+                .raw_transfer(&mut [Message::read(addr, &mut [0])])
+                .is_ok()
+            {
+                devices.push(self.identify(addr));
+            }
+        }
+
+        devices
+    }
+
+    fn identify(&mut self, addr: u16) -> ScannedDevice {
+        let mut guess = None;
+
+        for chip in KNOWN_CHIPS.iter().filter(|chip| chip.addrs.contains(&addr)) {
+            match chip.chip_id {
+                Some((reg_addr, expected_id)) => {
+                    let mut id_data = [0];
+                    let id_read = self.raw_transfer(&mut [
+                        Message::write(addr, &[reg_addr]),
+                        Message::read(addr, &mut id_data),
+                    ]);
+
+                    if id_read.is_ok() && id_data[0] == expected_id {
+                        return ScannedDevice {
+                            addr,
+                            chip: Some(chip.name),
+                            chip_id_matched: true,
+                        };
+                    }
+                }
+                None => guess = guess.or(Some(chip.name)),
+            }
+        }
+
+        ScannedDevice {
+            addr,
+            chip: guess,
+            chip_id_matched: false,
+        }
+    }
+
+    fn raw_transfer(&mut self, msgs: &mut [Message]) -> std::io::Result<()> {
+        use i2cdev::core::I2CMessage;
+
+        let mut linux_msgs: Vec<LinuxI2CMessage> = msgs
+            .iter_mut()
+            .map(|msg| match msg {
+                Message::Write { addr, data } => LinuxI2CMessage::write(data).with_address(*addr),
+                Message::Read { addr, data } => LinuxI2CMessage::read(data).with_address(*addr),
+            })
+            .collect();
+
         self.0
-            .transfer(msgs)
+            .transfer(&mut linux_msgs)
             .map(|_| ())
-            .map_err(|e| anyhow!("I2C transfer error. {e:?}").into())
+            .map_err(std::io::Error::from)
+    }
+
+    /// Snapshot of the transfer counters of this bus.
+    pub fn stats(&self) -> LinkStats {
+        self.1.clone()
+    }
+}
+
+impl I2cTransport for CommChannel {
+    fn transfer(&mut self, msgs: &mut [Message]) -> crate::utils::Result<()> {
+        let started_at = Instant::now();
+        let transfer_result = self.raw_transfer(msgs);
+
+        self.1.frames_sent += 1;
+
+        match transfer_result {
+            Ok(_) => {
+                self.1.replies_received += 1;
+                self.1.record_latency(started_at.elapsed());
+                Ok(())
+            }
+            Err(e) => {
+                self.1.transfer_errors += 1;
+                Err(anyhow!("I2C transfer error. {e:?}").into())
+            }
+        }
     }
 }
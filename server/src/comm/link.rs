@@ -0,0 +1,225 @@
+//! Upkeep of the links comm tasks run their protocol over, shared by framing
+//! protocol and Modbus paths: reopening a link whenever it breaks, failing
+//! requests until it's back, and keeping track of whether it's up.
+
+use std::future::Future;
+use std::io;
+use std::time::Duration;
+
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::sync::{mpsc, watch};
+use tokio::time::{self, Instant};
+
+use log::{info, warn};
+
+use super::stats::SharedLinkStats;
+
+use crate::utils::Result;
+
+pub(super) const RECONNECT_BACKOFF_MIN: Duration = Duration::from_secs(1);
+pub(super) const RECONNECT_BACKOFF_MAX: Duration = Duration::from_secs(60);
+
+/// Protocol a comm task runs over each link it opens.
+#[rocket::async_trait]
+pub(super) trait LinkProtocol: Send {
+    /// Request queued for the comm task, with the channel its outcome goes
+    /// back through.
+    type Request: Send;
+
+    /// Fails `request` with `CommError::LinkDown`.
+    fn reject(request: Self::Request);
+
+    /// Runs requests arriving on `channel_rx` over `comm`, until all their
+    /// senders are gone or an I/O error breaks the link.
+    async fn run<T>(
+        &mut self,
+        link_name: &str,
+        channel_rx: &mut mpsc::UnboundedReceiver<Self::Request>,
+        comm: T,
+    ) -> io::Result<()>
+    where
+        T: AsyncRead + AsyncWrite + Unpin + Send + 'static;
+}
+
+/// Waits for the first attempt to open the link to be over, and tells
+/// whether the link is up.
+pub(super) async fn wait_for_link(link_up: &watch::Receiver<Option<bool>>) -> bool {
+    let mut link_up = link_up.clone();
+
+    loop {
+        if let Some(link_up) = *link_up.borrow() {
+            return link_up;
+        }
+
+        if link_up.changed().await.is_err() {
+            return false;
+        }
+    }
+}
+
+/// Fails every request arriving on `channel_rx` until `duration` passes.
+/// Returns `false` right away once all senders are gone.
+async fn reject_requests_for<P: LinkProtocol>(
+    channel_rx: &mut mpsc::UnboundedReceiver<P::Request>,
+    duration: Duration,
+) -> bool {
+    let deadline = Instant::now() + duration;
+
+    loop {
+        match time::timeout_at(deadline, channel_rx.recv()).await {
+            Ok(Some(request)) => P::reject(request),
+            Ok(None) => return false,
+            Err(_) => return true,
+        }
+    }
+}
+
+/// Keeps the link opened by `open_link` up, running `protocol` over it and
+/// reopening it with an exponential backoff whenever it breaks or cannot be
+/// opened. Returns once all request senders are gone.
+pub(super) async fn link_task_func<P, T, F, Fut>(
+    link_name: String,
+    open_link: F,
+    mut protocol: P,
+    mut channel_rx: mpsc::UnboundedReceiver<P::Request>,
+    link_up: watch::Sender<Option<bool>>,
+    stats: SharedLinkStats,
+) where
+    P: LinkProtocol,
+    T: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    F: Fn() -> Fut,
+    Fut: Future<Output = Result<T>>,
+{
+    let mut backoff = RECONNECT_BACKOFF_MIN;
+    let mut first_attempt = true;
+
+    loop {
+        let link = open_link().await;
+        let _ = link_up.send(Some(link.is_ok()));
+
+        {
+            let mut stats = stats.lock().expect("mutex poisoned");
+            stats.link_up = link.is_ok();
+
+            if link.is_ok() && !first_attempt {
+                stats.reconnects += 1;
+            }
+        }
+
+        first_attempt = false;
+
+        match link {
+            Ok(comm) => {
+                info!("Comm link '{}' up.", link_name);
+                backoff = RECONNECT_BACKOFF_MIN;
+
+                let run_result = protocol.run(&link_name, &mut channel_rx, comm).await;
+
+                let _ = link_up.send(Some(false));
+                stats.lock().expect("mutex poisoned").link_up = false;
+
+                match run_result {
+                    Ok(()) => break,
+                    Err(e) => warn!("Comm link '{}' down. {:?}", link_name, e),
+                }
+            }
+            Err(e) => {
+                warn!(
+                    "Failed to (re)open comm link '{}', retrying in {:?}. {:?}",
+                    link_name, backoff, e
+                );
+            }
+        }
+
+        if !reject_requests_for::<P>(&mut channel_rx, backoff).await {
+            break;
+        }
+
+        backoff = (backoff * 2).min(RECONNECT_BACKOFF_MAX);
+    }
+
+    info!("Comm link '{}' no longer used, closed.", link_name);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use tokio::io::DuplexStream;
+    use tokio::sync::oneshot;
+
+    use anyhow::anyhow;
+
+    /// Answers `true` to every request while the link is up.
+    struct AnsweringProtocol;
+
+    #[rocket::async_trait]
+    impl LinkProtocol for AnsweringProtocol {
+        type Request = oneshot::Sender<bool>;
+
+        fn reject(request: oneshot::Sender<bool>) {
+            let _ = request.send(false);
+        }
+
+        async fn run<T>(
+            &mut self,
+            _link_name: &str,
+            channel_rx: &mut mpsc::UnboundedReceiver<oneshot::Sender<bool>>,
+            _comm: T,
+        ) -> io::Result<()>
+        where
+            T: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+        {
+            while let Some(request) = channel_rx.recv().await {
+                let _ = request.send(true);
+            }
+
+            Ok(())
+        }
+    }
+
+    async fn request(channel_tx: &mpsc::UnboundedSender<oneshot::Sender<bool>>) -> bool {
+        let (resp_tx, resp_rx) = oneshot::channel();
+        channel_tx.send(resp_tx).unwrap();
+        resp_rx.await.unwrap()
+    }
+
+    async fn assert_exits_once_senders_are_gone<F, Fut>(open_link: F, answer: bool)
+    where
+        F: Fn() -> Fut + Send + 'static,
+        Fut: Future<Output = Result<DuplexStream>> + Send + 'static,
+    {
+        let (channel_tx, channel_rx) = mpsc::unbounded_channel();
+        let (link_up_tx, link_up_rx) = watch::channel(None);
+
+        let join_handle = tokio::spawn(link_task_func(
+            "test".to_string(),
+            open_link,
+            AnsweringProtocol,
+            channel_rx,
+            link_up_tx,
+            SharedLinkStats::default(),
+        ));
+
+        assert_eq!(wait_for_link(&link_up_rx).await, answer);
+        assert_eq!(request(&channel_tx).await, answer);
+
+        drop(channel_tx);
+
+        time::timeout(Duration::from_secs(1), join_handle)
+            .await
+            .expect("link task still running")
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn exits_once_senders_are_gone_while_link_up() {
+        assert_exits_once_senders_are_gone(|| async { Ok(tokio::io::duplex(64).0) }, true).await;
+    }
+
+    #[tokio::test]
+    async fn exits_once_senders_are_gone_while_link_down() {
+        assert_exits_once_senders_are_gone(|| async { Err(anyhow!("No link.").into()) }, false)
+            .await;
+    }
+}
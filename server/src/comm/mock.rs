@@ -0,0 +1,402 @@
+//! In-memory comm paths for driving sensor nodes without any hardware.
+
+use std::collections::{HashMap, VecDeque};
+use std::path::PathBuf;
+use std::sync::mpsc::Sender;
+use std::sync::{Arc, Mutex};
+
+use super::i2c::Message;
+use super::modbus::{self, ModbusException, RegisterTable};
+use super::serial::{CommError, PushMessage};
+use super::{
+    CommPathProvider, I2cTransport, ModbusTransport, SerialTransport, SharedI2cTransport,
+    SharedModbusTransport, SharedSerialTransport,
+};
+
+use crate::utils::Result;
+use anyhow::anyhow;
+
+/// Expected request from a node, and the reply to it.
+type ScriptEntry = (u32, String, std::result::Result<String, CommError>);
+
+/// Holding and input registers of a mock Modbus slave.
+type SlaveRegisters = HashMap<(RegisterTable, u16), u16>;
+
+/// Serial transport replaying a script of expected requests and their
+/// replies, in order.
+#[derive(Default)]
+pub struct MockSerialTransport {
+    script: Mutex<VecDeque<ScriptEntry>>,
+    sent: Mutex<Vec<(u32, String)>>,
+    push_subscribers: Mutex<HashMap<String, Vec<Sender<PushMessage>>>>,
+}
+
+impl MockSerialTransport {
+    pub fn new() -> MockSerialTransport {
+        Default::default()
+    }
+
+    /// Appends an expected request from `node_id` to the script, answered by
+    /// `reply`.
+    pub fn expect(
+        mut self,
+        node_id: u32,
+        msg: &str,
+        reply: std::result::Result<&str, CommError>,
+    ) -> MockSerialTransport {
+        self.script.get_mut().expect("mutex poisoned").push_back((
+            node_id,
+            msg.to_string(),
+            reply.map(str::to_string),
+        ));
+        self
+    }
+
+    /// All requests sent so far, including unexpected ones.
+    pub fn sent_messages(&self) -> Vec<(u32, String)> {
+        self.sent.lock().expect("mutex poisoned").clone()
+    }
+
+    pub fn is_exhausted(&self) -> bool {
+        self.script.lock().expect("mutex poisoned").is_empty()
+    }
+
+    /// Delivers `payload` to the subscribers of its module as if `node_id`
+    /// had pushed it.
+    pub fn push(&self, node_id: u32, payload: &str) {
+        let push_msg = PushMessage {
+            node_id,
+            payload: payload.to_string(),
+        };
+
+        let mut push_subscribers = self.push_subscribers.lock().expect("mutex poisoned");
+
+        if let Some(senders) = push_subscribers.get_mut(push_msg.module()) {
+            senders.retain(|push_tx| push_tx.send(push_msg.clone()).is_ok());
+        }
+    }
+}
+
+#[rocket::async_trait]
+impl SerialTransport for MockSerialTransport {
+    async fn send(&self, node_id: u32, msg: String) -> Result<String> {
+        self.sent
+            .lock()
+            .expect("mutex poisoned")
+            .push((node_id, msg.clone()));
+
+        let (expected_node_id, expected_msg, reply) = self
+            .script
+            .lock()
+            .expect("mutex poisoned")
+            .pop_front()
+            .ok_or_else(|| anyhow!("Unexpected message to node {node_id}: '{msg}'."))?;
+
+        if expected_node_id != node_id || expected_msg != msg {
+            return Err(anyhow!(
+                "Expected message to node {expected_node_id}: '{expected_msg}', got message to node {node_id}: '{msg}'."
+            )
+            .into());
+        }
+
+        Ok(reply.map_err(anyhow::Error::new)?)
+    }
+
+    fn subscribe(&self, module: &str, push_tx: Sender<PushMessage>) {
+        self.push_subscribers
+            .lock()
+            .expect("mutex poisoned")
+            .entry(module.to_string())
+            .or_default()
+            .push(push_tx);
+    }
+}
+
+/// Register file of a simulated I2C chip. A write selects the register
+/// pointer with its first byte and stores any further bytes from there on,
+/// reads continue from the pointer. The pointer auto-increments.
+pub struct MockI2cDevice {
+    registers: [u8; 256],
+    reg_addr_mask: u8,
+    /// Bytes per register, 2 for chips with 16-bit registers.
+    reg_size: u8,
+    reg_ptr: u8,
+    /// Data returned by the next reads instead of the registers, for chips
+    /// whose readings change from one measurement to the next.
+    queued_reads: VecDeque<Vec<u8>>,
+}
+
+impl MockI2cDevice {
+    fn write(&mut self, data: &[u8]) {
+        if let Some((reg_addr, values)) = data.split_first() {
+            self.reg_ptr = (reg_addr & self.reg_addr_mask).wrapping_mul(self.reg_size);
+
+            for val in values {
+                self.registers[self.reg_ptr as usize] = *val;
+                self.reg_ptr = self.reg_ptr.wrapping_add(1);
+            }
+        }
+    }
+
+    fn read(&mut self, data: &mut [u8]) {
+        if let Some(queued) = self.queued_reads.pop_front() {
+            assert_eq!(queued.len(), data.len(), "queued I2C read of wrong length");
+            data.copy_from_slice(&queued);
+            return;
+        }
+
+        for val in data.iter_mut() {
+            *val = self.registers[self.reg_ptr as usize];
+            self.reg_ptr = self.reg_ptr.wrapping_add(1);
+        }
+    }
+}
+
+/// I2C bus with simulated chips at fixed addresses. Transfers to any other
+/// address fail like a NACK would.
+#[derive(Default)]
+pub struct MockI2cBus {
+    devices: HashMap<u16, MockI2cDevice>,
+}
+
+impl MockI2cBus {
+    pub fn new() -> MockI2cBus {
+        Default::default()
+    }
+
+    /// Adds a chip at `addr`. `reg_addr_mask` strips command bits (like the
+    /// TCS3472 command bit) from the register address byte.
+    pub fn with_device(self, addr: u16, reg_addr_mask: u8) -> MockI2cBus {
+        self.with_register_size(addr, reg_addr_mask, 1)
+    }
+
+    /// Adds a chip with 16-bit registers (like the ADS1015) at `addr`. Its
+    /// register bytes are laid out big endian, two per register address.
+    pub fn with_word_device(self, addr: u16) -> MockI2cBus {
+        self.with_register_size(addr, 0x7f, 2)
+    }
+
+    fn with_register_size(mut self, addr: u16, reg_addr_mask: u8, reg_size: u8) -> MockI2cBus {
+        self.devices.insert(
+            addr,
+            MockI2cDevice {
+                registers: [0; 256],
+                reg_addr_mask,
+                reg_size,
+                reg_ptr: 0,
+                queued_reads: VecDeque::new(),
+            },
+        );
+        self
+    }
+
+    /// Presets register bytes of the chip at `addr`, starting at `start_reg`.
+    pub fn set_registers(&mut self, addr: u16, start_reg: u8, values: &[u8]) {
+        let device = self
+            .devices
+            .get_mut(&addr)
+            .unwrap_or_else(|| panic!("no mock I2C device at address 0x{:X}", addr));
+
+        let start = start_reg as usize * device.reg_size as usize;
+
+        for (offset, val) in values.iter().enumerate() {
+            device.registers[start + offset] = *val;
+        }
+    }
+
+    /// Queues data for the next reads from the chip at `addr`, one read each.
+    /// Reads go back to the registers once the queue runs out.
+    pub fn queue_reads(&mut self, addr: u16, reads: &[&[u8]]) {
+        let device = self
+            .devices
+            .get_mut(&addr)
+            .unwrap_or_else(|| panic!("no mock I2C device at address 0x{:X}", addr));
+
+        device
+            .queued_reads
+            .extend(reads.iter().map(|read| read.to_vec()));
+    }
+
+    /// Removes the chip at `addr`, failing transfers to it from then on.
+    pub fn unplug(&mut self, addr: u16) {
+        self.devices.remove(&addr);
+    }
+
+    pub fn registers(&self, addr: u16) -> Option<&[u8; 256]> {
+        self.devices.get(&addr).map(|device| &device.registers)
+    }
+}
+
+impl I2cTransport for MockI2cBus {
+    fn transfer(&mut self, msgs: &mut [Message]) -> Result<()> {
+        for msg in msgs.iter_mut() {
+            match msg {
+                Message::Write { addr, data } => self
+                    .devices
+                    .get_mut(addr)
+                    .ok_or_else(|| anyhow!("I2C transfer error. No ACK from address 0x{addr:X}."))?
+                    .write(data),
+                Message::Read { addr, data } => self
+                    .devices
+                    .get_mut(addr)
+                    .ok_or_else(|| anyhow!("I2C transfer error. No ACK from address 0x{addr:X}."))?
+                    .read(data),
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Modbus bus with simulated slaves, each with its own holding and input
+/// registers. Reading a register that was never set gets an illegal data
+/// address exception, reading from a missing slave times out.
+#[derive(Default)]
+pub struct MockModbusBus {
+    slaves: Mutex<HashMap<u8, SlaveRegisters>>,
+}
+
+impl MockModbusBus {
+    pub fn new() -> MockModbusBus {
+        Default::default()
+    }
+
+    /// Adds a slave at `slave_addr`, without any registers.
+    pub fn with_slave(self, slave_addr: u8) -> MockModbusBus {
+        self.slaves
+            .lock()
+            .expect("mutex poisoned")
+            .insert(slave_addr, HashMap::new());
+        self
+    }
+
+    /// Sets registers of `table` of the slave at `slave_addr`, starting at
+    /// `start`.
+    pub fn set_registers(&self, slave_addr: u8, table: RegisterTable, start: u16, values: &[u16]) {
+        let mut slaves = self.slaves.lock().expect("mutex poisoned");
+
+        let registers = slaves
+            .get_mut(&slave_addr)
+            .unwrap_or_else(|| panic!("no mock Modbus slave at address {}", slave_addr));
+
+        for (offset, val) in values.iter().enumerate() {
+            registers.insert((table, start + offset as u16), *val);
+        }
+    }
+}
+
+#[rocket::async_trait]
+impl ModbusTransport for MockModbusBus {
+    async fn read_registers(
+        &self,
+        slave_addr: u8,
+        table: RegisterTable,
+        start: u16,
+        count: u16,
+    ) -> Result<Vec<u16>> {
+        let slaves = self.slaves.lock().expect("mutex poisoned");
+
+        let registers = slaves
+            .get(&slave_addr)
+            .ok_or_else(|| anyhow::Error::new(CommError::Timeout))?;
+
+        (start..start + count)
+            .map(|reg_addr| {
+                registers
+                    .get(&(table, reg_addr))
+                    .copied()
+                    .ok_or_else(|| modbus::exception_error(ModbusException(0x02)))
+            })
+            .collect()
+    }
+}
+
+/// Comm path provider handing out preconfigured (mock) transports.
+#[derive(Default)]
+pub struct MockCommPaths {
+    serial_paths: HashMap<u32, SharedSerialTransport>,
+    tcp_paths: HashMap<u32, SharedSerialTransport>,
+    i2c_paths: HashMap<u32, SharedI2cTransport>,
+    modbus_paths: HashMap<u32, SharedModbusTransport>,
+    sysfs_root: Option<PathBuf>,
+}
+
+impl MockCommPaths {
+    pub fn new() -> MockCommPaths {
+        Default::default()
+    }
+
+    pub fn with_serial_path<T>(mut self, serial_comm_path_id: u32, path: Arc<T>) -> Self
+    where
+        T: SerialTransport + 'static,
+    {
+        self.serial_paths.insert(serial_comm_path_id, path);
+        self
+    }
+
+    pub fn with_tcp_path<T>(mut self, tcp_comm_path_id: u32, path: Arc<T>) -> Self
+    where
+        T: SerialTransport + 'static,
+    {
+        self.tcp_paths.insert(tcp_comm_path_id, path);
+        self
+    }
+
+    pub fn with_i2c_path<T>(mut self, i2c_comm_path_id: u32, path: Arc<Mutex<T>>) -> Self
+    where
+        T: I2cTransport + 'static,
+    {
+        self.i2c_paths.insert(i2c_comm_path_id, path);
+        self
+    }
+
+    pub fn with_modbus_path<T>(mut self, serial_comm_path_id: u32, path: Arc<T>) -> Self
+    where
+        T: ModbusTransport + 'static,
+    {
+        self.modbus_paths.insert(serial_comm_path_id, path);
+        self
+    }
+
+    /// Points sysfs based nodes at a fake directory tree.
+    pub fn with_sysfs_root(mut self, sysfs_root: impl Into<PathBuf>) -> Self {
+        self.sysfs_root = Some(sysfs_root.into());
+        self
+    }
+}
+
+impl CommPathProvider for MockCommPaths {
+    fn serial_path(&self, serial_comm_path_id: u32) -> Result<SharedSerialTransport> {
+        self.serial_paths
+            .get(&serial_comm_path_id)
+            .cloned()
+            .ok_or_else(|| anyhow!("No mock serial comm path {serial_comm_path_id}.").into())
+    }
+
+    fn tcp_path(&self, tcp_comm_path_id: u32) -> Result<SharedSerialTransport> {
+        self.tcp_paths
+            .get(&tcp_comm_path_id)
+            .cloned()
+            .ok_or_else(|| anyhow!("No mock TCP comm path {tcp_comm_path_id}.").into())
+    }
+
+    fn i2c_path(&self, i2c_comm_path_id: u32) -> Result<SharedI2cTransport> {
+        self.i2c_paths
+            .get(&i2c_comm_path_id)
+            .cloned()
+            .ok_or_else(|| anyhow!("No mock I2C comm path {i2c_comm_path_id}.").into())
+    }
+
+    fn modbus_path(&self, serial_comm_path_id: u32) -> Result<SharedModbusTransport> {
+        self.modbus_paths
+            .get(&serial_comm_path_id)
+            .cloned()
+            .ok_or_else(|| anyhow!("No mock Modbus comm path {serial_comm_path_id}.").into())
+    }
+
+    fn sysfs_root(&self) -> Result<PathBuf> {
+        self.sysfs_root
+            .clone()
+            .ok_or_else(|| anyhow!("No mock sysfs root.").into())
+    }
+}
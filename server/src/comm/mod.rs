@@ -1,34 +1,216 @@
 use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::mpsc::Sender;
 use std::sync::{Arc, Mutex};
 
 use lazy_static::lazy_static;
 
-use crate::utils::Result;
+use crate::utils::{self, Result};
+use anyhow::anyhow;
 
+pub mod capture;
+mod framing;
 pub mod i2c;
+mod link;
+pub mod mock;
+pub mod modbus;
 pub mod serial;
+pub mod stats;
+pub mod tcp;
+
+/// Request/reply channel speaking the framed serial protocol to the nodes
+/// behind it.
+#[rocket::async_trait]
+pub trait SerialTransport: Send + Sync {
+    async fn send(&self, node_id: u32, msg: String) -> Result<String>;
+
+    /// Forwards messages the nodes push on their own for `module` to `push_tx`.
+    fn subscribe(&self, module: &str, push_tx: Sender<serial::PushMessage>);
+}
+
+/// Modbus RTU master on a bus shared by any number of slaves.
+#[rocket::async_trait]
+pub trait ModbusTransport: Send + Sync {
+    /// Reads `count` consecutive registers of `table`, from `start` on, from
+    /// slave `slave_addr`.
+    async fn read_registers(
+        &self,
+        slave_addr: u8,
+        table: modbus::RegisterTable,
+        start: u16,
+        count: u16,
+    ) -> Result<Vec<u16>>;
+}
+
+/// Bus running combined I2C transactions.
+pub trait I2cTransport: Send {
+    fn transfer(&mut self, msgs: &mut [i2c::Message]) -> Result<()>;
+}
+
+/// Errors a node reports instead of a reading, as `RET_VAL,<code>` replies or
+/// `ERR:<code>` entries of `MULTI_REPLY` batches.
+///
+/// | Code | Error             | Meaning                                       |
+/// |------|-------------------|-----------------------------------------------|
+/// | -1   | `UnknownChannel`  | No sensor with the requested ID               |
+/// | -2   | `NotReady`        | Sensor still warming up or converting         |
+/// | -3   | `SensorFault`     | Sensor present but not responding sensibly    |
+/// | -4   | `Busy`            | Node can't serve the request right now        |
+/// | -5   | `UnsupportedType` | Node has no sensors of the requested type     |
+///
+/// Any other negative code is kept as `Other`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NodeError {
+    UnknownChannel,
+    NotReady,
+    SensorFault,
+    Busy,
+    UnsupportedType,
+    Other(i32),
+}
+
+impl NodeError {
+    pub fn from_ret_val(ret_val: i32) -> NodeError {
+        match ret_val {
+            -1 => NodeError::UnknownChannel,
+            -2 => NodeError::NotReady,
+            -3 => NodeError::SensorFault,
+            -4 => NodeError::Busy,
+            -5 => NodeError::UnsupportedType,
+            other => NodeError::Other(other),
+        }
+    }
+
+    pub fn ret_val(&self) -> i32 {
+        match self {
+            NodeError::UnknownChannel => -1,
+            NodeError::NotReady => -2,
+            NodeError::SensorFault => -3,
+            NodeError::Busy => -4,
+            NodeError::UnsupportedType => -5,
+            NodeError::Other(ret_val) => *ret_val,
+        }
+    }
+}
+
+impl std::fmt::Display for NodeError {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            NodeError::UnknownChannel => write!(fmt, "Unknown sensor channel."),
+            NodeError::NotReady => write!(fmt, "Sensor not ready."),
+            NodeError::SensorFault => write!(fmt, "Sensor fault."),
+            NodeError::Busy => write!(fmt, "Node busy."),
+            NodeError::UnsupportedType => write!(fmt, "Unsupported sensor type."),
+            NodeError::Other(ret_val) => write!(fmt, "Node returned error code {}.", ret_val),
+        }
+    }
+}
+
+impl std::error::Error for NodeError {}
+
+impl AsRef<str> for NodeError {
+    fn as_ref(&self) -> &'static str {
+        match self {
+            NodeError::UnknownChannel => "unknown_channel",
+            NodeError::NotReady => "not_ready",
+            NodeError::SensorFault => "sensor_fault",
+            NodeError::Busy => "busy",
+            NodeError::UnsupportedType => "unsupported_type",
+            NodeError::Other(_) => "node_error",
+        }
+    }
+}
+
+pub type SharedSerialTransport = Arc<dyn SerialTransport>;
+pub type SharedI2cTransport = Arc<Mutex<dyn I2cTransport>>;
+pub type SharedModbusTransport = Arc<dyn ModbusTransport>;
+
+/// Source of the comm paths sensor nodes are built on top of.
+pub trait CommPathProvider {
+    fn serial_path(&self, serial_comm_path_id: u32) -> Result<SharedSerialTransport>;
+    fn tcp_path(&self, tcp_comm_path_id: u32) -> Result<SharedSerialTransport>;
+    fn i2c_path(&self, i2c_comm_path_id: u32) -> Result<SharedI2cTransport>;
+    /// Modbus RTU master on serial port `serial_comm_path_id`, which can't be
+    /// a serial path at the same time.
+    fn modbus_path(&self, serial_comm_path_id: u32) -> Result<SharedModbusTransport>;
+    /// Where sysfs is mounted, for nodes reading sensors through kernel
+    /// drivers.
+    fn sysfs_root(&self) -> Result<PathBuf>;
+}
+
+/// Comm paths backed by the real hardware, shared process-wide. Serial and TCP
+/// paths run as tasks on the Tokio runtime they are first requested from.
+pub struct SystemCommPaths;
+
+impl CommPathProvider for SystemCommPaths {
+    fn serial_path(&self, serial_comm_path_id: u32) -> Result<SharedSerialTransport> {
+        let comm_path: SharedSerialTransport = get_serial_comm_path(serial_comm_path_id)?;
+        Ok(comm_path)
+    }
+
+    fn tcp_path(&self, tcp_comm_path_id: u32) -> Result<SharedSerialTransport> {
+        let comm_path: SharedSerialTransport = get_tcp_comm_path(tcp_comm_path_id)?;
+        Ok(comm_path)
+    }
+
+    fn i2c_path(&self, i2c_comm_path_id: u32) -> Result<SharedI2cTransport> {
+        let comm_path: SharedI2cTransport = get_i2c_comm_path(i2c_comm_path_id)?;
+        Ok(comm_path)
+    }
+
+    fn modbus_path(&self, serial_comm_path_id: u32) -> Result<SharedModbusTransport> {
+        let comm_path: SharedModbusTransport = get_modbus_comm_path(serial_comm_path_id)?;
+        Ok(comm_path)
+    }
+
+    fn sysfs_root(&self) -> Result<PathBuf> {
+        utils::env_var_or("SYSFS_ROOT", PathBuf::from("/sys"))
+    }
+}
 
 lazy_static! {
-    static ref SERIAL_PATH_REGISTRY: Mutex<HashMap<u32, Arc<Mutex<serial::CommChannelTx>>>> =
+    static ref SERIAL_PATH_REGISTRY: Mutex<HashMap<u32, Arc<serial::CommChannelTx>>> =
+        Mutex::new(HashMap::new());
+    static ref TCP_PATH_REGISTRY: Mutex<HashMap<u32, Arc<serial::CommChannelTx>>> =
         Mutex::new(HashMap::new());
     static ref I2C_PATH_REGISTRY: Mutex<HashMap<u32, Arc<Mutex<i2c::CommChannel>>>> =
         Mutex::new(HashMap::new());
+    static ref MODBUS_PATH_REGISTRY: Mutex<HashMap<u32, Arc<modbus::ModbusChannelTx>>> =
+        Mutex::new(HashMap::new());
 }
 
-pub fn get_serial_comm_path(serial_comm_path_id: u32) -> Result<Arc<Mutex<serial::CommChannelTx>>> {
+pub fn get_serial_comm_path(serial_comm_path_id: u32) -> Result<Arc<serial::CommChannelTx>> {
+    if MODBUS_PATH_REGISTRY
+        .lock()
+        .expect("mutex poisoned")
+        .contains_key(&serial_comm_path_id)
+    {
+        return Err(anyhow!("Serial port {serial_comm_path_id} already runs Modbus.").into());
+    }
+
     let mut map = SERIAL_PATH_REGISTRY.lock().expect("mutex poisoned");
 
     if let Some(comm_path) = map.get(&serial_comm_path_id) {
         Ok(comm_path.clone())
     } else {
-        let comm_path = Arc::new(Mutex::new(
-            serial::create_serial_comm_task(serial_comm_path_id)?.0,
-        ));
+        let comm_path = Arc::new(serial::create_serial_comm_task(serial_comm_path_id)?.0);
         map.insert(serial_comm_path_id, comm_path.clone());
         Ok(comm_path)
     }
 }
 
+pub fn get_tcp_comm_path(tcp_comm_path_id: u32) -> Result<Arc<serial::CommChannelTx>> {
+    let mut map = TCP_PATH_REGISTRY.lock().expect("mutex poisoned");
+
+    if let Some(comm_path) = map.get(&tcp_comm_path_id) {
+        Ok(comm_path.clone())
+    } else {
+        let comm_path = Arc::new(tcp::create_tcp_comm_task(tcp_comm_path_id)?.0);
+        map.insert(tcp_comm_path_id, comm_path.clone());
+        Ok(comm_path)
+    }
+}
+
 pub fn get_i2c_comm_path(i2c_comm_path_id: u32) -> Result<Arc<Mutex<i2c::CommChannel>>> {
     let mut map = I2C_PATH_REGISTRY.lock().expect("mutex poisoned");
 
@@ -40,3 +222,64 @@ pub fn get_i2c_comm_path(i2c_comm_path_id: u32) -> Result<Arc<Mutex<i2c::CommCha
         Ok(comm_path)
     }
 }
+
+pub fn get_modbus_comm_path(serial_comm_path_id: u32) -> Result<Arc<modbus::ModbusChannelTx>> {
+    if SERIAL_PATH_REGISTRY
+        .lock()
+        .expect("mutex poisoned")
+        .contains_key(&serial_comm_path_id)
+    {
+        return Err(anyhow!(
+            "Serial port {serial_comm_path_id} already runs the serial framing protocol."
+        )
+        .into());
+    }
+
+    let mut map = MODBUS_PATH_REGISTRY.lock().expect("mutex poisoned");
+
+    if let Some(comm_path) = map.get(&serial_comm_path_id) {
+        Ok(comm_path.clone())
+    } else {
+        let comm_path = Arc::new(modbus::create_modbus_comm_task(serial_comm_path_id)?.0);
+        map.insert(serial_comm_path_id, comm_path.clone());
+        Ok(comm_path)
+    }
+}
+
+/// Link quality counters of all comm paths opened by this process so far.
+pub fn comm_stats() -> stats::CommStats {
+    let serial = SERIAL_PATH_REGISTRY
+        .lock()
+        .expect("mutex poisoned")
+        .iter()
+        .map(|(id, comm_path)| (*id, comm_path.stats()))
+        .collect();
+
+    let tcp = TCP_PATH_REGISTRY
+        .lock()
+        .expect("mutex poisoned")
+        .iter()
+        .map(|(id, comm_path)| (*id, comm_path.stats()))
+        .collect();
+
+    let i2c = I2C_PATH_REGISTRY
+        .lock()
+        .expect("mutex poisoned")
+        .iter()
+        .map(|(id, comm_path)| (*id, comm_path.lock().expect("mutex poisoned").stats()))
+        .collect();
+
+    let modbus = MODBUS_PATH_REGISTRY
+        .lock()
+        .expect("mutex poisoned")
+        .iter()
+        .map(|(id, comm_path)| (*id, comm_path.stats()))
+        .collect();
+
+    stats::CommStats {
+        serial,
+        tcp,
+        i2c,
+        modbus,
+    }
+}
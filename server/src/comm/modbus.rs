@@ -0,0 +1,872 @@
+//! Modbus RTU master, for reading off-the-shelf RS-485 sensors on a serial
+//! port.
+//!
+//! A port runs either the framing protocol of `comm::serial` or Modbus, and
+//! takes the same `SERIAL_PORT_<n>_*` line settings either way. Any number of
+//! slaves can share it: requests go out one at a time, `frame_gap` (at least
+//! 3.5 characters of silence) apart, and a reply is only taken from the slave
+//! the request went to. Anything else on the bus is skipped until it goes
+//! silent again.
+
+use std::str::FromStr;
+use std::time::Duration;
+
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::sync::{mpsc, oneshot, watch};
+use tokio::task::JoinHandle;
+use tokio::time::{self, Instant};
+
+use log::{debug, warn};
+
+use super::link::{self, link_task_func, LinkProtocol};
+use super::serial::{
+    open_serial_port, CommError, PortConfig, DEFAULT_FRAME_GAP_MS, DEFAULT_TRANSACTION_RETRIES,
+    DEFAULT_TRANSACTION_TIMEOUT_MS,
+};
+use super::stats::{LinkStats, SharedLinkStats};
+use super::NodeError;
+
+use std::io;
+
+use crate::utils::{self, Result};
+use anyhow::anyhow;
+
+/// Most registers a single read may ask for.
+pub const MAX_READ_COUNT: u16 = 125;
+
+/// Highest unicast slave address, 0 being broadcast and the rest reserved.
+pub const MAX_SLAVE_ADDR: u8 = 247;
+
+/// Function code bit set in exception replies.
+const EXCEPTION_FLAG: u8 = 0x80;
+
+/// Register table a read goes to, selected by the function code.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum RegisterTable {
+    /// Read/write registers, function code 3.
+    Holding,
+    /// Read-only registers, function code 4.
+    Input,
+}
+
+impl RegisterTable {
+    pub fn function_code(&self) -> u8 {
+        match self {
+            RegisterTable::Holding => 3,
+            RegisterTable::Input => 4,
+        }
+    }
+}
+
+impl FromStr for RegisterTable {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "3" | "holding" => Ok(RegisterTable::Holding),
+            "4" | "input" => Ok(RegisterTable::Input),
+            _ => Err("expected function code 3 (holding) or 4 (input)".to_string()),
+        }
+    }
+}
+
+/// Exception code a slave replied with instead of the registers asked for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ModbusException(pub u8);
+
+impl ModbusException {
+    fn name(&self) -> &'static str {
+        match self.0 {
+            0x01 => "illegal function",
+            0x02 => "illegal data address",
+            0x03 => "illegal data value",
+            0x04 => "slave device failure",
+            0x05 => "acknowledge",
+            0x06 => "slave device busy",
+            0x0a => "gateway path unavailable",
+            0x0b => "gateway target failed to respond",
+            _ => "unknown exception",
+        }
+    }
+
+    /// The node error closest to the exception, so that it's reported like
+    /// errors of any other node.
+    pub fn node_error(&self) -> NodeError {
+        match self.0 {
+            0x01 => NodeError::UnsupportedType,
+            0x02 => NodeError::UnknownChannel,
+            0x05 | 0x06 => NodeError::Busy,
+            _ => NodeError::SensorFault,
+        }
+    }
+}
+
+impl std::fmt::Display for ModbusException {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(fmt, "Modbus exception 0x{:02X} ({})", self.0, self.name())
+    }
+}
+
+impl std::error::Error for ModbusException {}
+
+/// Error for a read answered by `exception`, carrying both the exception and
+/// the matching node error.
+pub(super) fn exception_error(exception: ModbusException) -> utils::Error {
+    anyhow::Error::new(exception.node_error())
+        .context(exception)
+        .into()
+}
+
+/// CRC-16/MODBUS (reflected polynomial 0xA001, initial value 0xFFFF), sent
+/// low byte first.
+pub fn crc16(data: &[u8]) -> u16 {
+    data.iter().fold(0xFFFF, |crc, byte| {
+        (0..8).fold(crc ^ u16::from(*byte), |crc, _| {
+            if crc & 0x0001 != 0 {
+                (crc >> 1) ^ 0xA001
+            } else {
+                crc >> 1
+            }
+        })
+    })
+}
+
+#[derive(Debug, Clone, Copy)]
+struct ReadRequest {
+    slave_addr: u8,
+    table: RegisterTable,
+    start: u16,
+    count: u16,
+}
+
+impl ReadRequest {
+    fn encode(&self) -> Vec<u8> {
+        let mut frame = vec![self.slave_addr, self.table.function_code()];
+        frame.extend_from_slice(&self.start.to_be_bytes());
+        frame.extend_from_slice(&self.count.to_be_bytes());
+        frame.extend_from_slice(&crc16(&frame).to_le_bytes());
+        frame
+    }
+}
+
+/// Outcome of a read, as reported by the comm task.
+#[derive(Debug, Clone)]
+enum ReadError {
+    Comm(CommError),
+    Exception(ModbusException),
+}
+
+type ReadResult = std::result::Result<Vec<u16>, ReadError>;
+
+type RequestAndResponseChannel = (ReadRequest, oneshot::Sender<ReadResult>);
+
+/// Per-port protocol settings, read from the same `<prefix>_TIMEOUT_MS`,
+/// `<prefix>_RETRIES` and `<prefix>_FRAME_GAP_MS` env variables as on serial
+/// paths.
+#[derive(Debug, Clone)]
+struct ModbusConfig {
+    timeout: Duration,
+    retries: u32,
+    /// Silence kept before each request, and taken as the end of a frame
+    /// being skipped.
+    frame_gap: Duration,
+}
+
+impl ModbusConfig {
+    fn from_env(env_var_prefix: &str) -> Result<ModbusConfig> {
+        let timeout_ms = utils::env_var_or(
+            &format!("{}_TIMEOUT_MS", env_var_prefix),
+            DEFAULT_TRANSACTION_TIMEOUT_MS,
+        )?;
+
+        if timeout_ms == 0 {
+            return Err(anyhow!("{env_var_prefix}_TIMEOUT_MS must be greater than 0.").into());
+        }
+
+        let retries = utils::env_var_or(
+            &format!("{}_RETRIES", env_var_prefix),
+            DEFAULT_TRANSACTION_RETRIES,
+        )?;
+
+        let frame_gap_ms = utils::env_var_or(
+            &format!("{}_FRAME_GAP_MS", env_var_prefix),
+            DEFAULT_FRAME_GAP_MS,
+        )?;
+
+        Ok(ModbusConfig {
+            timeout: Duration::from_millis(timeout_ms),
+            retries,
+            frame_gap: Duration::from_millis(frame_gap_ms),
+        })
+    }
+}
+
+#[derive(Clone)]
+pub struct ModbusChannelTx {
+    msg_tx: mpsc::UnboundedSender<RequestAndResponseChannel>,
+    /// `None` until the first attempt to open the link is over.
+    link_up: watch::Receiver<Option<bool>>,
+    stats: SharedLinkStats,
+}
+
+impl ModbusChannelTx {
+    pub async fn read_registers(
+        &self,
+        slave_addr: u8,
+        table: RegisterTable,
+        start: u16,
+        count: u16,
+    ) -> Result<Vec<u16>> {
+        if slave_addr == 0 || slave_addr > MAX_SLAVE_ADDR {
+            return Err(anyhow!("Invalid Modbus slave address {slave_addr}.").into());
+        }
+
+        if count == 0 || count > MAX_READ_COUNT {
+            return Err(anyhow!("Can't read {count} Modbus registers at once.").into());
+        }
+
+        // Don't bother queueing anything while the port is being reopened.
+        if !link::wait_for_link(&self.link_up).await {
+            return Err(anyhow::Error::new(CommError::LinkDown).into());
+        }
+
+        let (response_tx, response_rx) = oneshot::channel();
+
+        let request = ReadRequest {
+            slave_addr,
+            table,
+            start,
+            count,
+        };
+
+        self.msg_tx
+            .send((request, response_tx))
+            .map_err(|e| anyhow!("Failed to send Modbus request. {e:?}"))?;
+
+        match response_rx
+            .await
+            .map_err(|e| anyhow!("Error while receiving Modbus reply. {e:?}"))?
+        {
+            Ok(registers) => Ok(registers),
+            Err(ReadError::Comm(comm_err)) => Err(anyhow::Error::new(comm_err).into()),
+            Err(ReadError::Exception(exception)) => Err(exception_error(exception)),
+        }
+    }
+
+    /// Snapshot of the link quality counters of this path.
+    pub fn stats(&self) -> LinkStats {
+        self.stats.lock().expect("mutex poisoned").clone()
+    }
+}
+
+#[rocket::async_trait]
+impl super::ModbusTransport for ModbusChannelTx {
+    async fn read_registers(
+        &self,
+        slave_addr: u8,
+        table: RegisterTable,
+        start: u16,
+        count: u16,
+    ) -> Result<Vec<u16>> {
+        ModbusChannelTx::read_registers(self, slave_addr, table, start, count).await
+    }
+}
+
+/// What the bytes received so far make of the reply to a request.
+#[derive(Debug)]
+enum ParsedReply {
+    Incomplete,
+    /// The request itself, handed back by a transceiver that keeps listening
+    /// while driving the bus, possibly followed by the start of the reply.
+    Echo,
+    /// Bytes that aren't from the slave the request went to, or aren't a
+    /// reply to it.
+    Foreign,
+    ChecksumMismatch,
+    /// A reply with the right CRC, but not the number of registers asked for.
+    Malformed,
+    Complete(std::result::Result<Vec<u16>, ModbusException>),
+}
+
+fn parse_reply(received: &[u8], request: &ReadRequest, request_frame: &[u8]) -> ParsedReply {
+    // The reply may have arrived in the same read as the echo
+    if received.starts_with(request_frame) {
+        return ParsedReply::Echo;
+    }
+
+    // Until proven otherwise, this may be the start of an echo
+    let maybe_echo = request_frame.starts_with(received);
+
+    let (slave_addr, function_code) = match received {
+        [] => return ParsedReply::Incomplete,
+        [slave_addr] => (*slave_addr, None),
+        [slave_addr, function_code, ..] => (*slave_addr, Some(*function_code)),
+    };
+
+    if slave_addr != request.slave_addr {
+        return ParsedReply::Foreign;
+    }
+
+    let function = request.table.function_code();
+
+    let reply_len = match function_code {
+        None => return ParsedReply::Incomplete,
+        Some(code) if code == function => match received.get(2) {
+            Some(byte_count) => 5 + *byte_count as usize,
+            None => return ParsedReply::Incomplete,
+        },
+        Some(code) if code == function | EXCEPTION_FLAG => 5,
+        Some(_) => return ParsedReply::Foreign,
+    };
+
+    if received.len() < reply_len {
+        return ParsedReply::Incomplete;
+    }
+
+    let (content, crc_bytes) = received[..reply_len].split_at(reply_len - 2);
+
+    if crc16(content) != u16::from_le_bytes([crc_bytes[0], crc_bytes[1]]) {
+        return if maybe_echo {
+            ParsedReply::Incomplete
+        } else {
+            ParsedReply::ChecksumMismatch
+        };
+    }
+
+    if content[1] & EXCEPTION_FLAG != 0 {
+        return ParsedReply::Complete(Err(ModbusException(content[2])));
+    }
+
+    let data = &content[3..];
+
+    if data.len() != 2 * request.count as usize {
+        return ParsedReply::Malformed;
+    }
+
+    ParsedReply::Complete(Ok(data
+        .chunks(2)
+        .map(|word| u16::from_be_bytes([word[0], word[1]]))
+        .collect()))
+}
+
+/// Waits for the reply to `request` until `deadline`, skipping anything else
+/// on the bus. `None` means there was no usable reply in time.
+async fn receive_reply<R>(
+    comm_rx: &mut R,
+    request: &ReadRequest,
+    request_frame: &[u8],
+    config: &ModbusConfig,
+    deadline: Instant,
+    last_bus_activity: &mut Instant,
+    stats: &SharedLinkStats,
+) -> io::Result<Option<std::result::Result<Vec<u16>, ModbusException>>>
+where
+    R: AsyncRead + Unpin,
+{
+    let mut received = Vec::new();
+    let mut incoming = [0; 256];
+    // Set while skipping a frame, until the bus goes silent
+    let mut skipping = false;
+
+    loop {
+        let wait_until = if skipping {
+            deadline.min(*last_bus_activity + config.frame_gap)
+        } else {
+            deadline
+        };
+
+        let incoming_len = match time::timeout_at(wait_until, comm_rx.read(&mut incoming)).await {
+            Ok(Ok(0)) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    "End of stream.",
+                ))
+            }
+            Ok(Ok(incoming_len)) => incoming_len,
+            Ok(Err(e)) => return Err(e),
+            Err(_) if skipping && Instant::now() < deadline => {
+                skipping = false;
+                continue;
+            }
+            Err(_) => return Ok(None),
+        };
+
+        *last_bus_activity = Instant::now();
+
+        debug!("Rx buffer is now: {:02X?}", &incoming[..incoming_len]);
+
+        if skipping {
+            continue;
+        }
+
+        received.extend_from_slice(&incoming[..incoming_len]);
+
+        loop {
+            match parse_reply(&received, request, request_frame) {
+                ParsedReply::Incomplete => break,
+                ParsedReply::Echo => {
+                    debug!("Ignoring echo of request {:02X?}.", request_frame);
+                    received.drain(..request_frame.len());
+                }
+                ParsedReply::Foreign => {
+                    debug!(
+                        "Skipping frame not from slave {}: {:02X?}",
+                        request.slave_addr, received
+                    );
+
+                    stats.lock().expect("mutex poisoned").foreign_frames += 1;
+                    received.clear();
+                    skipping = true;
+                    break;
+                }
+                ParsedReply::ChecksumMismatch => {
+                    warn!(
+                        "CRC mismatch in reply from Modbus slave {}: {:02X?}",
+                        request.slave_addr, received
+                    );
+
+                    stats.lock().expect("mutex poisoned").checksum_failures += 1;
+                    return Ok(None);
+                }
+                ParsedReply::Malformed => {
+                    warn!(
+                        "Unexpected reply from Modbus slave {}: {:02X?}",
+                        request.slave_addr, received
+                    );
+
+                    stats.lock().expect("mutex poisoned").malformed_frames += 1;
+                    return Ok(None);
+                }
+                ParsedReply::Complete(reply) => return Ok(Some(reply)),
+            }
+        }
+    }
+}
+
+/// Sends `request` and waits for its reply, retransmitting it after a
+/// timeout or a corrupted reply as long as retries are left.
+async fn transact<R, W>(
+    comm_rx: &mut R,
+    comm_tx: &mut W,
+    request: &ReadRequest,
+    config: &ModbusConfig,
+    last_bus_activity: &mut Instant,
+    stats: &SharedLinkStats,
+) -> io::Result<ReadResult>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let request_frame = request.encode();
+
+    for attempt in 0..=config.retries {
+        time::sleep_until(*last_bus_activity + config.frame_gap).await;
+
+        debug!(
+            "server -> slave {}: {:?} registers {}..+{}",
+            request.slave_addr, request.table, request.start, request.count
+        );
+
+        comm_tx.write_all(&request_frame).await?;
+        comm_tx.flush().await?;
+
+        let sent_at = Instant::now();
+        *last_bus_activity = sent_at;
+
+        {
+            let mut stats = stats.lock().expect("mutex poisoned");
+            stats.frames_sent += 1;
+
+            if attempt > 0 {
+                stats.retransmits += 1;
+            }
+        }
+
+        if let Some(reply) = receive_reply(
+            comm_rx,
+            request,
+            &request_frame,
+            config,
+            sent_at + config.timeout,
+            last_bus_activity,
+            stats,
+        )
+        .await?
+        {
+            {
+                let mut stats = stats.lock().expect("mutex poisoned");
+                stats.replies_received += 1;
+                stats.record_latency(sent_at.elapsed());
+            }
+
+            debug!("Reply from slave {}: {:?}", request.slave_addr, reply);
+
+            return Ok(reply.map_err(ReadError::Exception));
+        }
+
+        debug!(
+            "No reply from slave {} to attempt {}.",
+            request.slave_addr,
+            attempt + 1
+        );
+    }
+
+    warn!("Read from Modbus slave {} timed out.", request.slave_addr);
+    stats.lock().expect("mutex poisoned").timeouts += 1;
+
+    Ok(Err(ReadError::Comm(CommError::Timeout)))
+}
+
+/// Runs requests over `comm` one at a time, until all request senders are
+/// gone or an I/O error breaks the link.
+async fn modbus_func<T>(
+    channel_rx: &mut mpsc::UnboundedReceiver<RequestAndResponseChannel>,
+    comm: T,
+    config: &ModbusConfig,
+    stats: &SharedLinkStats,
+) -> io::Result<()>
+where
+    T: AsyncRead + AsyncWrite,
+{
+    let (mut comm_rx, mut comm_tx) = tokio::io::split(comm);
+
+    let mut last_bus_activity = Instant::now();
+    let mut incoming = [0; 256];
+
+    loop {
+        tokio::select! {
+            request = channel_rx.recv() => {
+                let (request, resp_tx) = match request {
+                    Some(request) => request,
+                    None => return Ok(()),
+                };
+
+                match transact(
+                    &mut comm_rx,
+                    &mut comm_tx,
+                    &request,
+                    config,
+                    &mut last_bus_activity,
+                    stats,
+                )
+                .await
+                {
+                    Ok(result) => {
+                        let _ = resp_tx.send(result);
+                    }
+                    Err(e) => {
+                        let _ = resp_tx.send(Err(ReadError::Comm(CommError::LinkDown)));
+                        return Err(e);
+                    }
+                }
+            }
+
+            // Nothing should arrive between transactions but late replies,
+            // which only matter for keeping the frame gap.
+            read_result = comm_rx.read(&mut incoming) => {
+                match read_result {
+                    Ok(0) => {
+                        return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "End of stream."))
+                    }
+                    Ok(incoming_len) => {
+                        last_bus_activity = Instant::now();
+                        debug!("Discarding {:02X?} between transactions.", &incoming[..incoming_len]);
+                    }
+                    Err(e) => return Err(e),
+                }
+            }
+        }
+    }
+}
+
+/// Modbus RTU master, reading registers one request at a time.
+struct ModbusProtocol {
+    config: ModbusConfig,
+    stats: SharedLinkStats,
+}
+
+#[rocket::async_trait]
+impl LinkProtocol for ModbusProtocol {
+    type Request = RequestAndResponseChannel;
+
+    fn reject((_, resp_tx): RequestAndResponseChannel) {
+        let _ = resp_tx.send(Err(ReadError::Comm(CommError::LinkDown)));
+    }
+
+    async fn run<T>(
+        &mut self,
+        _link_name: &str,
+        channel_rx: &mut mpsc::UnboundedReceiver<RequestAndResponseChannel>,
+        comm: T,
+    ) -> io::Result<()>
+    where
+        T: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    {
+        modbus_func(channel_rx, comm, &self.config, &self.stats).await
+    }
+}
+
+/// Starts a Modbus RTU master on the serial port given by the
+/// `SERIAL_PORT_<n>_PATH` env variable, on the current Tokio runtime.
+pub fn create_modbus_comm_task(serial_id: u32) -> Result<(ModbusChannelTx, JoinHandle<()>)> {
+    let env_var_str = format!("SERIAL_PORT_{}_PATH", serial_id);
+
+    let port_path = dotenv::var(&env_var_str)
+        .map_err(|e| anyhow!("Missing {env_var_str} env variable. {e:?}"))?;
+
+    let port_config = PortConfig::from_env(serial_id)?;
+    let config = ModbusConfig::from_env(&format!("SERIAL_PORT_{}", serial_id))?;
+
+    let (channel_tx, channel_rx) = mpsc::unbounded_channel();
+    let (link_up_tx, link_up_rx) = watch::channel(None);
+    let stats = SharedLinkStats::default();
+
+    let port_path_clone = port_path.clone();
+
+    let protocol = ModbusProtocol {
+        config,
+        stats: stats.clone(),
+    };
+
+    let join_handle = tokio::spawn(link_task_func(
+        port_path,
+        move || {
+            let link = open_serial_port(&port_path_clone, &port_config);
+            async move { link }
+        },
+        protocol,
+        channel_rx,
+        link_up_tx,
+        stats.clone(),
+    ));
+
+    Ok((
+        ModbusChannelTx {
+            msg_tx: channel_tx,
+            link_up: link_up_rx,
+            stats,
+        },
+        join_handle,
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use ratfist_node_stub::modbus::{Stations, FRAME_GAP};
+
+    use tokio::io::DuplexStream;
+
+    fn frame(content: &[u8]) -> Vec<u8> {
+        let mut frame = content.to_vec();
+        frame.extend_from_slice(&crc16(content).to_le_bytes());
+        frame
+    }
+
+    fn read_request() -> ReadRequest {
+        ReadRequest {
+            slave_addr: 7,
+            table: RegisterTable::Input,
+            start: 0x0102,
+            count: 2,
+        }
+    }
+
+    #[test]
+    fn parses_reply_after_echo_in_the_same_read() {
+        let request = read_request();
+        let request_frame = request.encode();
+        let reply = frame(&[7, 4, 4, 0x12, 0x34, 0xab, 0xcd]);
+
+        let received = [request_frame.clone(), reply.clone()].concat();
+        assert!(matches!(
+            parse_reply(&received, &request, &request_frame),
+            ParsedReply::Echo
+        ));
+
+        // The echo alone, and then only part of it
+        assert!(matches!(
+            parse_reply(&request_frame, &request, &request_frame),
+            ParsedReply::Echo
+        ));
+        assert!(matches!(
+            parse_reply(&request_frame[..5], &request, &request_frame),
+            ParsedReply::Incomplete
+        ));
+
+        match parse_reply(&reply, &request, &request_frame) {
+            ParsedReply::Complete(Ok(registers)) => assert_eq!(registers, vec![0x1234, 0xabcd]),
+            other => panic!("Unexpected {:?}", other),
+        }
+    }
+
+    #[test]
+    fn tells_apart_exceptions_and_bad_replies() {
+        let request = read_request();
+        let request_frame = request.encode();
+
+        assert!(matches!(
+            parse_reply(&frame(&[7, 0x84, 0x02]), &request, &request_frame),
+            ParsedReply::Complete(Err(ModbusException(0x02)))
+        ));
+        assert!(matches!(
+            parse_reply(&frame(&[9, 4, 2, 0x12, 0x34]), &request, &request_frame),
+            ParsedReply::Foreign
+        ));
+        assert!(matches!(
+            parse_reply(&frame(&[7, 3, 2, 0x12, 0x34]), &request, &request_frame),
+            ParsedReply::Foreign
+        ));
+        assert!(matches!(
+            parse_reply(&frame(&[7, 4, 2, 0x12, 0x34]), &request, &request_frame),
+            ParsedReply::Malformed
+        ));
+
+        let mut corrupted = frame(&[7, 4, 4, 0x12, 0x34, 0xab, 0xcd]);
+        corrupted[3] ^= 0x01;
+        assert!(matches!(
+            parse_reply(&corrupted, &request, &request_frame),
+            ParsedReply::ChecksumMismatch
+        ));
+    }
+
+    /// What the simulated slaves send ahead of each reply.
+    #[derive(Debug, Clone, Copy)]
+    enum Preamble {
+        None,
+        /// The request itself, in the same write as the reply.
+        Echo,
+        /// A reply from slave 99, a frame gap before the actual reply.
+        Foreign,
+    }
+
+    /// Runs the node stub's simulated slaves on the other end of `link`,
+    /// answering each request once the line has been silent for a frame gap.
+    fn spawn_stub_slaves(mut link: DuplexStream, slave_addrs: &[u8], preamble: Preamble) {
+        let mut stations = Stations::new(slave_addrs);
+
+        tokio::spawn(async move {
+            let mut request = Vec::new();
+            let mut incoming = [0; 256];
+
+            loop {
+                match time::timeout(FRAME_GAP, link.read(&mut incoming)).await {
+                    Ok(Ok(0)) | Ok(Err(_)) => return,
+                    Ok(Ok(incoming_len)) => request.extend_from_slice(&incoming[..incoming_len]),
+                    Err(_) if request.is_empty() => continue,
+                    Err(_) => {
+                        let mut out = match preamble {
+                            Preamble::None => Vec::new(),
+                            Preamble::Echo => request.clone(),
+                            Preamble::Foreign => {
+                                let foreign = frame(&[99, 4, 2, 0x12, 0x34]);
+                                if link.write_all(&foreign).await.is_err() {
+                                    return;
+                                }
+                                time::sleep(FRAME_GAP * 2).await;
+                                Vec::new()
+                            }
+                        };
+
+                        out.extend(stations.answer(&request).unwrap_or_default());
+                        request.clear();
+
+                        if link.write_all(&out).await.is_err() {
+                            return;
+                        }
+                    }
+                }
+            }
+        });
+    }
+
+    /// Reads `count` registers from `slave_addr` of the stub's slaves 3 and 4,
+    /// over an in-memory link.
+    async fn read_from_stub(
+        preamble: Preamble,
+        slave_addr: u8,
+        table: RegisterTable,
+        start: u16,
+        count: u16,
+    ) -> (ReadResult, LinkStats) {
+        let (master_link, slave_link) = tokio::io::duplex(256);
+        spawn_stub_slaves(slave_link, &[3, 4], preamble);
+
+        let (mut comm_rx, mut comm_tx) = tokio::io::split(master_link);
+        let config = ModbusConfig {
+            timeout: Duration::from_millis(200),
+            retries: 0,
+            frame_gap: FRAME_GAP,
+        };
+        let stats = SharedLinkStats::default();
+        let request = ReadRequest {
+            slave_addr,
+            table,
+            start,
+            count,
+        };
+
+        let result = transact(
+            &mut comm_rx,
+            &mut comm_tx,
+            &request,
+            &config,
+            &mut Instant::now(),
+            &stats,
+        )
+        .await
+        .expect("link broke");
+
+        let stats = stats.lock().unwrap().clone();
+        (result, stats)
+    }
+
+    /// Checks the stub's wind speed, in 0.1 m/s and as an f32 in m/s.
+    fn assert_wind_speed(registers: &[u16]) {
+        assert_eq!(registers.len(), 3);
+
+        let wind_speed = f32::from_bits(u32::from(registers[1]) << 16 | u32::from(registers[2]));
+        assert!(wind_speed >= 0.0);
+        assert!((registers[0] as f32 / 10.0 - wind_speed).abs() <= 0.051);
+    }
+
+    #[tokio::test]
+    async fn reads_registers_of_stub_slave() {
+        for &preamble in &[Preamble::None, Preamble::Echo] {
+            let (result, stats) = read_from_stub(preamble, 4, RegisterTable::Input, 0, 3).await;
+
+            match result {
+                Ok(registers) => assert_wind_speed(&registers),
+                Err(e) => panic!("Read with {:?} failed: {:?}", preamble, e),
+            }
+            assert_eq!(stats.replies_received, 1);
+            assert_eq!(stats.foreign_frames, 0);
+        }
+    }
+
+    #[tokio::test]
+    async fn skips_replies_of_other_slaves() {
+        let (result, stats) =
+            read_from_stub(Preamble::Foreign, 3, RegisterTable::Holding, 2, 1).await;
+
+        // Temperature in 0.1 °C, starting out at 15 °C
+        let temperature = result.expect("read failed")[0] as i16;
+        assert!((0..300).contains(&temperature), "{}", temperature);
+        assert_eq!(stats.foreign_frames, 1);
+        assert_eq!(stats.replies_received, 1);
+    }
+
+    #[tokio::test]
+    async fn reports_exceptions_and_silent_slaves() {
+        let (result, _) = read_from_stub(Preamble::None, 3, RegisterTable::Holding, 2, 2).await;
+        assert!(matches!(
+            result,
+            Err(ReadError::Exception(ModbusException(0x02)))
+        ));
+
+        let (result, stats) = read_from_stub(Preamble::None, 5, RegisterTable::Input, 0, 1).await;
+        assert!(matches!(result, Err(ReadError::Comm(CommError::Timeout))));
+        assert_eq!(stats.timeouts, 1);
+    }
+}
@@ -2,178 +2,892 @@ use std::collections::HashMap;
 
 use serial::prelude::*;
 
-use std::thread;
+use std::future::Future;
+use std::pin::Pin;
+use std::task::{ready, Context, Poll};
 
-use std::sync::mpsc;
-use std::sync::mpsc::{Receiver, Sender};
+use std::sync::mpsc::Sender;
+use std::sync::{Arc, Mutex};
 
-use std::io::{Read, Write};
+use std::io::{self, Read, Write};
 
 use std::time::Duration;
 
+use tokio::io::unix::AsyncFd;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf};
+use tokio::sync::{mpsc, oneshot, watch};
+use tokio::task::JoinHandle;
+use tokio::time::{self, Instant};
+
 use log::{debug, warn};
 
-use crate::utils::Result;
+use super::capture::{self, CaptureLink};
+use super::framing::{ChecksumMismatch, FrameDecoder, Framing, IncomingFrame};
+use super::link::{self, link_task_func, LinkProtocol};
+use super::stats::{LinkStats, SharedLinkStats};
+
+use crate::utils::{self, Result};
 use anyhow::anyhow;
 
-type MsgAndResponseChannel = (u32, String, Sender<String>);
+/// Transaction ID reserved for messages the nodes send on their own. Such
+/// frames carry the sender's node ID next, like `$0,<node_id>,METEO,...*CS`
+/// with ASCII framing.
+pub const PUSH_TRANSACTION_ID: u64 = 0;
+
+pub(super) const DEFAULT_TRANSACTION_TIMEOUT_MS: u64 = 1000;
+pub(super) const DEFAULT_TRANSACTION_RETRIES: u32 = 2;
+
+/// Silence kept on a half-duplex bus before transmitting, for the previous
+/// sender's driver to turn off.
+pub(super) const DEFAULT_FRAME_GAP_MS: u64 = 5;
+
+/// Baud rates the termios backend of the `serial` crate can set on Linux.
+const SUPPORTED_BAUD_RATES: [usize; 28] = [
+    50, 75, 110, 134, 150, 200, 300, 600, 1200, 1800, 2400, 4800, 9600, 19200, 38400, 57600,
+    115200, 230400, 460800, 500000, 576000, 921600, 1000000, 1152000, 1500000, 2000000, 2500000,
+    3000000,
+];
+
+/// Errors reported by the comm thread instead of a reply payload.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CommError {
+    /// The underlying port is not open, or broke while the request was pending.
+    LinkDown,
+    /// No reply arrived in time, not even after all retransmissions.
+    Timeout,
+}
+
+impl std::fmt::Display for CommError {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            CommError::LinkDown => write!(fmt, "Serial link down."),
+            CommError::Timeout => write!(fmt, "Serial transaction timed out."),
+        }
+    }
+}
+
+impl std::error::Error for CommError {}
+
+impl AsRef<str> for CommError {
+    fn as_ref(&self) -> &'static str {
+        match self {
+            CommError::LinkDown => "link_down",
+            CommError::Timeout => "timeout",
+        }
+    }
+}
+
+type ResponseResult = std::result::Result<String, CommError>;
+
+type MsgAndResponseChannel = (u32, String, oneshot::Sender<ResponseResult>);
+
+/// Message a node sent without being asked, e.g. a reading or an event.
+#[derive(Debug, Clone)]
+pub struct PushMessage {
+    pub node_id: u32,
+    /// Payload including the leading module token, same as in replies.
+    pub payload: String,
+}
+
+impl PushMessage {
+    pub fn module(&self) -> &str {
+        self.payload.split(',').next().unwrap_or("")
+    }
+}
+
+/// Senders of push message subscribers, keyed by module token.
+type PushSubscribers = Arc<Mutex<HashMap<String, Vec<Sender<PushMessage>>>>>;
+
+/// Per-path protocol settings, read from `<prefix>_TIMEOUT_MS`,
+/// `<prefix>_RETRIES`, `<prefix>_FRAMING`, `<prefix>_HALF_DUPLEX`,
+/// `<prefix>_FRAME_GAP_MS` and `<prefix>_CAPTURE` env variables, e.g.
+/// `SERIAL_PORT_0_RETRIES`.
+#[derive(Debug, Clone)]
+pub(super) struct TransactionConfig {
+    timeout: Duration,
+    retries: u32,
+    framing: Framing,
+    /// Multi-drop bus mode, e.g. RS-485: one transaction at a time, with
+    /// `frame_gap` of silence before each transmission, and replies echoing
+    /// the node ID.
+    half_duplex: bool,
+    frame_gap: Duration,
+    /// File to record all traffic on the path to, see `comm::capture`.
+    capture_path: Option<String>,
+}
+
+impl TransactionConfig {
+    pub(super) fn from_env(env_var_prefix: &str) -> Result<TransactionConfig> {
+        let timeout_ms = utils::env_var_or(
+            &format!("{}_TIMEOUT_MS", env_var_prefix),
+            DEFAULT_TRANSACTION_TIMEOUT_MS,
+        )?;
+
+        if timeout_ms == 0 {
+            return Err(anyhow!("{env_var_prefix}_TIMEOUT_MS must be greater than 0.").into());
+        }
+
+        let retries = utils::env_var_or(
+            &format!("{}_RETRIES", env_var_prefix),
+            DEFAULT_TRANSACTION_RETRIES,
+        )?;
+
+        let framing = utils::env_var_or(&format!("{}_FRAMING", env_var_prefix), Framing::Ascii)?;
+
+        let half_duplex = utils::env_var_or(&format!("{}_HALF_DUPLEX", env_var_prefix), false)?;
+
+        let frame_gap_ms = utils::env_var_or(
+            &format!("{}_FRAME_GAP_MS", env_var_prefix),
+            DEFAULT_FRAME_GAP_MS,
+        )?;
+
+        let capture_path = dotenv::var(format!("{}_CAPTURE", env_var_prefix)).ok();
+
+        // Links are read asynchronously now, without a polling timeout.
+        if dotenv::var(format!("{}_READ_TIMEOUT_MS", env_var_prefix)).is_ok() {
+            warn!("{env_var_prefix}_READ_TIMEOUT_MS is no longer used, ignoring it.");
+        }
+
+        Ok(TransactionConfig {
+            timeout: Duration::from_millis(timeout_ms),
+            retries,
+            framing,
+            half_duplex,
+            frame_gap: Duration::from_millis(frame_gap_ms),
+            capture_path,
+        })
+    }
+
+    /// Default settings for replaying a capture made with `framing`.
+    pub(super) fn for_replay(framing: Framing, half_duplex: bool) -> TransactionConfig {
+        TransactionConfig {
+            timeout: Duration::from_millis(DEFAULT_TRANSACTION_TIMEOUT_MS),
+            retries: DEFAULT_TRANSACTION_RETRIES,
+            framing,
+            half_duplex,
+            frame_gap: Duration::from_millis(DEFAULT_FRAME_GAP_MS),
+            capture_path: None,
+        }
+    }
+}
+
+/// Line settings of a serial port, read from `SERIAL_PORT_<n>_*` env variables.
+/// Anything not set defaults to 115200 8N1 without flow control.
+#[derive(Debug, Clone, Copy)]
+pub(super) struct PortConfig {
+    settings: serial::PortSettings,
+    /// Drive an RS-485 transceiver's driver enable input with RTS.
+    rts_driver_enable: bool,
+}
+
+impl PortConfig {
+    pub(super) fn from_env(serial_id: u32) -> Result<PortConfig> {
+        let baud_rate = parse_port_setting(
+            serial_id,
+            "BAUD_RATE",
+            serial::Baud115200,
+            |val| {
+                val.parse()
+                    .ok()
+                    .filter(|speed| SUPPORTED_BAUD_RATES.contains(speed))
+                    .map(serial::BaudRate::from_speed)
+            },
+            "a standard baud rate, e.g. 9600, 57600 or 115200",
+        )?;
+
+        let char_size = parse_port_setting(
+            serial_id,
+            "DATA_BITS",
+            serial::Bits8,
+            |val| match val {
+                "5" => Some(serial::Bits5),
+                "6" => Some(serial::Bits6),
+                "7" => Some(serial::Bits7),
+                "8" => Some(serial::Bits8),
+                _ => None,
+            },
+            "one of 5, 6, 7, 8",
+        )?;
+
+        let parity = parse_port_setting(
+            serial_id,
+            "PARITY",
+            serial::ParityNone,
+            |val| match val.to_lowercase().as_str() {
+                "none" => Some(serial::ParityNone),
+                "odd" => Some(serial::ParityOdd),
+                "even" => Some(serial::ParityEven),
+                _ => None,
+            },
+            "one of none, odd, even",
+        )?;
+
+        let stop_bits = parse_port_setting(
+            serial_id,
+            "STOP_BITS",
+            serial::Stop1,
+            |val| match val {
+                "1" => Some(serial::Stop1),
+                "2" => Some(serial::Stop2),
+                _ => None,
+            },
+            "one of 1, 2",
+        )?;
+
+        let flow_control = parse_port_setting(
+            serial_id,
+            "FLOW_CONTROL",
+            serial::FlowNone,
+            |val| match val.to_lowercase().as_str() {
+                "none" => Some(serial::FlowNone),
+                "software" | "xonxoff" => Some(serial::FlowSoftware),
+                "hardware" | "rtscts" => Some(serial::FlowHardware),
+                _ => None,
+            },
+            "one of none, software (xonxoff), hardware (rtscts)",
+        )?;
+
+        let rts_driver_enable = parse_port_setting(
+            serial_id,
+            "RTS_DRIVER_ENABLE",
+            false,
+            |val| val.parse().ok(),
+            "one of true, false",
+        )?;
+
+        Ok(PortConfig {
+            settings: serial::PortSettings {
+                baud_rate,
+                char_size,
+                parity,
+                stop_bits,
+                flow_control,
+            },
+            rts_driver_enable,
+        })
+    }
+}
+
+/// Reads the optional `SERIAL_PORT_<serial_id>_<setting>` env variable,
+/// explaining what was expected if `parse` rejects its value.
+fn parse_port_setting<T, F>(
+    serial_id: u32,
+    setting: &str,
+    default: T,
+    parse: F,
+    expected: &str,
+) -> Result<T>
+where
+    F: Fn(&str) -> Option<T>,
+{
+    let env_var_str = format!("SERIAL_PORT_{}_{}", serial_id, setting);
+
+    match dotenv::var(&env_var_str) {
+        Ok(val_str) => parse(val_str.trim()).ok_or_else(|| {
+            anyhow!(
+                "Invalid value '{val_str}' for {env_var_str} env variable, expected {expected}."
+            )
+            .into()
+        }),
+        Err(_) => Ok(default),
+    }
+}
+
+struct PendingTransaction {
+    node_id: u32,
+    msg: String,
+    resp_tx: oneshot::Sender<ResponseResult>,
+    sent_at: Instant,
+    deadline: Instant,
+    retries_left: u32,
+}
 
 #[derive(Clone)]
-pub struct CommChannelTx(Sender<MsgAndResponseChannel>);
+pub struct CommChannelTx {
+    msg_tx: mpsc::UnboundedSender<MsgAndResponseChannel>,
+    /// `None` until the first attempt to open the link is over.
+    link_up: watch::Receiver<Option<bool>>,
+    push_subscribers: PushSubscribers,
+    stats: SharedLinkStats,
+}
 
 impl CommChannelTx {
-    pub fn send(&self, node_id: u32, msg: String) -> Result<String> {
-        let (response_tx, response_rx) = mpsc::channel();
+    pub async fn send(&self, node_id: u32, msg: String) -> Result<String> {
+        // Don't bother queueing anything while the port is being reopened.
+        if !link::wait_for_link(&self.link_up).await {
+            return Err(anyhow::Error::new(CommError::LinkDown).into());
+        }
+
+        let (response_tx, response_rx) = oneshot::channel();
 
-        self.0
+        self.msg_tx
             .send((node_id, msg, response_tx))
             .map_err(|e| anyhow!("Failed to send message. {e:?}"))?;
 
+        // The comm task tracks the deadline, so a reply (or an error) is
+        // guaranteed unless the task itself is gone.
         let raw_response_msg = response_rx
-            .recv_timeout(Duration::from_secs(3))
-            .map_err(|e| anyhow::anyhow!("Error while receiving serial message. {e:?}"))?;
+            .await
+            .map_err(|e| anyhow::anyhow!("Error while receiving serial message. {e:?}"))?
+            .map_err(anyhow::Error::new)?;
 
         Ok(raw_response_msg)
     }
-}
 
-fn calc_checksum(input: &str) -> u8 {
-    input.as_bytes().iter().fold(0, |csum, ch| csum ^ ch)
+    pub fn is_link_up(&self) -> bool {
+        *self.link_up.borrow() == Some(true)
+    }
+
+    /// Snapshot of the link quality counters of this path.
+    pub fn stats(&self) -> LinkStats {
+        self.stats.lock().expect("mutex poisoned").clone()
+    }
+
+    /// Forwards push messages for `module` (`METEO`, `SYS`, ...) from any
+    /// node on this link to `push_tx`, until its receiver is dropped.
+    pub fn subscribe(&self, module: &str, push_tx: Sender<PushMessage>) {
+        self.push_subscribers
+            .lock()
+            .expect("mutex poisoned")
+            .entry(module.to_string())
+            .or_default()
+            .push(push_tx);
+    }
 }
 
-fn process_incoming_msg(raw_msg: &str) -> Result<(u64, &str)> {
-    if raw_msg.len() < 4 {
-        return Err(anyhow!("Incoming message too short.").into());
+#[rocket::async_trait]
+impl super::SerialTransport for CommChannelTx {
+    async fn send(&self, node_id: u32, msg: String) -> Result<String> {
+        CommChannelTx::send(self, node_id, msg).await
+    }
+
+    fn subscribe(&self, module: &str, push_tx: Sender<PushMessage>) {
+        CommChannelTx::subscribe(self, module, push_tx)
     }
+}
+
+/// Advances the transaction counter, skipping the ID reserved for pushes.
+/// IDs wrap within the `u32` range, as that is what the nodes parse them as.
+fn next_transaction_id(transaction_id_ctr: &mut u64) -> u64 {
+    *transaction_id_ctr = (*transaction_id_ctr + 1) % (u64::from(u32::MAX) + 1);
 
-    if raw_msg.as_bytes()[raw_msg.len() - 3] as char != '*' {
-        return Err(anyhow!("Incoming message invalid format.").into());
+    if *transaction_id_ctr == PUSH_TRANSACTION_ID {
+        *transaction_id_ctr += 1;
     }
 
-    let packet_csum = u8::from_str_radix(&raw_msg[(raw_msg.len() - 2)..], 16).unwrap();
+    *transaction_id_ctr
+}
 
-    let msg_str = &raw_msg[..(raw_msg.len() - 3)];
-    let calc_csum = calc_checksum(msg_str);
+/// Hands a push message over to the subscribers of its module.
+fn dispatch_push_msg(push_subscribers: &PushSubscribers, push_msg: PushMessage) {
+    let mut subscribers = push_subscribers.lock().expect("mutex poisoned");
 
-    if packet_csum != calc_csum {
-        return Err(anyhow!("Incoming message invalid checksum. Expecting 0x{calc_csum:2X}, got 0x{packet_csum:2X}.").into());
+    match subscribers.get_mut(push_msg.module()) {
+        Some(senders) => senders.retain(|push_tx| push_tx.send(push_msg.clone()).is_ok()),
+        None => debug!(
+            "No subscribers for push message from node {}: '{}'",
+            push_msg.node_id, push_msg.payload
+        ),
     }
+}
 
-    let comma_pos = msg_str
-        .find(',')
-        .ok_or(anyhow!("Incoming message invalid format."))?;
-    let transaction_id = msg_str[..comma_pos]
-        .parse()
-        .map_err(|e| anyhow!("Incoming message parsing error. {e:?}"))?;
-    let msg_payload_str = &msg_str[(comma_pos + 1)..];
+async fn transmit_msg<T>(
+    comm: &mut T,
+    framing: Framing,
+    transaction_id: u64,
+    node_id: u32,
+    msg: &str,
+) -> io::Result<()>
+where
+    T: AsyncWrite + Unpin,
+{
+    debug!(
+        "server -> mcu: trans id {}, node {}, '{}'",
+        transaction_id, node_id, msg
+    );
+
+    comm.write_all(&framing.encode(transaction_id, node_id, msg))
+        .await?;
+    comm.flush().await
+}
 
-    Ok((transaction_id, msg_payload_str))
+/// Keeps the bus silent until `frame_gap` has passed since
+/// `last_bus_activity`, on half-duplex paths.
+async fn wait_for_frame_gap(config: &TransactionConfig, last_bus_activity: Instant) {
+    if config.half_duplex {
+        time::sleep_until(last_bus_activity + config.frame_gap).await;
+    }
 }
 
-fn comm_func<T>(channel_rx: Receiver<MsgAndResponseChannel>, mut comm: T) -> !
+/// Runs the framing protocol over `comm` until all request senders are gone,
+/// or an I/O error breaks the link. Transactions still waiting for a reply at
+/// that point are failed with `CommError::LinkDown`.
+async fn comm_func<T>(
+    channel_rx: &mut mpsc::UnboundedReceiver<MsgAndResponseChannel>,
+    comm: T,
+    transaction_id_ctr: &mut u64,
+    config: &TransactionConfig,
+    push_subscribers: &PushSubscribers,
+    stats: &SharedLinkStats,
+) -> io::Result<()>
 where
-    T: Read + Write,
+    T: AsyncRead + AsyncWrite,
 {
-    let mut transaction_id_ctr: u64 = 0;
-    let mut pending_transactions = HashMap::new();
+    let (mut comm_rx, mut comm_tx) = tokio::io::split(comm);
 
-    let mut current_packet = String::new();
+    let mut pending_transactions: HashMap<u64, PendingTransaction> = HashMap::new();
+    let mut last_bus_activity = Instant::now();
 
-    enum ParserState {
-        WaitingForDollar,
-        Receiving,
-    }
+    let mut frame_decoder = FrameDecoder::new(config.framing, config.half_duplex);
+    let mut incoming = [0; 100];
 
-    let mut current_state = ParserState::WaitingForDollar;
+    let link_result = 'link: loop {
+        // Only one transaction at a time on a half-duplex bus
+        let accepting_requests = !config.half_duplex || pending_transactions.is_empty();
 
-    loop {
-        // Transmit all pending messages
-        while let Ok((node_id, msg, resp_tx)) = channel_rx.try_recv() {
-            transaction_id_ctr = transaction_id_ctr.wrapping_add(1);
+        let next_deadline = pending_transactions
+            .values()
+            .map(|transaction| transaction.deadline)
+            .min();
 
-            pending_transactions.insert(transaction_id_ctr, resp_tx);
+        tokio::select! {
+            request = channel_rx.recv(), if accepting_requests => {
+                let (node_id, msg, resp_tx) = match request {
+                    Some(request) => request,
+                    None => break 'link Ok(()),
+                };
 
-            let mut out = format!("${},{},{}*", transaction_id_ctr, node_id, msg);
-            let csum = calc_checksum(&out[1..(out.len() - 1)]);
-            out.push_str(&format!("{:02X}\r\n", csum));
+                let trans_id = next_transaction_id(transaction_id_ctr);
 
-            debug!("server -> mcu: '{}'", &out[..(out.len() - 2)]);
+                wait_for_frame_gap(config, last_bus_activity).await;
 
-            let _ = comm.write_all(out.as_bytes());
-        }
+                if let Err(e) =
+                    transmit_msg(&mut comm_tx, config.framing, trans_id, node_id, &msg).await
+                {
+                    let _ = resp_tx.send(Err(CommError::LinkDown));
+                    break 'link Err(e);
+                }
 
-        // Parse all incoming chars
-        let mut incoming = [0; 100];
-        while let Ok(incoming_len) = comm.read(&mut incoming) {
-            debug!("Rx buffer is now: {:?}", incoming.to_vec());
+                last_bus_activity = Instant::now();
+                stats.lock().expect("mutex poisoned").frames_sent += 1;
+
+                pending_transactions.insert(
+                    trans_id,
+                    PendingTransaction {
+                        node_id,
+                        msg,
+                        resp_tx,
+                        sent_at: last_bus_activity,
+                        deadline: last_bus_activity + config.timeout,
+                        retries_left: config.retries,
+                    },
+                );
+            }
 
-            for byte in incoming.iter().take(incoming_len) {
-                let ch = char::from(*byte);
+            // Expire overdue transactions, retransmitting those that have
+            // retries left under a fresh transaction ID so a late reply to the
+            // old one can't be mistaken for the new one.
+            _ = time::sleep_until(next_deadline.unwrap_or_else(Instant::now)),
+                if next_deadline.is_some() =>
+            {
+                let now = Instant::now();
+                let expired_ids: Vec<u64> = pending_transactions
+                    .iter()
+                    .filter(|(_, transaction)| transaction.deadline <= now)
+                    .map(|(trans_id, _)| *trans_id)
+                    .collect();
+
+                for trans_id in expired_ids {
+                    let mut transaction = pending_transactions
+                        .remove(&trans_id)
+                        .expect("expired transaction vanished");
+
+                    if transaction.retries_left == 0 {
+                        warn!("Transaction {} timed out.", trans_id);
+                        stats.lock().expect("mutex poisoned").timeouts += 1;
+                        let _ = transaction.resp_tx.send(Err(CommError::Timeout));
+                        continue;
+                    }
 
-                match current_state {
-                    ParserState::WaitingForDollar => {
-                        if ch == '$' {
-                            current_state = ParserState::Receiving;
-                        }
+                    let new_trans_id = next_transaction_id(transaction_id_ctr);
+
+                    debug!(
+                        "Transaction {} timed out, retransmitting as {}.",
+                        trans_id, new_trans_id
+                    );
+
+                    wait_for_frame_gap(config, last_bus_activity).await;
+
+                    if let Err(e) = transmit_msg(
+                        &mut comm_tx,
+                        config.framing,
+                        new_trans_id,
+                        transaction.node_id,
+                        &transaction.msg,
+                    )
+                    .await
+                    {
+                        let _ = transaction.resp_tx.send(Err(CommError::LinkDown));
+                        break 'link Err(e);
+                    }
+
+                    last_bus_activity = Instant::now();
+
+                    {
+                        let mut stats = stats.lock().expect("mutex poisoned");
+                        stats.frames_sent += 1;
+                        stats.retransmits += 1;
                     }
-                    ParserState::Receiving => {
-                        current_packet.push(ch);
-
-                        let len = current_packet.len();
-                        if len >= 2 && &current_packet[(len - 2)..] == "\r\n" {
-                            match process_incoming_msg(&current_packet[..(len - 2)]) {
-                                Ok((trans_id, payload_str)) => {
-                                    debug!(
-                                        "Received trans id {}, payload {}",
-                                        trans_id, payload_str
-                                    );
-
-                                    if let Some(response_channel) =
-                                        pending_transactions.remove(&trans_id)
-                                    {
-                                        let _ = response_channel.send(payload_str.to_string());
-                                    } else {
-                                        warn!("Unexpected transition id {}!", trans_id);
-                                    }
+
+                    transaction.sent_at = last_bus_activity;
+                    transaction.retries_left -= 1;
+                    transaction.deadline = last_bus_activity + config.timeout;
+                    pending_transactions.insert(new_trans_id, transaction);
+                }
+            }
+
+            // Parse all incoming chars
+            read_result = comm_rx.read(&mut incoming) => {
+                let incoming_len = match read_result {
+                    Ok(0) => {
+                        break 'link Err(io::Error::new(io::ErrorKind::UnexpectedEof, "End of stream."))
+                    }
+                    Ok(incoming_len) => incoming_len,
+                    Err(e) => break 'link Err(e),
+                };
+
+                last_bus_activity = Instant::now();
+
+                debug!("Rx buffer is now: {:?}", &incoming[..incoming_len]);
+
+                for frame in frame_decoder.push_bytes(&incoming[..incoming_len]) {
+                    match frame {
+                        Ok(IncomingFrame::Push(push_msg)) => {
+                            debug!(
+                                "Received push from node {}, payload {}",
+                                push_msg.node_id, push_msg.payload
+                            );
+
+                            stats.lock().expect("mutex poisoned").pushes_received += 1;
+                            dispatch_push_msg(push_subscribers, push_msg);
+                        }
+                        Ok(IncomingFrame::Reply {
+                            trans_id,
+                            node_id,
+                            payload,
+                        }) => {
+                            debug!("Received trans id {}, payload {}", trans_id, payload);
+
+                            let transaction = match pending_transactions.get(&trans_id) {
+                                Some(transaction) => transaction,
+                                None => {
+                                    warn!("Unexpected transition id {}!", trans_id);
+                                    stats.lock().expect("mutex poisoned").unexpected_trans_ids += 1;
+                                    continue;
                                 }
-                                Err(_) => {
-                                    warn!("Unexpected response: '{}'", &current_packet[..(len - 2)])
+                            };
+
+                            // Transceivers that keep listening while driving
+                            // the bus hand our own requests back.
+                            if config.half_duplex && payload == transaction.msg {
+                                debug!("Ignoring echo of trans id {}.", trans_id);
+                                continue;
+                            }
+
+                            if let Some(node_id) = node_id.filter(|id| *id != transaction.node_id)
+                            {
+                                warn!(
+                                    "Reply to trans id {} from node {}, expected node {}.",
+                                    trans_id, node_id, transaction.node_id
+                                );
+                                stats.lock().expect("mutex poisoned").foreign_frames += 1;
+                                continue;
+                            }
+
+                            if let Some(transaction) = pending_transactions.remove(&trans_id) {
+                                {
+                                    let mut stats = stats.lock().expect("mutex poisoned");
+                                    stats.replies_received += 1;
+                                    stats.record_latency(transaction.sent_at.elapsed());
                                 }
+
+                                let _ = transaction.resp_tx.send(Ok(payload));
                             }
+                        }
+                        Err(e) => {
+                            warn!("Unexpected response. {:?}", e);
+
+                            let mut stats = stats.lock().expect("mutex poisoned");
 
-                            current_packet.clear();
-                            current_state = ParserState::WaitingForDollar;
+                            if e.downcast_ref::<ChecksumMismatch>().is_some() {
+                                stats.checksum_failures += 1;
+                            } else {
+                                stats.malformed_frames += 1;
+                            }
                         }
                     }
                 }
             }
         }
+    };
+
+    for (_, transaction) in pending_transactions.drain() {
+        let _ = transaction.resp_tx.send(Err(CommError::LinkDown));
     }
+
+    link_result
 }
 
-pub fn create_serial_comm_task(serial_id: u32) -> Result<(CommChannelTx, thread::JoinHandle<()>)> {
-    let env_var_str = format!("SERIAL_PORT_{}_PATH", serial_id);
+/// Serial port registered with the Tokio reactor, optionally asserting RTS
+/// only while writing, to switch an RS-485 transceiver between driving the
+/// bus and listening to it.
+pub(super) struct SerialLink {
+    port: AsyncFd<serial::SystemPort>,
+    rts_driver_enable: bool,
+}
+
+/// The port polls with a zero timeout once the reactor reports it ready, so a
+/// timeout just means the readiness was stale.
+fn would_block_on_timeout(err: io::Error) -> io::Error {
+    if err.kind() == io::ErrorKind::TimedOut {
+        io::ErrorKind::WouldBlock.into()
+    } else {
+        err
+    }
+}
+
+impl AsyncRead for SerialLink {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+
+        loop {
+            let mut guard = ready!(this.port.poll_read_ready_mut(cx))?;
+
+            let unfilled = buf.initialize_unfilled();
+
+            match guard.try_io(|port| {
+                port.get_mut()
+                    .read(unfilled)
+                    .map_err(would_block_on_timeout)
+            }) {
+                Ok(read_result) => {
+                    let len = read_result?;
+                    buf.advance(len);
+                    return Poll::Ready(Ok(()));
+                }
+                Err(_would_block) => continue,
+            }
+        }
+    }
+}
+
+impl AsyncWrite for SerialLink {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+
+        loop {
+            let mut guard = ready!(this.port.poll_write_ready_mut(cx))?;
+            let rts_driver_enable = this.rts_driver_enable;
+
+            let write_result = guard.try_io(|port| {
+                let port = port.get_mut();
+
+                if !rts_driver_enable {
+                    return port.write(buf).map_err(would_block_on_timeout);
+                }
+
+                port.set_rts(true)?;
+
+                // Flushing waits until the last byte is out on the wire, only
+                // then may the driver be released. Frames are short enough
+                // for blocking the worker meanwhile to be fine.
+                let write_result = port
+                    .write(buf)
+                    .map_err(would_block_on_timeout)
+                    .and_then(|len| port.flush().map(|_| len));
+
+                port.set_rts(false)?;
+
+                write_result
+            });
+
+            match write_result {
+                Ok(write_result) => return Poll::Ready(write_result),
+                Err(_would_block) => continue,
+            }
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        // Writes go straight to the port, nothing is buffered here.
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+}
+
+pub(super) fn open_serial_port(port_path: &str, port_config: &PortConfig) -> Result<SerialLink> {
+    let mut serial_port =
+        serial::open(port_path).map_err(|e| anyhow!("Could not open serial port. {e:?}"))?;
 
-    let mut serial_port = serial::open(
-        &dotenv::var(&env_var_str)
-            .map_err(|e| anyhow!("Missing {env_var_str} env variable. {e:?}"))?,
-    )
-    .map_err(|e| anyhow!("Could not open serial port. {e:?}"))?;
-
-    let settings = serial::PortSettings {
-        baud_rate: serial::Baud115200,
-        char_size: serial::Bits8,
-        parity: serial::ParityNone,
-        stop_bits: serial::Stop1,
-        flow_control: serial::FlowNone,
-    };
     serial_port
-        .configure(&settings)
+        .configure(&port_config.settings)
         .map_err(|e| anyhow!("Could not configure the serial port. {e:?}"))?;
 
-    let (channel_tx, channel_rx) = mpsc::channel();
+    serial_port
+        .set_timeout(Duration::ZERO)
+        .map_err(|e| anyhow!("Could not set the serial port timeout. {e:?}"))?;
+
+    if port_config.rts_driver_enable {
+        serial_port
+            .set_rts(false)
+            .map_err(|e| anyhow!("Could not release the RS-485 driver enable line. {e:?}"))?;
+    }
+
+    let port = AsyncFd::new(serial_port)
+        .map_err(|e| anyhow!("Could not register the serial port with the runtime. {e:?}"))?;
+
+    Ok(SerialLink {
+        port,
+        rts_driver_enable: port_config.rts_driver_enable,
+    })
+}
+
+/// The framing protocol, with the state kept from one link to the next.
+struct FramingProtocol {
+    config: TransactionConfig,
+    push_subscribers: PushSubscribers,
+    stats: SharedLinkStats,
+    transaction_id_ctr: u64,
+}
+
+#[rocket::async_trait]
+impl LinkProtocol for FramingProtocol {
+    type Request = MsgAndResponseChannel;
+
+    fn reject((_, _, resp_tx): MsgAndResponseChannel) {
+        let _ = resp_tx.send(Err(CommError::LinkDown));
+    }
+
+    async fn run<T>(
+        &mut self,
+        link_name: &str,
+        channel_rx: &mut mpsc::UnboundedReceiver<MsgAndResponseChannel>,
+        comm: T,
+    ) -> io::Result<()>
+    where
+        T: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    {
+        let config = &self.config;
+
+        let capture = config.capture_path.as_ref().and_then(|capture_path| {
+            capture::open_capture(capture_path, link_name, config.framing, config.half_duplex)
+                .map_err(|e| warn!("Not capturing traffic on '{}'. {:?}", link_name, e))
+                .ok()
+        });
+
+        match capture {
+            Some(capture) => {
+                comm_func(
+                    channel_rx,
+                    CaptureLink::new(comm, capture),
+                    &mut self.transaction_id_ctr,
+                    config,
+                    &self.push_subscribers,
+                    &self.stats,
+                )
+                .await
+            }
+            None => {
+                comm_func(
+                    channel_rx,
+                    comm,
+                    &mut self.transaction_id_ctr,
+                    config,
+                    &self.push_subscribers,
+                    &self.stats,
+                )
+                .await
+            }
+        }
+    }
+}
+
+/// Spawns the task running the framing protocol over links opened by
+/// `open_link` on the current Tokio runtime, and returns the handle used to
+/// send requests through it. Transaction IDs are counted up from
+/// `transaction_id_ctr`.
+pub(super) fn spawn_link_task<T, F, Fut>(
+    link_name: String,
+    open_link: F,
+    config: TransactionConfig,
+    transaction_id_ctr: u64,
+) -> (CommChannelTx, JoinHandle<()>)
+where
+    T: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    F: Fn() -> Fut + Send + 'static,
+    Fut: Future<Output = Result<T>> + Send + 'static,
+{
+    let (channel_tx, channel_rx) = mpsc::unbounded_channel();
+    let (link_up_tx, link_up_rx) = watch::channel(None);
+    let push_subscribers = PushSubscribers::default();
+    let stats = SharedLinkStats::default();
+
+    let protocol = FramingProtocol {
+        config,
+        push_subscribers: push_subscribers.clone(),
+        stats: stats.clone(),
+        transaction_id_ctr,
+    };
+
+    let join_handle = tokio::spawn(link_task_func(
+        link_name,
+        open_link,
+        protocol,
+        channel_rx,
+        link_up_tx,
+        stats.clone(),
+    ));
+
+    (
+        CommChannelTx {
+            msg_tx: channel_tx,
+            link_up: link_up_rx,
+            push_subscribers,
+            stats,
+        },
+        join_handle,
+    )
+}
+
+pub fn create_serial_comm_task(serial_id: u32) -> Result<(CommChannelTx, JoinHandle<()>)> {
+    let env_var_str = format!("SERIAL_PORT_{}_PATH", serial_id);
+
+    let port_path = dotenv::var(&env_var_str)
+        .map_err(|e| anyhow!("Missing {env_var_str} env variable. {e:?}"))?;
+
+    let port_config = PortConfig::from_env(serial_id)?;
+    let config = TransactionConfig::from_env(&format!("SERIAL_PORT_{}", serial_id))?;
 
-    let join_handle = thread::spawn(|| comm_func(channel_rx, serial_port));
+    let port_path_clone = port_path.clone();
 
-    Ok((CommChannelTx(channel_tx), join_handle))
+    Ok(spawn_link_task(
+        port_path,
+        move || {
+            let link = open_serial_port(&port_path_clone, &port_config);
+            async move { link }
+        },
+        config,
+        0,
+    ))
 }
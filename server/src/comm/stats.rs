@@ -0,0 +1,112 @@
+//! Link quality counters kept by every comm path, exposed through
+//! `comm::comm_stats()`.
+
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// Upper bounds of the round-trip latency histogram buckets, in milliseconds.
+/// Anything slower ends up in a last, unbounded bucket.
+const LATENCY_BUCKETS_MS: [u64; 12] = [1, 2, 5, 10, 20, 50, 100, 200, 500, 1000, 2000, 5000];
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LatencyBucket {
+    /// Upper bound of the bucket, `None` for the last one.
+    pub le_ms: Option<u64>,
+    pub count: u64,
+}
+
+/// Histogram of round-trip times, from transmitting a request (or its last
+/// retransmission) to receiving the reply.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LatencyHistogram {
+    pub count: u64,
+    pub sum_ms: f64,
+    pub max_ms: f64,
+    pub buckets: Vec<LatencyBucket>,
+}
+
+impl Default for LatencyHistogram {
+    fn default() -> LatencyHistogram {
+        LatencyHistogram {
+            count: 0,
+            sum_ms: 0.0,
+            max_ms: 0.0,
+            buckets: LATENCY_BUCKETS_MS
+                .iter()
+                .map(|le_ms| Some(*le_ms))
+                .chain(std::iter::once(None))
+                .map(|le_ms| LatencyBucket { le_ms, count: 0 })
+                .collect(),
+        }
+    }
+}
+
+impl LatencyHistogram {
+    fn record(&mut self, latency: Duration) {
+        let latency_ms = latency.as_secs_f64() * 1000.0;
+
+        self.count += 1;
+        self.sum_ms += latency_ms;
+        self.max_ms = self.max_ms.max(latency_ms);
+
+        if let Some(bucket) = self
+            .buckets
+            .iter_mut()
+            .find(|bucket| bucket.le_ms.is_none_or(|le_ms| latency_ms <= le_ms as f64))
+        {
+            bucket.count += 1;
+        }
+    }
+
+    pub fn mean_ms(&self) -> Option<f64> {
+        if self.count == 0 {
+            None
+        } else {
+            Some(self.sum_ms / self.count as f64)
+        }
+    }
+}
+
+/// Counters of a single comm path. I2C paths only count transfers as frames
+/// sent, successful ones as replies and failed ones as transfer errors.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct LinkStats {
+    pub link_up: bool,
+    pub frames_sent: u64,
+    pub replies_received: u64,
+    pub pushes_received: u64,
+    pub checksum_failures: u64,
+    /// Other frames that could not be decoded.
+    pub malformed_frames: u64,
+    /// Replies to transactions not (or no longer) pending.
+    pub unexpected_trans_ids: u64,
+    /// Frames on a shared bus from another node or Modbus slave than the one
+    /// the pending request went to.
+    pub foreign_frames: u64,
+    pub retransmits: u64,
+    /// Transactions that got no reply, not even after all retransmissions.
+    pub timeouts: u64,
+    pub transfer_errors: u64,
+    /// Times the link came back up after going down or failing to open.
+    pub reconnects: u64,
+    pub latency: LatencyHistogram,
+}
+
+impl LinkStats {
+    pub(super) fn record_latency(&mut self, latency: Duration) {
+        self.latency.record(latency);
+    }
+}
+
+pub(super) type SharedLinkStats = Arc<Mutex<LinkStats>>;
+
+/// Snapshot of the stats of all comm paths opened so far, keyed by path ID.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct CommStats {
+    pub serial: BTreeMap<u32, LinkStats>,
+    pub tcp: BTreeMap<u32, LinkStats>,
+    pub i2c: BTreeMap<u32, LinkStats>,
+    /// Modbus RTU masters, keyed by serial port ID.
+    pub modbus: BTreeMap<u32, LinkStats>,
+}
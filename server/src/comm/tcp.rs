@@ -0,0 +1,133 @@
+use std::time::Duration;
+
+use tokio::net::TcpStream;
+use tokio::task::JoinHandle;
+use tokio::time;
+
+use super::serial::{spawn_link_task, CommChannelTx, TransactionConfig};
+
+use crate::utils::Result;
+use anyhow::anyhow;
+
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+
+async fn connect(addr: &str) -> Result<TcpStream> {
+    let stream = time::timeout(CONNECT_TIMEOUT, TcpStream::connect(addr))
+        .await
+        .map_err(|_| anyhow!("Timed out connecting to '{addr}'."))?
+        .map_err(|e| anyhow!("Could not connect to '{addr}'. {e:?}"))?;
+
+    stream
+        .set_nodelay(true)
+        .map_err(|e| anyhow!("Could not disable Nagle's algorithm. {e:?}"))?;
+
+    Ok(stream)
+}
+
+/// Starts a comm task speaking the serial framing protocol over a TCP
+/// connection to a serial bridge (ser2net, ESP-Link, ...) at the address
+/// given by the `TCP_PORT_<n>_ADDR` env variable.
+pub fn create_tcp_comm_task(tcp_id: u32) -> Result<(CommChannelTx, JoinHandle<()>)> {
+    let env_var_str = format!("TCP_PORT_{}_ADDR", tcp_id);
+
+    let addr = dotenv::var(&env_var_str)
+        .map_err(|e| anyhow!("Missing {env_var_str} env variable. {e:?}"))?;
+
+    let config = TransactionConfig::from_env(&format!("TCP_PORT_{}", tcp_id))?;
+
+    Ok(spawn_tcp_link_task(addr, config))
+}
+
+fn spawn_tcp_link_task(addr: String, config: TransactionConfig) -> (CommChannelTx, JoinHandle<()>) {
+    let addr_clone = addr.clone();
+
+    spawn_link_task(
+        format!("tcp://{}", addr),
+        move || {
+            let addr = addr_clone.clone();
+            async move { connect(&addr).await }
+        },
+        config,
+        0,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use super::super::link::RECONNECT_BACKOFF_MIN;
+
+    use ratfist_node_stub::dispatcher::Dispatcher;
+    use ratfist_node_stub::framing::Framing;
+    use ratfist_node_stub::meteo::MeteoModule;
+    use ratfist_node_stub::sys::SysModule;
+
+    use std::net::TcpListener;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Arc;
+    use std::thread;
+
+    use tokio::time::Instant;
+
+    /// Serves a stub node on a local port, one connection at a time, each
+    /// until `hang_up` gets set. Returns the address of the port.
+    fn spawn_stub_node(hang_up: Arc<AtomicBool>) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let stream = stream.unwrap();
+                stream
+                    .set_read_timeout(Some(Duration::from_millis(10)))
+                    .unwrap();
+
+                let mut disp = Dispatcher::new(stream, 0, Framing::Ascii, false);
+                disp.register_handler_module("SYS", Box::new(SysModule));
+                disp.register_handler_module("METEO", Box::new(MeteoModule::new(None)));
+                disp.run_until(&hang_up);
+
+                hang_up.store(false, Ordering::SeqCst);
+            }
+        });
+
+        addr
+    }
+
+    async fn assert_temperature_reply(comm: &CommChannelTx) {
+        let reply = comm
+            .send(0, "METEO,GET_TEMPERATURE,0".to_string())
+            .await
+            .unwrap();
+
+        let val = reply
+            .strip_prefix("METEO,TEMPERATURE_REPLY,0,")
+            .unwrap_or_else(|| panic!("unexpected reply '{}'", reply));
+        assert!(val.parse::<f32>().is_ok(), "unexpected reply '{}'", reply);
+    }
+
+    #[tokio::test]
+    async fn reconnects_after_the_bridge_hangs_up() {
+        let hang_up = Arc::new(AtomicBool::new(false));
+        let addr = spawn_stub_node(hang_up.clone());
+
+        // Nothing set for this prefix, so the defaults apply
+        let config = TransactionConfig::from_env("TCP_PORT_TEST").unwrap();
+        let (comm, _) = spawn_tcp_link_task(addr, config);
+
+        assert_temperature_reply(&comm).await;
+
+        hang_up.store(true, Ordering::SeqCst);
+
+        let deadline = Instant::now() + RECONNECT_BACKOFF_MIN * 5;
+
+        while comm.stats().reconnects == 0 {
+            assert!(Instant::now() < deadline, "link not reopened");
+            time::sleep(Duration::from_millis(20)).await;
+        }
+
+        assert!(comm.is_link_up());
+        assert_temperature_reply(&comm).await;
+    }
+}
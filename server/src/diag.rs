@@ -0,0 +1,31 @@
+use rocket::serde::json::Json;
+use rocket::Route;
+
+use crate::comm::{self, i2c::ScannedDevice, stats::CommStats};
+
+use crate::utils::Result;
+use anyhow::anyhow;
+
+pub fn get_routes() -> Vec<Route> {
+    routes![query_comm_stats, scan_i2c_bus]
+}
+
+#[get("/comm", format = "application/json")]
+pub fn query_comm_stats() -> Json<CommStats> {
+    Json(comm::comm_stats())
+}
+
+/// Lists the devices answering on I2C bus `bus_id`, see
+/// `comm::i2c::CommChannel::scan()`.
+#[get("/i2c/<bus_id>", format = "application/json")]
+pub async fn scan_i2c_bus(bus_id: u32) -> Result<Json<Vec<ScannedDevice>>> {
+    let comm_path = comm::get_i2c_comm_path(bus_id)?;
+
+    // Over a hundred transfers, too many to keep an async worker busy with.
+    let devices =
+        tokio::task::spawn_blocking(move || comm_path.lock().expect("mutex poisoned").scan())
+            .await
+            .map_err(|e| anyhow!("I2C scan failed. {e:?}"))?;
+
+    Ok(Json(devices))
+}
@@ -17,6 +17,7 @@ pub mod meteo;
 
 pub mod comm;
 pub mod db;
+pub mod diag;
 mod utils;
 
 embed_migrations!("migrations");
@@ -1,5 +1,12 @@
+use std::collections::BTreeMap;
 use std::convert::TryInto;
+use std::time::Duration;
 
+use tokio::task::JoinHandle;
+use tokio::time;
+
+use crate::comm::serial::CommError;
+use crate::comm::NodeError;
 use crate::db::models::Node;
 use crate::db::DbConnPool;
 
@@ -8,64 +15,225 @@ use crate::meteo::node::SensorNodeRegistry;
 
 use diesel::insert_into;
 use diesel::prelude::*;
+use diesel::sqlite::SqliteConnection;
 
-use crate::utils::Result;
+use crate::utils::{Error, Result};
 use anyhow::anyhow;
 
 use log::warn;
 
 use crate::utils::DateTimeUtc;
 
-pub fn fetcher_iteration(
+/// Stores a single measured value of the sensor with the (DB) ID `sensor_db_id`.
+pub(super) fn store_measurement(
+    db: &SqliteConnection,
+    sensor_db_id: i32,
+    measured_val: f32,
+    measurement_time: &DateTimeUtc,
+) -> Result<()> {
+    use crate::meteo::schema::measurements::dsl::*;
+
+    insert_into(measurements)
+        .values((
+            sensor_id.eq(sensor_db_id),
+            value.eq(measured_val),
+            measured_at.eq(measurement_time),
+        ))
+        .execute(db)
+        .map(|_| ())
+        .map_err(|e| anyhow!("Error while inserting measurement. {e:?}").into())
+}
+
+/// Tag telling apart the errors worth distinguishing in a sensor's history,
+/// e.g. a faulty sensor from a faulty link to its node.
+fn error_kind(err: &Error) -> &str {
+    if let Some(node_err) = err.downcast_ref::<NodeError>() {
+        node_err.as_ref()
+    } else if let Some(comm_err) = err.downcast_ref::<CommError>() {
+        comm_err.as_ref()
+    } else {
+        "other"
+    }
+}
+
+/// Stores why measuring the sensor with the (DB) ID `sensor_db_id` failed.
+fn store_measurement_error(
+    db: &SqliteConnection,
+    sensor_db_id: i32,
+    err: &Error,
+    error_time: &DateTimeUtc,
+) -> Result<()> {
+    use crate::meteo::schema::measurement_errors::dsl::*;
+
+    insert_into(measurement_errors)
+        .values((
+            sensor_id.eq(sensor_db_id),
+            kind.eq(error_kind(err)),
+            message.eq(err.message()),
+            occurred_at.eq(error_time),
+        ))
+        .execute(db)
+        .map(|_| ())
+        .map_err(|e| anyhow!("Error while inserting measurement error. {e:?}").into())
+}
+
+fn record_measurement_error(
+    db: &SqliteConnection,
+    sensor: &Sensor,
+    err: &Error,
+    error_time: &DateTimeUtc,
+) {
+    warn!(
+        "Error while measuring sensor (id {}): {}",
+        sensor.id,
+        err.message()
+    );
+
+    if store_measurement_error(db, sensor.id, err, error_time).is_err() {
+        warn!(
+            "Error while inserting measurement error: (id {}, kind {}, occurred_at {:?})",
+            sensor.id,
+            error_kind(err),
+            error_time
+        );
+    }
+}
+
+/// Measures all sensors and stores the values. Sensors that fail to measure
+/// get the error recorded instead, without holding up the others.
+pub async fn fetcher_iteration(
     db_conn_pool: &DbConnPool,
     node_registry: &SensorNodeRegistry,
 ) -> Result<()> {
-    let db = db_conn_pool
-        .get()
-        .map_err(|e| anyhow!("Failed to get DB connection. {e:?}"))?;
+    let get_db = |db_conn_pool: &DbConnPool| {
+        db_conn_pool
+            .get()
+            .map_err(|e| anyhow!("Failed to get DB connection. {e:?}"))
+    };
 
-    // Get all sensors
-    let sensors = {
+    // Get all sensors. Diesel blocks, so the DB is only used off the async
+    // workers.
+    let db_conn_pool_clone = db_conn_pool.clone();
+    let sensors = tokio::task::spawn_blocking(move || -> Result<_> {
         use crate::db::schema::*;
         use crate::meteo::schema::*;
 
         sensors::table
             .inner_join(nodes::table)
-            .load::<(Sensor, Node)>(&db)
-            .map_err(|e| anyhow!("{e:?}"))?
-    };
+            .load::<(Sensor, Node)>(&get_db(&db_conn_pool_clone)?)
+            .map_err(|e| anyhow!("{e:?}").into())
+    })
+    .await
+    .map_err(|e| anyhow!("Loading sensors failed. {e:?}"))??;
 
     let curr_time = DateTimeUtc::now();
 
-    for (ref sensor, ref node) in &sensors {
-        // Send message querying each sensor
-        let sens_id = sensor.public_id.try_into().unwrap(); // FIXME switch to map_err()?
-        let node_id = node.public_id.try_into().unwrap();
-
-        let measured_val = node_registry
-            .get_node(node_id)?
-            .measure(sensor.sensor_type, sens_id)?;
-
-        // Push to db (use same timestamp for all values)
-        {
-            use crate::meteo::schema::measurements::dsl::*;
-
-            if insert_into(measurements)
-                .values((
-                    sensor_id.eq(sensor.id),
-                    value.eq(measured_val),
-                    measured_at.eq(&curr_time),
-                ))
-                .execute(&db)
-                .is_err()
-            {
-                warn!(
-                    "Error while inserting measurement: (id {}, value {}, measured_at {:?})",
-                    sensor.id, measured_val, curr_time
-                );
+    // Group sensors by node, so each node is queried with a single batch
+    let mut sensors_by_node: BTreeMap<u32, Vec<Sensor>> = BTreeMap::new();
+
+    for (sensor, node) in sensors {
+        let node_id = node.public_id.try_into().unwrap(); // FIXME switch to map_err()?
+        sensors_by_node.entry(node_id).or_default().push(sensor);
+    }
+
+    // No DB connection is held while waiting for the nodes
+    let mut measured_vals_by_node = Vec::with_capacity(sensors_by_node.len());
+    // Sensors that can't even be requested, with the reason
+    let mut invalid_sensors = Vec::new();
+
+    for (node_id, all_node_sensors) in sensors_by_node {
+        let mut requests = Vec::with_capacity(all_node_sensors.len());
+        let mut node_sensors = Vec::with_capacity(all_node_sensors.len());
+
+        for sensor in all_node_sensors {
+            let sensor_id = sensor.public_id.try_into().map_err(|e| {
+                Error::from(
+                    anyhow::Error::new(NodeError::UnknownChannel)
+                        .context(format!("Invalid sensor ID {}. {e:?}", sensor.public_id)),
+                )
+            });
+
+            match sensor_id {
+                Ok(sensor_id) => {
+                    requests.push((sensor.sensor_type, sensor_id));
+                    node_sensors.push(sensor);
+                }
+                Err(e) => invalid_sensors.push((sensor, e)),
             }
         }
+
+        if requests.is_empty() {
+            continue;
+        }
+
+        let measured_vals = match node_registry.get_node(node_id) {
+            Ok(node) => node.measure_batch(&requests).await,
+            Err(e) => Err(e),
+        };
+
+        measured_vals_by_node.push((node_sensors, measured_vals));
     }
 
-    Ok(())
+    let db_conn_pool = db_conn_pool.clone();
+    tokio::task::spawn_blocking(move || {
+        let db = get_db(&db_conn_pool)?;
+
+        for (sensor, e) in &invalid_sensors {
+            record_measurement_error(&db, sensor, e, &curr_time);
+        }
+
+        for (node_sensors, measured_vals) in measured_vals_by_node {
+            let measured_vals = match measured_vals {
+                Ok(measured_vals) => measured_vals,
+                Err(e) => {
+                    for sensor in &node_sensors {
+                        record_measurement_error(&db, sensor, &e, &curr_time);
+                    }
+                    continue;
+                }
+            };
+
+            for (sensor, measured_val) in node_sensors.iter().zip(measured_vals) {
+                let measured_val = match measured_val {
+                    Ok(measured_val) => measured_val,
+                    Err(e) => {
+                        record_measurement_error(&db, sensor, &e, &curr_time);
+                        continue;
+                    }
+                };
+
+                // Push to db (use same timestamp for all values)
+                if store_measurement(&db, sensor.id, measured_val, &curr_time).is_err() {
+                    warn!(
+                        "Error while inserting measurement: (id {}, value {}, measured_at {:?})",
+                        sensor.id, measured_val, curr_time
+                    );
+                }
+            }
+        }
+
+        Ok(())
+    })
+    .await
+    .map_err(|e| anyhow!("Storing measurements failed. {e:?}"))?
+}
+
+/// Spawns the task running a fetcher iteration every `period`, on the current
+/// Tokio runtime.
+pub fn start_fetcher(
+    db_conn_pool: DbConnPool,
+    node_registry: SensorNodeRegistry,
+    period: Duration,
+) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        let mut interval = time::interval_at(time::Instant::now() + period, period);
+
+        loop {
+            interval.tick().await;
+
+            if let Err(err) = fetcher_iteration(&db_conn_pool, &node_registry).await {
+                warn!("Fetcher task error.: {err}");
+            }
+        }
+    })
 }
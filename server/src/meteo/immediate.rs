@@ -2,7 +2,7 @@ use rocket::serde::json::Json;
 use rocket::State;
 
 use super::models::SensorTypeEnum;
-use super::node::SensorNodeRegistry;
+use super::node::{NodeCapabilities, SensorNodeRegistry};
 
 use super::MeteoResponse;
 
@@ -11,7 +11,7 @@ use crate::utils::IdRange;
 use std::collections::HashMap;
 
 #[get("/<node_id>/<sensor_type>/<sensor_ids>", format = "application/json")]
-pub fn query_current_values(
+pub async fn query_current_values(
     node_id: u32,
     sensor_type: SensorTypeEnum,
     sensor_ids: IdRange,
@@ -19,13 +19,27 @@ pub fn query_current_values(
 ) -> MeteoResponse<HashMap<u32, f32>> {
     let mut response_map = HashMap::new();
 
-    for sensor_id in sensor_ids.iter() {
-        let measured_val = node_registry
-            .get_node(node_id)?
-            .measure(sensor_type, *sensor_id)?;
+    let requests = sensor_ids
+        .iter()
+        .map(|sensor_id| (sensor_type, *sensor_id))
+        .collect::<Vec<_>>();
 
-        response_map.insert(*sensor_id, measured_val);
+    let measured_vals = node_registry
+        .get_node(node_id)?
+        .measure_batch(&requests)
+        .await?;
+
+    for (sensor_id, measured_val) in sensor_ids.iter().zip(measured_vals) {
+        response_map.insert(*sensor_id, measured_val?);
     }
 
     Ok(Json(response_map))
 }
+
+#[get("/<node_id>/capabilities", format = "application/json")]
+pub async fn query_capabilities(
+    node_id: u32,
+    node_registry: &State<SensorNodeRegistry>,
+) -> MeteoResponse<NodeCapabilities> {
+    Ok(Json(node_registry.get_node(node_id)?.describe().await?))
+}
@@ -5,6 +5,7 @@ pub mod fetcher;
 mod immediate;
 pub mod models;
 pub mod node;
+pub mod push;
 pub mod schema;
 mod stored;
 
@@ -15,6 +16,7 @@ type MeteoResponse<T> = Result<Json<T>>;
 pub fn get_routes() -> Vec<Route> {
     routes![
         immediate::query_current_values,
+        immediate::query_capabilities,
         stored::get_stored_values,
         stored::get_global_structure,
     ]
@@ -36,14 +36,43 @@ pub(super) struct Measurement {
     pub measured_at: DateTimeUtc,
 }
 
-#[derive(Debug, PartialEq, Eq, Copy, Clone, FromSqlRow, AsExpression)]
+#[derive(Debug, PartialEq, Eq, Copy, Clone, Serialize, Deserialize, FromSqlRow, AsExpression)]
+#[serde(rename_all = "snake_case")]
 #[sql_type = "Integer"]
 #[repr(i32)]
 pub enum SensorTypeEnum {
     Pressure = 0,
     Temperature = 1,
     Humidity = 2,
+    /// Light level in whatever unit the source has, see `Illuminance` for lux.
     LightLevel = 3,
+    /// Red, green and blue channels of a colour sensor, as fractions of the
+    /// full scale at 1x gain.
+    ColorRed = 4,
+    ColorGreen = 5,
+    ColorBlue = 6,
+    /// Illuminance in lux.
+    Illuminance = 7,
+    /// Correlated colour temperature in kelvins.
+    ColorTemperature = 8,
+    /// Acceleration along the X, Y and Z axes in g.
+    AccelerationX = 9,
+    AccelerationY = 10,
+    AccelerationZ = 11,
+    /// Magnetic field along the X, Y and Z axes in gauss.
+    MagneticFieldX = 12,
+    MagneticFieldY = 13,
+    MagneticFieldZ = 14,
+    /// Tilt compensated compass heading in degrees, clockwise from magnetic
+    /// north.
+    Heading = 15,
+    /// Analog input voltage in volts, or whatever unit a per-sensor scaling
+    /// turns it into.
+    Voltage = 16,
+    /// Wind speed in metres per second.
+    WindSpeed = 17,
+    /// Precipitation in millimetres, as counted by a rain gauge.
+    Rainfall = 18,
 }
 
 impl AsRef<str> for SensorTypeEnum {
@@ -53,6 +82,21 @@ impl AsRef<str> for SensorTypeEnum {
             SensorTypeEnum::Temperature => "temperature",
             SensorTypeEnum::Humidity => "humidity",
             SensorTypeEnum::LightLevel => "light_level",
+            SensorTypeEnum::ColorRed => "color_red",
+            SensorTypeEnum::ColorGreen => "color_green",
+            SensorTypeEnum::ColorBlue => "color_blue",
+            SensorTypeEnum::Illuminance => "illuminance",
+            SensorTypeEnum::ColorTemperature => "color_temperature",
+            SensorTypeEnum::AccelerationX => "acceleration_x",
+            SensorTypeEnum::AccelerationY => "acceleration_y",
+            SensorTypeEnum::AccelerationZ => "acceleration_z",
+            SensorTypeEnum::MagneticFieldX => "magnetic_field_x",
+            SensorTypeEnum::MagneticFieldY => "magnetic_field_y",
+            SensorTypeEnum::MagneticFieldZ => "magnetic_field_z",
+            SensorTypeEnum::Heading => "heading",
+            SensorTypeEnum::Voltage => "voltage",
+            SensorTypeEnum::WindSpeed => "wind_speed",
+            SensorTypeEnum::Rainfall => "rainfall",
         }
     }
 }
@@ -66,6 +110,21 @@ impl<'a> TryFrom<&'a str> for SensorTypeEnum {
             "temperature" => Ok(SensorTypeEnum::Temperature),
             "humidity" => Ok(SensorTypeEnum::Humidity),
             "light_level" => Ok(SensorTypeEnum::LightLevel),
+            "color_red" => Ok(SensorTypeEnum::ColorRed),
+            "color_green" => Ok(SensorTypeEnum::ColorGreen),
+            "color_blue" => Ok(SensorTypeEnum::ColorBlue),
+            "illuminance" => Ok(SensorTypeEnum::Illuminance),
+            "color_temperature" => Ok(SensorTypeEnum::ColorTemperature),
+            "acceleration_x" => Ok(SensorTypeEnum::AccelerationX),
+            "acceleration_y" => Ok(SensorTypeEnum::AccelerationY),
+            "acceleration_z" => Ok(SensorTypeEnum::AccelerationZ),
+            "magnetic_field_x" => Ok(SensorTypeEnum::MagneticFieldX),
+            "magnetic_field_y" => Ok(SensorTypeEnum::MagneticFieldY),
+            "magnetic_field_z" => Ok(SensorTypeEnum::MagneticFieldZ),
+            "heading" => Ok(SensorTypeEnum::Heading),
+            "voltage" => Ok(SensorTypeEnum::Voltage),
+            "wind_speed" => Ok(SensorTypeEnum::WindSpeed),
+            "rainfall" => Ok(SensorTypeEnum::Rainfall),
             _ => Err(anyhow!("Invalid sensor type.").into()),
         }
     }
@@ -83,6 +142,23 @@ where
             x if x == SensorTypeEnum::Temperature as i32 => Ok(SensorTypeEnum::Temperature),
             x if x == SensorTypeEnum::Humidity as i32 => Ok(SensorTypeEnum::Humidity),
             x if x == SensorTypeEnum::LightLevel as i32 => Ok(SensorTypeEnum::LightLevel),
+            x if x == SensorTypeEnum::ColorRed as i32 => Ok(SensorTypeEnum::ColorRed),
+            x if x == SensorTypeEnum::ColorGreen as i32 => Ok(SensorTypeEnum::ColorGreen),
+            x if x == SensorTypeEnum::ColorBlue as i32 => Ok(SensorTypeEnum::ColorBlue),
+            x if x == SensorTypeEnum::Illuminance as i32 => Ok(SensorTypeEnum::Illuminance),
+            x if x == SensorTypeEnum::ColorTemperature as i32 => {
+                Ok(SensorTypeEnum::ColorTemperature)
+            }
+            x if x == SensorTypeEnum::AccelerationX as i32 => Ok(SensorTypeEnum::AccelerationX),
+            x if x == SensorTypeEnum::AccelerationY as i32 => Ok(SensorTypeEnum::AccelerationY),
+            x if x == SensorTypeEnum::AccelerationZ as i32 => Ok(SensorTypeEnum::AccelerationZ),
+            x if x == SensorTypeEnum::MagneticFieldX as i32 => Ok(SensorTypeEnum::MagneticFieldX),
+            x if x == SensorTypeEnum::MagneticFieldY as i32 => Ok(SensorTypeEnum::MagneticFieldY),
+            x if x == SensorTypeEnum::MagneticFieldZ as i32 => Ok(SensorTypeEnum::MagneticFieldZ),
+            x if x == SensorTypeEnum::Heading as i32 => Ok(SensorTypeEnum::Heading),
+            x if x == SensorTypeEnum::Voltage as i32 => Ok(SensorTypeEnum::Voltage),
+            x if x == SensorTypeEnum::WindSpeed as i32 => Ok(SensorTypeEnum::WindSpeed),
+            x if x == SensorTypeEnum::Rainfall as i32 => Ok(SensorTypeEnum::Rainfall),
             _ => Err(Box::new(utils::Error::from(anyhow!(
                 "Error parsing sensor type value from DB."
             )))),
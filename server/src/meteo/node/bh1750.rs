@@ -0,0 +1,277 @@
+use super::{reading_error, NodeCapabilities, SensorCapability, SensorNode};
+
+use crate::comm::i2c::Message;
+use crate::comm::{NodeError, SharedI2cTransport};
+
+use crate::meteo::models::SensorTypeEnum;
+
+use std::time::Duration;
+
+use crate::utils::Result;
+use anyhow::anyhow;
+
+use log::{debug, warn};
+
+/// Measurement settings, from the most sensitive to the one reaching full
+/// sunlight.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Range {
+    /// High resolution mode 2 (0.5 lx steps), longest measurement time, up to
+    /// ~7400 lx.
+    Dark,
+    /// High resolution mode 2 at the default measurement time, up to
+    /// ~27000 lx.
+    Normal,
+    /// High resolution mode (1 lx steps), shortest measurement time, up to
+    /// ~120000 lx.
+    Bright,
+}
+
+impl Range {
+    fn mode_cmd(&self) -> u8 {
+        match self {
+            Range::Dark | Range::Normal => Bh1750Node::CMD_ONE_TIME_H_RES_MODE2,
+            Range::Bright => Bh1750Node::CMD_ONE_TIME_H_RES_MODE,
+        }
+    }
+
+    /// Value of the measurement time register.
+    fn mtreg(&self) -> u8 {
+        match self {
+            Range::Dark => 254,
+            Range::Normal => Bh1750Node::MTREG_DEFAULT,
+            Range::Bright => 31,
+        }
+    }
+
+    /// Lux per count.
+    fn resolution(&self) -> f32 {
+        let mode_factor = match self {
+            Range::Dark | Range::Normal => 2.0,
+            Range::Bright => 1.0,
+        };
+
+        Bh1750Node::MTREG_DEFAULT as f32 / self.mtreg() as f32 / 1.2 / mode_factor
+    }
+
+    fn measurement_time(&self) -> Duration {
+        Bh1750Node::H_RES_MAX_TIME * self.mtreg() as u32 / Bh1750Node::MTREG_DEFAULT as u32
+    }
+
+    fn more_sensitive(&self) -> Option<Range> {
+        match self {
+            Range::Dark => None,
+            Range::Normal => Some(Range::Dark),
+            Range::Bright => Some(Range::Normal),
+        }
+    }
+
+    fn less_sensitive(&self) -> Option<Range> {
+        match self {
+            Range::Dark => Some(Range::Normal),
+            Range::Normal => Some(Range::Bright),
+            Range::Bright => None,
+        }
+    }
+}
+
+/// ROHM BH1750 ambient light sensor, the `bh1750` route type.
+pub struct Bh1750Node {
+    comm_path: SharedI2cTransport,
+    addr: u16,
+    /// Range of the last reading, where the next one starts. Held while
+    /// measuring, so commands of concurrent readings don't mix.
+    range: tokio::sync::Mutex<Range>,
+}
+
+impl Bh1750Node {
+    pub const I2C_ADDR_DEFAULT: u16 = 0x23;
+
+    const CMD_POWER_ON: u8 = 0x01;
+    const CMD_RESET: u8 = 0x07;
+    const CMD_ONE_TIME_H_RES_MODE: u8 = 0x20;
+    const CMD_ONE_TIME_H_RES_MODE2: u8 = 0x21;
+    /// Measurement time register, set through its high 3 and low 5 bits.
+    const CMD_MTREG_HIGH: u8 = 0x40;
+    const CMD_MTREG_LOW: u8 = 0x60;
+
+    const MTREG_DEFAULT: u8 = 69;
+    /// Longest high resolution measurement at the default measurement time.
+    const H_RES_MAX_TIME: Duration = Duration::from_millis(180);
+
+    /// Auto ranging switches to a less sensitive range above this fraction of
+    /// the full scale, and to a more sensitive one below the lower one.
+    const AUTO_RANGE_HIGH: f32 = 0.9;
+    const AUTO_RANGE_LOW: f32 = 0.1;
+
+    /// Enough to range through all of them.
+    const MAX_READ_ATTEMPTS: usize = 3;
+
+    pub fn new(comm_path: SharedI2cTransport, addr: u16) -> Result<Bh1750Node> {
+        let node = Bh1750Node {
+            comm_path,
+            addr,
+            range: tokio::sync::Mutex::new(Range::Normal),
+        };
+
+        // There's no chip ID to check, so just make sure something answers
+        debug!("Resetting BH1750");
+        node.write_cmd(Self::CMD_POWER_ON)?;
+        node.write_cmd(Self::CMD_RESET)?;
+
+        Ok(node)
+    }
+
+    fn write_cmd(&self, cmd: u8) -> Result<()> {
+        self.comm_path
+            .lock()
+            .expect("Mutex poisoned.")
+            .transfer(&mut [Message::write(self.addr, &[cmd])])
+    }
+
+    async fn measure_raw(&self, range: Range) -> Result<u16> {
+        let mtreg = range.mtreg();
+
+        self.write_cmd(Self::CMD_MTREG_HIGH | (mtreg >> 5))?;
+        self.write_cmd(Self::CMD_MTREG_LOW | (mtreg & 0x1f))?;
+        self.write_cmd(range.mode_cmd())?;
+
+        tokio::time::sleep(range.measurement_time()).await;
+
+        let mut data = [0; 2];
+        self.comm_path
+            .lock()
+            .expect("Mutex poisoned.")
+            .transfer(&mut [Message::read(self.addr, &mut data)])?;
+
+        Ok(u16::from_be_bytes(data))
+    }
+
+    /// Measures illuminance in lux, retaking readings near either end of the
+    /// range in the next range in the right direction.
+    async fn query_lux(&self) -> Result<f32> {
+        let mut range = self.range.lock().await;
+
+        for _ in 0..Self::MAX_READ_ATTEMPTS {
+            let raw = self.measure_raw(*range).await?;
+            let fraction = raw as f32 / u16::MAX as f32;
+
+            let next_range = if fraction > Self::AUTO_RANGE_HIGH {
+                range.less_sensitive()
+            } else if fraction < Self::AUTO_RANGE_LOW {
+                range.more_sensitive()
+            } else {
+                None
+            };
+
+            match next_range {
+                Some(next_range) => {
+                    debug!(
+                        "BH1750 at {:.3} of full scale, switching range from {:?} to {:?}.",
+                        fraction, *range, next_range
+                    );
+
+                    *range = next_range;
+                }
+                None => {
+                    if raw == u16::MAX {
+                        warn!("BH1750 saturated in range {:?}.", *range);
+                    }
+
+                    let lux = raw as f32 * range.resolution();
+                    debug!(
+                        "BH1750 reading in range {:?}: raw {}, {} lx",
+                        *range, raw, lux
+                    );
+
+                    return Ok(lux);
+                }
+            }
+        }
+
+        Err(anyhow!(
+            "BH1750 reading not settled after {} attempts.",
+            Self::MAX_READ_ATTEMPTS
+        )
+        .into())
+    }
+}
+
+#[rocket::async_trait]
+impl SensorNode for Bh1750Node {
+    async fn measure(&self, measurement_type: SensorTypeEnum, sensor_id: u32) -> Result<f32> {
+        match measurement_type {
+            _ if sensor_id != 0 => Err(reading_error(
+                NodeError::UnknownChannel,
+                measurement_type,
+                sensor_id,
+            )),
+            SensorTypeEnum::Illuminance => self.query_lux().await,
+            _ => Err(reading_error(
+                NodeError::UnsupportedType,
+                measurement_type,
+                sensor_id,
+            )),
+        }
+    }
+
+    async fn describe(&self) -> Result<NodeCapabilities> {
+        Ok(NodeCapabilities {
+            firmware_version: None,
+            protocol_version: None,
+            sensors: vec![SensorCapability {
+                sensor_type: SensorTypeEnum::Illuminance,
+                sensor_id: 0,
+            }],
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use super::super::tests::assert_close;
+
+    use crate::comm::mock::MockI2cBus;
+
+    use std::sync::{Arc, Mutex};
+
+    #[tokio::test]
+    async fn switches_range_near_either_end() {
+        let bus = Arc::new(Mutex::new(MockI2cBus::new().with_device(0x23, 0xff)));
+        let node = Bh1750Node::new(bus.clone(), 0x23).expect("BH1750 not found");
+
+        // Near full scale in the normal range, then 30000 in the bright one
+        bus.lock()
+            .unwrap()
+            .queue_reads(0x23, &[&[0xfd, 0xe8], &[0x75, 0x30]]);
+
+        // 30000 lx / 1.2 at 31 instead of 69 measurement time
+        assert_close(node.query_lux().await.expect("reading failed"), 55645.16);
+        assert_eq!(*node.range.lock().await, Range::Bright);
+
+        // The next reading starts where the last one left off, too low to
+        // stay there
+        bus.lock()
+            .unwrap()
+            .queue_reads(0x23, &[&[0x0b, 0xb8], &[0x4e, 0x20]]);
+
+        // 20000 / 1.2 / 2 lx in high resolution mode 2
+        assert_close(node.query_lux().await.expect("reading failed"), 8333.33);
+        assert_eq!(*node.range.lock().await, Range::Normal);
+    }
+
+    #[tokio::test]
+    async fn fails_when_the_reading_does_not_settle() {
+        let bus = Arc::new(Mutex::new(MockI2cBus::new().with_device(0x23, 0xff)));
+        let node = Bh1750Node::new(bus.clone(), 0x23).expect("BH1750 not found");
+
+        // Saturated in the normal range, dark in the bright one
+        bus.lock()
+            .unwrap()
+            .queue_reads(0x23, &[&[0xff, 0xff], &[0x00, 0x05], &[0xff, 0xff]]);
+
+        assert!(node.query_lux().await.is_err());
+    }
+}
@@ -0,0 +1,82 @@
+use super::enviro_phat::bmp280::{Bmp280, Chip, IIRCoeficient, Mode, Oversampling, StandbyTime};
+use super::{reading_error, NodeCapabilities, SensorCapability, SensorNode};
+
+use crate::comm::{NodeError, SharedI2cTransport};
+
+use crate::meteo::models::SensorTypeEnum;
+
+use crate::utils::Result;
+
+/// Standalone BME280 (or BMP280) breakout board, the `bme280` route type.
+pub struct Bme280Node {
+    bme: Bmp280,
+}
+
+impl Bme280Node {
+    pub fn new(comm_path: SharedI2cTransport, addr: u16) -> Result<Bme280Node> {
+        let bme = Bmp280::new(
+            comm_path,
+            addr,
+            StandbyTime::Time1000ms,
+            IIRCoeficient::Mult4X,
+            Oversampling::Mult16X,
+            Oversampling::Mult2X,
+            Oversampling::Mult1X,
+            Mode::Normal,
+        )?;
+
+        Ok(Bme280Node { bme })
+    }
+}
+
+#[rocket::async_trait]
+impl SensorNode for Bme280Node {
+    async fn measure(&self, measurement_type: SensorTypeEnum, sensor_id: u32) -> Result<f32> {
+        Ok(self
+            .measure_batch(&[(measurement_type, sensor_id)])
+            .await?
+            .remove(0)?)
+    }
+
+    async fn measure_batch(&self, requests: &[(SensorTypeEnum, u32)]) -> Result<Vec<Result<f32>>> {
+        // All values come from the same read.
+        let reading = self.bme.query()?;
+
+        Ok(requests
+            .iter()
+            .map(|&(measurement_type, sensor_id)| {
+                if sensor_id != 0 {
+                    return Err(reading_error(
+                        NodeError::UnknownChannel,
+                        measurement_type,
+                        sensor_id,
+                    ));
+                }
+
+                reading.value(measurement_type).ok_or_else(|| {
+                    reading_error(NodeError::UnsupportedType, measurement_type, sensor_id)
+                })
+            })
+            .collect())
+    }
+
+    async fn describe(&self) -> Result<NodeCapabilities> {
+        let mut sensor_types = vec![SensorTypeEnum::Pressure, SensorTypeEnum::Temperature];
+
+        if self.bme.chip() == Chip::Bme280 {
+            sensor_types.push(SensorTypeEnum::Humidity);
+        }
+
+        Ok(NodeCapabilities {
+            firmware_version: None,
+            protocol_version: None,
+            sensors: sensor_types
+                .into_iter()
+                .map(|sensor_type| SensorCapability {
+                    sensor_type,
+                    sensor_id: 0,
+                })
+                .collect(),
+        })
+    }
+}
@@ -0,0 +1,215 @@
+use crate::comm::i2c::Message;
+use crate::comm::SharedI2cTransport;
+
+use std::str::FromStr;
+use std::time::Duration;
+
+use anyhow::{anyhow, Result};
+
+use log::debug;
+
+/// Programmable gain, named after the full scale input voltage.
+#[repr(u16)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Gain {
+    FullScale6144mV = 0b000,
+    FullScale4096mV = 0b001,
+    FullScale2048mV = 0b010,
+    FullScale1024mV = 0b011,
+    FullScale512mV = 0b100,
+    FullScale256mV = 0b101,
+}
+
+impl Gain {
+    fn full_scale_volts(&self) -> f32 {
+        match self {
+            Gain::FullScale6144mV => 6.144,
+            Gain::FullScale4096mV => 4.096,
+            Gain::FullScale2048mV => 2.048,
+            Gain::FullScale1024mV => 1.024,
+            Gain::FullScale512mV => 0.512,
+            Gain::FullScale256mV => 0.256,
+        }
+    }
+}
+
+impl FromStr for Gain {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "6.144" => Ok(Gain::FullScale6144mV),
+            "4.096" => Ok(Gain::FullScale4096mV),
+            "2.048" => Ok(Gain::FullScale2048mV),
+            "1.024" => Ok(Gain::FullScale1024mV),
+            "0.512" => Ok(Gain::FullScale512mV),
+            "0.256" => Ok(Gain::FullScale256mV),
+            _ => Err(
+                "expected a full scale voltage of 6.144, 4.096, 2.048, 1.024, 0.512 or 0.256"
+                    .to_string(),
+            ),
+        }
+    }
+}
+
+/// Samples per second.
+#[repr(u16)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SampleRate {
+    Sps128 = 0b000,
+    Sps250 = 0b001,
+    Sps490 = 0b010,
+    Sps920 = 0b011,
+    Sps1600 = 0b100,
+    Sps2400 = 0b101,
+    Sps3300 = 0b110,
+}
+
+impl SampleRate {
+    fn samples_per_sec(&self) -> u32 {
+        match self {
+            SampleRate::Sps128 => 128,
+            SampleRate::Sps250 => 250,
+            SampleRate::Sps490 => 490,
+            SampleRate::Sps920 => 920,
+            SampleRate::Sps1600 => 1600,
+            SampleRate::Sps2400 => 2400,
+            SampleRate::Sps3300 => 3300,
+        }
+    }
+}
+
+impl FromStr for SampleRate {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "128" => Ok(SampleRate::Sps128),
+            "250" => Ok(SampleRate::Sps250),
+            "490" => Ok(SampleRate::Sps490),
+            "920" => Ok(SampleRate::Sps920),
+            "1600" => Ok(SampleRate::Sps1600),
+            "2400" => Ok(SampleRate::Sps2400),
+            "3300" => Ok(SampleRate::Sps3300),
+            _ => Err("expected one of 128, 250, 490, 920, 1600, 2400, 3300".to_string()),
+        }
+    }
+}
+
+pub struct Ads1015 {
+    comm_path: SharedI2cTransport,
+    gain: Gain,
+    sample_rate: SampleRate,
+    /// Held for a whole conversion, so concurrent reads of different channels
+    /// can't switch the multiplexer under each other.
+    conversion_lock: tokio::sync::Mutex<()>,
+}
+
+impl Ads1015 {
+    const I2C_ADDR: u16 = 0x49;
+
+    pub const CHANNEL_COUNT: u32 = 4;
+
+    const CONVERSION_REG_ADDR: u8 = 0x00;
+    const CONFIG_REG_ADDR: u8 = 0x01;
+
+    /// Starts a conversion when written, reads back as set once it's done.
+    const CONFIG_OS: u16 = 0x8000;
+    /// Single ended input from AIN0, the other channels follow.
+    const CONFIG_MUX_AIN0: u16 = 0b100;
+    const CONFIG_MUX_SHIFT: u16 = 12;
+    const CONFIG_PGA_SHIFT: u16 = 9;
+    const CONFIG_MODE_SINGLE_SHOT: u16 = 0x0100;
+    const CONFIG_DR_SHIFT: u16 = 5;
+    const CONFIG_COMP_QUE_DISABLE: u16 = 0b11;
+
+    /// Polls for a finished conversion before giving up.
+    const MAX_POLL_ATTEMPTS: usize = 10;
+
+    pub fn new(
+        comm_path: SharedI2cTransport,
+        gain: Gain,
+        sample_rate: SampleRate,
+    ) -> Result<Ads1015> {
+        let ads = Ads1015 {
+            comm_path,
+            gain,
+            sample_rate,
+            conversion_lock: tokio::sync::Mutex::new(()),
+        };
+
+        // There's no chip ID to check, so just make sure something answers
+        debug!("Reading out ADS1015 config");
+        let config = ads.read_reg(Self::CONFIG_REG_ADDR)?;
+        debug!("ADS1015 config is 0x{:04X}", config);
+
+        Ok(ads)
+    }
+
+    fn read_reg(&self, reg_addr: u8) -> Result<u16> {
+        let reg_addr_data = [reg_addr];
+        let mut read_data_buf = [0; 2];
+
+        let mut read_data_msgs = [
+            Message::write(Self::I2C_ADDR, &reg_addr_data),
+            Message::read(Self::I2C_ADDR, &mut read_data_buf),
+        ];
+
+        self.comm_path
+            .lock()
+            .expect("Mutex poisoned.")
+            .transfer(&mut read_data_msgs)?;
+
+        Ok(u16::from_be_bytes(read_data_buf))
+    }
+
+    fn conversion_time(&self) -> Duration {
+        Duration::from_micros(1_000_000 / self.sample_rate.samples_per_sec() as u64 + 100)
+    }
+
+    /// Does a single shot conversion of `channel`, in volts.
+    pub async fn read_channel(&self, channel: u32) -> Result<f32> {
+        if channel >= Self::CHANNEL_COUNT {
+            return Err(anyhow!("ADS1015 has no channel {channel}."));
+        }
+
+        let _conversion_guard = self.conversion_lock.lock().await;
+
+        let config = Self::CONFIG_OS
+            | (Self::CONFIG_MUX_AIN0 + channel as u16) << Self::CONFIG_MUX_SHIFT
+            | (self.gain as u16) << Self::CONFIG_PGA_SHIFT
+            | Self::CONFIG_MODE_SINGLE_SHOT
+            | (self.sample_rate as u16) << Self::CONFIG_DR_SHIFT
+            | Self::CONFIG_COMP_QUE_DISABLE;
+
+        let config_bytes = config.to_be_bytes();
+        let config_data = [Self::CONFIG_REG_ADDR, config_bytes[0], config_bytes[1]];
+        let mut config_msgs = [Message::write(Self::I2C_ADDR, &config_data)];
+
+        self.comm_path
+            .lock()
+            .expect("Mutex poisoned.")
+            .transfer(&mut config_msgs)?;
+
+        for _ in 0..Self::MAX_POLL_ATTEMPTS {
+            tokio::time::sleep(self.conversion_time()).await;
+
+            if self.read_reg(Self::CONFIG_REG_ADDR)? & Self::CONFIG_OS == 0 {
+                continue;
+            }
+
+            // 12-bit result, left aligned
+            let raw = self.read_reg(Self::CONVERSION_REG_ADDR)? as i16 >> 4;
+            let volts = raw as f32 * self.gain.full_scale_volts() / 2048.0;
+
+            debug!("ADS1015 channel {}: raw {}, {} V", channel, raw, volts);
+
+            return Ok(volts);
+        }
+
+        Err(anyhow!(
+            "ADS1015 conversion not finished after {} attempts.",
+            Self::MAX_POLL_ATTEMPTS
+        ))
+    }
+}
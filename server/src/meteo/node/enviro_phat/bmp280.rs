@@ -1,9 +1,7 @@
-use i2cdev::core::*;
-use i2cdev::linux::LinuxI2CMessage;
+use crate::comm::i2c::Message;
+use crate::comm::SharedI2cTransport;
 
-use std::sync::{Arc, Mutex};
-
-use crate::comm::i2c;
+use crate::meteo::models::SensorTypeEnum;
 
 use crate::utils::Result;
 use anyhow::anyhow;
@@ -55,6 +53,47 @@ pub enum Mode {
     Normal = 0b11,
 }
 
+/// The pin and register compatible chips the driver handles, told apart by
+/// their chip ID.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Chip {
+    Bmp280,
+    /// BMP280 with an added humidity sensor.
+    Bme280,
+}
+
+/// Compensated output of a single measurement.
+#[derive(Debug, Clone, Copy)]
+pub struct Reading {
+    /// Pressure in Pa.
+    pub pressure: f32,
+    /// Temperature in degrees Celsius.
+    pub temperature: f32,
+    /// Relative humidity in %, BME280 only.
+    pub humidity: Option<f32>,
+}
+
+impl Reading {
+    /// The value for `sensor_type`, if the chip measures it.
+    pub fn value(&self, sensor_type: SensorTypeEnum) -> Option<f32> {
+        match sensor_type {
+            SensorTypeEnum::Pressure => Some(self.pressure),
+            SensorTypeEnum::Temperature => Some(self.temperature),
+            SensorTypeEnum::Humidity => self.humidity,
+            _ => None,
+        }
+    }
+}
+
+struct HumidityCalibrationData {
+    dig_h1: u8,
+    dig_h2: i16,
+    dig_h3: u8,
+    dig_h4: i16,
+    dig_h5: i16,
+    dig_h6: i8,
+}
+
 struct CalibrationData {
     dig_t1: u16,
     dig_t2: i16,
@@ -68,44 +107,60 @@ struct CalibrationData {
     dig_p7: i16,
     dig_p8: i16,
     dig_p9: i16,
+    /// Only present on the BME280.
+    hum: Option<HumidityCalibrationData>,
 }
 
 pub struct Bmp280 {
-    comm_path: Arc<Mutex<i2c::CommChannel>>,
+    comm_path: SharedI2cTransport,
+    addr: u16,
+    chip: Chip,
     calib: CalibrationData,
     press_oversampling: Oversampling,
     temp_oversampling: Oversampling,
+    hum_oversampling: Oversampling,
     mode: Mode,
 }
 
 impl Bmp280 {
-    const I2C_ADDR: u16 = 0x77;
+    /// Address with SDO pulled high, as on the Enviro pHAT.
+    pub const I2C_ADDR_DEFAULT: u16 = 0x77;
 
     const CHIP_ID_REG_ADDR: u8 = 0xd0;
-    const CHIP_ID_EXPECTED: u8 = 0x58;
+    const CHIP_ID_BMP280: u8 = 0x58;
+    const CHIP_ID_BME280: u8 = 0x60;
 
     const CALIB_REG_ADDR: u8 = 0x88;
     const CALIB_DATA_SIZE: usize = 24;
 
+    const HUM_CALIB_H1_REG_ADDR: u8 = 0xa1;
+    const HUM_CALIB_REG_ADDR: u8 = 0xe1;
+    const HUM_CALIB_DATA_SIZE: usize = 7;
+
+    const CTRL_HUM_REG_ADDR: u8 = 0xf2;
     const CTRL_MEAS_REG_ADDR: u8 = 0xf4;
     const CONFIG_REG_ADDR: u8 = 0xf5;
 
     const DATA_REG_ADDR: u8 = 0xf7;
     const DATA_REG_SIZE: usize = 6;
+    const DATA_REG_SIZE_WITH_HUM: usize = 8;
 
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
-        comm_path: Arc<Mutex<i2c::CommChannel>>,
+        comm_path: SharedI2cTransport,
+        addr: u16,
         standby_time: StandbyTime,
         iir_coef: IIRCoeficient,
         press_oversampling: Oversampling,
         temp_oversampling: Oversampling,
+        hum_oversampling: Oversampling,
         mode: Mode,
     ) -> Result<Bmp280> {
         // Check that we're dealing with the correct chip
         let mut id_data = [0];
         let mut id_msgs = [
-            LinuxI2CMessage::write(&[Self::CHIP_ID_REG_ADDR]).with_address(Self::I2C_ADDR),
-            LinuxI2CMessage::read(&mut id_data).with_address(Self::I2C_ADDR),
+            Message::write(addr, &[Self::CHIP_ID_REG_ADDR]),
+            Message::read(addr, &mut id_data),
         ];
 
         debug!("Reading out chip ID");
@@ -116,22 +171,26 @@ impl Bmp280 {
 
         debug!("Chip ID is {}", id_data[0]);
 
-        if id_data[0] != Self::CHIP_ID_EXPECTED {
-            return Err(anyhow!(
-                "BMP280 unexpected chip ID (0x{:X}) at address 0x{:X}",
-                id_data[0],
-                Self::CHIP_ID_REG_ADDR
-            )
-            .into());
-        }
+        let chip = match id_data[0] {
+            Self::CHIP_ID_BMP280 => Chip::Bmp280,
+            Self::CHIP_ID_BME280 => Chip::Bme280,
+            chip_id => {
+                return Err(anyhow!(
+                    "BMP280/BME280 unexpected chip ID (0x{:X}) at address 0x{:X}",
+                    chip_id,
+                    addr
+                )
+                .into());
+            }
+        };
 
-        debug!("Reading out BMP280 calibration data.");
+        debug!("Reading out {:?} calibration data.", chip);
 
         // Read out the factory calibration data
         let mut calib_data = [0; Self::CALIB_DATA_SIZE];
         let mut calib_msgs = [
-            LinuxI2CMessage::write(&[Self::CALIB_REG_ADDR]).with_address(Self::I2C_ADDR),
-            LinuxI2CMessage::read(&mut calib_data).with_address(Self::I2C_ADDR),
+            Message::write(addr, &[Self::CALIB_REG_ADDR]),
+            Message::read(addr, &mut calib_data),
         ];
 
         comm_path
@@ -152,6 +211,10 @@ impl Bmp280 {
             dig_p7: (((calib_data[19] as u16) << 8) | (calib_data[18] as u16)) as i16,
             dig_p8: (((calib_data[21] as u16) << 8) | (calib_data[20] as u16)) as i16,
             dig_p9: (((calib_data[23] as u16) << 8) | (calib_data[22] as u16)) as i16,
+            hum: match chip {
+                Chip::Bmp280 => None,
+                Chip::Bme280 => Some(Self::read_hum_calib(&comm_path, addr)?),
+            },
         };
 
         debug!("Calibration read out OK.");
@@ -159,29 +222,67 @@ impl Bmp280 {
         // Create the sensor struct & configure it.
         let bmp = Bmp280 {
             comm_path,
+            addr,
+            chip,
             calib,
             press_oversampling,
             temp_oversampling,
+            hum_oversampling,
             mode,
         };
 
-        debug!("Configuring BMP280.");
+        debug!("Configuring {:?}.", chip);
 
         bmp.reconfigure(
             standby_time,
             iir_coef,
             press_oversampling,
             temp_oversampling,
+            hum_oversampling,
             mode,
         )?;
 
-        debug!("BMP280 configuration OK.");
+        debug!("{:?} configuration OK.", chip);
 
         Ok(bmp)
     }
 
+    /// The humidity calibration block of the BME280 is split in two, with
+    /// H4 and H5 sharing a byte.
+    fn read_hum_calib(
+        comm_path: &SharedI2cTransport,
+        addr: u16,
+    ) -> Result<HumidityCalibrationData> {
+        let mut h1_data = [0];
+        let mut hum_calib_data = [0; Self::HUM_CALIB_DATA_SIZE];
+        let mut calib_msgs = [
+            Message::write(addr, &[Self::HUM_CALIB_H1_REG_ADDR]),
+            Message::read(addr, &mut h1_data),
+            Message::write(addr, &[Self::HUM_CALIB_REG_ADDR]),
+            Message::read(addr, &mut hum_calib_data),
+        ];
+
+        comm_path
+            .lock()
+            .expect("Mutex poisoned.")
+            .transfer(&mut calib_msgs)?;
+
+        Ok(HumidityCalibrationData {
+            dig_h1: h1_data[0],
+            dig_h2: (((hum_calib_data[1] as u16) << 8) | (hum_calib_data[0] as u16)) as i16,
+            dig_h3: hum_calib_data[2],
+            dig_h4: ((hum_calib_data[3] as i8 as i16) << 4) | ((hum_calib_data[4] & 0x0f) as i16),
+            dig_h5: ((hum_calib_data[5] as i8 as i16) << 4) | ((hum_calib_data[4] >> 4) as i16),
+            dig_h6: hum_calib_data[6] as i8,
+        })
+    }
+
+    pub fn chip(&self) -> Chip {
+        self.chip
+    }
+
     #[rustfmt::skip]
-    pub fn query_press_and_temp(&self) -> Result<(f32, f32)> {
+    pub fn query(&self) -> Result<Reading> {
 
         if self.mode != Mode::Normal {
             let ctrl_meas_reg =
@@ -189,10 +290,8 @@ impl Bmp280 {
                 ((self.press_oversampling as u8) << 2) |
                 (Mode::Forced as u8);
 
-            let mut config_msgs = [
-                LinuxI2CMessage::write(&[Self::CTRL_MEAS_REG_ADDR, ctrl_meas_reg])
-                    .with_address(Self::I2C_ADDR),
-            ];
+            let ctrl_meas_data = [Self::CTRL_MEAS_REG_ADDR, ctrl_meas_reg];
+            let mut config_msgs = [Message::write(self.addr, &ctrl_meas_data)];
 
             self.comm_path
                 .lock()
@@ -202,25 +301,36 @@ impl Bmp280 {
             // Wait times for single samples calculated from table 13
             // (3.8. Measurement Time) in the datasheet.
             // Add 2ms and round up just to be sure.
+            // The BME280 adds the humidity conversion, per section 9.1 of its
+            // datasheet.
             let t_press_sample_ms: f32 = 2.2;
             let t_temp_sample_ms: f32 = 4.3;
+            let t_hum_sample_ms: f32 = match self.chip {
+                Chip::Bmp280 => 0.0,
+                Chip::Bme280 => 2.9,
+            };
             let wait_time_ms: u64 =
                 (t_press_sample_ms * ((self.press_oversampling as u8) as f32) +
                  t_temp_sample_ms * ((self.temp_oversampling as u8) as f32) +
+                 t_hum_sample_ms * ((self.hum_oversampling as u8) as f32) +
                  2.0)
                 .ceil() as u64;
 
             std::thread::sleep(std::time::Duration::from_millis(wait_time_ms));
         }
 
-        let mut raw_data = [0; Self::DATA_REG_SIZE];
+        let mut raw_data = [0; Self::DATA_REG_SIZE_WITH_HUM];
+        let data_size = match self.chip {
+            Chip::Bmp280 => Self::DATA_REG_SIZE,
+            Chip::Bme280 => Self::DATA_REG_SIZE_WITH_HUM,
+        };
 
         let mut read_data_msgs = [
-            LinuxI2CMessage::write(&[Self::DATA_REG_ADDR]).with_address(Self::I2C_ADDR),
-            LinuxI2CMessage::read(&mut raw_data).with_address(Self::I2C_ADDR),
+            Message::write(self.addr, &[Self::DATA_REG_ADDR]),
+            Message::read(self.addr, &mut raw_data[..data_size]),
         ];
 
-        debug!("Reading out raw BMP280 data.");
+        debug!("Reading out raw {:?} data.", self.chip);
         self.comm_path
             .lock()
             .expect("Mutex poisoned.")
@@ -269,10 +379,32 @@ impl Bmp280 {
         p_var2 = p_var3 * (self.calib.dig_p8 as f32) / 32768.0;
         let output_press = p_var3 + (p_var1 + p_var2 + (self.calib.dig_p7 as f32)) / 16.0;
 
-        debug!("Calculated BMP280 output: Pressure {} Pa, Temperature {} C",
-               output_press, output_temp);
-
-        Ok((output_press, output_temp))
+        // See section 4.2.3 in the BME280 datasheet.
+        let output_hum = self.calib.hum.as_ref().map(|hum| {
+            let raw_hum =
+                ((raw_data[6] as u32) << 8) |
+                (raw_data[7] as u32);
+
+            let mut h_var: f32 = t_fine - 76800.0;
+            h_var =
+                ((raw_hum as f32) -
+                 ((hum.dig_h4 as f32) * 64.0 + (hum.dig_h5 as f32) / 16384.0 * h_var)) *
+                ((hum.dig_h2 as f32) / 65536.0 *
+                 (1.0 + (hum.dig_h6 as f32) / 67108864.0 * h_var *
+                  (1.0 + (hum.dig_h3 as f32) / 67108864.0 * h_var)));
+            h_var *= 1.0 - (hum.dig_h1 as f32) * h_var / 524288.0;
+
+            h_var.clamp(0.0, 100.0)
+        });
+
+        debug!("Calculated {:?} output: Pressure {} Pa, Temperature {} C, Humidity {:?} %",
+               self.chip, output_press, output_temp, output_hum);
+
+        Ok(Reading {
+            pressure: output_press,
+            temperature: output_temp,
+            humidity: output_hum,
+        })
     }
 
     #[rustfmt::skip]
@@ -282,12 +414,14 @@ impl Bmp280 {
         iir_coef: IIRCoeficient,
         press_oversampling: Oversampling,
         temp_oversampling: Oversampling,
+        hum_oversampling: Oversampling,
         mode: Mode,
     ) -> Result<()> {
 
-        debug!("Reconfiguring BMP280: standby_time {:?}, iir_coef {:?},\
-                press_oversampling {:?}, temp_oversampling {:?}, mode {:?}",
-                standby_time, iir_coef, press_oversampling, temp_oversampling, mode);
+        debug!("Reconfiguring {:?}: standby_time {:?}, iir_coef {:?},\
+                press_oversampling {:?}, temp_oversampling {:?}, hum_oversampling {:?}, mode {:?}",
+                self.chip, standby_time, iir_coef, press_oversampling, temp_oversampling,
+                hum_oversampling, mode);
 
         let ctrl_meas_reg =
             ((temp_oversampling as u8) << 5) |
@@ -296,11 +430,23 @@ impl Bmp280 {
 
         let config_reg = ((standby_time as u8) << 5) | ((iir_coef as u8) << 2);
 
+        let ctrl_meas_data = [Self::CTRL_MEAS_REG_ADDR, ctrl_meas_reg];
+        let config_data = [Self::CONFIG_REG_ADDR, config_reg];
+
+        // Humidity settings only take effect with the next ctrl_meas write.
+        if self.chip == Chip::Bme280 {
+            let ctrl_hum_data = [Self::CTRL_HUM_REG_ADDR, hum_oversampling as u8];
+            let mut ctrl_hum_msgs = [Message::write(self.addr, &ctrl_hum_data)];
+
+            self.comm_path
+                .lock()
+                .expect("Mutex poisoned.")
+                .transfer(&mut ctrl_hum_msgs)?;
+        }
+
         let mut config_msgs = [
-            LinuxI2CMessage::write(&[Self::CTRL_MEAS_REG_ADDR, ctrl_meas_reg])
-                .with_address(Self::I2C_ADDR),
-            LinuxI2CMessage::write(&[Self::CONFIG_REG_ADDR, config_reg])
-                .with_address(Self::I2C_ADDR),
+            Message::write(self.addr, &ctrl_meas_data),
+            Message::write(self.addr, &config_data),
         ];
 
         self.comm_path
@@ -0,0 +1,171 @@
+use crate::comm::i2c::Message;
+use crate::comm::SharedI2cTransport;
+
+use crate::meteo::models::SensorTypeEnum;
+
+use anyhow::{anyhow, Result};
+
+use log::debug;
+
+/// Accelerometer and magnetometer values of a single reading, and the heading
+/// derived from them.
+#[derive(Debug, Clone, Copy)]
+pub struct MotionReading {
+    /// X, Y and Z acceleration in g.
+    pub accel: [f32; 3],
+    /// X, Y and Z magnetic field in gauss.
+    pub mag: [f32; 3],
+    /// Tilt compensated heading in degrees, 0 to 360.
+    pub heading: f32,
+}
+
+impl MotionReading {
+    pub fn value(&self, sensor_type: SensorTypeEnum) -> Option<f32> {
+        match sensor_type {
+            SensorTypeEnum::AccelerationX => Some(self.accel[0]),
+            SensorTypeEnum::AccelerationY => Some(self.accel[1]),
+            SensorTypeEnum::AccelerationZ => Some(self.accel[2]),
+            SensorTypeEnum::MagneticFieldX => Some(self.mag[0]),
+            SensorTypeEnum::MagneticFieldY => Some(self.mag[1]),
+            SensorTypeEnum::MagneticFieldZ => Some(self.mag[2]),
+            SensorTypeEnum::Heading => Some(self.heading),
+            _ => None,
+        }
+    }
+}
+
+pub struct Lsm303d {
+    comm_path: SharedI2cTransport,
+}
+
+impl Lsm303d {
+    const I2C_ADDR: u16 = 0x1d;
+
+    const REG_AUTOINCREMENT: u8 = 0x80;
+
+    const CHIP_ID_REG_ADDR: u8 = 0x0f;
+    const CHIP_ID_EXPECTED: u8 = 0x49;
+
+    const OUT_M_REG_ADDR: u8 = 0x08;
+    const OUT_A_REG_ADDR: u8 = 0x28;
+
+    const CTRL1_REG_ADDR: u8 = 0x20;
+    /// 50 Hz output data rate, block data update, all axes enabled.
+    const CTRL1_VALUE: u8 = 0x5f;
+
+    /// CTRL2 to CTRL7 follow CTRL1, configuring:
+    /// - accelerometer ±2 g full scale
+    /// - no interrupts
+    /// - temperature sensor off, high magnetic resolution, 50 Hz data rate
+    /// - magnetometer ±2 gauss full scale
+    /// - continuous conversion of both sensors
+    const CTRL2_TO_7_VALUES: [u8; 6] = [0x00, 0x00, 0x00, 0x70, 0x00, 0x00];
+
+    /// Sensitivities at the full scales set, per LSB.
+    const ACCEL_G_PER_LSB: f32 = 0.000061;
+    const MAG_GAUSS_PER_LSB: f32 = 0.00008;
+
+    pub fn new(comm_path: SharedI2cTransport) -> Result<Lsm303d> {
+        // Check we have the correct sensor
+        let mut id_data = [0];
+        let mut id_msgs = [
+            Message::write(Self::I2C_ADDR, &[Self::CHIP_ID_REG_ADDR]),
+            Message::read(Self::I2C_ADDR, &mut id_data),
+        ];
+
+        debug!("Reading out chip ID");
+        comm_path
+            .lock()
+            .expect("Mutex poisoned.")
+            .transfer(&mut id_msgs)?;
+
+        debug!("Chip ID is {}", id_data[0]);
+
+        if id_data[0] != Self::CHIP_ID_EXPECTED {
+            return Err(anyhow!(
+                "LSM303D unexpected chip ID (0x{:X}) at address 0x{:X}",
+                id_data[0],
+                Self::I2C_ADDR
+            ));
+        }
+
+        let mut config_data = vec![
+            Self::REG_AUTOINCREMENT | Self::CTRL1_REG_ADDR,
+            Self::CTRL1_VALUE,
+        ];
+        config_data.extend_from_slice(&Self::CTRL2_TO_7_VALUES);
+
+        let mut config_msgs = [Message::write(Self::I2C_ADDR, &config_data)];
+
+        debug!("Configuring LSM303D");
+        comm_path
+            .lock()
+            .expect("Mutex poisoned.")
+            .transfer(&mut config_msgs)?;
+
+        Ok(Lsm303d { comm_path })
+    }
+
+    /// Reads the X, Y and Z output registers starting at `reg_addr`.
+    fn read_axes(&self, reg_addr: u8) -> Result<[i16; 3]> {
+        let read_cmd = [Self::REG_AUTOINCREMENT | reg_addr];
+        let mut read_data_buf = [0; 6];
+
+        let mut read_data_msgs = [
+            Message::write(Self::I2C_ADDR, &read_cmd),
+            Message::read(Self::I2C_ADDR, &mut read_data_buf),
+        ];
+
+        self.comm_path
+            .lock()
+            .expect("Mutex poisoned.")
+            .transfer(&mut read_data_msgs)?;
+
+        let mut axes = [0; 3];
+        for (axis, bytes) in axes.iter_mut().zip(read_data_buf.chunks(2)) {
+            *axis = i16::from_le_bytes([bytes[0], bytes[1]]);
+        }
+
+        Ok(axes)
+    }
+
+    pub fn query(&self) -> Result<MotionReading> {
+        let accel = self
+            .read_axes(Self::OUT_A_REG_ADDR)?
+            .map(|raw| raw as f32 * Self::ACCEL_G_PER_LSB);
+        let mag = self
+            .read_axes(Self::OUT_M_REG_ADDR)?
+            .map(|raw| raw as f32 * Self::MAG_GAUSS_PER_LSB);
+
+        let reading = MotionReading {
+            accel,
+            mag,
+            heading: Self::heading(accel, mag),
+        };
+
+        debug!("LSM303D reading: {:?}", reading);
+
+        Ok(reading)
+    }
+
+    /// Projects the magnetic field onto the horizontal plane, using pitch
+    /// and roll from the accelerometer (see the ST AN3192 application note).
+    fn heading(accel: [f32; 3], mag: [f32; 3]) -> f32 {
+        // Anything beyond 1 g is movement, not tilt
+        let [ax, ay, _] = accel.map(|a| a.clamp(-1.0, 1.0));
+        let [mx, my, mz] = mag;
+
+        let pitch = (-ax).asin();
+        // Roll is undefined with the board on its side
+        let roll = if pitch.cos().abs() >= ay.abs() {
+            (ay / pitch.cos()).asin()
+        } else {
+            0.0
+        };
+
+        let x_h = mx * pitch.cos() + mz * pitch.sin();
+        let y_h = mx * roll.sin() * pitch.sin() + my * roll.cos() - mz * roll.sin() * pitch.cos();
+
+        y_h.atan2(x_h).to_degrees().rem_euclid(360.0)
+    }
+}
@@ -1,56 +1,237 @@
-use super::SensorNode;
+use super::{reading_error, NodeCapabilities, SensorCapability, SensorNode};
+
+use crate::comm::NodeError;
 
 use crate::meteo::models::SensorTypeEnum;
 
-use crate::comm;
+use crate::comm::SharedI2cTransport;
 
-mod bmp280;
+mod ads1015;
+pub(super) mod bmp280;
+mod lsm303d;
 mod tcs3472;
 
-use bmp280::{Bmp280, IIRCoeficient, Mode, Oversampling, StandbyTime};
+use ads1015::Ads1015;
+
+use bmp280::{Bmp280, Chip, IIRCoeficient, Mode, Oversampling, StandbyTime};
 
-use tcs3472::Tcs3472;
+use lsm303d::Lsm303d;
 
-use crate::utils::Result;
-use anyhow::anyhow;
+use tcs3472::{GainSetting, Tcs3472};
+
+use crate::utils::{self, Result};
 
 pub struct EnviroPHat {
     bmp: Bmp280,
     tcs: Tcs3472,
+    lsm: Lsm303d,
+    ads: Ads1015,
+    /// Scale and offset turning the volts of each ADS1015 channel into the
+    /// reported value.
+    voltage_scaling: Vec<(f32, f32)>,
 }
 
 impl EnviroPHat {
-    pub fn new(i2c_comm_path_id: u32) -> Result<EnviroPHat> {
-        let comm_path = comm::get_i2c_comm_path(i2c_comm_path_id)?;
-
+    pub fn new(public_id: u32, comm_path: SharedI2cTransport) -> Result<EnviroPHat> {
         let bmp = bmp280::Bmp280::new(
             comm_path.clone(),
+            Bmp280::I2C_ADDR_DEFAULT,
             StandbyTime::Time1000ms,
             IIRCoeficient::Mult4X,
             Oversampling::Mult16X,
             Oversampling::Mult2X,
+            Oversampling::Mult1X,
             Mode::Normal,
         )?;
 
-        let tcs = tcs3472::Tcs3472::new(comm_path)?;
+        let env_var_prefix = format!("NODE_{public_id}");
+
+        let tcs_gain = utils::env_var_or(
+            &format!("{}_TCS3472_GAIN", env_var_prefix),
+            GainSetting::Auto,
+        )?;
+        let tcs_integration_ms = utils::env_var_or(
+            &format!("{}_TCS3472_INTEGRATION_MS", env_var_prefix),
+            Tcs3472::INTEGRATION_MS_DEFAULT,
+        )?;
+
+        let tcs = tcs3472::Tcs3472::new(comm_path.clone(), tcs_gain, tcs_integration_ms)?;
+
+        let lsm = lsm303d::Lsm303d::new(comm_path.clone())?;
+
+        let ads_gain = utils::env_var_or(
+            &format!("{}_ADS1015_GAIN", env_var_prefix),
+            ads1015::Gain::FullScale4096mV,
+        )?;
+        let ads_sample_rate = utils::env_var_or(
+            &format!("{}_ADS1015_SAMPLE_RATE", env_var_prefix),
+            ads1015::SampleRate::Sps1600,
+        )?;
+
+        let ads = ads1015::Ads1015::new(comm_path, ads_gain, ads_sample_rate)?;
 
-        Ok(EnviroPHat { bmp, tcs })
+        let voltage_scaling = (0..Ads1015::CHANNEL_COUNT)
+            .map(|channel| {
+                Ok((
+                    utils::env_var_or(
+                        &format!("{}_VOLTAGE_{}_SCALE", env_var_prefix, channel),
+                        1.0,
+                    )?,
+                    utils::env_var_or(
+                        &format!("{}_VOLTAGE_{}_OFFSET", env_var_prefix, channel),
+                        0.0,
+                    )?,
+                ))
+            })
+            .collect::<Result<_>>()?;
+
+        Ok(EnviroPHat {
+            bmp,
+            tcs,
+            lsm,
+            ads,
+            voltage_scaling,
+        })
     }
 }
 
+// The I2C transfers only take a few milliseconds, short enough to do right on
+// the async worker.
+#[rocket::async_trait]
 impl SensorNode for EnviroPHat {
-    fn measure(&self, measurement_type: SensorTypeEnum, sensor_id: u32) -> Result<f32> {
-        if sensor_id != 0 {
-            return Err(anyhow!("Invalid sensor ID {sensor_id}. ID must be 0.").into());
+    async fn measure(&self, measurement_type: SensorTypeEnum, sensor_id: u32) -> Result<f32> {
+        Ok(self
+            .measure_batch(&[(measurement_type, sensor_id)])
+            .await?
+            .remove(0)?)
+    }
+
+    async fn measure_batch(&self, requests: &[(SensorTypeEnum, u32)]) -> Result<Vec<Result<f32>>> {
+        // Each chip gives all its values in a single read, so it is read at
+        // most once per batch.
+        let mut bmp_reading = None;
+        let mut tcs_reading = None;
+        let mut lsm_reading = None;
+        let mut results = Vec::with_capacity(requests.len());
+
+        for &(measurement_type, sensor_id) in requests {
+            let unsupported =
+                || reading_error(NodeError::UnsupportedType, measurement_type, sensor_id);
+
+            let result = match measurement_type {
+                // One sensor per ADS1015 channel, the rest are all sensor 0
+                SensorTypeEnum::Voltage if sensor_id < Ads1015::CHANNEL_COUNT => {
+                    let (scale, offset) = self.voltage_scaling[sensor_id as usize];
+
+                    self.ads
+                        .read_channel(sensor_id)
+                        .await
+                        .map(|volts| volts * scale + offset)
+                        .map_err(|e| e.context("ADS1015 read failed.").into())
+                }
+                _ if sensor_id != 0 || measurement_type == SensorTypeEnum::Voltage => Err(
+                    reading_error(NodeError::UnknownChannel, measurement_type, sensor_id),
+                ),
+                SensorTypeEnum::LightLevel
+                | SensorTypeEnum::ColorRed
+                | SensorTypeEnum::ColorGreen
+                | SensorTypeEnum::ColorBlue
+                | SensorTypeEnum::Illuminance
+                | SensorTypeEnum::ColorTemperature => {
+                    if tcs_reading.is_none() {
+                        tcs_reading =
+                            Some(self.tcs.query_color().await.map_err(|e| {
+                                utils::Error::from(e.context("TCS3472 read failed."))
+                            }));
+                    }
+
+                    match tcs_reading.as_ref().expect("TCS3472 reading missing.") {
+                        Ok(reading) => match reading.value(measurement_type) {
+                            Some(value) => value.map_err(|e| e.into()),
+                            None => Err(unsupported()),
+                        },
+                        Err(e) => Err(e.duplicate()),
+                    }
+                }
+                SensorTypeEnum::AccelerationX
+                | SensorTypeEnum::AccelerationY
+                | SensorTypeEnum::AccelerationZ
+                | SensorTypeEnum::MagneticFieldX
+                | SensorTypeEnum::MagneticFieldY
+                | SensorTypeEnum::MagneticFieldZ
+                | SensorTypeEnum::Heading => {
+                    match lsm_reading.get_or_insert_with(|| {
+                        self.lsm
+                            .query()
+                            .map_err(|e| utils::Error::from(e.context("LSM303D read failed.")))
+                    }) {
+                        Ok(reading) => reading.value(measurement_type).ok_or_else(unsupported),
+                        Err(e) => Err(e.duplicate()),
+                    }
+                }
+                // Humidity only on boards fitted with a BME280 instead of the BMP280
+                SensorTypeEnum::Pressure
+                | SensorTypeEnum::Temperature
+                | SensorTypeEnum::Humidity => {
+                    match bmp_reading.get_or_insert_with(|| {
+                        self.bmp
+                            .query()
+                            .map_err(|e| e.context("BMP280 read failed."))
+                    }) {
+                        Ok(reading) => reading.value(measurement_type).ok_or_else(unsupported),
+                        Err(e) => Err(e.duplicate()),
+                    }
+                }
+                _ => Err(unsupported()),
+            };
+
+            results.push(result);
         }
 
-        match measurement_type {
-            SensorTypeEnum::Pressure => Ok(self.bmp.query_press_and_temp()?.0),
-            SensorTypeEnum::Temperature => Ok(self.bmp.query_press_and_temp()?.1),
-            SensorTypeEnum::Humidity => {
-                Err(anyhow!("Humidity measurements not supported on the EnviroPHat.").into())
-            }
-            SensorTypeEnum::LightLevel => Ok(self.tcs.query_light_level()?),
+        Ok(results)
+    }
+
+    async fn describe(&self) -> Result<NodeCapabilities> {
+        let mut sensor_types = vec![
+            SensorTypeEnum::Pressure,
+            SensorTypeEnum::Temperature,
+            SensorTypeEnum::LightLevel,
+            SensorTypeEnum::ColorRed,
+            SensorTypeEnum::ColorGreen,
+            SensorTypeEnum::ColorBlue,
+            SensorTypeEnum::Illuminance,
+            SensorTypeEnum::ColorTemperature,
+            SensorTypeEnum::AccelerationX,
+            SensorTypeEnum::AccelerationY,
+            SensorTypeEnum::AccelerationZ,
+            SensorTypeEnum::MagneticFieldX,
+            SensorTypeEnum::MagneticFieldY,
+            SensorTypeEnum::MagneticFieldZ,
+            SensorTypeEnum::Heading,
+        ];
+
+        if self.bmp.chip() == Chip::Bme280 {
+            sensor_types.push(SensorTypeEnum::Humidity);
         }
+
+        let sensors = sensor_types
+            .iter()
+            .map(|&sensor_type| SensorCapability {
+                sensor_type,
+                sensor_id: 0,
+            })
+            .chain(
+                (0..Ads1015::CHANNEL_COUNT).map(|sensor_id| SensorCapability {
+                    sensor_type: SensorTypeEnum::Voltage,
+                    sensor_id,
+                }),
+            )
+            .collect();
+
+        Ok(NodeCapabilities {
+            firmware_version: None,
+            protocol_version: None,
+            sensors,
+        })
     }
 }
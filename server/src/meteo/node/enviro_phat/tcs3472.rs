@@ -1,17 +1,17 @@
-use i2cdev::core::*;
-use i2cdev::linux::LinuxI2CMessage;
+use crate::comm::i2c::Message;
+use crate::comm::SharedI2cTransport;
 
-use std::sync::{Arc, Mutex};
+use crate::meteo::models::SensorTypeEnum;
 
-use crate::comm::i2c;
+use std::str::FromStr;
+use std::time::Duration;
 
 use anyhow::{anyhow, Result};
 
-use log::debug;
+use log::{debug, warn};
 
 #[repr(u8)]
-#[derive(Debug, Clone, Copy)]
-#[allow(dead_code)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum Gain {
     Mult1X = 0b00,
     Mult4X = 0b01,
@@ -19,8 +19,98 @@ pub enum Gain {
     Mult60X = 0b11,
 }
 
+impl Gain {
+    fn factor(&self) -> f32 {
+        match self {
+            Gain::Mult1X => 1.0,
+            Gain::Mult4X => 4.0,
+            Gain::Mult16X => 16.0,
+            Gain::Mult60X => 60.0,
+        }
+    }
+
+    fn lower(&self) -> Option<Gain> {
+        match self {
+            Gain::Mult1X => None,
+            Gain::Mult4X => Some(Gain::Mult1X),
+            Gain::Mult16X => Some(Gain::Mult4X),
+            Gain::Mult60X => Some(Gain::Mult16X),
+        }
+    }
+
+    fn higher(&self) -> Option<Gain> {
+        match self {
+            Gain::Mult1X => Some(Gain::Mult4X),
+            Gain::Mult4X => Some(Gain::Mult16X),
+            Gain::Mult16X => Some(Gain::Mult60X),
+            Gain::Mult60X => None,
+        }
+    }
+}
+
+/// Gain setting, either fixed or picked per reading to keep the clear channel
+/// within range.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GainSetting {
+    Fixed(Gain),
+    Auto,
+}
+
+impl FromStr for GainSetting {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "auto" => Ok(GainSetting::Auto),
+            "1" => Ok(GainSetting::Fixed(Gain::Mult1X)),
+            "4" => Ok(GainSetting::Fixed(Gain::Mult4X)),
+            "16" => Ok(GainSetting::Fixed(Gain::Mult16X)),
+            "60" => Ok(GainSetting::Fixed(Gain::Mult60X)),
+            _ => Err("expected one of auto, 1, 4, 16, 60".to_string()),
+        }
+    }
+}
+
+/// Colour channels of a single reading, each as a fraction of the full scale
+/// at 1x gain, and what is derived from them.
+#[derive(Debug, Clone, Copy)]
+pub struct ColorReading {
+    pub clear: f32,
+    pub red: f32,
+    pub green: f32,
+    pub blue: f32,
+    /// Illuminance in lux.
+    pub lux: f32,
+    /// Correlated colour temperature in kelvins, unless there's too little
+    /// light to tell.
+    pub cct: Option<f32>,
+}
+
+impl ColorReading {
+    /// Value for `sensor_type`, `None` if it's not a colour sensor type.
+    pub fn value(&self, sensor_type: SensorTypeEnum) -> Option<Result<f32>> {
+        match sensor_type {
+            SensorTypeEnum::LightLevel => Some(Ok(self.clear)),
+            SensorTypeEnum::ColorRed => Some(Ok(self.red)),
+            SensorTypeEnum::ColorGreen => Some(Ok(self.green)),
+            SensorTypeEnum::ColorBlue => Some(Ok(self.blue)),
+            SensorTypeEnum::Illuminance => Some(Ok(self.lux)),
+            SensorTypeEnum::ColorTemperature => Some(
+                self.cct
+                    .ok_or_else(|| anyhow!("Too little light to tell the colour temperature.")),
+            ),
+            _ => None,
+        }
+    }
+}
+
 pub struct Tcs3472 {
-    comm_path: Arc<Mutex<i2c::CommChannel>>,
+    comm_path: SharedI2cTransport,
+    gain_setting: GainSetting,
+    /// Gain currently set on the chip. Held while measuring, so gain switches
+    /// and reads of concurrent readings don't mix.
+    gain: tokio::sync::Mutex<Gain>,
+    integration_cycles: u16,
 }
 
 impl Tcs3472 {
@@ -33,26 +123,55 @@ impl Tcs3472 {
     const ENABLE_REG_AEN: u8 = 0x02;
     const ENABLE_REG_PON: u8 = 0x01;
 
-    #[allow(dead_code)]
-    const TIMING_REG_ADDR: u8 = 0x01;
-    #[allow(dead_code)]
     const TIMING_REG_STEP_MS: f32 = 2.4;
+    const TIMING_MAX_CYCLES: u16 = 256;
+
+    /// 64 cycles, the shortest integration time reaching the full 16-bit
+    /// range.
+    pub const INTEGRATION_MS_DEFAULT: f32 = 153.6;
 
     const CONTROL_REG_ADDR: u8 = 0x0f;
 
     const CHIP_ID_REG_ADDR: u8 = 0x12;
     const CHIP_ID_EXPECTED: u8 = 0x44;
 
-    const CLEAR_DATA_REG_ADDR: u8 = 0x14;
-    const CLEAR_DATA_REG_SIZE: usize = 2;
+    const STATUS_REG_ADDR: u8 = 0x13;
+    const STATUS_REG_AVALID: u8 = 0x01;
 
-    pub fn new(comm_path: Arc<Mutex<i2c::CommChannel>>) -> Result<Tcs3472> {
+    /// Status register followed by the clear, red, green and blue data
+    /// registers.
+    const STATUS_AND_DATA_SIZE: usize = 9;
+
+    /// Auto gain ranging steps the gain down above this fraction of the full
+    /// scale, and up below the lower one.
+    const AUTO_GAIN_HIGH: f32 = 0.9;
+    const AUTO_GAIN_LOW: f32 = 0.1;
+
+    /// Enough to range through all gains, with a wait for valid data at each.
+    const MAX_READ_ATTEMPTS: usize = 8;
+
+    /// Coefficients for lux and CCT from the AMS DN40 application note,
+    /// assuming open air (no glass attenuation).
+    const DEVICE_FACTOR: f32 = 310.0;
+    const LUX_COEF_R: f32 = 0.136;
+    const LUX_COEF_G: f32 = 1.0;
+    const LUX_COEF_B: f32 = -0.444;
+    const CT_COEF: f32 = 3810.0;
+    const CT_OFFSET: f32 = 1391.0;
+
+    pub fn new(
+        comm_path: SharedI2cTransport,
+        gain_setting: GainSetting,
+        integration_ms: f32,
+    ) -> Result<Tcs3472> {
         // Check we have the correct sensor
         let mut id_data = [0];
         let mut id_msgs = [
-            LinuxI2CMessage::write(&[Self::CMD_REG_MASK | Self::CHIP_ID_REG_ADDR])
-                .with_address(Self::I2C_ADDR),
-            LinuxI2CMessage::read(&mut id_data).with_address(Self::I2C_ADDR),
+            Message::write(
+                Self::I2C_ADDR,
+                &[Self::CMD_REG_MASK | Self::CHIP_ID_REG_ADDR],
+            ),
+            Message::read(Self::I2C_ADDR, &mut id_data),
         ];
 
         debug!("Reading out chip ID");
@@ -71,42 +190,84 @@ impl Tcs3472 {
             ));
         }
 
-        debug!("Configuring TCS3472.");
-        // Configure the sensor
-        // Continuous integration at 1x Gain, 64 periods per integration (total time 154ms)
+        let integration_cycles = (integration_ms / Self::TIMING_REG_STEP_MS)
+            .round()
+            .clamp(1.0, Self::TIMING_MAX_CYCLES as f32) as u16;
+
+        // Auto gain ranging starts out at the lowest gain, so the first
+        // reading can't saturate.
+        let gain = match gain_setting {
+            GainSetting::Fixed(gain) => gain,
+            GainSetting::Auto => Gain::Mult1X,
+        };
+
+        let tcs = Tcs3472 {
+            comm_path,
+            gain_setting,
+            gain: tokio::sync::Mutex::new(gain),
+            integration_cycles,
+        };
+
+        debug!(
+            "Configuring TCS3472: gain {:?}, {} integration cycles.",
+            gain_setting, integration_cycles
+        );
+
+        tcs.configure(gain)?;
+
+        Ok(tcs)
+    }
+
+    fn integration_time(&self) -> Duration {
+        Duration::from_secs_f32(self.integration_cycles as f32 * Self::TIMING_REG_STEP_MS / 1000.0)
+    }
+
+    /// Highest count the data registers reach with the integration time set.
+    fn max_count(&self) -> f32 {
+        (1024.0 * self.integration_cycles as f32).min(u16::MAX as f32)
+    }
+
+    /// Sets `gain` and (re)starts integrating. Turning the ADC off first drops
+    /// the cycle in progress, so the next valid data is all at the new gain.
+    fn configure(&self, gain: Gain) -> Result<()> {
         let cmd_reg_enable_autoinc =
             Self::CMD_REG_MASK | Self::CMD_REG_AUTOINCREMENT | Self::ENABLE_REG_ADDR;
-        let enable_reg = Self::ENABLE_REG_AEN | Self::ENABLE_REG_PON;
-
-        let period_count = 64;
-        let timing_reg = u8::MAX - period_count;
+        let timing_reg = (Self::TIMING_MAX_CYCLES - self.integration_cycles) as u8;
 
         let cmd_reg_control = Self::CMD_REG_MASK | Self::CONTROL_REG_ADDR;
-        let control_reg = Gain::Mult1X as u8;
+
+        let disable_data = [cmd_reg_enable_autoinc, Self::ENABLE_REG_PON, timing_reg];
+        let control_data = [cmd_reg_control, gain as u8];
+        let enable_data = [
+            cmd_reg_enable_autoinc,
+            Self::ENABLE_REG_AEN | Self::ENABLE_REG_PON,
+        ];
 
         let mut config_msgs = [
-            LinuxI2CMessage::write(&[cmd_reg_enable_autoinc, enable_reg, timing_reg])
-                .with_address(Self::I2C_ADDR),
-            LinuxI2CMessage::write(&[cmd_reg_control, control_reg]).with_address(Self::I2C_ADDR),
+            Message::write(Self::I2C_ADDR, &disable_data),
+            Message::write(Self::I2C_ADDR, &control_data),
+            Message::write(Self::I2C_ADDR, &enable_data),
         ];
 
-        comm_path
+        self.comm_path
             .lock()
             .expect("Mutex poisoned.")
             .transfer(&mut config_msgs)?;
 
-        Ok(Tcs3472 { comm_path })
+        Ok(())
     }
 
-    pub fn query_light_level(&self) -> Result<f32> {
-        let cmd_reg_read_color_autoinc =
-            Self::CMD_REG_MASK | Self::CMD_REG_AUTOINCREMENT | Self::CLEAR_DATA_REG_ADDR;
+    /// Reads the status register and the raw clear, red, green and blue
+    /// counts.
+    fn read_raw(&self) -> Result<(bool, [u16; 4])> {
+        let cmd_reg_read_status_autoinc =
+            [Self::CMD_REG_MASK | Self::CMD_REG_AUTOINCREMENT | Self::STATUS_REG_ADDR];
 
-        let mut read_data_buf = [0; Self::CLEAR_DATA_REG_SIZE];
+        let mut read_data_buf = [0; Self::STATUS_AND_DATA_SIZE];
 
         let mut read_data_msgs = [
-            LinuxI2CMessage::write(&[cmd_reg_read_color_autoinc]).with_address(Self::I2C_ADDR),
-            LinuxI2CMessage::read(&mut read_data_buf).with_address(Self::I2C_ADDR),
+            Message::write(Self::I2C_ADDR, &cmd_reg_read_status_autoinc),
+            Message::read(Self::I2C_ADDR, &mut read_data_buf),
         ];
 
         self.comm_path
@@ -114,8 +275,103 @@ impl Tcs3472 {
             .expect("Mutex poisoned.")
             .transfer(&mut read_data_msgs)?;
 
-        let raw_val: u16 = ((read_data_buf[1] as u16) << 8) | (read_data_buf[0] as u16);
+        let valid = read_data_buf[0] & Self::STATUS_REG_AVALID != 0;
+
+        let mut counts = [0; 4];
+        for (count, bytes) in counts.iter_mut().zip(read_data_buf[1..].chunks(2)) {
+            *count = ((bytes[1] as u16) << 8) | (bytes[0] as u16);
+        }
+
+        Ok((valid, counts))
+    }
+
+    /// Reads all colour channels. With auto gain ranging, readings near
+    /// either end of the range are retaken at the next gain in the right
+    /// direction, waiting out an integration cycle each time.
+    pub async fn query_color(&self) -> Result<ColorReading> {
+        let max_count = self.max_count();
+        let mut gain = self.gain.lock().await;
+
+        for _ in 0..Self::MAX_READ_ATTEMPTS {
+            let (valid, counts) = self.read_raw()?;
+
+            if !valid {
+                debug!("TCS3472 data not valid yet, waiting for the integration to finish.");
+                tokio::time::sleep(self.integration_time()).await;
+                continue;
+            }
+
+            let clear_fraction = counts[0] as f32 / max_count;
+
+            let next_gain = match self.gain_setting {
+                GainSetting::Fixed(_) => None,
+                GainSetting::Auto if clear_fraction > Self::AUTO_GAIN_HIGH => gain.lower(),
+                GainSetting::Auto if clear_fraction < Self::AUTO_GAIN_LOW => gain.higher(),
+                GainSetting::Auto => None,
+            };
+
+            match next_gain {
+                Some(next_gain) => {
+                    debug!(
+                        "TCS3472 clear channel at {:.3} of full scale, switching gain from {:?} to {:?}.",
+                        clear_fraction, *gain, next_gain
+                    );
+
+                    self.configure(next_gain)?;
+                    *gain = next_gain;
+                    tokio::time::sleep(self.integration_time() + Duration::from_millis(3)).await;
+                }
+                None => {
+                    if counts.iter().any(|count| *count as f32 >= max_count) {
+                        warn!("TCS3472 saturated at gain {:?}.", *gain);
+                    }
+
+                    return Ok(self.compensate(counts, *gain));
+                }
+            }
+        }
+
+        Err(anyhow!(
+            "TCS3472 reading not settled after {} attempts.",
+            Self::MAX_READ_ATTEMPTS
+        ))
+    }
+
+    fn compensate(&self, counts: [u16; 4], gain: Gain) -> ColorReading {
+        let full_scale = gain.factor() * self.max_count();
+        let [clear, red, green, blue] = counts.map(|count| count as f32);
+
+        // See the AMS DN40 application note, the IR component is removed from
+        // all colour channels first.
+        let ir = ((red + green + blue - clear) / 2.0).max(0.0);
+        let (red_ir, green_ir, blue_ir) = (red - ir, green - ir, blue - ir);
+
+        let integration_ms = self.integration_cycles as f32 * Self::TIMING_REG_STEP_MS;
+        let counts_per_lux = integration_ms * gain.factor() / Self::DEVICE_FACTOR;
+
+        let lux = ((Self::LUX_COEF_R * red_ir
+            + Self::LUX_COEF_G * green_ir
+            + Self::LUX_COEF_B * blue_ir)
+            / counts_per_lux)
+            .max(0.0);
+
+        let cct = if red_ir > 0.0 {
+            Some(Self::CT_COEF * blue_ir / red_ir + Self::CT_OFFSET)
+        } else {
+            None
+        };
+
+        let reading = ColorReading {
+            clear: clear / full_scale,
+            red: red / full_scale,
+            green: green / full_scale,
+            blue: blue / full_scale,
+            lux,
+            cct,
+        };
+
+        debug!("TCS3472 reading at gain {:?}: {:?}", gain, reading);
 
-        Ok((raw_val as f32) / (u16::MAX as f32))
+        reading
     }
 }
@@ -0,0 +1,357 @@
+use super::register_map::{parse_int, parse_scale_and_offset, parse_sensor_type, split_fields};
+use super::{reading_error, NodeCapabilities, SensorCapability, SensorNode};
+
+use crate::comm::i2c::Message;
+use crate::comm::{NodeError, SharedI2cTransport};
+
+use crate::meteo::models::SensorTypeEnum;
+
+use std::collections::BTreeMap;
+use std::str::FromStr;
+
+use crate::utils::{self, Result};
+use anyhow::anyhow;
+
+use log::debug;
+
+/// Register writes setting the chip up, `<byte>,<byte>...` each (register
+/// address first), separated by `;`.
+#[derive(Debug, Clone, Default)]
+pub struct InitSequence(Vec<Vec<u8>>);
+
+impl FromStr for InitSequence {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        s.split(';')
+            .map(str::trim)
+            .filter(|write| !write.is_empty())
+            .map(|write| {
+                write
+                    .split(',')
+                    .map(|b| parse_int(b.trim(), "byte"))
+                    .collect()
+            })
+            .collect::<std::result::Result<_, _>>()
+            .map(InitSequence)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Endianness {
+    Big,
+    Little,
+}
+
+/// Where and how a sensor's value is read, given as
+/// `<type>,<reg>,<bytes>,<be|le>,<signed|unsigned>[,<scale>[,<offset>]]`.
+/// The value reported is `raw * scale + offset`.
+#[derive(Debug, Clone, Copy)]
+pub struct RegisterMap {
+    sensor_type: SensorTypeEnum,
+    reg_addr: u8,
+    byte_count: usize,
+    endianness: Endianness,
+    signed: bool,
+    scale: f32,
+    offset: f32,
+}
+
+impl FromStr for RegisterMap {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        let fields = split_fields(
+            s,
+            5,
+            "<type>,<reg>,<bytes>,<be|le>,<signed|unsigned>[,<scale>[,<offset>]]",
+        )?;
+
+        let sensor_type = parse_sensor_type(fields[0])?;
+
+        let reg_addr = parse_int(fields[1], "register")?;
+
+        let byte_count = match fields[2].parse::<usize>() {
+            Ok(byte_count) if (1..=4).contains(&byte_count) => byte_count,
+            _ => return Err(format!("byte count '{}' not within 1 to 4", fields[2])),
+        };
+
+        let endianness = match fields[3].to_lowercase().as_str() {
+            "be" => Endianness::Big,
+            "le" => Endianness::Little,
+            _ => return Err(format!("expected be or le, got '{}'", fields[3])),
+        };
+
+        let signed = match fields[4].to_lowercase().as_str() {
+            "signed" => true,
+            "unsigned" => false,
+            _ => return Err(format!("expected signed or unsigned, got '{}'", fields[4])),
+        };
+
+        let (scale, offset) = parse_scale_and_offset(&fields[5..])?;
+
+        Ok(RegisterMap {
+            sensor_type,
+            reg_addr,
+            byte_count,
+            endianness,
+            signed,
+            scale,
+            offset,
+        })
+    }
+}
+
+impl RegisterMap {
+    fn convert(&self, data: &[u8]) -> f32 {
+        let mut bytes = data.to_vec();
+
+        if self.endianness == Endianness::Little {
+            bytes.reverse();
+        }
+
+        let raw = bytes.iter().fold(0u32, |acc, &byte| acc << 8 | byte as u32);
+
+        let raw = if self.signed {
+            // Sign extend from the top bit of the value
+            let unused_bits = 32 - 8 * self.byte_count as u32;
+            ((raw << unused_bits) as i32 >> unused_bits) as f32
+        } else {
+            raw as f32
+        };
+
+        raw * self.scale + self.offset
+    }
+}
+
+/// A simple chip described entirely by configuration, the `generic_i2c` route
+/// type. The init sequence is written once, then sensors are read register by
+/// register.
+pub struct GenericI2cNode {
+    comm_path: SharedI2cTransport,
+    addr: u16,
+    sensors: BTreeMap<u32, RegisterMap>,
+}
+
+impl GenericI2cNode {
+    /// Sets up the chip at `addr` from the `NODE_<public_id>_GENERIC_I2C_INIT`
+    /// and `NODE_<public_id>_GENERIC_I2C_SENSOR_<sensor_id>` env variables.
+    pub fn from_env(
+        public_id: u32,
+        comm_path: SharedI2cTransport,
+        addr: u16,
+    ) -> Result<GenericI2cNode> {
+        let env_var_prefix = format!("NODE_{public_id}_GENERIC_I2C");
+
+        let init = utils::env_var_or(&format!("{}_INIT", env_var_prefix), InitSequence::default())?;
+
+        let sensor_prefix = format!("{}_SENSOR_", env_var_prefix);
+
+        let sensors = utils::env_vars_by_id(&sensor_prefix)?;
+
+        if sensors.is_empty() {
+            return Err(anyhow!("No {}<sensor_id> env variables set.", sensor_prefix).into());
+        }
+
+        GenericI2cNode::new(comm_path, addr, &init, sensors)
+    }
+
+    pub fn new(
+        comm_path: SharedI2cTransport,
+        addr: u16,
+        init: &InitSequence,
+        sensors: BTreeMap<u32, RegisterMap>,
+    ) -> Result<GenericI2cNode> {
+        let node = GenericI2cNode {
+            comm_path,
+            addr,
+            sensors,
+        };
+
+        debug!(
+            "Writing init sequence {:02X?} to chip at 0x{:X}",
+            init.0, addr
+        );
+
+        for write in &init.0 {
+            node.comm_path
+                .lock()
+                .expect("Mutex poisoned.")
+                .transfer(&mut [Message::write(addr, write)])
+                .map_err(|e| anyhow!("Init write {write:02X?} to 0x{addr:X} failed. {e:?}"))?;
+        }
+
+        Ok(node)
+    }
+
+    fn read(&self, register_map: &RegisterMap) -> Result<f32> {
+        let reg_addr_data = [register_map.reg_addr];
+        let mut data = [0; 4];
+        let data = &mut data[..register_map.byte_count];
+
+        self.comm_path
+            .lock()
+            .expect("Mutex poisoned.")
+            .transfer(&mut [
+                Message::write(self.addr, &reg_addr_data),
+                Message::read(self.addr, data),
+            ])?;
+
+        let value = register_map.convert(data);
+
+        debug!(
+            "Register 0x{:02X} of chip at 0x{:X}: {:02X?}, {} {}",
+            register_map.reg_addr,
+            self.addr,
+            data,
+            register_map.sensor_type.as_ref(),
+            value
+        );
+
+        Ok(value)
+    }
+}
+
+#[rocket::async_trait]
+impl SensorNode for GenericI2cNode {
+    async fn measure(&self, measurement_type: SensorTypeEnum, sensor_id: u32) -> Result<f32> {
+        match self.sensors.get(&sensor_id) {
+            Some(register_map) if register_map.sensor_type == measurement_type => {
+                self.read(register_map)
+            }
+            Some(_) => Err(reading_error(
+                NodeError::UnsupportedType,
+                measurement_type,
+                sensor_id,
+            )),
+            None => Err(reading_error(
+                NodeError::UnknownChannel,
+                measurement_type,
+                sensor_id,
+            )),
+        }
+    }
+
+    async fn describe(&self) -> Result<NodeCapabilities> {
+        Ok(NodeCapabilities {
+            firmware_version: None,
+            protocol_version: None,
+            sensors: self
+                .sensors
+                .iter()
+                .map(|(&sensor_id, register_map)| SensorCapability {
+                    sensor_type: register_map.sensor_type,
+                    sensor_id,
+                })
+                .collect(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::comm::mock::MockI2cBus;
+
+    use std::sync::{Arc, Mutex};
+
+    fn register_map(s: &str) -> RegisterMap {
+        s.parse()
+            .unwrap_or_else(|e| panic!("'{}' rejected: {}", s, e))
+    }
+
+    #[test]
+    fn parses_init_sequence() {
+        let init: InitSequence = "0xf4,0x27; 0xf5, 160;".parse().unwrap();
+        assert_eq!(init.0, vec![vec![0xf4, 0x27], vec![0xf5, 0xa0]]);
+
+        let empty: InitSequence = "".parse().unwrap();
+        assert!(empty.0.is_empty());
+
+        assert!("0xf4,256".parse::<InitSequence>().is_err());
+        assert!("0xf4,0xzz".parse::<InitSequence>().is_err());
+        assert!("0xf4,,0x27".parse::<InitSequence>().is_err());
+    }
+
+    #[test]
+    fn parses_register_map() {
+        let map = register_map("Temperature, 0xfa, 2, be, signed, 0.01, -40");
+        assert_eq!(map.sensor_type, SensorTypeEnum::Temperature);
+        assert_eq!(map.reg_addr, 0xfa);
+        assert_eq!(map.byte_count, 2);
+        assert_eq!(map.endianness, Endianness::Big);
+        assert!(map.signed);
+        assert_eq!((map.scale, map.offset), (0.01, -40.0));
+
+        let map = register_map("humidity,16,1,LE,unsigned");
+        assert_eq!(map.reg_addr, 16);
+        assert_eq!(map.endianness, Endianness::Little);
+        assert!(!map.signed);
+        assert_eq!((map.scale, map.offset), (1.0, 0.0));
+
+        for invalid in &[
+            "temperature,0xfa,2,be",
+            "temperature,0xfa,2,be,signed,1,0,extra",
+            "windchill,0xfa,2,be,signed",
+            "temperature,0x1fa,2,be,signed",
+            "temperature,0xfa,0,be,signed",
+            "temperature,0xfa,5,be,signed",
+            "temperature,0xfa,2,middle,signed",
+            "temperature,0xfa,2,be,maybe",
+            "temperature,0xfa,2,be,signed,x",
+        ] {
+            assert!(
+                invalid.parse::<RegisterMap>().is_err(),
+                "'{}' accepted",
+                invalid
+            );
+        }
+    }
+
+    #[test]
+    fn converts_raw_values() {
+        let be_signed = register_map("temperature,0,2,be,signed,0.5,1");
+        assert_eq!(be_signed.convert(&[0x00, 0x10]), 9.0);
+        assert_eq!(be_signed.convert(&[0xff, 0xfe]), 0.0);
+
+        let le_signed = register_map("temperature,0,2,le,signed");
+        assert_eq!(le_signed.convert(&[0xfe, 0xff]), -2.0);
+        assert_eq!(le_signed.convert(&[0x00, 0x80]), -32768.0);
+
+        let le_unsigned = register_map("pressure,0,2,le,unsigned");
+        assert_eq!(le_unsigned.convert(&[0xfe, 0xff]), 65534.0);
+
+        // Sign extension from bit 23 and 7
+        let three_bytes = register_map("pressure,0,3,be,signed");
+        assert_eq!(three_bytes.convert(&[0xff, 0xff, 0x9c]), -100.0);
+        assert_eq!(three_bytes.convert(&[0x7f, 0xff, 0xff]), 8388607.0);
+
+        let one_byte = register_map("temperature,0,1,be,signed");
+        assert_eq!(one_byte.convert(&[0x80]), -128.0);
+
+        let four_bytes = register_map("pressure,0,4,le,unsigned");
+        assert_eq!(four_bytes.convert(&[0x01, 0x00, 0x00, 0x80]), 2147483649.0);
+    }
+
+    #[tokio::test]
+    async fn writes_init_sequence_and_reads_registers() {
+        let mut bus = MockI2cBus::new().with_device(0x48, 0xff);
+        bus.set_registers(0x48, 0x04, &[0xe7, 0x00]);
+        let bus = Arc::new(Mutex::new(bus));
+
+        let sensors = vec![(0, register_map("temperature,0x04,2,be,signed,0.00390625"))]
+            .into_iter()
+            .collect();
+        let init = "0x01,0x60".parse().unwrap();
+        let node = GenericI2cNode::new(bus.clone(), 0x48, &init, sensors).unwrap();
+
+        assert_eq!(bus.lock().unwrap().registers(0x48).unwrap()[0x01], 0x60);
+        assert_eq!(
+            node.measure(SensorTypeEnum::Temperature, 0).await.unwrap(),
+            -25.0
+        );
+        assert!(node.measure(SensorTypeEnum::Humidity, 0).await.is_err());
+        assert!(node.measure(SensorTypeEnum::Temperature, 1).await.is_err());
+    }
+}
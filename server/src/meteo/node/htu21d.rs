@@ -0,0 +1,206 @@
+use super::{reading_error, NodeCapabilities, SensorCapability, SensorNode};
+
+use crate::comm::i2c::{self, Message};
+use crate::comm::{NodeError, SharedI2cTransport};
+
+use crate::meteo::models::SensorTypeEnum;
+
+use std::time::Duration;
+
+use crate::utils::Result;
+
+use log::debug;
+
+/// TE HTU21D or Silicon Labs Si7021 humidity and temperature sensor, the
+/// `htu21d` route type.
+pub struct Htu21dNode {
+    comm_path: SharedI2cTransport,
+    addr: u16,
+    /// Held while measuring, so commands of concurrent readings don't mix.
+    measurement_lock: tokio::sync::Mutex<()>,
+}
+
+impl Htu21dNode {
+    pub const I2C_ADDR_DEFAULT: u16 = 0x40;
+
+    const CMD_SOFT_RESET: u8 = 0xfe;
+    const CMD_READ_USER_REG: u8 = 0xe7;
+    /// Measurements without holding the bus (no clock stretching).
+    const CMD_MEASURE_TEMP: u8 = 0xf3;
+    const CMD_MEASURE_HUM: u8 = 0xf5;
+
+    const CRC_INIT: u8 = 0x00;
+    /// The two lowest bits of a result are status bits.
+    const STATUS_BITS_MASK: u16 = 0x0003;
+
+    const RESET_TIME: Duration = Duration::from_millis(15);
+    /// Longest conversion times at the default resolution (14-bit
+    /// temperature, 12-bit humidity), both from the HTU21D.
+    const TEMP_MEASUREMENT_TIME: Duration = Duration::from_millis(50);
+    const HUM_MEASUREMENT_TIME: Duration = Duration::from_millis(16);
+
+    pub fn new(comm_path: SharedI2cTransport, addr: u16) -> Result<Htu21dNode> {
+        let node = Htu21dNode {
+            comm_path,
+            addr,
+            measurement_lock: tokio::sync::Mutex::new(()),
+        };
+
+        debug!("Resetting HTU21D");
+        node.transfer(&mut [Message::write(addr, &[Self::CMD_SOFT_RESET])])?;
+        std::thread::sleep(Self::RESET_TIME);
+
+        // There's no chip ID to check, so just make sure something answers
+        let mut user_reg = [0];
+        node.transfer(&mut [
+            Message::write(addr, &[Self::CMD_READ_USER_REG]),
+            Message::read(addr, &mut user_reg),
+        ])?;
+
+        debug!("HTU21D user register is 0x{:02X}", user_reg[0]);
+
+        Ok(node)
+    }
+
+    fn transfer(&self, msgs: &mut [Message]) -> Result<()> {
+        self.comm_path
+            .lock()
+            .expect("Mutex poisoned.")
+            .transfer(msgs)
+    }
+
+    /// Runs a measurement and returns its raw result, status bits cleared.
+    async fn measure_raw(&self, cmd: u8, measurement_time: Duration) -> Result<u16> {
+        let _measurement_guard = self.measurement_lock.lock().await;
+
+        self.transfer(&mut [Message::write(self.addr, &[cmd])])?;
+        tokio::time::sleep(measurement_time).await;
+
+        let mut data = [0; 3];
+        self.transfer(&mut [Message::read(self.addr, &mut data)])?;
+
+        let calc_crc = i2c::crc8(&data[..2], Self::CRC_INIT);
+
+        // A corrupted result is down to the sensor (or its wiring), not the bus
+        if calc_crc != data[2] {
+            return Err(anyhow::Error::new(NodeError::SensorFault)
+                .context(format!(
+                    "HTU21D CRC mismatch on {:02X?}, expecting 0x{:02X}.",
+                    data, calc_crc
+                ))
+                .into());
+        }
+
+        Ok(u16::from_be_bytes([data[0], data[1]]) & !Self::STATUS_BITS_MASK)
+    }
+
+    async fn query(&self, measurement_type: SensorTypeEnum) -> Option<Result<f32>> {
+        let value = match measurement_type {
+            SensorTypeEnum::Temperature => self
+                .measure_raw(Self::CMD_MEASURE_TEMP, Self::TEMP_MEASUREMENT_TIME)
+                .await
+                .map(|raw| -46.85 + 175.72 * raw as f32 / 65536.0),
+            // Can go slightly out of range near either end
+            SensorTypeEnum::Humidity => self
+                .measure_raw(Self::CMD_MEASURE_HUM, Self::HUM_MEASUREMENT_TIME)
+                .await
+                .map(|raw| (-6.0 + 125.0 * raw as f32 / 65536.0).clamp(0.0, 100.0)),
+            _ => return None,
+        };
+
+        debug!("HTU21D {} reading: {:?}", measurement_type.as_ref(), value);
+
+        Some(value)
+    }
+}
+
+#[rocket::async_trait]
+impl SensorNode for Htu21dNode {
+    async fn measure(&self, measurement_type: SensorTypeEnum, sensor_id: u32) -> Result<f32> {
+        if sensor_id != 0 {
+            return Err(reading_error(
+                NodeError::UnknownChannel,
+                measurement_type,
+                sensor_id,
+            ));
+        }
+
+        self.query(measurement_type).await.unwrap_or_else(|| {
+            Err(reading_error(
+                NodeError::UnsupportedType,
+                measurement_type,
+                sensor_id,
+            ))
+        })
+    }
+
+    async fn describe(&self) -> Result<NodeCapabilities> {
+        Ok(NodeCapabilities {
+            firmware_version: None,
+            protocol_version: None,
+            sensors: [SensorTypeEnum::Temperature, SensorTypeEnum::Humidity]
+                .iter()
+                .map(|&sensor_type| SensorCapability {
+                    sensor_type,
+                    sensor_id: 0,
+                })
+                .collect(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use super::super::tests::{assert_close, node_error};
+
+    use crate::comm::mock::MockI2cBus;
+
+    use std::sync::{Arc, Mutex};
+
+    /// The mock takes a measurement command as the register address, so its
+    /// result is read from there on.
+    fn set_result(bus: &Mutex<MockI2cBus>, cmd: u8, raw: u16) {
+        let bytes = raw.to_be_bytes();
+        let crc = i2c::crc8(&bytes, Htu21dNode::CRC_INIT);
+
+        bus.lock()
+            .unwrap()
+            .set_registers(0x40, cmd, &[bytes[0], bytes[1], crc]);
+    }
+
+    #[test]
+    fn computes_crc_of_datasheet_examples() {
+        assert_eq!(i2c::crc8(&[0xdc], Htu21dNode::CRC_INIT), 0x79);
+        assert_eq!(i2c::crc8(&[0x68, 0x3a], Htu21dNode::CRC_INIT), 0x7c);
+        assert_eq!(i2c::crc8(&[0x4e, 0x85], Htu21dNode::CRC_INIT), 0x6b);
+    }
+
+    #[tokio::test]
+    async fn converts_datasheet_examples() {
+        let bus = Arc::new(Mutex::new(MockI2cBus::new().with_device(0x40, 0xff)));
+        let node = Htu21dNode::new(bus.clone(), 0x40).unwrap();
+
+        // Status bits set, which don't count
+        set_result(&bus, Htu21dNode::CMD_MEASURE_TEMP, 0x683a);
+        assert_close(
+            node.measure(SensorTypeEnum::Temperature, 0).await.unwrap(),
+            24.686,
+        );
+
+        set_result(&bus, Htu21dNode::CMD_MEASURE_HUM, 0x7c80);
+        assert_close(
+            node.measure(SensorTypeEnum::Humidity, 0).await.unwrap(),
+            54.791,
+        );
+
+        // CRC off
+        bus.lock()
+            .unwrap()
+            .set_registers(0x40, Htu21dNode::CMD_MEASURE_HUM + 2, &[0x00]);
+
+        let err = node.measure(SensorTypeEnum::Humidity, 0).await.unwrap_err();
+        assert_eq!(node_error(&err), Some(NodeError::SensorFault));
+    }
+}
@@ -1,7 +1,10 @@
 use std::collections::BTreeMap;
 use std::convert::TryInto;
+use std::sync::mpsc::{self, Receiver};
 use std::sync::Arc;
 
+use crate::comm::serial::PushMessage;
+use crate::comm::{self, CommPathProvider, NodeError, SharedSerialTransport};
 use crate::db;
 use crate::db::models::Node;
 
@@ -9,22 +12,117 @@ use super::models::SensorTypeEnum;
 
 use diesel::prelude::*;
 
+mod bh1750;
+mod bme280;
 mod enviro_phat;
+mod generic_i2c;
+mod htu21d;
+mod modbus_rtu;
+mod onewire;
+mod register_map;
 mod serial_node;
+mod sht3x;
+mod sysfs;
+mod veml7700;
 
-use crate::utils::Result;
+#[cfg(test)]
+mod tests;
+
+pub(crate) use serial_node::parse_reading;
+
+use crate::utils::{self, Result};
 use anyhow::anyhow;
 
+/// Wraps an error a node reported for a reading with the sensor it concerns.
+fn reading_error(err: NodeError, sensor_type: SensorTypeEnum, sensor_id: u32) -> utils::Error {
+    anyhow::Error::new(err)
+        .context(format!(
+            "Error reading {} sensor {sensor_id}",
+            sensor_type.as_ref()
+        ))
+        .into()
+}
+
+/// Parses the `<bus>` or `<bus>,<addr>` route param of nodes on an I2C bus,
+/// with the address in decimal or `0x` prefixed hex.
+fn parse_i2c_route_param(
+    route_param: Option<String>,
+    public_id: u32,
+) -> Result<(u32, Option<u16>)> {
+    let route_param_str =
+        route_param.ok_or(anyhow!("Missing route param info for node ID {public_id}."))?;
+
+    let invalid_param = |e: &dyn std::fmt::Debug| {
+        anyhow!("Invalid route param '{route_param_str}' for node ID {public_id}. {e:?}")
+    };
+
+    let mut parts = route_param_str.splitn(2, ',').map(str::trim);
+
+    let comm_path_id = parts
+        .next()
+        .unwrap_or("")
+        .parse::<u32>()
+        .map_err(|e| invalid_param(&e))?;
+
+    let addr = match parts.next() {
+        Some(addr_str) => Some(
+            match addr_str.strip_prefix("0x") {
+                Some(hex_str) => u16::from_str_radix(hex_str, 16),
+                None => addr_str.parse(),
+            }
+            .map_err(|e| invalid_param(&e))?,
+        ),
+        None => None,
+    };
+
+    Ok((comm_path_id, addr))
+}
+
+/// Sensor channel a node reports it has.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SensorCapability {
+    pub sensor_type: SensorTypeEnum,
+    pub sensor_id: u32,
+}
+
+/// What a node reports about itself during discovery.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct NodeCapabilities {
+    pub firmware_version: Option<String>,
+    pub protocol_version: Option<u32>,
+    pub sensors: Vec<SensorCapability>,
+}
+
+#[rocket::async_trait]
 pub trait SensorNode: Sync + Send {
-    fn measure(&self, measurement_type: SensorTypeEnum, sensor_id: u32) -> Result<f32>;
+    async fn measure(&self, measurement_type: SensorTypeEnum, sensor_id: u32) -> Result<f32>;
+
+    /// Measures all the (type, ID) pairs in `requests` in one go. The outer
+    /// error means no readings could be taken at all, otherwise there is one
+    /// result per request, in the same order.
+    async fn measure_batch(&self, requests: &[(SensorTypeEnum, u32)]) -> Result<Vec<Result<f32>>> {
+        let mut results = Vec::with_capacity(requests.len());
+
+        for &(measurement_type, sensor_id) in requests {
+            results.push(self.measure(measurement_type, sensor_id).await);
+        }
+
+        Ok(results)
+    }
+
+    /// Asks the node for its firmware and protocol version, and the sensor
+    /// channels it has.
+    async fn describe(&self) -> Result<NodeCapabilities>;
 }
 
 #[derive(Clone)]
 pub struct SensorNodeRegistry {
     node_map: Arc<BTreeMap<u32, Arc<dyn SensorNode>>>,
+    serial_paths: Arc<BTreeMap<(&'static str, u32), SharedSerialTransport>>,
 }
 
 impl SensorNodeRegistry {
+    /// Builds nodes for all entries in the DB, on top of the real comm paths.
     pub fn new(db_conn: db::Db) -> Result<SensorNodeRegistry> {
         let nodes = {
             use crate::db::schema::nodes;
@@ -34,7 +132,18 @@ impl SensorNodeRegistry {
                 .map_err(|e| anyhow!("Error loading Node entries from DB. {e:?}"))?
         };
 
+        Self::from_nodes(nodes, &comm::SystemCommPaths)
+    }
+
+    /// Builds nodes for the given entries, taking their comm paths from
+    /// `comm_paths`. Handing in `comm::mock::MockCommPaths` makes the whole
+    /// meteo stack run without hardware.
+    pub fn from_nodes(
+        nodes: Vec<Node>,
+        comm_paths: &dyn CommPathProvider,
+    ) -> Result<SensorNodeRegistry> {
         let mut node_map: BTreeMap<u32, Arc<dyn SensorNode>> = BTreeMap::new();
+        let mut serial_paths = BTreeMap::new();
 
         for node in nodes {
             let public_id: u32 = node.public_id.try_into().map_err(|e| {
@@ -57,7 +166,26 @@ impl SensorNodeRegistry {
                     )
                     })?;
 
-                    Arc::new(serial_node::SerialNode::new(public_id, comm_path_id)?)
+                    let comm_path = comm_paths.serial_path(comm_path_id)?;
+                    serial_paths.insert(("serial", comm_path_id), comm_path.clone());
+
+                    Arc::new(serial_node::SerialNode::new(public_id, comm_path))
+                }
+                "tcp" => {
+                    let route_param_str = node
+                        .route_param
+                        .ok_or(anyhow!("Missing route param info for node ID {public_id}."))?;
+
+                    let comm_path_id = route_param_str.parse::<u32>().map_err(|e| {
+                        anyhow!(
+                            "Invalid route param '{route_param_str}' for node ID {public_id}. {e:?}"
+                        )
+                    })?;
+
+                    let comm_path = comm_paths.tcp_path(comm_path_id)?;
+                    serial_paths.insert(("tcp", comm_path_id), comm_path.clone());
+
+                    Arc::new(serial_node::SerialNode::new(public_id, comm_path))
                 }
                 "envirophat" => {
                     let route_param_str = node
@@ -68,7 +196,140 @@ impl SensorNodeRegistry {
                             "Invalid route param '{route_param_str}' for node ID {public_id}. {e:?}")
                     )?;
 
-                    Arc::new(enviro_phat::EnviroPHat::new(comm_path_id)?)
+                    Arc::new(enviro_phat::EnviroPHat::new(
+                        public_id,
+                        comm_paths.i2c_path(comm_path_id)?,
+                    )?)
+                }
+                "bme280" => {
+                    let (comm_path_id, addr) = parse_i2c_route_param(node.route_param, public_id)?;
+
+                    Arc::new(bme280::Bme280Node::new(
+                        comm_paths.i2c_path(comm_path_id)?,
+                        addr.unwrap_or(enviro_phat::bmp280::Bmp280::I2C_ADDR_DEFAULT),
+                    )?)
+                }
+                "sht3x" => {
+                    let (comm_path_id, addr) = parse_i2c_route_param(node.route_param, public_id)?;
+
+                    let mode = utils::env_var_or(
+                        &format!("NODE_{public_id}_SHT3X_MODE"),
+                        sht3x::Mode::SingleShot,
+                    )?;
+
+                    Arc::new(sht3x::Sht3xNode::new(
+                        comm_paths.i2c_path(comm_path_id)?,
+                        addr.unwrap_or(sht3x::Sht3xNode::I2C_ADDR_DEFAULT),
+                        mode,
+                    )?)
+                }
+                "htu21d" => {
+                    let (comm_path_id, addr) = parse_i2c_route_param(node.route_param, public_id)?;
+
+                    Arc::new(htu21d::Htu21dNode::new(
+                        comm_paths.i2c_path(comm_path_id)?,
+                        addr.unwrap_or(htu21d::Htu21dNode::I2C_ADDR_DEFAULT),
+                    )?)
+                }
+                "bh1750" => {
+                    let (comm_path_id, addr) = parse_i2c_route_param(node.route_param, public_id)?;
+
+                    Arc::new(bh1750::Bh1750Node::new(
+                        comm_paths.i2c_path(comm_path_id)?,
+                        addr.unwrap_or(bh1750::Bh1750Node::I2C_ADDR_DEFAULT),
+                    )?)
+                }
+                "veml7700" => {
+                    let (comm_path_id, addr) = parse_i2c_route_param(node.route_param, public_id)?;
+
+                    Arc::new(veml7700::Veml7700Node::new(
+                        comm_paths.i2c_path(comm_path_id)?,
+                        addr.unwrap_or(veml7700::Veml7700Node::I2C_ADDR_DEFAULT),
+                    )?)
+                }
+                "generic_i2c" => {
+                    let (comm_path_id, addr) = parse_i2c_route_param(node.route_param, public_id)?;
+
+                    let addr = addr.ok_or(anyhow!(
+                        "Missing I2C address in route param for node ID {public_id}."
+                    ))?;
+
+                    Arc::new(generic_i2c::GenericI2cNode::from_env(
+                        public_id,
+                        comm_paths.i2c_path(comm_path_id)?,
+                        addr,
+                    )?)
+                }
+                "modbus_rtu" => {
+                    let route_param_str = node
+                        .route_param
+                        .ok_or(anyhow!("Missing route param info for node ID {public_id}."))?;
+
+                    let invalid_param = |e: &dyn std::fmt::Debug| {
+                        anyhow!(
+                            "Invalid route param '{route_param_str}' for node ID {public_id}, expected <serial_port>,<slave_addr>. {e:?}"
+                        )
+                    };
+
+                    let (comm_path_id_str, slave_addr_str) = route_param_str
+                        .split_once(',')
+                        .ok_or_else(|| invalid_param(&"Missing slave address."))?;
+
+                    let comm_path_id = comm_path_id_str
+                        .trim()
+                        .parse::<u32>()
+                        .map_err(|e| invalid_param(&e))?;
+
+                    let slave_addr = slave_addr_str
+                        .trim()
+                        .parse::<u8>()
+                        .map_err(|e| invalid_param(&e))?;
+
+                    if slave_addr == 0 || slave_addr > comm::modbus::MAX_SLAVE_ADDR {
+                        return Err(invalid_param(&"Slave address not within 1 to 247.").into());
+                    }
+
+                    Arc::new(modbus_rtu::ModbusRtuNode::from_env(
+                        public_id,
+                        comm_paths.modbus_path(comm_path_id)?,
+                        slave_addr,
+                    )?)
+                }
+                "onewire" => {
+                    let route_param = node.route_param.filter(|param| !param.is_empty());
+
+                    Arc::new(
+                        onewire::OneWireNode::new(
+                            &comm_paths.sysfs_root()?,
+                            route_param.as_deref(),
+                        )
+                        .map_err(|e| {
+                            anyhow!("Invalid 1-Wire setup for node ID {public_id}. {e:?}")
+                        })?,
+                    )
+                }
+                "iio" | "hwmon" => {
+                    let subsystem = match node.route_type.as_str() {
+                        "iio" => sysfs::Subsystem::Iio,
+                        _ => sysfs::Subsystem::Hwmon,
+                    };
+
+                    let route_param_str = node
+                        .route_param
+                        .ok_or(anyhow!("Missing route param info for node ID {public_id}."))?;
+
+                    Arc::new(
+                        sysfs::SysfsNode::new(
+                            subsystem,
+                            &comm_paths.sysfs_root()?,
+                            &route_param_str,
+                        )
+                        .map_err(|e| {
+                            anyhow!(
+                                "Invalid route param '{route_param_str}' for node ID {public_id}. {e:?}"
+                            )
+                        })?,
+                    )
                 }
                 route_type => {
                     return Err(anyhow!(
@@ -83,6 +344,7 @@ impl SensorNodeRegistry {
 
         Ok(SensorNodeRegistry {
             node_map: Arc::new(node_map),
+            serial_paths: Arc::new(serial_paths),
         })
     }
 
@@ -92,6 +354,18 @@ impl SensorNodeRegistry {
             .ok_or_else(|| anyhow!("Could not find node {node_id} in sensor node registry.").into())
             .map(|arc| arc.clone())
     }
+
+    /// Subscribes to push messages for `module` from the nodes on all serial
+    /// and TCP comm paths in use.
+    pub fn subscribe_push(&self, module: &str) -> Receiver<PushMessage> {
+        let (push_tx, push_rx) = mpsc::channel();
+
+        for comm_path in self.serial_paths.values() {
+            comm_path.subscribe(module, push_tx.clone());
+        }
+
+        push_rx
+    }
 }
 
 unsafe impl Sync for SensorNodeRegistry {}
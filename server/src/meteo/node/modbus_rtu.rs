@@ -0,0 +1,217 @@
+use super::register_map::{parse_int, parse_scale_and_offset, parse_sensor_type, split_fields};
+use super::{reading_error, NodeCapabilities, SensorCapability, SensorNode};
+
+use crate::comm::modbus::RegisterTable;
+use crate::comm::{NodeError, SharedModbusTransport};
+
+use crate::meteo::models::SensorTypeEnum;
+
+use std::collections::BTreeMap;
+use std::str::FromStr;
+
+use crate::utils::{self, Result};
+use anyhow::anyhow;
+
+use log::debug;
+
+/// How the registers of a sensor make up its raw value. 32-bit values span
+/// two registers, high word first unless swapped.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum DataType {
+    U16,
+    I16,
+    U32,
+    I32,
+    F32,
+    U32Swapped,
+    I32Swapped,
+    F32Swapped,
+}
+
+impl FromStr for DataType {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "u16" => Ok(DataType::U16),
+            "i16" => Ok(DataType::I16),
+            "u32" => Ok(DataType::U32),
+            "i32" => Ok(DataType::I32),
+            "f32" => Ok(DataType::F32),
+            "u32_swapped" => Ok(DataType::U32Swapped),
+            "i32_swapped" => Ok(DataType::I32Swapped),
+            "f32_swapped" => Ok(DataType::F32Swapped),
+            _ => Err(format!(
+                "expected one of u16, i16, u32, i32, f32, u32_swapped, i32_swapped, f32_swapped, got '{}'",
+                s
+            )),
+        }
+    }
+}
+
+impl DataType {
+    fn register_count(&self) -> u16 {
+        match self {
+            DataType::U16 | DataType::I16 => 1,
+            _ => 2,
+        }
+    }
+
+    fn decode(&self, registers: &[u16]) -> f32 {
+        let word = || match self {
+            DataType::U32Swapped | DataType::I32Swapped | DataType::F32Swapped => {
+                u32::from(registers[1]) << 16 | u32::from(registers[0])
+            }
+            _ => u32::from(registers[0]) << 16 | u32::from(registers[1]),
+        };
+
+        match self {
+            DataType::U16 => registers[0] as f32,
+            DataType::I16 => registers[0] as i16 as f32,
+            DataType::U32 | DataType::U32Swapped => word() as f32,
+            DataType::I32 | DataType::I32Swapped => word() as i32 as f32,
+            DataType::F32 | DataType::F32Swapped => f32::from_bits(word()),
+        }
+    }
+}
+
+/// Where and how a sensor's value is read, given as
+/// `<type>,<function>,<register>,<data_type>[,<scale>[,<offset>]]`, with the
+/// function code 3 (holding) or 4 (input) and the zero-based register
+/// address. The value reported is `raw * scale + offset`.
+#[derive(Debug, Clone, Copy)]
+pub struct RegisterMap {
+    sensor_type: SensorTypeEnum,
+    table: RegisterTable,
+    start: u16,
+    data_type: DataType,
+    scale: f32,
+    offset: f32,
+}
+
+impl FromStr for RegisterMap {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        let fields = split_fields(
+            s,
+            4,
+            "<type>,<function>,<register>,<data_type>[,<scale>[,<offset>]]",
+        )?;
+
+        let sensor_type = parse_sensor_type(fields[0])?;
+
+        let table = fields[1].parse::<RegisterTable>()?;
+
+        let start = parse_int(fields[2], "register")?;
+
+        let data_type = fields[3].parse::<DataType>()?;
+
+        let (scale, offset) = parse_scale_and_offset(&fields[4..])?;
+
+        Ok(RegisterMap {
+            sensor_type,
+            table,
+            start,
+            data_type,
+            scale,
+            offset,
+        })
+    }
+}
+
+/// Sensors of a Modbus RTU slave (anemometer, rain gauge, ...), the
+/// `modbus_rtu` route type. Other slaves can share the serial port, each as a
+/// node of its own.
+pub struct ModbusRtuNode {
+    comm_path: SharedModbusTransport,
+    slave_addr: u8,
+    sensors: BTreeMap<u32, RegisterMap>,
+}
+
+impl ModbusRtuNode {
+    /// Sets up the slave at `slave_addr` with the sensors given by the
+    /// `NODE_<public_id>_MODBUS_SENSOR_<sensor_id>` env variables.
+    pub fn from_env(
+        public_id: u32,
+        comm_path: SharedModbusTransport,
+        slave_addr: u8,
+    ) -> Result<ModbusRtuNode> {
+        let sensor_prefix = format!("NODE_{public_id}_MODBUS_SENSOR_");
+
+        let sensors = utils::env_vars_by_id(&sensor_prefix)?;
+
+        if sensors.is_empty() {
+            return Err(anyhow!("No {}<sensor_id> env variables set.", sensor_prefix).into());
+        }
+
+        Ok(ModbusRtuNode {
+            comm_path,
+            slave_addr,
+            sensors,
+        })
+    }
+
+    async fn read(&self, register_map: &RegisterMap) -> Result<f32> {
+        let registers = self
+            .comm_path
+            .read_registers(
+                self.slave_addr,
+                register_map.table,
+                register_map.start,
+                register_map.data_type.register_count(),
+            )
+            .await?;
+
+        let value =
+            register_map.data_type.decode(&registers) * register_map.scale + register_map.offset;
+
+        debug!(
+            "Registers {:?} {} of slave {}: {:04X?}, {} {}",
+            register_map.table,
+            register_map.start,
+            self.slave_addr,
+            registers,
+            register_map.sensor_type.as_ref(),
+            value
+        );
+
+        Ok(value)
+    }
+}
+
+#[rocket::async_trait]
+impl SensorNode for ModbusRtuNode {
+    async fn measure(&self, measurement_type: SensorTypeEnum, sensor_id: u32) -> Result<f32> {
+        match self.sensors.get(&sensor_id) {
+            Some(register_map) if register_map.sensor_type == measurement_type => {
+                self.read(register_map).await
+            }
+            Some(_) => Err(reading_error(
+                NodeError::UnsupportedType,
+                measurement_type,
+                sensor_id,
+            )),
+            None => Err(reading_error(
+                NodeError::UnknownChannel,
+                measurement_type,
+                sensor_id,
+            )),
+        }
+    }
+
+    async fn describe(&self) -> Result<NodeCapabilities> {
+        Ok(NodeCapabilities {
+            firmware_version: None,
+            protocol_version: None,
+            sensors: self
+                .sensors
+                .iter()
+                .map(|(&sensor_id, register_map)| SensorCapability {
+                    sensor_type: register_map.sensor_type,
+                    sensor_id,
+                })
+                .collect(),
+        })
+    }
+}
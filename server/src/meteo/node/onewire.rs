@@ -0,0 +1,311 @@
+use super::{reading_error, NodeCapabilities, SensorCapability, SensorNode};
+
+use crate::comm::NodeError;
+
+use crate::meteo::models::SensorTypeEnum;
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::utils::Result;
+use anyhow::anyhow;
+
+use log::{info, warn};
+
+/// Family code prefix of DS18B20 probe directories.
+const DS18B20_PREFIX: &str = "28-";
+
+/// DS18B20 probes on the 1-Wire bus, read through the kernel's w1-gpio and
+/// w1-therm drivers, the `onewire` route type.
+pub struct OneWireNode {
+    devices_dir: PathBuf,
+    /// Probe serial number (the sysfs directory name) of each sensor ID.
+    probes: BTreeMap<u32, String>,
+}
+
+impl OneWireNode {
+    /// Maps sensor IDs to probes as given in `route_param`, a comma separated
+    /// list of `<sensor_id>:<serial>` pairs. The map is required, numbering
+    /// probes by serial would renumber them when one is added or replaced.
+    pub fn new(sysfs_root: &Path, route_param: Option<&str>) -> Result<OneWireNode> {
+        let devices_dir = sysfs_root.join("bus/w1/devices");
+
+        let probes = match route_param {
+            Some(route_param) => parse_probe_map(route_param)?,
+            None => {
+                return Err(anyhow!(
+                    "Missing <sensor_id>:<serial> probe map, probes present: {:?}",
+                    list_probes(&devices_dir).unwrap_or_default()
+                )
+                .into())
+            }
+        };
+
+        info!("1-Wire probes by sensor ID: {:?}", probes);
+
+        Ok(OneWireNode {
+            devices_dir,
+            probes,
+        })
+    }
+}
+
+/// Reads the probe of `sensor_id`. Readings the driver flags as corrupted, or
+/// that can't be made sense of, are a `SensorFault`.
+fn read_probe(devices_dir: &Path, serial: &str, sensor_id: u32) -> Result<f32> {
+    let w1_slave_path = devices_dir.join(serial).join("w1_slave");
+
+    let contents = fs::read_to_string(&w1_slave_path).map_err(|e| {
+        anyhow!(
+            "Error reading DS18B20 probe {serial} at {}. {e:?}",
+            w1_slave_path.display()
+        )
+    })?;
+
+    parse_w1_slave(&contents).map_err(|e| {
+        warn!("DS18B20 probe {}: {}", serial, e);
+        reading_error(
+            NodeError::SensorFault,
+            SensorTypeEnum::Temperature,
+            sensor_id,
+        )
+    })
+}
+
+/// Serial numbers of the DS18B20 probes in `devices_dir`, sorted.
+fn list_probes(devices_dir: &Path) -> Result<Vec<String>> {
+    let entries = fs::read_dir(devices_dir).map_err(|e| {
+        anyhow!(
+            "Error listing 1-Wire devices in {}. {e:?}",
+            devices_dir.display()
+        )
+    })?;
+
+    let mut probes = entries
+        .filter_map(|entry| entry.ok())
+        .filter_map(|entry| entry.file_name().into_string().ok())
+        .filter(|name| name.starts_with(DS18B20_PREFIX))
+        .collect::<Vec<_>>();
+
+    probes.sort();
+
+    Ok(probes)
+}
+
+fn parse_probe_map(route_param: &str) -> Result<BTreeMap<u32, String>> {
+    route_param
+        .split(',')
+        .map(str::trim)
+        .map(|pair| {
+            let (sensor_id_str, serial) = pair
+                .split_once(':')
+                .ok_or_else(|| anyhow!("Expected <sensor_id>:<serial>, got '{pair}'."))?;
+
+            let sensor_id = sensor_id_str
+                .trim()
+                .parse::<u32>()
+                .map_err(|e| anyhow!("Invalid sensor ID in '{pair}'. {e:?}"))?;
+
+            Ok((sensor_id, serial.trim().to_string()))
+        })
+        .collect()
+}
+
+/// Parses the `w1_slave` output of the w1-therm driver, the scratchpad bytes
+/// with the CRC check result, then the bytes again with the temperature in
+/// millidegrees:
+///
+/// ```text
+/// 72 01 4b 46 7f ff 0e 10 57 : crc=57 YES
+/// 72 01 4b 46 7f ff 0e 10 57 t=23125
+/// ```
+fn parse_w1_slave(contents: &str) -> std::result::Result<f32, String> {
+    let mut lines = contents.lines();
+
+    let crc_line = lines.next().ok_or("Empty w1_slave output.")?;
+
+    if !crc_line.trim_end().ends_with("YES") {
+        return Err(format!("CRC check failed ({}).", crc_line.trim()));
+    }
+
+    let temp_str = lines
+        .next()
+        .and_then(|line| line.rsplit_once("t="))
+        .map(|(_, temp_str)| temp_str.trim())
+        .ok_or("Missing temperature in w1_slave output.")?;
+
+    let millidegrees = temp_str
+        .parse::<i32>()
+        .map_err(|e| format!("Invalid temperature '{temp_str}'. {e:?}"))?;
+
+    Ok(millidegrees as f32 / 1000.0)
+}
+
+#[rocket::async_trait]
+impl SensorNode for OneWireNode {
+    async fn measure(&self, measurement_type: SensorTypeEnum, sensor_id: u32) -> Result<f32> {
+        if measurement_type != SensorTypeEnum::Temperature {
+            return Err(reading_error(
+                NodeError::UnsupportedType,
+                measurement_type,
+                sensor_id,
+            ));
+        }
+
+        let serial =
+            self.probes.get(&sensor_id).cloned().ok_or_else(|| {
+                reading_error(NodeError::UnknownChannel, measurement_type, sensor_id)
+            })?;
+
+        // The kernel driver runs the conversion during the read, up to 750 ms
+        let devices_dir = self.devices_dir.clone();
+        tokio::task::spawn_blocking(move || read_probe(&devices_dir, &serial, sensor_id))
+            .await
+            .map_err(|e| anyhow!("DS18B20 read failed. {e:?}"))?
+    }
+
+    async fn describe(&self) -> Result<NodeCapabilities> {
+        let present = list_probes(&self.devices_dir)?;
+
+        for serial in &present {
+            if !self.probes.values().any(|probe| probe == serial) {
+                warn!("1-Wire probe {} not mapped to any sensor ID.", serial);
+            }
+        }
+
+        let sensors = self
+            .probes
+            .iter()
+            .filter(|(_, serial)| present.contains(serial))
+            .map(|(&sensor_id, _)| SensorCapability {
+                sensor_type: SensorTypeEnum::Temperature,
+                sensor_id,
+            })
+            .collect();
+
+        Ok(NodeCapabilities {
+            firmware_version: None,
+            protocol_version: None,
+            sensors,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use super::super::tests::node_error;
+
+    const W1_SLAVE_OK: &str = "72 01 4b 46 7f ff 0e 10 57 : crc=57 YES\n\
+                               72 01 4b 46 7f ff 0e 10 57 t=23125\n";
+
+    /// Fake sysfs root with a DS18B20 probe per serial, all reading
+    /// `W1_SLAVE_OK`, and a bus master that isn't a probe.
+    fn sysfs_with_probes(serials: &[&str]) -> tempfile::TempDir {
+        let sysfs_root = tempfile::tempdir().unwrap();
+        let devices_dir = sysfs_root.path().join("bus/w1/devices");
+
+        fs::create_dir_all(devices_dir.join("w1_bus_master1")).unwrap();
+
+        for serial in serials {
+            fs::create_dir(devices_dir.join(serial)).unwrap();
+            fs::write(devices_dir.join(serial).join("w1_slave"), W1_SLAVE_OK).unwrap();
+        }
+
+        sysfs_root
+    }
+
+    #[test]
+    fn parses_w1_slave_temperature() {
+        assert_eq!(parse_w1_slave(W1_SLAVE_OK), Ok(23.125));
+
+        let below_zero = "5e ff 4b 46 7f ff 02 10 b2 : crc=b2 YES\n\
+                          5e ff 4b 46 7f ff 02 10 b2 t=-10125\n";
+        assert_eq!(parse_w1_slave(below_zero), Ok(-10.125));
+    }
+
+    #[tokio::test]
+    async fn rejects_w1_slave_crc_failure_and_garbage() {
+        let crc_failed = "72 01 4b 46 7f ff 0e 10 57 : crc=56 NO\n\
+                          72 01 4b 46 7f ff 0e 10 57 t=23125\n";
+        let missing_temp = "72 01 4b 46 7f ff 0e 10 57 : crc=57 YES\n";
+        let garbled_temp = "72 01 : crc=57 YES\n72 01 t=abc\n";
+
+        assert!(parse_w1_slave(crc_failed).is_err());
+        assert!(parse_w1_slave("").is_err());
+        assert!(parse_w1_slave(missing_temp).is_err());
+        assert!(parse_w1_slave(garbled_temp).is_err());
+
+        let sysfs_root = sysfs_with_probes(&["28-0000075a3c1f"]);
+        let w1_slave_path = sysfs_root
+            .path()
+            .join("bus/w1/devices/28-0000075a3c1f/w1_slave");
+        let node = OneWireNode::new(sysfs_root.path(), Some("0:28-0000075a3c1f")).unwrap();
+
+        for contents in &[crc_failed, missing_temp, garbled_temp] {
+            fs::write(&w1_slave_path, contents).unwrap();
+
+            let err = node
+                .measure(SensorTypeEnum::Temperature, 0)
+                .await
+                .unwrap_err();
+            assert_eq!(node_error(&err), Some(NodeError::SensorFault));
+        }
+    }
+
+    #[test]
+    fn parses_probe_map() {
+        let probes = parse_probe_map("0:28-0000075a3c1f, 3 : 28-0316a27948ff").unwrap();
+
+        assert_eq!(probes.len(), 2);
+        assert_eq!(probes[&0], "28-0000075a3c1f");
+        assert_eq!(probes[&3], "28-0316a27948ff");
+
+        assert!(parse_probe_map("28-0000075a3c1f").is_err());
+        assert!(parse_probe_map("x:28-0000075a3c1f").is_err());
+    }
+
+    #[test]
+    fn lists_only_ds18b20_probes_sorted() {
+        let sysfs_root = sysfs_with_probes(&["28-0316a27948ff", "28-0000075a3c1f"]);
+        let devices_dir = sysfs_root.path().join("bus/w1/devices");
+
+        assert_eq!(
+            list_probes(&devices_dir).unwrap(),
+            vec!["28-0000075a3c1f", "28-0316a27948ff"]
+        );
+        assert!(list_probes(&sysfs_root.path().join("missing")).is_err());
+    }
+
+    #[test]
+    fn requires_probe_map() {
+        let sysfs_root = sysfs_with_probes(&["28-0000075a3c1f"]);
+
+        assert!(OneWireNode::new(sysfs_root.path(), None).is_err());
+        assert!(OneWireNode::new(sysfs_root.path(), Some("0:28-0000075a3c1f")).is_ok());
+    }
+
+    #[tokio::test]
+    async fn measures_mapped_probes() {
+        let sysfs_root = sysfs_with_probes(&["28-0000075a3c1f"]);
+        let node = OneWireNode::new(
+            sysfs_root.path(),
+            Some("2:28-0000075a3c1f, 5:28-ffffffffffff"),
+        )
+        .unwrap();
+
+        let temperature = node.measure(SensorTypeEnum::Temperature, 2).await.unwrap();
+        assert_eq!(temperature, 23.125);
+
+        // Mapped, but not on the bus
+        assert!(node.measure(SensorTypeEnum::Temperature, 5).await.is_err());
+        assert!(node.measure(SensorTypeEnum::Temperature, 1).await.is_err());
+        assert!(node.measure(SensorTypeEnum::Humidity, 2).await.is_err());
+
+        let capabilities = node.describe().await.unwrap();
+        assert_eq!(capabilities.sensors.len(), 1);
+        assert_eq!(capabilities.sensors[0].sensor_id, 2);
+    }
+}
@@ -0,0 +1,97 @@
+//! Field parsing shared by the register maps of the `generic_i2c` and
+//! `modbus_rtu` sensors, `<type>,...[,<scale>[,<offset>]]` each.
+
+use crate::meteo::models::SensorTypeEnum;
+
+use std::convert::TryFrom;
+use std::fmt::Display;
+
+/// Splits a register map into its trimmed fields, `required` of them followed
+/// by the optional scale and offset. Fails with `usage` otherwise.
+pub(super) fn split_fields<'a>(
+    s: &'a str,
+    required: usize,
+    usage: &str,
+) -> Result<Vec<&'a str>, String> {
+    let fields = s.split(',').map(str::trim).collect::<Vec<_>>();
+
+    if fields.len() < required || fields.len() > required + 2 {
+        return Err(format!("expected {}", usage));
+    }
+
+    Ok(fields)
+}
+
+/// Parses the sensor type leading a register map, case insensitive.
+pub(super) fn parse_sensor_type(field: &str) -> Result<SensorTypeEnum, String> {
+    SensorTypeEnum::try_from(field.to_lowercase().as_str())
+        .map_err(|_| format!("unknown sensor type '{}'", field))
+}
+
+/// Parses an unsigned number, in decimal or `0x` prefixed hex. `what` names
+/// it in the error.
+pub(super) fn parse_int<T>(field: &str, what: &str) -> Result<T, String>
+where
+    T: TryFrom<u32>,
+    T::Error: Display,
+{
+    match field.strip_prefix("0x") {
+        Some(hex_str) => u32::from_str_radix(hex_str, 16),
+        None => field.parse(),
+    }
+    .map_err(|e| e.to_string())
+    .and_then(|value| T::try_from(value).map_err(|e| e.to_string()))
+    .map_err(|e| format!("invalid {} '{}', {}", what, field, e))
+}
+
+/// Parses the scale and offset following the required fields, 1 and 0 if
+/// left out.
+pub(super) fn parse_scale_and_offset(fields: &[&str]) -> Result<(f32, f32), String> {
+    let parse_f32 = |field: Option<&&str>, default| match field {
+        Some(field) => field
+            .parse::<f32>()
+            .map_err(|e| format!("invalid number '{}', {}", field, e)),
+        None => Ok(default),
+    };
+
+    Ok((
+        parse_f32(fields.first(), 1.0)?,
+        parse_f32(fields.get(1), 0.0)?,
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn splits_required_and_optional_fields() {
+        assert_eq!(
+            split_fields(" a, b ,c", 2, "usage"),
+            Ok(vec!["a", "b", "c"])
+        );
+        assert_eq!(split_fields("a,b,c,d", 2, "usage").unwrap().len(), 4);
+        assert_eq!(
+            split_fields("a", 2, "<x>,<y>"),
+            Err("expected <x>,<y>".to_string())
+        );
+        assert!(split_fields("a,b,c,d,e", 2, "usage").is_err());
+    }
+
+    #[test]
+    fn parses_decimal_and_hex_numbers() {
+        assert_eq!(parse_int::<u8>("0x1F", "byte"), Ok(0x1F));
+        assert_eq!(parse_int::<u16>("300", "register"), Ok(300));
+        assert!(parse_int::<u8>("256", "byte").is_err());
+        assert!(parse_int::<u16>("0xG", "register").is_err());
+        assert!(parse_int::<u16>("-1", "register").is_err());
+    }
+
+    #[test]
+    fn defaults_scale_and_offset() {
+        assert_eq!(parse_scale_and_offset(&[]), Ok((1.0, 0.0)));
+        assert_eq!(parse_scale_and_offset(&["0.1"]), Ok((0.1, 0.0)));
+        assert_eq!(parse_scale_and_offset(&["2", "-40"]), Ok((2.0, -40.0)));
+        assert!(parse_scale_and_offset(&["x"]).is_err());
+    }
+}
@@ -1,20 +1,25 @@
-use std::sync::{Arc, Mutex};
+use crate::comm::serial::CommError;
+use crate::comm::SharedSerialTransport;
 
-use crate::comm;
-use crate::comm::serial::CommChannelTx;
+use super::{reading_error, NodeCapabilities, SensorCapability, SensorNode};
 
-use super::SensorNode;
+use crate::comm::NodeError;
 
 use crate::meteo::models::SensorTypeEnum;
 
 use crate::utils;
 
 use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
 
 use log::{debug, warn};
 
 use anyhow::anyhow;
 
+/// Most readings requested in a single `GET_MULTI` message, to keep frames
+/// within what the node firmware buffers.
+const MAX_BATCH_LEN: usize = 8;
+
 #[allow(clippy::enum_variant_names)]
 #[derive(Debug)]
 pub(super) enum OutgoingMessage {
@@ -22,6 +27,9 @@ pub(super) enum OutgoingMessage {
     GetTemperature(u32),
     GetHumidity(u32),
     GetLightLevel(u32),
+    GetMulti(Vec<(SensorTypeEnum, u32)>),
+    Hello,
+    ListSensors,
 }
 
 impl From<&OutgoingMessage> for String {
@@ -31,6 +39,14 @@ impl From<&OutgoingMessage> for String {
             OutgoingMessage::GetTemperature(ch) => format!("METEO,GET_TEMPERATURE,{}", ch),
             OutgoingMessage::GetHumidity(ch) => format!("METEO,GET_HUMIDITY,{}", ch),
             OutgoingMessage::GetLightLevel(ch) => format!("METEO,GET_LIGHT_LEVEL,{}", ch),
+            OutgoingMessage::GetMulti(requests) => requests.iter().fold(
+                "METEO,GET_MULTI".to_string(),
+                |msg_str, (sensor_type, ch)| {
+                    format!("{},{}:{}", msg_str, sensor_type_token(*sensor_type), ch)
+                },
+            ),
+            OutgoingMessage::Hello => "SYS,HELLO".to_string(),
+            OutgoingMessage::ListSensors => "METEO,LIST_SENSORS".to_string(),
         }
     }
 }
@@ -42,6 +58,82 @@ enum IncomingMessage {
     Humidity(u32, f32),
     LightLevel(u32, f32),
     RetVal(i32),
+    HelloReply(String, u32),
+    SensorList(Vec<SensorCapability>),
+    MultiReply(Vec<(SensorTypeEnum, u32, Result<f32, i32>)>),
+}
+
+/// Sensor type token used in `GET_MULTI` requests and in `SENSOR_LIST` and
+/// `MULTI_REPLY` replies.
+fn sensor_type_token(sensor_type: SensorTypeEnum) -> &'static str {
+    match sensor_type {
+        SensorTypeEnum::Pressure => "PRESSURE",
+        SensorTypeEnum::Temperature => "TEMPERATURE",
+        SensorTypeEnum::Humidity => "HUMIDITY",
+        SensorTypeEnum::LightLevel => "LIGHT_LEVEL",
+        SensorTypeEnum::ColorRed => "COLOR_RED",
+        SensorTypeEnum::ColorGreen => "COLOR_GREEN",
+        SensorTypeEnum::ColorBlue => "COLOR_BLUE",
+        SensorTypeEnum::Illuminance => "ILLUMINANCE",
+        SensorTypeEnum::ColorTemperature => "COLOR_TEMPERATURE",
+        SensorTypeEnum::AccelerationX => "ACCELERATION_X",
+        SensorTypeEnum::AccelerationY => "ACCELERATION_Y",
+        SensorTypeEnum::AccelerationZ => "ACCELERATION_Z",
+        SensorTypeEnum::MagneticFieldX => "MAGNETIC_FIELD_X",
+        SensorTypeEnum::MagneticFieldY => "MAGNETIC_FIELD_Y",
+        SensorTypeEnum::MagneticFieldZ => "MAGNETIC_FIELD_Z",
+        SensorTypeEnum::Heading => "HEADING",
+        SensorTypeEnum::Voltage => "VOLTAGE",
+        SensorTypeEnum::WindSpeed => "WIND_SPEED",
+        SensorTypeEnum::Rainfall => "RAINFALL",
+    }
+}
+
+/// Maps a sensor type token back to its type.
+fn parse_sensor_type_token(token: &str) -> utils::Result<SensorTypeEnum> {
+    match token {
+        "PRESSURE" => Ok(SensorTypeEnum::Pressure),
+        "TEMPERATURE" => Ok(SensorTypeEnum::Temperature),
+        "HUMIDITY" => Ok(SensorTypeEnum::Humidity),
+        "LIGHT_LEVEL" => Ok(SensorTypeEnum::LightLevel),
+        "COLOR_RED" => Ok(SensorTypeEnum::ColorRed),
+        "COLOR_GREEN" => Ok(SensorTypeEnum::ColorGreen),
+        "COLOR_BLUE" => Ok(SensorTypeEnum::ColorBlue),
+        "ILLUMINANCE" => Ok(SensorTypeEnum::Illuminance),
+        "COLOR_TEMPERATURE" => Ok(SensorTypeEnum::ColorTemperature),
+        "ACCELERATION_X" => Ok(SensorTypeEnum::AccelerationX),
+        "ACCELERATION_Y" => Ok(SensorTypeEnum::AccelerationY),
+        "ACCELERATION_Z" => Ok(SensorTypeEnum::AccelerationZ),
+        "MAGNETIC_FIELD_X" => Ok(SensorTypeEnum::MagneticFieldX),
+        "MAGNETIC_FIELD_Y" => Ok(SensorTypeEnum::MagneticFieldY),
+        "MAGNETIC_FIELD_Z" => Ok(SensorTypeEnum::MagneticFieldZ),
+        "HEADING" => Ok(SensorTypeEnum::Heading),
+        "VOLTAGE" => Ok(SensorTypeEnum::Voltage),
+        "WIND_SPEED" => Ok(SensorTypeEnum::WindSpeed),
+        "RAINFALL" => Ok(SensorTypeEnum::Rainfall),
+        _ => Err(anyhow!("Invalid sensor type token '{token}'.").into()),
+    }
+}
+
+/// Parses the messages of the SYS module, shared by all node firmwares.
+fn parse_sys_msg<'a>(mut tokens: impl Iterator<Item = &'a str>) -> utils::Result<IncomingMessage> {
+    match tokens.next() {
+        Some("HELLO_REPLY") => {
+            let firmware_version = tokens.next().ok_or(anyhow!("Missing token."))?;
+            let protocol_version = tokens
+                .next()
+                .ok_or(anyhow!("Missing token."))?
+                .parse()
+                .map_err(|e| anyhow!("Error while parsing token. {e:?}"))?;
+
+            Ok(IncomingMessage::HelloReply(
+                firmware_version.to_string(),
+                protocol_version,
+            ))
+        }
+        Some(_) => Err(anyhow!("Invalid message type.").into()),
+        None => Err(anyhow!("Empty message.").into()),
+    }
 }
 
 impl FromStr for IncomingMessage {
@@ -49,8 +141,10 @@ impl FromStr for IncomingMessage {
 
     fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
         let mut tokens = s.split(',');
-        if tokens.next() != Some("METEO") {
-            return Err(anyhow!("Invalid module token in incoming message.").into());
+        match tokens.next() {
+            Some("METEO") => {}
+            Some("SYS") => return parse_sys_msg(tokens),
+            _ => return Err(anyhow!("Invalid module token in incoming message.").into()),
         }
 
         if let Some(msg_type) = tokens.next() {
@@ -120,6 +214,55 @@ impl FromStr for IncomingMessage {
 
                     Ok(IncomingMessage::RetVal(ret_val))
                 }
+                "SENSOR_LIST" => {
+                    let sensors = tokens
+                        .map(|token| {
+                            let (type_token, id_token) = token
+                                .split_once(':')
+                                .ok_or(anyhow!("Invalid sensor list entry '{token}'."))?;
+
+                            Ok(SensorCapability {
+                                sensor_type: parse_sensor_type_token(type_token)?,
+                                sensor_id: id_token
+                                    .parse()
+                                    .map_err(|e| anyhow!("Error while parsing token. {e:?}"))?,
+                            })
+                        })
+                        .collect::<utils::Result<Vec<_>>>()?;
+
+                    Ok(IncomingMessage::SensorList(sensors))
+                }
+                "MULTI_REPLY" => {
+                    // Entries are either TYPE:ch:value, or TYPE:ch:ERR:ret_val
+                    // for readings the node failed to take.
+                    let entries = tokens
+                        .map(|token| {
+                            let mut entry_tokens = token.splitn(3, ':');
+                            let sensor_type = parse_sensor_type_token(
+                                entry_tokens.next().ok_or(anyhow!("Missing token."))?,
+                            )?;
+                            let ch = entry_tokens
+                                .next()
+                                .ok_or(anyhow!("Missing token."))?
+                                .parse()
+                                .map_err(|e| anyhow!("Error while parsing token. {e:?}"))?;
+                            let val_str = entry_tokens.next().ok_or(anyhow!("Missing token."))?;
+
+                            let val = match val_str.strip_prefix("ERR:") {
+                                Some(ret_val_str) => Err(ret_val_str
+                                    .parse()
+                                    .map_err(|e| anyhow!("Error while parsing token. {e:?}"))?),
+                                None => Ok(val_str
+                                    .parse()
+                                    .map_err(|e| anyhow!("Error while parsing token. {e:?}"))?),
+                            };
+
+                            Ok((sensor_type, ch, val))
+                        })
+                        .collect::<utils::Result<Vec<_>>>()?;
+
+                    Ok(IncomingMessage::MultiReply(entries))
+                }
                 _ => Err(anyhow!("Invalid message type.").into()),
             }
         } else {
@@ -128,45 +271,92 @@ impl FromStr for IncomingMessage {
     }
 }
 
+/// Parses a reading pushed by a node, in the same format as replies to
+/// measurement requests, into its sensor type, sensor ID and value.
+pub(crate) fn parse_reading(payload: &str) -> utils::Result<(SensorTypeEnum, u32, f32)> {
+    match payload.parse()? {
+        IncomingMessage::Pressure(id, val) => Ok((SensorTypeEnum::Pressure, id, val)),
+        IncomingMessage::Temperature(id, val) => Ok((SensorTypeEnum::Temperature, id, val)),
+        IncomingMessage::Humidity(id, val) => Ok((SensorTypeEnum::Humidity, id, val)),
+        IncomingMessage::LightLevel(id, val) => Ok((SensorTypeEnum::LightLevel, id, val)),
+        msg => Err(anyhow!("Not a reading: {:?}", msg).into()),
+    }
+}
+
+/// Picks the readings of `chunk` out of a `MULTI_REPLY`, in request order.
+fn chunk_results(
+    chunk: &[(SensorTypeEnum, u32)],
+    entries: &[(SensorTypeEnum, u32, Result<f32, i32>)],
+) -> Vec<utils::Result<f32>> {
+    chunk
+        .iter()
+        .map(|&(sensor_type, sensor_id)| {
+            match entries
+                .iter()
+                .find(|(t, id, _)| *t == sensor_type && *id == sensor_id)
+            {
+                Some((_, _, Ok(val))) => Ok(*val),
+                Some((_, _, Err(ret_val))) => Err(reading_error(
+                    NodeError::from_ret_val(*ret_val),
+                    sensor_type,
+                    sensor_id,
+                )),
+                None => Err(anyhow!(
+                    "No reading for {} sensor {sensor_id} in reply.",
+                    sensor_type.as_ref()
+                )
+                .into()),
+            }
+        })
+        .collect()
+}
+
 pub struct SerialNode {
     node_public_id: u32,
-    comm_channel: Arc<Mutex<CommChannelTx>>,
+    comm_channel: SharedSerialTransport,
+    /// Set once the node turned out to predate `GET_MULTI`, batches are read
+    /// one sensor at a time from then on.
+    multi_unsupported: AtomicBool,
 }
 
 impl SerialNode {
-    pub fn new(node_public_id: u32, serial_comm_path_id: u32) -> utils::Result<SerialNode> {
-        Ok(SerialNode {
+    pub fn new(node_public_id: u32, comm_channel: SharedSerialTransport) -> SerialNode {
+        SerialNode {
             node_public_id,
-            comm_channel: comm::get_serial_comm_path(serial_comm_path_id)?,
-        })
+            comm_channel,
+            multi_unsupported: AtomicBool::new(false),
+        }
     }
 
-    fn transfer(&self, msg: OutgoingMessage) -> utils::Result<IncomingMessage> {
+    /// Message reading a single sensor, only the oldest sensor types have one.
+    fn single_message(measurement_type: SensorTypeEnum, sensor_id: u32) -> Option<OutgoingMessage> {
+        match measurement_type {
+            SensorTypeEnum::Pressure => Some(OutgoingMessage::GetPressure(sensor_id)),
+            SensorTypeEnum::Temperature => Some(OutgoingMessage::GetTemperature(sensor_id)),
+            SensorTypeEnum::Humidity => Some(OutgoingMessage::GetHumidity(sensor_id)),
+            SensorTypeEnum::LightLevel => Some(OutgoingMessage::GetLightLevel(sensor_id)),
+            _ => None,
+        }
+    }
+
+    async fn transfer(&self, msg: OutgoingMessage) -> utils::Result<IncomingMessage> {
         let msg_str = (&msg).into();
         debug!("Sending: {}", msg_str);
 
-        let raw_response_msg = self
-            .comm_channel
-            .lock()
-            .expect("mutex poisoned")
-            .send(self.node_public_id, msg_str)?;
+        let raw_response_msg = self.comm_channel.send(self.node_public_id, msg_str).await?;
 
         debug!("Response: {}", raw_response_msg);
 
         raw_response_msg.parse()
     }
-}
 
-impl SensorNode for SerialNode {
-    fn measure(&self, measurement_type: SensorTypeEnum, sensor_id: u32) -> utils::Result<f32> {
-        let outgoing_msg = match measurement_type {
-            SensorTypeEnum::Pressure => OutgoingMessage::GetPressure(sensor_id),
-            SensorTypeEnum::Temperature => OutgoingMessage::GetTemperature(sensor_id),
-            SensorTypeEnum::Humidity => OutgoingMessage::GetHumidity(sensor_id),
-            SensorTypeEnum::LightLevel => OutgoingMessage::GetLightLevel(sensor_id),
-        };
-
-        match self.transfer(outgoing_msg) {
+    async fn measure_single(
+        &self,
+        outgoing_msg: OutgoingMessage,
+        measurement_type: SensorTypeEnum,
+        sensor_id: u32,
+    ) -> utils::Result<f32> {
+        match self.transfer(outgoing_msg).await {
             Ok(IncomingMessage::Pressure(id, val))
                 if id == sensor_id && measurement_type == SensorTypeEnum::Pressure =>
             {
@@ -187,14 +377,164 @@ impl SensorNode for SerialNode {
             {
                 Ok(val)
             }
+            Ok(IncomingMessage::RetVal(ret_val)) if ret_val < 0 => Err(reading_error(
+                NodeError::from_ret_val(ret_val),
+                measurement_type,
+                sensor_id,
+            )),
             Ok(msg) => {
                 warn!("Unexpected reply message: {:?}", msg);
                 Err(anyhow::anyhow!("Unexpected reply message: {:?}", msg).into())
             }
             Err(e) => {
+                // Passed through as-is, so link down and timeout errors from
+                // the comm thread stay recognizable.
                 warn!("Communication error: {:?}", e);
-                Err(anyhow::anyhow!("Communication error: {:?}", e).into())
+                Err(e)
+            }
+        }
+    }
+
+    /// Reads `requests` one message each, for nodes without `GET_MULTI`.
+    /// Sensor types only readable through `GET_MULTI` are unsupported there.
+    /// Fails as a whole if the node doesn't answer before the first reading.
+    async fn measure_each(
+        &self,
+        requests: &[(SensorTypeEnum, u32)],
+    ) -> utils::Result<Vec<utils::Result<f32>>> {
+        let mut results = Vec::with_capacity(requests.len());
+        let mut answered = false;
+
+        for &(measurement_type, sensor_id) in requests {
+            let outgoing_msg = match Self::single_message(measurement_type, sensor_id) {
+                Some(outgoing_msg) => outgoing_msg,
+                None => {
+                    results.push(Err(reading_error(
+                        NodeError::UnsupportedType,
+                        measurement_type,
+                        sensor_id,
+                    )));
+                    continue;
+                }
+            };
+
+            match self
+                .measure_single(outgoing_msg, measurement_type, sensor_id)
+                .await
+            {
+                Err(e) if !answered && e.downcast_ref::<CommError>().is_some() => return Err(e),
+                result => {
+                    answered = true;
+                    results.push(result);
+                }
+            }
+        }
+
+        Ok(results)
+    }
+}
+
+#[rocket::async_trait]
+impl SensorNode for SerialNode {
+    async fn measure(
+        &self,
+        measurement_type: SensorTypeEnum,
+        sensor_id: u32,
+    ) -> utils::Result<f32> {
+        match Self::single_message(measurement_type, sensor_id) {
+            Some(outgoing_msg) => {
+                self.measure_single(outgoing_msg, measurement_type, sensor_id)
+                    .await
+            }
+            // Newer sensor types have no message of their own, only a token
+            // usable in GET_MULTI.
+            None => self
+                .measure_batch(&[(measurement_type, sensor_id)])
+                .await?
+                .remove(0),
+        }
+    }
+
+    async fn measure_batch(
+        &self,
+        requests: &[(SensorTypeEnum, u32)],
+    ) -> utils::Result<Vec<utils::Result<f32>>> {
+        if self.multi_unsupported.load(Ordering::Relaxed) {
+            return self.measure_each(requests).await;
+        }
+
+        let mut results = Vec::with_capacity(requests.len());
+
+        for (chunk_index, chunk) in requests.chunks(MAX_BATCH_LEN).enumerate() {
+            let multi_err = match self
+                .transfer(OutgoingMessage::GetMulti(chunk.to_vec()))
+                .await
+            {
+                Ok(IncomingMessage::MultiReply(entries)) => {
+                    results.extend(chunk_results(chunk, &entries));
+                    continue;
+                }
+                Ok(msg) => anyhow!("Unexpected reply message: {:?}", msg).into(),
+                Err(e) => match e.downcast_ref::<CommError>() {
+                    // Old firmware may not answer unknown messages at all
+                    Some(CommError::Timeout) | None => e,
+                    Some(_) => {
+                        warn!("Communication error: {:?}", e);
+                        return Err(e);
+                    }
+                },
+            };
+
+            // Only remembered once the node answers the single messages, a
+            // node that is just down would otherwise lose GET_MULTI for good.
+            match self
+                .measure_each(&requests[chunk_index * MAX_BATCH_LEN..])
+                .await
+            {
+                Ok(rest) => {
+                    warn!(
+                        "Node {} doesn't support GET_MULTI ({}), reading sensors one by one.",
+                        self.node_public_id,
+                        multi_err.message()
+                    );
+                    self.multi_unsupported.store(true, Ordering::Relaxed);
+                    results.extend(rest);
+                    return Ok(results);
+                }
+                Err(e) => {
+                    warn!("Communication error: {:?}", e);
+                    return Err(e);
+                }
             }
         }
+
+        Ok(results)
+    }
+
+    async fn describe(&self) -> utils::Result<NodeCapabilities> {
+        let (firmware_version, protocol_version) =
+            match self.transfer(OutgoingMessage::Hello).await? {
+                IncomingMessage::HelloReply(firmware_version, protocol_version) => {
+                    (firmware_version, protocol_version)
+                }
+                msg => {
+                    warn!("Unexpected reply message: {:?}", msg);
+                    return Err(anyhow!("Unexpected reply message: {:?}", msg).into());
+                }
+            };
+
+        let sensors = match self.transfer(OutgoingMessage::ListSensors).await? {
+            IncomingMessage::SensorList(sensors) => sensors,
+            msg => {
+                warn!("Unexpected reply message: {:?}", msg);
+                return Err(anyhow!("Unexpected reply message: {:?}", msg).into());
+            }
+        };
+
+        Ok(NodeCapabilities {
+            firmware_version: Some(firmware_version),
+            protocol_version: Some(protocol_version),
+            sensors,
+        })
     }
 }
@@ -0,0 +1,324 @@
+use super::{reading_error, NodeCapabilities, SensorCapability, SensorNode};
+
+use crate::comm::i2c::{self, Message};
+use crate::comm::{NodeError, SharedI2cTransport};
+
+use crate::meteo::models::SensorTypeEnum;
+
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+
+use crate::utils::Result;
+use anyhow::anyhow;
+
+use log::debug;
+
+/// How the SHT3x takes its measurements.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Mode {
+    /// A measurement started for every reading.
+    SingleShot,
+    /// The sensor measures once a second on its own, readings fetch the
+    /// latest result.
+    Periodic,
+}
+
+impl FromStr for Mode {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "single_shot" => Ok(Mode::SingleShot),
+            "periodic" => Ok(Mode::Periodic),
+            _ => Err("expected single_shot or periodic".to_string()),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Reading {
+    temperature: f32,
+    humidity: f32,
+}
+
+/// Sensirion SHT30/SHT31/SHT35 humidity and temperature sensor, the `sht3x`
+/// route type.
+pub struct Sht3xNode {
+    comm_path: SharedI2cTransport,
+    addr: u16,
+    mode: Mode,
+    /// Held while measuring, so commands of concurrent readings don't mix.
+    /// Keeps the last periodic mode reading with when it was fetched.
+    last_reading: tokio::sync::Mutex<Option<(Instant, Reading)>>,
+}
+
+impl Sht3xNode {
+    pub const I2C_ADDR_DEFAULT: u16 = 0x44;
+
+    const CMD_BREAK: u16 = 0x3093;
+    const CMD_SOFT_RESET: u16 = 0x30a2;
+    const CMD_READ_STATUS: u16 = 0xf32d;
+    /// High repeatability, without clock stretching.
+    const CMD_SINGLE_SHOT: u16 = 0x2400;
+    /// High repeatability, 1 measurement per second.
+    const CMD_PERIODIC_1MPS: u16 = 0x2130;
+    const CMD_FETCH_DATA: u16 = 0xe000;
+
+    const CRC_INIT: u8 = 0xff;
+
+    /// Longest high repeatability measurement.
+    const MEASUREMENT_TIME: Duration = Duration::from_millis(16);
+    const RESET_TIME: Duration = Duration::from_millis(2);
+
+    /// In periodic mode, fetching again before the next measurement is done
+    /// fails, so the last reading is reused if it's no older than this.
+    const PERIODIC_READING_MAX_AGE: Duration = Duration::from_millis(1500);
+
+    pub fn new(comm_path: SharedI2cTransport, addr: u16, mode: Mode) -> Result<Sht3xNode> {
+        let node = Sht3xNode {
+            comm_path,
+            addr,
+            mode,
+            last_reading: tokio::sync::Mutex::new(None),
+        };
+
+        // A sensor still in periodic mode ignores everything but a break.
+        // Missing sensors show up on the reset, so its result doesn't matter.
+        debug!("Resetting SHT3x");
+        let _ = node.write_cmd(Self::CMD_BREAK);
+        std::thread::sleep(Self::RESET_TIME);
+        node.write_cmd(Self::CMD_SOFT_RESET)?;
+        std::thread::sleep(Self::RESET_TIME);
+
+        // Check we have the correct sensor
+        let mut status_data = [0; 3];
+        node.write_cmd(Self::CMD_READ_STATUS)?;
+        node.transfer(&mut [Message::read(addr, &mut status_data)])?;
+        let status = Self::check_crc(&status_data)
+            .map_err(|e| anyhow!("No SHT3x at address 0x{:X}. {e}", addr))?;
+
+        debug!("SHT3x status is 0x{:04X}", status);
+
+        if mode == Mode::Periodic {
+            node.write_cmd(Self::CMD_PERIODIC_1MPS)?;
+        }
+
+        Ok(node)
+    }
+
+    fn transfer(&self, msgs: &mut [Message]) -> Result<()> {
+        self.comm_path
+            .lock()
+            .expect("Mutex poisoned.")
+            .transfer(msgs)
+    }
+
+    fn write_cmd(&self, cmd: u16) -> Result<()> {
+        self.transfer(&mut [Message::write(self.addr, &cmd.to_be_bytes())])
+    }
+
+    /// Checks the CRC following a 16-bit word.
+    fn check_crc(data: &[u8]) -> std::result::Result<u16, String> {
+        let calc_crc = i2c::crc8(&data[..2], Self::CRC_INIT);
+
+        if calc_crc != data[2] {
+            return Err(format!(
+                "CRC mismatch on {:02X?}, expecting 0x{:02X}.",
+                data, calc_crc
+            ));
+        }
+
+        Ok(u16::from_be_bytes([data[0], data[1]]))
+    }
+
+    fn read_result(&self) -> Result<Reading> {
+        let mut data = [0; 6];
+        self.transfer(&mut [Message::read(self.addr, &mut data)])?;
+
+        // A corrupted result is down to the sensor (or its wiring), not the bus
+        let sensor_fault =
+            |e| anyhow::Error::new(NodeError::SensorFault).context(format!("SHT3x {e}"));
+
+        let raw_temp = Self::check_crc(&data[..3]).map_err(sensor_fault)?;
+        let raw_hum = Self::check_crc(&data[3..]).map_err(sensor_fault)?;
+
+        let reading = Reading {
+            temperature: -45.0 + 175.0 * raw_temp as f32 / 65535.0,
+            humidity: 100.0 * raw_hum as f32 / 65535.0,
+        };
+
+        debug!("SHT3x reading: {:?}", reading);
+
+        Ok(reading)
+    }
+
+    async fn query(&self) -> Result<Reading> {
+        let mut last_reading = self.last_reading.lock().await;
+
+        let reading = match self.mode {
+            Mode::SingleShot => {
+                self.write_cmd(Self::CMD_SINGLE_SHOT)?;
+                tokio::time::sleep(Self::MEASUREMENT_TIME).await;
+                self.read_result()?
+            }
+            Mode::Periodic => {
+                let fetched = self
+                    .write_cmd(Self::CMD_FETCH_DATA)
+                    .and_then(|_| self.read_result());
+
+                match (fetched, *last_reading) {
+                    (Ok(reading), _) => reading,
+                    (Err(_), Some((fetched_at, reading)))
+                        if fetched_at.elapsed() < Self::PERIODIC_READING_MAX_AGE =>
+                    {
+                        return Ok(reading);
+                    }
+                    (Err(e), _) => return Err(e),
+                }
+            }
+        };
+
+        *last_reading = Some((Instant::now(), reading));
+
+        Ok(reading)
+    }
+}
+
+#[rocket::async_trait]
+impl SensorNode for Sht3xNode {
+    async fn measure(&self, measurement_type: SensorTypeEnum, sensor_id: u32) -> Result<f32> {
+        Ok(self
+            .measure_batch(&[(measurement_type, sensor_id)])
+            .await?
+            .remove(0)?)
+    }
+
+    async fn measure_batch(&self, requests: &[(SensorTypeEnum, u32)]) -> Result<Vec<Result<f32>>> {
+        // Both values come from the same measurement.
+        let reading = self.query().await?;
+
+        Ok(requests
+            .iter()
+            .map(|&(measurement_type, sensor_id)| match measurement_type {
+                _ if sensor_id != 0 => Err(reading_error(
+                    NodeError::UnknownChannel,
+                    measurement_type,
+                    sensor_id,
+                )),
+                SensorTypeEnum::Temperature => Ok(reading.temperature),
+                SensorTypeEnum::Humidity => Ok(reading.humidity),
+                _ => Err(reading_error(
+                    NodeError::UnsupportedType,
+                    measurement_type,
+                    sensor_id,
+                )),
+            })
+            .collect())
+    }
+
+    async fn describe(&self) -> Result<NodeCapabilities> {
+        Ok(NodeCapabilities {
+            firmware_version: None,
+            protocol_version: None,
+            sensors: [SensorTypeEnum::Temperature, SensorTypeEnum::Humidity]
+                .iter()
+                .map(|&sensor_type| SensorCapability {
+                    sensor_type,
+                    sensor_id: 0,
+                })
+                .collect(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use super::super::tests::{assert_close, node_error};
+
+    use crate::comm::mock::MockI2cBus;
+
+    use std::sync::{Arc, Mutex};
+
+    /// Temperature and humidity words of a result, each with its CRC.
+    fn result_bytes(raw_temp: u16, raw_hum: u16) -> Vec<u8> {
+        [raw_temp, raw_hum]
+            .iter()
+            .flat_map(|raw| {
+                let bytes = raw.to_be_bytes();
+                [bytes[0], bytes[1], i2c::crc8(&bytes, Sht3xNode::CRC_INIT)]
+            })
+            .collect()
+    }
+
+    /// The mock takes the first byte of a command as the register address,
+    /// so results are read from right after it, e.g. 0xe1 for a fetch
+    /// (0xe000).
+    fn sht3x_bus(result_reg: u8, result: &[u8]) -> Arc<Mutex<MockI2cBus>> {
+        let mut bus = MockI2cBus::new().with_device(0x44, 0xff);
+        // Status 0x0000, after the read status command (0xf32d)
+        bus.set_registers(0x44, 0xf4, &[0x00, 0x00, 0x81]);
+        bus.set_registers(0x44, result_reg, result);
+
+        Arc::new(Mutex::new(bus))
+    }
+
+    #[test]
+    fn checks_crc_of_datasheet_example() {
+        assert_eq!(Sht3xNode::check_crc(&[0xbe, 0xef, 0x92]), Ok(0xbeef));
+        assert!(Sht3xNode::check_crc(&[0xbe, 0xef, 0x93]).is_err());
+    }
+
+    #[tokio::test]
+    async fn converts_single_shot_reading() {
+        // After the single shot command (0x2400)
+        let bus = sht3x_bus(0x25, &result_bytes(0x6666, 0x8000));
+        let node = Sht3xNode::new(bus.clone(), 0x44, Mode::SingleShot).unwrap();
+
+        let values = node
+            .measure_batch(&[
+                (SensorTypeEnum::Temperature, 0),
+                (SensorTypeEnum::Humidity, 0),
+            ])
+            .await
+            .unwrap();
+
+        assert_close(*values[0].as_ref().unwrap(), 25.0);
+        assert_close(*values[1].as_ref().unwrap(), 50.0);
+
+        // Humidity CRC off
+        bus.lock().unwrap().set_registers(0x44, 0x2a, &[0x00]);
+
+        let err = node
+            .measure(SensorTypeEnum::Temperature, 0)
+            .await
+            .unwrap_err();
+        assert_eq!(node_error(&err), Some(NodeError::SensorFault));
+    }
+
+    #[tokio::test]
+    async fn reuses_last_periodic_reading_until_the_next_one() {
+        let bus = sht3x_bus(0xe1, &result_bytes(0x6666, 0x8000));
+        let node = Sht3xNode::new(bus.clone(), 0x44, Mode::Periodic).unwrap();
+
+        assert_close(
+            node.measure(SensorTypeEnum::Temperature, 0).await.unwrap(),
+            25.0,
+        );
+
+        // Nothing new to fetch, which the mock can only fake by a bad CRC
+        let mut stale = result_bytes(0x7000, 0x9000);
+        stale[2] ^= 0xff;
+        bus.lock().unwrap().set_registers(0x44, 0xe1, &stale);
+
+        assert_close(
+            node.measure(SensorTypeEnum::Temperature, 0).await.unwrap(),
+            25.0,
+        );
+        assert_close(
+            node.measure(SensorTypeEnum::Humidity, 0).await.unwrap(),
+            50.0,
+        );
+    }
+}
@@ -0,0 +1,513 @@
+use super::{reading_error, NodeCapabilities, SensorCapability, SensorNode};
+
+use crate::comm::NodeError;
+
+use crate::meteo::models::SensorTypeEnum;
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::utils::Result;
+use anyhow::anyhow;
+
+use log::info;
+
+/// Standard gravity, IIO reports acceleration in m/s².
+const STANDARD_GRAVITY: f32 = 9.80665;
+
+/// Kernel subsystem a `SysfsNode` reads its sensors from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Subsystem {
+    /// Industrial I/O, the `iio` route type.
+    Iio,
+    /// Hardware monitoring, the `hwmon` route type.
+    Hwmon,
+}
+
+impl Subsystem {
+    fn devices_dir(&self, sysfs_root: &Path) -> PathBuf {
+        match self {
+            Subsystem::Iio => sysfs_root.join("bus/iio/devices"),
+            Subsystem::Hwmon => sysfs_root.join("class/hwmon"),
+        }
+    }
+
+    /// Channel name prefixes, the sensor type they map to and the factor
+    /// from the kernel's unit to ours.
+    fn channel_types(&self) -> &'static [(&'static str, SensorTypeEnum, f32)] {
+        match self {
+            Subsystem::Iio => &[
+                ("in_temp", SensorTypeEnum::Temperature, 0.001),
+                ("in_pressure", SensorTypeEnum::Pressure, 1000.0),
+                ("in_humidityrelative", SensorTypeEnum::Humidity, 0.001),
+                ("in_illuminance", SensorTypeEnum::Illuminance, 1.0),
+                ("in_voltage", SensorTypeEnum::Voltage, 0.001),
+                (
+                    "in_accel_x",
+                    SensorTypeEnum::AccelerationX,
+                    1.0 / STANDARD_GRAVITY,
+                ),
+                (
+                    "in_accel_y",
+                    SensorTypeEnum::AccelerationY,
+                    1.0 / STANDARD_GRAVITY,
+                ),
+                (
+                    "in_accel_z",
+                    SensorTypeEnum::AccelerationZ,
+                    1.0 / STANDARD_GRAVITY,
+                ),
+                ("in_magn_x", SensorTypeEnum::MagneticFieldX, 1.0),
+                ("in_magn_y", SensorTypeEnum::MagneticFieldY, 1.0),
+                ("in_magn_z", SensorTypeEnum::MagneticFieldZ, 1.0),
+            ],
+            Subsystem::Hwmon => &[
+                ("temp", SensorTypeEnum::Temperature, 0.001),
+                ("humidity", SensorTypeEnum::Humidity, 0.001),
+                ("in", SensorTypeEnum::Voltage, 0.001),
+            ],
+        }
+    }
+
+    /// Sensor type of channel `name` (like `in_voltage0` or `temp1`) and the
+    /// factor converting its values.
+    fn channel_type(&self, name: &str) -> Option<(SensorTypeEnum, f32)> {
+        self.channel_types()
+            .iter()
+            .find(|(prefix, _, _)| match name.strip_prefix(prefix) {
+                Some(rest) => match self {
+                    // Index and/or modifier, like in_temp_ambient
+                    Subsystem::Iio => {
+                        rest.is_empty()
+                            || rest.starts_with(|c: char| c.is_ascii_digit() || c == '_')
+                    }
+                    Subsystem::Hwmon => {
+                        !rest.is_empty() && rest.chars().all(|c| c.is_ascii_digit())
+                    }
+                },
+                None => false,
+            })
+            .map(|&(_, sensor_type, factor)| (sensor_type, factor))
+    }
+}
+
+#[derive(Clone)]
+struct Channel {
+    name: String,
+    sensor_type: SensorTypeEnum,
+    factor: f32,
+}
+
+/// Sensors exposed by a kernel driver through sysfs, IIO channels with their
+/// scale and offset applied, or hwmon inputs.
+pub struct SysfsNode {
+    subsystem: Subsystem,
+    device_dir: PathBuf,
+    channels: BTreeMap<u32, Channel>,
+}
+
+impl SysfsNode {
+    /// Sets up the device given by `route_param`, `<device>[,<sensor_id>:<channel>...]`.
+    /// The device is either its sysfs directory (like `iio:device0`) or the
+    /// contents of its `name` file. Without sensor IDs, all channels of known
+    /// types are numbered from 0 in name order.
+    pub fn new(subsystem: Subsystem, sysfs_root: &Path, route_param: &str) -> Result<SysfsNode> {
+        let mut params = route_param.split(',').map(str::trim);
+
+        let device = params
+            .next()
+            .filter(|device| !device.is_empty())
+            .ok_or_else(|| anyhow!("Missing device name."))?;
+
+        let device_dir = find_device(&subsystem.devices_dir(sysfs_root), device)?;
+
+        let channel_names = params
+            .map(|pair| {
+                let (sensor_id_str, name) = pair
+                    .split_once(':')
+                    .ok_or_else(|| anyhow!("Expected <sensor_id>:<channel>, got '{pair}'."))?;
+
+                let sensor_id = sensor_id_str
+                    .trim()
+                    .parse::<u32>()
+                    .map_err(|e| anyhow!("Invalid sensor ID in '{pair}'. {e:?}"))?;
+
+                Ok((sensor_id, name.trim().to_string()))
+            })
+            .collect::<Result<BTreeMap<_, _>>>()?;
+
+        let channel_names = if channel_names.is_empty() {
+            let channel_names = (0..)
+                .zip(list_channels(subsystem, &device_dir)?)
+                .collect::<BTreeMap<_, _>>();
+
+            info!(
+                "Numbered channels of {} by name: {:?}",
+                device_dir.display(),
+                channel_names
+            );
+
+            channel_names
+        } else {
+            channel_names
+        };
+
+        let channels = channel_names
+            .into_iter()
+            .map(|(sensor_id, name)| {
+                let (sensor_type, factor) = subsystem
+                    .channel_type(&name)
+                    .ok_or_else(|| anyhow!("Unsupported {subsystem:?} channel '{name}'."))?;
+
+                Ok((
+                    sensor_id,
+                    Channel {
+                        name,
+                        sensor_type,
+                        factor,
+                    },
+                ))
+            })
+            .collect::<Result<_>>()?;
+
+        Ok(SysfsNode {
+            subsystem,
+            device_dir,
+            channels,
+        })
+    }
+}
+
+/// Looks up `device` in `devices_dir` by directory or driver name.
+fn find_device(devices_dir: &Path, device: &str) -> Result<PathBuf> {
+    let entries = fs::read_dir(devices_dir)
+        .map_err(|e| anyhow!("Error listing devices in {}. {e:?}", devices_dir.display()))?;
+
+    entries
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.path())
+        .find(|path| {
+            path.file_name().is_some_and(|dir_name| dir_name == device)
+                || fs::read_to_string(path.join("name")).is_ok_and(|name| name.trim() == device)
+        })
+        .ok_or_else(|| anyhow!("No device '{device}' in {}.", devices_dir.display()).into())
+}
+
+/// Names of the channels of known types in `device_dir`, sorted.
+fn list_channels(subsystem: Subsystem, device_dir: &Path) -> Result<Vec<String>> {
+    let entries = fs::read_dir(device_dir)
+        .map_err(|e| anyhow!("Error listing channels in {}. {e:?}", device_dir.display()))?;
+
+    let mut channels = entries
+        .filter_map(|entry| entry.ok())
+        .filter_map(|entry| entry.file_name().into_string().ok())
+        .filter_map(|file_name| {
+            file_name
+                .strip_suffix("_input")
+                .or_else(|| match subsystem {
+                    Subsystem::Iio => file_name.strip_suffix("_raw"),
+                    Subsystem::Hwmon => None,
+                })
+                .map(str::to_string)
+        })
+        .filter(|name| subsystem.channel_type(name).is_some())
+        .collect::<Vec<_>>();
+
+    channels.sort();
+    channels.dedup();
+
+    Ok(channels)
+}
+
+fn read_attr(device_dir: &Path, attr: &str) -> Result<f32> {
+    let attr_path = device_dir.join(attr);
+
+    let value_str = fs::read_to_string(&attr_path)
+        .map_err(|e| anyhow!("Error reading {}. {e:?}", attr_path.display()))?;
+
+    value_str.trim().parse::<f32>().map_err(|e| {
+        anyhow!(
+            "Invalid value '{}' in {}. {e:?}",
+            value_str.trim(),
+            attr_path.display()
+        )
+        .into()
+    })
+}
+
+/// Reads the first of the `_<suffix>` attributes of `channel` present, from
+/// the most specific to the ones shared by all channels of its type (like
+/// `in_voltage0_scale`, then `in_voltage_scale`).
+fn read_shared_attr(device_dir: &Path, channel: &str, suffix: &str) -> Result<Option<f32>> {
+    let mut base = channel.to_string();
+
+    loop {
+        let attr = format!("{base}_{suffix}");
+
+        if device_dir.join(&attr).exists() {
+            return read_attr(device_dir, &attr).map(Some);
+        }
+
+        let without_index = base.trim_end_matches(|c: char| c.is_ascii_digit());
+
+        if without_index != base {
+            base = without_index.to_string();
+            continue;
+        }
+
+        // Down to in_<type>
+        match base.rfind('_') {
+            Some(pos) if base[..pos].contains('_') => base.truncate(pos),
+            _ => return Ok(None),
+        }
+    }
+}
+
+fn read_channel(subsystem: Subsystem, device_dir: &Path, channel: &Channel) -> Result<f32> {
+    let input_attr = format!("{}_input", channel.name);
+
+    // Processed values are already in the subsystem's units
+    let value = if subsystem == Subsystem::Hwmon || device_dir.join(&input_attr).exists() {
+        read_attr(device_dir, &input_attr)?
+    } else {
+        let raw = read_attr(device_dir, &format!("{}_raw", channel.name))?;
+        let offset = read_shared_attr(device_dir, &channel.name, "offset")?.unwrap_or(0.0);
+        let scale = read_shared_attr(device_dir, &channel.name, "scale")?.unwrap_or(1.0);
+
+        (raw + offset) * scale
+    };
+
+    Ok(value * channel.factor)
+}
+
+#[rocket::async_trait]
+impl SensorNode for SysfsNode {
+    async fn measure(&self, measurement_type: SensorTypeEnum, sensor_id: u32) -> Result<f32> {
+        Ok(self
+            .measure_batch(&[(measurement_type, sensor_id)])
+            .await?
+            .remove(0)?)
+    }
+
+    async fn measure_batch(&self, requests: &[(SensorTypeEnum, u32)]) -> Result<Vec<Result<f32>>> {
+        let mut to_read = Vec::with_capacity(requests.len());
+
+        for &(measurement_type, sensor_id) in requests {
+            let channel = match self.channels.get(&sensor_id) {
+                Some(channel) if channel.sensor_type == measurement_type => Ok(channel.clone()),
+                Some(_) => Err(reading_error(
+                    NodeError::UnsupportedType,
+                    measurement_type,
+                    sensor_id,
+                )),
+                None => Err(reading_error(
+                    NodeError::UnknownChannel,
+                    measurement_type,
+                    sensor_id,
+                )),
+            };
+
+            to_read.push(channel);
+        }
+
+        // Some drivers convert on reading, which can take a while
+        let subsystem = self.subsystem;
+        let device_dir = self.device_dir.clone();
+
+        tokio::task::spawn_blocking(move || {
+            to_read
+                .into_iter()
+                .map(|channel| read_channel(subsystem, &device_dir, &channel?))
+                .collect()
+        })
+        .await
+        .map_err(|e| anyhow!("{subsystem:?} read failed. {e:?}").into())
+    }
+
+    async fn describe(&self) -> Result<NodeCapabilities> {
+        Ok(NodeCapabilities {
+            firmware_version: None,
+            protocol_version: None,
+            sensors: self
+                .channels
+                .iter()
+                .map(|(&sensor_id, channel)| SensorCapability {
+                    sensor_type: channel.sensor_type,
+                    sensor_id,
+                })
+                .collect(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_close(val: f32, expected: f32) {
+        assert!((val - expected).abs() < 1e-4, "{} is not {}", val, expected);
+    }
+
+    /// Fake sysfs root with an IIO ADC and a BME280, and a hwmon chip.
+    fn sysfs_fixture() -> tempfile::TempDir {
+        let sysfs_root = tempfile::tempdir().unwrap();
+
+        let write_attrs = |device_dir: &str, attrs: &[(&str, &str)]| {
+            let device_dir = sysfs_root.path().join(device_dir);
+            fs::create_dir_all(&device_dir).unwrap();
+
+            for (attr, value) in attrs {
+                fs::write(device_dir.join(attr), format!("{}\n", value)).unwrap();
+            }
+        };
+
+        write_attrs(
+            "bus/iio/devices/iio:device0",
+            &[
+                ("name", "ads1015"),
+                ("in_voltage0_raw", "1000"),
+                ("in_voltage0_scale", "2"),
+                ("in_voltage1_raw", "500"),
+                ("in_voltage_scale", "3"),
+                ("in_voltage_offset", "-100"),
+                ("sampling_frequency", "1600"),
+            ],
+        );
+        write_attrs(
+            "bus/iio/devices/iio:device1",
+            &[
+                ("name", "bme280"),
+                ("in_temp_input", "21340"),
+                ("in_pressure_input", "101.325"),
+                ("in_humidityrelative_input", "45123"),
+            ],
+        );
+        write_attrs(
+            "class/hwmon/hwmon0",
+            &[
+                ("name", "cpu_thermal"),
+                ("temp1_input", "48250"),
+                ("temp1_crit", "110000"),
+            ],
+        );
+
+        sysfs_root
+    }
+
+    #[test]
+    fn maps_channel_names_to_types() {
+        let iio = Subsystem::Iio;
+        assert_eq!(
+            iio.channel_type("in_voltage0").map(|(t, _)| t),
+            Some(SensorTypeEnum::Voltage)
+        );
+        assert_eq!(
+            iio.channel_type("in_temp").map(|(t, _)| t),
+            Some(SensorTypeEnum::Temperature)
+        );
+        assert_eq!(
+            iio.channel_type("in_temp_ambient").map(|(t, _)| t),
+            Some(SensorTypeEnum::Temperature)
+        );
+        assert_eq!(
+            iio.channel_type("in_accel_x").map(|(t, _)| t),
+            Some(SensorTypeEnum::AccelerationX)
+        );
+        assert_close(iio.channel_type("in_pressure").unwrap().1, 1000.0);
+        assert_eq!(iio.channel_type("in_temperature"), None);
+        assert_eq!(iio.channel_type("in_current0"), None);
+
+        let hwmon = Subsystem::Hwmon;
+        assert_eq!(
+            hwmon.channel_type("temp1").map(|(t, _)| t),
+            Some(SensorTypeEnum::Temperature)
+        );
+        assert_eq!(
+            hwmon.channel_type("in0").map(|(t, _)| t),
+            Some(SensorTypeEnum::Voltage)
+        );
+        assert_eq!(hwmon.channel_type("temp"), None);
+        assert_eq!(hwmon.channel_type("temp1_crit"), None);
+        assert_eq!(hwmon.channel_type("intrusion0"), None);
+    }
+
+    #[test]
+    fn falls_back_to_shared_attrs() {
+        let sysfs_root = sysfs_fixture();
+        let device_dir = sysfs_root.path().join("bus/iio/devices/iio:device0");
+
+        // Own scale first, then the one of all voltage channels
+        assert_eq!(
+            read_shared_attr(&device_dir, "in_voltage0", "scale").unwrap(),
+            Some(2.0)
+        );
+        assert_eq!(
+            read_shared_attr(&device_dir, "in_voltage1", "scale").unwrap(),
+            Some(3.0)
+        );
+        assert_eq!(
+            read_shared_attr(&device_dir, "in_voltage1", "offset").unwrap(),
+            Some(-100.0)
+        );
+        assert_eq!(
+            read_shared_attr(&device_dir, "in_temp_ambient", "scale").unwrap(),
+            None
+        );
+    }
+
+    #[tokio::test]
+    async fn applies_offset_and_scale_to_raw_values() {
+        let sysfs_root = sysfs_fixture();
+        let node = SysfsNode::new(Subsystem::Iio, sysfs_root.path(), "ads1015").unwrap();
+
+        let results = node
+            .measure_batch(&[
+                (SensorTypeEnum::Voltage, 0),
+                (SensorTypeEnum::Voltage, 1),
+                (SensorTypeEnum::Temperature, 0),
+                (SensorTypeEnum::Voltage, 2),
+            ])
+            .await
+            .unwrap();
+
+        // (1000 - 100) * 2 mV and (500 - 100) * 3 mV, the offset is shared
+        assert_close(*results[0].as_ref().unwrap(), 1.8);
+        assert_close(*results[1].as_ref().unwrap(), 1.2);
+        assert!(results[2].is_err());
+        assert!(results[3].is_err());
+    }
+
+    #[tokio::test]
+    async fn reads_processed_values_by_sensor_id() {
+        let sysfs_root = sysfs_fixture();
+        let iio = SysfsNode::new(
+            Subsystem::Iio,
+            sysfs_root.path(),
+            "iio:device1, 4:in_humidityrelative, 7:in_pressure",
+        )
+        .unwrap();
+
+        assert_close(
+            iio.measure(SensorTypeEnum::Humidity, 4).await.unwrap(),
+            45.123,
+        );
+        assert_close(
+            iio.measure(SensorTypeEnum::Pressure, 7).await.unwrap(),
+            101325.0,
+        );
+        assert!(iio.measure(SensorTypeEnum::Temperature, 0).await.is_err());
+
+        let hwmon = SysfsNode::new(Subsystem::Hwmon, sysfs_root.path(), "cpu_thermal").unwrap();
+        assert_close(
+            hwmon.measure(SensorTypeEnum::Temperature, 0).await.unwrap(),
+            48.25,
+        );
+        assert_eq!(hwmon.describe().await.unwrap().sensors.len(), 1);
+
+        assert!(SysfsNode::new(Subsystem::Iio, sysfs_root.path(), "bmp180").is_err());
+        assert!(
+            SysfsNode::new(Subsystem::Iio, sysfs_root.path(), "ads1015,0:in_current0").is_err()
+        );
+    }
+}
@@ -0,0 +1,485 @@
+//! End-to-end tests of nodes built by `SensorNodeRegistry::from_nodes` on top
+//! of the in-memory comm paths of `comm::mock`.
+
+use std::sync::{Arc, Mutex};
+
+use super::SensorNodeRegistry;
+
+use crate::comm::capture::create_replay_comm_task;
+use crate::comm::mock::{MockCommPaths, MockI2cBus, MockSerialTransport};
+use crate::comm::serial::CommError;
+use crate::comm::NodeError;
+use crate::db::models::Node;
+use crate::meteo::models::SensorTypeEnum;
+
+pub(super) fn node(public_id: u32, route_type: &str, route_param: Option<&str>) -> Node {
+    Node {
+        id: public_id as i32,
+        public_id: public_id as i32,
+        name: format!("{} node", route_type),
+        route_type: route_type.to_string(),
+        route_param: route_param.map(str::to_string),
+    }
+}
+
+pub(super) fn registry(nodes: Vec<Node>, comm_paths: &MockCommPaths) -> SensorNodeRegistry {
+    match SensorNodeRegistry::from_nodes(nodes, comm_paths) {
+        Ok(registry) => registry,
+        Err(e) => panic!("Building nodes failed: {}", e.message()),
+    }
+}
+
+pub(super) fn assert_close(val: f32, expected: f32) {
+    assert!(
+        (val - expected).abs() <= expected.abs() * 1e-4 + 1e-4,
+        "{} is not close to {}",
+        val,
+        expected
+    );
+}
+
+pub(super) fn node_error(e: &crate::utils::Error) -> Option<NodeError> {
+    e.downcast_ref::<NodeError>().copied()
+}
+
+#[tokio::test]
+async fn serial_node_measures_through_the_transport() {
+    let transport = Arc::new(
+        MockSerialTransport::new()
+            .expect(
+                3,
+                "METEO,GET_TEMPERATURE,0",
+                Ok("METEO,TEMPERATURE_REPLY,0,21.5"),
+            )
+            .expect(3, "METEO,GET_PRESSURE,1", Ok("METEO,RET_VAL,-1"))
+            .expect(3, "METEO,GET_HUMIDITY,0", Err(CommError::Timeout)),
+    );
+    let comm_paths = MockCommPaths::new().with_serial_path(1, transport.clone());
+    let registry = registry(vec![node(3, "serial", Some("1"))], &comm_paths);
+    let node = registry.get_node(3).expect("node not built");
+
+    let temperature = node.measure(SensorTypeEnum::Temperature, 0).await;
+    assert_close(temperature.expect("temperature reading failed"), 21.5);
+
+    let pressure_err = node
+        .measure(SensorTypeEnum::Pressure, 1)
+        .await
+        .expect_err("pressure reading should fail");
+    assert_eq!(node_error(&pressure_err), Some(NodeError::UnknownChannel));
+
+    let humidity_err = node
+        .measure(SensorTypeEnum::Humidity, 0)
+        .await
+        .expect_err("humidity reading should fail");
+    assert!(matches!(
+        humidity_err.downcast_ref::<CommError>(),
+        Some(CommError::Timeout)
+    ));
+
+    assert!(transport.is_exhausted());
+}
+
+#[tokio::test]
+async fn serial_node_batches_readings_in_one_message() {
+    let transport = Arc::new(MockSerialTransport::new().expect(
+        4,
+        "METEO,GET_MULTI,TEMPERATURE:0,HUMIDITY:0,PRESSURE:2",
+        Ok("METEO,MULTI_REPLY,TEMPERATURE:0:19.25,HUMIDITY:0:55,PRESSURE:2:ERR:-3"),
+    ));
+    let comm_paths = MockCommPaths::new().with_tcp_path(2, transport.clone());
+    let registry = registry(vec![node(4, "tcp", Some("2"))], &comm_paths);
+    let node = registry.get_node(4).expect("node not built");
+
+    let results = node
+        .measure_batch(&[
+            (SensorTypeEnum::Temperature, 0),
+            (SensorTypeEnum::Humidity, 0),
+            (SensorTypeEnum::Pressure, 2),
+        ])
+        .await
+        .expect("batch failed");
+
+    assert_close(*results[0].as_ref().expect("no temperature"), 19.25);
+    assert_close(*results[1].as_ref().expect("no humidity"), 55.0);
+    assert_eq!(
+        node_error(results[2].as_ref().expect_err("pressure should fail")),
+        Some(NodeError::SensorFault)
+    );
+    assert!(transport.is_exhausted());
+}
+
+#[tokio::test]
+async fn serial_node_falls_back_to_single_messages_without_get_multi() {
+    let transport = Arc::new(
+        MockSerialTransport::new()
+            .expect(
+                7,
+                "METEO,GET_MULTI,TEMPERATURE:0,VOLTAGE:1,HUMIDITY:0",
+                Ok("METEO,RET_VAL,-6"),
+            )
+            .expect(
+                7,
+                "METEO,GET_TEMPERATURE,0",
+                Ok("METEO,TEMPERATURE_REPLY,0,18.5"),
+            )
+            .expect(7, "METEO,GET_HUMIDITY,0", Ok("METEO,HUMIDITY_REPLY,0,61"))
+            // Remembered, the second batch skips GET_MULTI
+            .expect(
+                7,
+                "METEO,GET_TEMPERATURE,0",
+                Ok("METEO,TEMPERATURE_REPLY,0,18.75"),
+            ),
+    );
+    let comm_paths = MockCommPaths::new().with_serial_path(1, transport.clone());
+    let registry = registry(vec![node(7, "serial", Some("1"))], &comm_paths);
+    let node = registry.get_node(7).expect("node not built");
+
+    let results = node
+        .measure_batch(&[
+            (SensorTypeEnum::Temperature, 0),
+            (SensorTypeEnum::Voltage, 1),
+            (SensorTypeEnum::Humidity, 0),
+        ])
+        .await
+        .expect("batch failed");
+
+    assert_close(*results[0].as_ref().expect("no temperature"), 18.5);
+    assert_eq!(
+        node_error(results[1].as_ref().expect_err("voltage should fail")),
+        Some(NodeError::UnsupportedType)
+    );
+    assert_close(*results[2].as_ref().expect("no humidity"), 61.0);
+
+    let results = node
+        .measure_batch(&[(SensorTypeEnum::Temperature, 0)])
+        .await
+        .expect("second batch failed");
+    assert_close(*results[0].as_ref().expect("no temperature"), 18.75);
+
+    assert!(transport.is_exhausted());
+}
+
+#[tokio::test]
+async fn serial_node_keeps_get_multi_while_the_node_is_silent() {
+    let transport = Arc::new(
+        MockSerialTransport::new()
+            .expect(
+                8,
+                "METEO,GET_MULTI,PRESSURE:0,TEMPERATURE:0",
+                Err(CommError::Timeout),
+            )
+            .expect(8, "METEO,GET_PRESSURE,0", Err(CommError::Timeout))
+            .expect(
+                8,
+                "METEO,GET_MULTI,PRESSURE:0,TEMPERATURE:0",
+                Ok("METEO,MULTI_REPLY,PRESSURE:0:101325,TEMPERATURE:0:20"),
+            ),
+    );
+    let comm_paths = MockCommPaths::new().with_serial_path(1, transport.clone());
+    let registry = registry(vec![node(8, "serial", Some("1"))], &comm_paths);
+    let node = registry.get_node(8).expect("node not built");
+    let requests = [
+        (SensorTypeEnum::Pressure, 0),
+        (SensorTypeEnum::Temperature, 0),
+    ];
+
+    let err = node
+        .measure_batch(&requests)
+        .await
+        .expect_err("batch should time out");
+    assert!(matches!(
+        err.downcast_ref::<CommError>(),
+        Some(CommError::Timeout)
+    ));
+
+    let results = node
+        .measure_batch(&requests)
+        .await
+        .expect("second batch failed");
+    assert_close(*results[0].as_ref().expect("no pressure"), 101325.0);
+    assert_close(*results[1].as_ref().expect("no temperature"), 20.0);
+
+    assert!(transport.is_exhausted());
+}
+
+/// Writes a capture file of one ASCII framed session, each of `exchanges` a
+/// request and its reply payload, and returns its path.
+fn write_capture(name: &str, exchanges: &[(u64, &str, &str)]) -> String {
+    fn frame(msg: &str) -> String {
+        let csum = msg.bytes().fold(0, |csum, ch| csum ^ ch);
+        format!("${}*{:02X}\\r\\n", msg, csum)
+    }
+
+    let mut contents = String::from("# 2024-01-01T00:00:00.000Z link 'test' up, framing ascii\n");
+
+    for (trans_id, request, reply) in exchanges {
+        contents += &format!("2024-01-01T00:00:00.000Z tx {}\n", frame(request));
+        contents += &format!(
+            "2024-01-01T00:00:00.100Z rx {}\n",
+            frame(&format!("{},{}", trans_id, reply))
+        );
+    }
+
+    let path =
+        std::env::temp_dir().join(format!("ratfist-{}-{}.capture", name, std::process::id()));
+    std::fs::write(&path, contents).expect("writing capture failed");
+    path.to_string_lossy().into_owned()
+}
+
+#[tokio::test]
+async fn replay_fails_once_the_requests_diverge() {
+    let capture_path = write_capture(
+        "diverge",
+        &[
+            (
+                5,
+                "5,9,METEO,GET_TEMPERATURE,0",
+                "METEO,TEMPERATURE_REPLY,0,20.5",
+            ),
+            (6, "6,9,METEO,GET_HUMIDITY,0", "METEO,HUMIDITY_REPLY,0,40"),
+        ],
+    );
+    let (replay_tx, _) = create_replay_comm_task(&capture_path).expect("loading capture failed");
+    let comm_paths = MockCommPaths::new().with_serial_path(1, Arc::new(replay_tx));
+    let registry = registry(vec![node(9, "serial", Some("1"))], &comm_paths);
+    let node = registry.get_node(9).expect("node not built");
+
+    let temperature = node.measure(SensorTypeEnum::Temperature, 0).await;
+    assert_close(temperature.expect("temperature reading failed"), 20.5);
+
+    let pressure_err = node
+        .measure(SensorTypeEnum::Pressure, 0)
+        .await
+        .expect_err("diverging request should fail");
+    assert!(pressure_err.downcast_ref::<CommError>().is_some());
+
+    // The rest of the capture isn't replayed either
+    assert!(node.measure(SensorTypeEnum::Humidity, 0).await.is_err());
+
+    let _ = std::fs::remove_file(capture_path);
+}
+
+#[tokio::test]
+async fn replay_rejects_request_with_transaction_id_0() {
+    let capture_path = write_capture(
+        "trans-id-0",
+        &[(0, "0,9,METEO,GET_TEMPERATURE,0", "METEO,RET_VAL,-1")],
+    );
+
+    assert!(create_replay_comm_task(&capture_path).is_err());
+
+    let _ = std::fs::remove_file(capture_path);
+}
+
+/// Sets up a BMP280 (or BME280, by `chip_id`) at `addr` with the compensation
+/// example of the BMP280 datasheet.
+fn set_bmp280_example(bus: &mut MockI2cBus, addr: u16, chip_id: u8) {
+    bus.set_registers(addr, 0xd0, &[chip_id]);
+    let calib = [
+        27504i32, 26435, -1000, 36477, -10685, 3024, 2855, 140, -7, 15500, -14600, 6000,
+    ]
+    .iter()
+    .flat_map(|&val| (val as u16).to_le_bytes())
+    .collect::<Vec<_>>();
+    bus.set_registers(addr, 0x88, &calib);
+    // adc_P = 415148, adc_T = 519888
+    bus.set_registers(addr, 0xf7, &[0x65, 0x5a, 0xc0, 0x7e, 0xed, 0x00]);
+}
+
+/// Bus with all the Enviro pHAT chips. The BMP280 holds the compensation
+/// example of its datasheet, the TCS3472 a valid reading at 1x gain.
+fn enviro_phat_bus() -> MockI2cBus {
+    let mut bus = MockI2cBus::new()
+        .with_device(0x77, 0xff)
+        .with_device(0x29, 0x1f)
+        .with_device(0x1d, 0x7f)
+        .with_word_device(0x49);
+
+    set_bmp280_example(&mut bus, 0x77, 0x58);
+
+    // Status (valid), then clear 20000, red 8000, green 7000 and blue 6000
+    bus.set_registers(0x29, 0x12, &[0x44, 0x01]);
+    bus.set_registers(
+        0x29,
+        0x14,
+        &[0x20, 0x4e, 0x40, 0x1f, 0x58, 0x1b, 0x70, 0x17],
+    );
+
+    bus.set_registers(0x1d, 0x0f, &[0x49]);
+
+    // Conversion done
+    bus.set_registers(0x49, 0x00, &[0x2e, 0xe0, 0x85, 0x83]);
+
+    bus
+}
+
+#[tokio::test]
+async fn enviro_phat_reads_bmp280_and_tcs3472() {
+    let bus = Arc::new(Mutex::new(enviro_phat_bus()));
+    let comm_paths = MockCommPaths::new().with_i2c_path(1, bus.clone());
+    let registry = registry(vec![node(5, "envirophat", Some("1"))], &comm_paths);
+    let node = registry.get_node(5).expect("node not built");
+
+    let results = node
+        .measure_batch(&[
+            (SensorTypeEnum::Temperature, 0),
+            (SensorTypeEnum::Pressure, 0),
+            (SensorTypeEnum::ColorRed, 0),
+            (SensorTypeEnum::LightLevel, 0),
+            (SensorTypeEnum::Illuminance, 0),
+        ])
+        .await
+        .expect("batch failed");
+
+    let values = results
+        .into_iter()
+        .map(|result| result.map_err(|e| e.message()))
+        .collect::<Result<Vec<_>, _>>()
+        .expect("reading failed");
+
+    assert_close(values[0], 25.08);
+    assert_close(values[1], 100653.27);
+    assert_close(values[2], 8000.0 / 65535.0);
+    assert_close(values[3], 20000.0 / 65535.0);
+    // (0.136 * 7500 + 6500 - 0.444 * 5500) / (153.6 / 310), IR removed
+    assert_close(values[4], 10248.6);
+
+    let regs = *bus.lock().unwrap().registers(0x29).expect("no TCS3472");
+    assert_eq!(regs[0x00], 0x03, "TCS3472 not enabled");
+    assert_eq!(regs[0x0f], 0x00, "TCS3472 not at 1x gain");
+}
+
+#[tokio::test]
+async fn enviro_phat_reports_unsupported_types_and_chip_failures() {
+    let bus = Arc::new(Mutex::new(enviro_phat_bus()));
+    let comm_paths = MockCommPaths::new().with_i2c_path(1, bus.clone());
+    let registry = registry(vec![node(5, "envirophat", Some("1"))], &comm_paths);
+    let node = registry.get_node(5).expect("node not built");
+
+    bus.lock().unwrap().unplug(0x77);
+
+    let results = node
+        .measure_batch(&[
+            (SensorTypeEnum::WindSpeed, 0),
+            (SensorTypeEnum::Humidity, 0),
+            (SensorTypeEnum::Temperature, 0),
+            (SensorTypeEnum::Pressure, 0),
+        ])
+        .await
+        .expect("batch failed");
+
+    let errors = results
+        .into_iter()
+        .map(|result| result.expect_err("reading should fail"))
+        .collect::<Vec<_>>();
+
+    assert_eq!(node_error(&errors[0]), Some(NodeError::UnsupportedType));
+
+    // The BMP280 is read once, and its error reported for all its sensors
+    for err in &errors[1..] {
+        assert!(
+            err.message().contains("BMP280 read failed"),
+            "unexpected error: {}",
+            err.message()
+        );
+    }
+}
+
+#[tokio::test]
+async fn enviro_phat_reads_scaled_voltages() {
+    // Env variables are process wide, the node ID keeps them to this test
+    std::env::set_var("NODE_8_ADS1015_GAIN", "2.048");
+    std::env::set_var("NODE_8_VOLTAGE_2_SCALE", "3");
+    std::env::set_var("NODE_8_VOLTAGE_2_OFFSET", "0.25");
+
+    let mut bus = enviro_phat_bus();
+    // -500, left aligned in the 12-bit conversion register
+    bus.set_registers(0x49, 0x00, &[0xe0, 0xc0]);
+
+    let bus = Arc::new(Mutex::new(bus));
+    let comm_paths = MockCommPaths::new().with_i2c_path(1, bus.clone());
+    let registry = registry(vec![node(8, "envirophat", Some("1"))], &comm_paths);
+    let node = registry.get_node(8).expect("node not built");
+
+    let results = node
+        .measure_batch(&[(SensorTypeEnum::Voltage, 0), (SensorTypeEnum::Voltage, 2)])
+        .await
+        .expect("batch failed");
+
+    let values = results
+        .into_iter()
+        .map(|result| result.map_err(|e| e.message()))
+        .collect::<Result<Vec<_>, _>>()
+        .expect("reading failed");
+
+    // -500 * 2.048 V / 2048, as is on channel 0 and * 3 + 0.25 on channel 2
+    assert_close(values[0], -0.5);
+    assert_close(values[1], -1.25);
+
+    let regs = *bus.lock().unwrap().registers(0x49).expect("no ADS1015");
+    let config = u16::from_be_bytes([regs[2], regs[3]]);
+    assert_eq!(config >> 12 & 0x7, 0b110, "ADS1015 not muxed to AIN2");
+    assert_eq!(config >> 9 & 0x7, 0b010, "ADS1015 not at 2.048 V");
+
+    let err = node
+        .measure(SensorTypeEnum::Voltage, 4)
+        .await
+        .expect_err("channel 4 should not exist");
+    assert_eq!(node_error(&err), Some(NodeError::UnknownChannel));
+}
+
+#[tokio::test]
+async fn enviro_phat_fails_without_chips() {
+    let bus = Arc::new(Mutex::new(MockI2cBus::new()));
+    let comm_paths = MockCommPaths::new().with_i2c_path(1, bus);
+
+    assert!(
+        SensorNodeRegistry::from_nodes(vec![node(6, "envirophat", Some("1"))], &comm_paths)
+            .is_err()
+    );
+}
+
+#[tokio::test]
+async fn bme280_reads_humidity() {
+    let mut bus = MockI2cBus::new().with_device(0x76, 0xff);
+    set_bmp280_example(&mut bus, 0x76, 0x60);
+
+    // dig_H1 = 75, dig_H2 = 362, dig_H3 = 0, dig_H4 = 324 (0x144),
+    // dig_H5 = 50 (0x032), dig_H6 = 30. H4 and H5 share the nibbles of 0xe5.
+    bus.set_registers(0x76, 0xa1, &[75]);
+    bus.set_registers(0x76, 0xe1, &[0x6a, 0x01, 0x00, 0x14, 0x24, 0x03, 0x1e]);
+    // adc_H = 0x7000
+    bus.set_registers(0x76, 0xfd, &[0x70, 0x00]);
+
+    let bus = Arc::new(Mutex::new(bus));
+    let comm_paths = MockCommPaths::new().with_i2c_path(1, bus.clone());
+    let registry = registry(vec![node(7, "bme280", Some("1,0x76"))], &comm_paths);
+    let node = registry.get_node(7).expect("node not built");
+
+    let capabilities = node.describe().await.expect("describe failed");
+    assert!(capabilities
+        .sensors
+        .iter()
+        .any(|sensor| sensor.sensor_type == SensorTypeEnum::Humidity));
+
+    let values = node
+        .measure_batch(&[
+            (SensorTypeEnum::Temperature, 0),
+            (SensorTypeEnum::Pressure, 0),
+            (SensorTypeEnum::Humidity, 0),
+        ])
+        .await
+        .expect("batch failed")
+        .into_iter()
+        .map(|result| result.map_err(|e| e.message()))
+        .collect::<Result<Vec<_>, _>>()
+        .expect("reading failed");
+
+    assert_close(values[0], 25.08);
+    assert_close(values[1], 100653.27);
+    // t_fine = 128422.3, per section 4.2.3 of the BME280 datasheet
+    assert_close(values[2], 43.6809);
+
+    let regs = *bus.lock().unwrap().registers(0x76).expect("no BME280");
+    assert_eq!(regs[0xf2], 0x01, "BME280 humidity oversampling not set");
+}
@@ -0,0 +1,281 @@
+use super::{reading_error, NodeCapabilities, SensorCapability, SensorNode};
+
+use crate::comm::i2c::Message;
+use crate::comm::{NodeError, SharedI2cTransport};
+
+use crate::meteo::models::SensorTypeEnum;
+
+use std::time::Duration;
+
+use crate::utils::Result;
+use anyhow::anyhow;
+
+use log::{debug, warn};
+
+/// ALS gains, in the order auto ranging steps through them.
+const GAINS: [(u16, f32); 4] = [(0b10, 0.125), (0b11, 0.25), (0b00, 1.0), (0b01, 2.0)];
+
+/// ALS integration times in milliseconds, shortest first.
+const INTEGRATION_TIMES: [(u16, u32); 6] = [
+    (0b1100, 25),
+    (0b1000, 50),
+    (0b0000, 100),
+    (0b0001, 200),
+    (0b0010, 400),
+    (0b0011, 800),
+];
+
+/// Vishay VEML7700 ambient light sensor, the `veml7700` route type.
+pub struct Veml7700Node {
+    comm_path: SharedI2cTransport,
+    addr: u16,
+    /// Held while measuring, so commands of concurrent readings don't mix.
+    measurement_lock: tokio::sync::Mutex<()>,
+}
+
+impl Veml7700Node {
+    pub const I2C_ADDR_DEFAULT: u16 = 0x10;
+
+    const ALS_CONF_REG_ADDR: u8 = 0x00;
+    const ALS_CONF_GAIN_SHIFT: u16 = 11;
+    const ALS_CONF_IT_SHIFT: u16 = 6;
+    const ALS_CONF_SD: u16 = 0x0001;
+
+    const ALS_REG_ADDR: u8 = 0x04;
+
+    const ID_REG_ADDR: u8 = 0x07;
+    const ID_EXPECTED: u8 = 0x81;
+
+    /// Index of the 100 ms integration time, where auto ranging starts.
+    const IT_INDEX_START: usize = 2;
+
+    /// Counts auto ranging keeps the reading above, by raising gain and
+    /// integration time, and below, by shortening the integration time (see
+    /// the "Designing the VEML7700 Into an Application" note).
+    const AUTO_RANGE_LOW: u16 = 100;
+    const AUTO_RANGE_HIGH: u16 = 10000;
+
+    /// Lux per count at the highest gain and longest integration time.
+    const RESOLUTION_MAX: f32 = 0.0042;
+
+    /// Coefficients of the polynomial correcting the non-linearity at high
+    /// illuminance, highest power first.
+    const CORRECTION_COEFS: [f32; 4] = [6.0135e-13, -9.3924e-9, 8.1488e-5, 1.0023];
+    const CORRECTION_THRESHOLD_LUX: f32 = 100.0;
+
+    pub fn new(comm_path: SharedI2cTransport, addr: u16) -> Result<Veml7700Node> {
+        let node = Veml7700Node {
+            comm_path,
+            addr,
+            measurement_lock: tokio::sync::Mutex::new(()),
+        };
+
+        // Check we have the correct sensor
+        debug!("Reading out chip ID");
+        let id = node.read_reg(Self::ID_REG_ADDR)?.to_le_bytes()[0];
+
+        debug!("Chip ID is {}", id);
+
+        if id != Self::ID_EXPECTED {
+            return Err(anyhow!(
+                "VEML7700 unexpected chip ID (0x{:X}) at address 0x{:X}",
+                id,
+                addr
+            )
+            .into());
+        }
+
+        // Stays shut down between readings
+        node.write_reg(Self::ALS_CONF_REG_ADDR, Self::ALS_CONF_SD)?;
+
+        Ok(node)
+    }
+
+    fn write_reg(&self, reg_addr: u8, value: u16) -> Result<()> {
+        let value_bytes = value.to_le_bytes();
+        let data = [reg_addr, value_bytes[0], value_bytes[1]];
+
+        self.comm_path
+            .lock()
+            .expect("Mutex poisoned.")
+            .transfer(&mut [Message::write(self.addr, &data)])
+    }
+
+    fn read_reg(&self, reg_addr: u8) -> Result<u16> {
+        let reg_addr_data = [reg_addr];
+        let mut data = [0; 2];
+
+        self.comm_path
+            .lock()
+            .expect("Mutex poisoned.")
+            .transfer(&mut [
+                Message::write(self.addr, &reg_addr_data),
+                Message::read(self.addr, &mut data),
+            ])?;
+
+        Ok(u16::from_le_bytes(data))
+    }
+
+    /// Powers up with the given gain and integration time, waits for a full
+    /// integration and shuts down again.
+    async fn measure_raw(&self, gain_index: usize, it_index: usize) -> Result<u16> {
+        let (gain_bits, _) = GAINS[gain_index];
+        let (it_bits, it_ms) = INTEGRATION_TIMES[it_index];
+
+        let conf = gain_bits << Self::ALS_CONF_GAIN_SHIFT | it_bits << Self::ALS_CONF_IT_SHIFT;
+
+        self.write_reg(Self::ALS_CONF_REG_ADDR, conf)?;
+        tokio::time::sleep(Duration::from_millis((it_ms * 6 / 5 + 5) as u64)).await;
+        let raw = self.read_reg(Self::ALS_REG_ADDR)?;
+        self.write_reg(Self::ALS_CONF_REG_ADDR, conf | Self::ALS_CONF_SD)?;
+
+        Ok(raw)
+    }
+
+    /// Measures illuminance in lux, starting at the lowest gain and 100 ms,
+    /// then raising the gain and the integration time while the reading is
+    /// too low, or shortening the integration time while it is too high.
+    async fn query_lux(&self) -> Result<f32> {
+        let _measurement_guard = self.measurement_lock.lock().await;
+
+        let mut gain_index = 0;
+        let mut it_index = Self::IT_INDEX_START;
+        let mut raw = self.measure_raw(gain_index, it_index).await?;
+
+        if raw <= Self::AUTO_RANGE_LOW {
+            while raw <= Self::AUTO_RANGE_LOW && gain_index + 1 < GAINS.len() {
+                gain_index += 1;
+                raw = self.measure_raw(gain_index, it_index).await?;
+            }
+
+            while raw <= Self::AUTO_RANGE_LOW && it_index + 1 < INTEGRATION_TIMES.len() {
+                it_index += 1;
+                raw = self.measure_raw(gain_index, it_index).await?;
+            }
+        } else {
+            while raw > Self::AUTO_RANGE_HIGH && it_index > 0 {
+                it_index -= 1;
+                raw = self.measure_raw(gain_index, it_index).await?;
+            }
+        }
+
+        let (_, gain) = GAINS[gain_index];
+        let (_, it_ms) = INTEGRATION_TIMES[it_index];
+
+        if raw == u16::MAX {
+            warn!("VEML7700 saturated at gain {} and {} ms.", gain, it_ms);
+        }
+
+        let (_, gain_max) = GAINS[GAINS.len() - 1];
+        let (_, it_ms_max) = INTEGRATION_TIMES[INTEGRATION_TIMES.len() - 1];
+        let resolution =
+            Self::RESOLUTION_MAX * (gain_max / gain) * (it_ms_max as f32 / it_ms as f32);
+
+        let mut lux = raw as f32 * resolution;
+
+        if lux > Self::CORRECTION_THRESHOLD_LUX {
+            lux = Self::CORRECTION_COEFS
+                .iter()
+                .fold(0.0, |acc, coef| (acc + coef) * lux);
+        }
+
+        debug!(
+            "VEML7700 reading at gain {} and {} ms: raw {}, {} lx",
+            gain, it_ms, raw, lux
+        );
+
+        Ok(lux)
+    }
+}
+
+#[rocket::async_trait]
+impl SensorNode for Veml7700Node {
+    async fn measure(&self, measurement_type: SensorTypeEnum, sensor_id: u32) -> Result<f32> {
+        match measurement_type {
+            _ if sensor_id != 0 => Err(reading_error(
+                NodeError::UnknownChannel,
+                measurement_type,
+                sensor_id,
+            )),
+            SensorTypeEnum::Illuminance => self.query_lux().await,
+            _ => Err(reading_error(
+                NodeError::UnsupportedType,
+                measurement_type,
+                sensor_id,
+            )),
+        }
+    }
+
+    async fn describe(&self) -> Result<NodeCapabilities> {
+        Ok(NodeCapabilities {
+            firmware_version: None,
+            protocol_version: None,
+            sensors: vec![SensorCapability {
+                sensor_type: SensorTypeEnum::Illuminance,
+                sensor_id: 0,
+            }],
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use super::super::tests::assert_close;
+
+    use crate::comm::mock::MockI2cBus;
+
+    use std::sync::{Arc, Mutex};
+
+    fn veml7700_node() -> (Veml7700Node, Arc<Mutex<MockI2cBus>>) {
+        let mut bus = MockI2cBus::new().with_device(0x10, 0xff);
+        bus.set_registers(0x10, 0x07, &[0x81, 0xc4]);
+
+        let bus = Arc::new(Mutex::new(bus));
+        let node = Veml7700Node::new(bus.clone(), 0x10).expect("VEML7700 not found");
+
+        (node, bus)
+    }
+
+    /// Gain and integration time bits of the last configuration written.
+    fn last_conf(bus: &Mutex<MockI2cBus>) -> (u16, u16) {
+        let regs = *bus.lock().unwrap().registers(0x10).expect("no VEML7700");
+        let conf = u16::from_le_bytes([regs[0x00], regs[0x01]]);
+
+        (conf >> 11 & 0b11, conf >> 6 & 0b1111)
+    }
+
+    #[tokio::test]
+    async fn raises_gain_and_integration_time_in_the_dark() {
+        let (node, bus) = veml7700_node();
+
+        // 1/8, 1/4, 1 and 2 gain at 100 ms, then 200 ms
+        bus.lock()
+            .unwrap()
+            .queue_reads(0x10, &[&[50, 0], &[70, 0], &[90, 0], &[100, 0], &[210, 0]]);
+
+        // 210 * 0.0042 lx * 800 ms / 200 ms
+        assert_close(node.query_lux().await.expect("reading failed"), 3.528);
+        assert_eq!(last_conf(&bus), (0b01, 0b0001));
+    }
+
+    #[tokio::test]
+    async fn shortens_integration_time_and_corrects_bright_light() {
+        let (node, bus) = veml7700_node();
+
+        // 1/8 gain at 100, 50 and 25 ms
+        let reads = [20000u16, 12000, 6000]
+            .iter()
+            .map(|raw| raw.to_le_bytes())
+            .collect::<Vec<_>>();
+        bus.lock().unwrap().queue_reads(
+            0x10,
+            &reads.iter().map(|read| &read[..]).collect::<Vec<_>>(),
+        );
+
+        // 6000 * 0.0042 lx * 16 * 32 = 12902.4 lx before the correction
+        assert_close(node.query_lux().await.expect("reading failed"), 22988.876);
+        assert_eq!(last_conf(&bus), (0b10, 0b1100));
+    }
+}
@@ -0,0 +1,78 @@
+use std::convert::TryInto;
+use std::sync::mpsc::Receiver;
+use std::thread;
+
+use crate::comm::serial::PushMessage;
+use crate::db::models::Node;
+use crate::db::DbConnPool;
+
+use crate::meteo::fetcher::store_measurement;
+use crate::meteo::models::Sensor;
+use crate::meteo::node::{parse_reading, SensorNodeRegistry};
+
+use diesel::prelude::*;
+
+use crate::utils::{DateTimeUtc, Result};
+use anyhow::anyhow;
+
+use log::{debug, warn};
+
+/// Stores a reading pushed by a node like a fetched one, timestamped on
+/// arrival.
+fn store_pushed_reading(db_conn_pool: &DbConnPool, push_msg: &PushMessage) -> Result<()> {
+    let (pushed_type, pushed_sensor_id, measured_val) = parse_reading(&push_msg.payload)?;
+
+    let db = db_conn_pool
+        .get()
+        .map_err(|e| anyhow!("Failed to get DB connection. {e:?}"))?;
+
+    let node_public_id: i32 = push_msg.node_id.try_into().map_err(|e| anyhow!("{e:?}"))?;
+    let sensor_public_id: i32 = pushed_sensor_id.try_into().map_err(|e| anyhow!("{e:?}"))?;
+
+    let (sensor, _) = {
+        use crate::db::schema::nodes;
+        use crate::meteo::schema::sensors;
+
+        sensors::table
+            .inner_join(nodes::table)
+            .filter(nodes::public_id.eq(node_public_id))
+            .filter(sensors::public_id.eq(sensor_public_id))
+            .filter(sensors::sensor_type.eq(pushed_type))
+            .first::<(Sensor, Node)>(&db)
+            .map_err(|e| {
+                anyhow!(
+                    "No {} sensor {} on node {} for pushed reading. {:?}",
+                    pushed_type.as_ref(),
+                    pushed_sensor_id,
+                    push_msg.node_id,
+                    e
+                )
+            })?
+    };
+
+    store_measurement(&db, sensor.id, measured_val, &DateTimeUtc::now())
+}
+
+fn push_listener_func(db_conn_pool: DbConnPool, push_rx: Receiver<PushMessage>) {
+    for push_msg in push_rx {
+        debug!(
+            "Pushed by node {}: '{}'",
+            push_msg.node_id, push_msg.payload
+        );
+
+        if let Err(err) = store_pushed_reading(&db_conn_pool, &push_msg) {
+            warn!("Failed to store pushed reading: {err}");
+        }
+    }
+}
+
+/// Spawns the thread storing readings the nodes push on their own, next to
+/// the periodically fetched ones.
+pub fn start_push_listener(
+    db_conn_pool: DbConnPool,
+    node_registry: &SensorNodeRegistry,
+) -> thread::JoinHandle<()> {
+    let push_rx = node_registry.subscribe_push("METEO");
+
+    thread::spawn(move || push_listener_func(db_conn_pool, push_rx))
+}
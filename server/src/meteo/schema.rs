@@ -9,6 +9,16 @@ table! {
     }
 }
 
+table! {
+    measurement_errors (id) {
+        id -> Integer,
+        sensor_id -> Integer,
+        kind -> Text,
+        message -> Text,
+        occurred_at -> BigInt,
+    }
+}
+
 table! {
     sensors (id) {
         id -> Integer,
@@ -20,6 +30,7 @@ table! {
 }
 
 joinable!(measurements -> sensors (sensor_id));
+joinable!(measurement_errors -> sensors (sensor_id));
 joinable!(sensors -> nodes (node_id));
 
-allow_tables_to_appear_in_same_query!(measurements, nodes, sensors,);
+allow_tables_to_appear_in_same_query!(measurements, measurement_errors, nodes, sensors,);
@@ -7,6 +7,16 @@ table! {
     }
 }
 
+table! {
+    measurement_errors (id) {
+        id -> Integer,
+        sensor_id -> Integer,
+        kind -> Text,
+        message -> Text,
+        occurred_at -> Integer,
+    }
+}
+
 table! {
     nodes (id) {
         id -> Integer,
@@ -27,10 +37,12 @@ table! {
     }
 }
 
+joinable!(measurement_errors -> sensors (sensor_id));
 joinable!(measurements -> sensors (sensor_id));
 joinable!(sensors -> nodes (node_id));
 
 allow_tables_to_appear_in_same_query!(
+    measurement_errors,
     measurements,
     nodes,
     sensors,
@@ -10,6 +10,7 @@ use rocket::request::FromParam;
 use std::io::Cursor;
 
 use std::ops::Deref;
+use std::str::FromStr;
 
 use diesel::backend::Backend;
 use diesel::deserialize::{self, FromSql};
@@ -24,6 +25,49 @@ use std::io::Write;
 
 use anyhow::anyhow;
 
+use crate::comm::serial::CommError;
+use crate::comm::NodeError;
+
+/// Reads and parses an optional env variable, falling back to `default` when
+/// it is not set. A value that is set but fails to parse is an error.
+pub fn env_var_or<T>(env_var_str: &str, default: T) -> Result<T>
+where
+    T: FromStr,
+    T::Err: std::fmt::Debug,
+{
+    match dotenv::var(env_var_str) {
+        Ok(val_str) => val_str.trim().parse().map_err(|e| {
+            anyhow!("Invalid value '{val_str}' for {env_var_str} env variable. {e:?}").into()
+        }),
+        Err(_) => Ok(default),
+    }
+}
+
+/// Reads and parses all `<prefix><id>` env variables, keyed by their numeric
+/// ID suffix.
+#[cfg(feature = "meteo")]
+pub fn env_vars_by_id<T>(prefix: &str) -> Result<std::collections::BTreeMap<u32, T>>
+where
+    T: FromStr,
+    T::Err: std::fmt::Display,
+{
+    dotenv::vars()
+        .filter(|(var, _)| var.starts_with(prefix))
+        .map(|(var, val_str)| {
+            let id = var[prefix.len()..]
+                .parse::<u32>()
+                .map_err(|e| anyhow!("Invalid ID in {var} env variable. {e:?}"))?;
+
+            let val = val_str
+                .trim()
+                .parse()
+                .map_err(|e| anyhow!("Invalid value '{val_str}' for {var} env variable, {e}."))?;
+
+            Ok((id, val))
+        })
+        .collect()
+}
+
 #[derive(Debug, Clone)]
 pub struct IdRange(HashSet<u32>);
 
@@ -144,12 +188,68 @@ impl<'r> FromFormField<'r> for DateTimeUtc {
 #[derive(Debug)]
 pub struct Error(anyhow::Error);
 
+impl Error {
+    /// Looks for an error of type `E` anywhere in the chain of this one.
+    pub fn downcast_ref<E>(&self) -> Option<&E>
+    where
+        E: std::fmt::Display + std::fmt::Debug + Send + Sync + 'static,
+    {
+        self.0.downcast_ref::<E>()
+    }
+
+    /// Wraps the error with `context`, keeping it in the chain.
+    pub fn context<C>(self, context: C) -> Error
+    where
+        C: std::fmt::Display + Send + Sync + 'static,
+    {
+        Error(self.0.context(context))
+    }
+
+    /// Copy of the error, for when it fails several readings at once. Keeps
+    /// the node or comm error in its chain, so that it's reported the same.
+    pub fn duplicate(&self) -> Error {
+        let message = self.message();
+
+        if let Some(node_err) = self.downcast_ref::<NodeError>() {
+            Error(anyhow::Error::new(*node_err).context(message))
+        } else if let Some(comm_err) = self.downcast_ref::<CommError>() {
+            Error(anyhow::Error::new(*comm_err).context(message))
+        } else {
+            Error(anyhow!(message))
+        }
+    }
+
+    /// The error message, including the context it was wrapped in.
+    pub fn message(&self) -> String {
+        format!("{:#}", self.0)
+    }
+
+    /// HTTP status telling a faulty sensor from a faulty link to its node.
+    fn status(&self) -> Status {
+        if let Some(node_err) = self.downcast_ref::<NodeError>() {
+            match node_err {
+                NodeError::UnknownChannel => Status::NotFound,
+                NodeError::UnsupportedType => Status::NotImplemented,
+                NodeError::NotReady | NodeError::Busy => Status::ServiceUnavailable,
+                NodeError::SensorFault | NodeError::Other(_) => Status::BadGateway,
+            }
+        } else if let Some(comm_err) = self.downcast_ref::<CommError>() {
+            match comm_err {
+                CommError::LinkDown => Status::ServiceUnavailable,
+                CommError::Timeout => Status::GatewayTimeout,
+            }
+        } else {
+            Status::InternalServerError
+        }
+    }
+}
+
 impl<'r> Responder<'r, 'static> for Error {
     fn respond_to(self, _: &'r Request<'_>) -> response::Result<'static> {
-        let err_str = self.0.to_string();
+        let err_str = self.message();
         Ok(
             Response::build()
-                .status(Status::InternalServerError)
+                .status(self.status())
                 .sized_body(err_str.len(), Cursor::new(err_str))
                 .finalize()
         )